@@ -1,26 +1,145 @@
+use crate::args::AnonCommand;
+use crate::certificate::{
+    effective_config, file_digest, policy_hash, write_certificate, RunSummary,
+};
+use crate::consolidate::merge_series;
+use crate::dicomdir::write_dicomdir;
+use crate::mapping::{read_mapping_store, write_mapping_store};
+use crate::notify::send_run_report;
+use crate::runs::record_run;
+use crate::sidecar::scrub_sidecars;
 use anyhow::Result;
 use crossbeam::sync::WaitGroup;
-use dcmrig_rs::*;
+use dcmrig_rs::delivery::deliver_archives;
+use dcmrig_rs::pipeline::{FileRun, FileTransform};
+use dcmrig_rs::{
+    confidentiality::StandardProfile,
+    frames::{output_instances, OutputInstance},
+    tracker::RunTracker,
+    *,
+};
 use dicom::{
-    core::{DataElement, VR},
-    dicom_value,
     dictionary_std::tags,
-    object::{open_file, FileDicomObject, InMemDicomObject},
+    object::{FileDicomObject, InMemDicomObject, Tag},
 };
-use rayon::prelude::*;
 use std::{
-    collections::HashMap,
-    fs::File,
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    collections::{BTreeSet, HashMap},
+    fs::{self, create_dir_all},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::Mutex,
+    time::Instant,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
-pub fn dicom_anon(
-    source_path: PathBuf,
-    destination_path: PathBuf,
-    anon_prefix: String,
-) -> Result<()> {
+// Identifiers replaced in the output, one per kind and original value
+#[derive(Default)]
+struct ReidTable {
+    // Kind, original, anonymized
+    entries: Mutex<BTreeSet<(&'static str, String, String)>>,
+}
+
+impl ReidTable {
+    // Tags whose original and anonymized values are kept besides the patient
+    const TAGS: [(&'static str, Tag); 4] = [
+        ("AccessionNumber", tags::ACCESSION_NUMBER),
+        ("StudyInstanceUID", tags::STUDY_INSTANCE_UID),
+        ("SeriesInstanceUID", tags::SERIES_INSTANCE_UID),
+        ("SOPInstanceUID", tags::SOP_INSTANCE_UID),
+    ];
+
+    fn record(
+        &self,
+        patient: &str,
+        anon_id: &str,
+        source: &FileDicomObject<InMemDicomObject>,
+        new: &FileDicomObject<InMemDicomObject>,
+    ) {
+        let value_of = |dcm_obj: &FileDicomObject<InMemDicomObject>, tag: Tag| {
+            dcm_obj
+                .element(tag)
+                .ok()
+                .and_then(|element| element.to_str().ok())
+                .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+                .unwrap_or_default()
+        };
+        let mut entries = self.entries.lock().expect("Failed to lock mutex");
+        entries.insert(("Patient", patient.to_string(), anon_id.to_string()));
+        for (kind, tag) in ReidTable::TAGS {
+            let original = value_of(source, tag);
+            if !original.is_empty() {
+                entries.insert((kind, original, value_of(new, tag)));
+            }
+        }
+    }
+
+    // JSON for a .json path, kind,original,anonymized CSV otherwise
+    fn write(&self, output: &Path) -> Result<()> {
+        let entries = self.entries.lock().expect("Failed to lock mutex");
+        let json = output
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+        let content = match json {
+            true => {
+                let rows: Vec<String> = entries
+                    .iter()
+                    .map(|(kind, original, anonymized)| {
+                        format!(
+                            "  {{\"kind\": {}, \"original\": {}, \"anonymized\": {}}}",
+                            json_string(kind),
+                            json_string(original),
+                            json_string(anonymized)
+                        )
+                    })
+                    .collect();
+                format!("[\n{}\n]\n", rows.join(",\n"))
+            }
+            false => {
+                let mut csv = String::from("kind,original,anonymized\n");
+                for (kind, original, anonymized) in entries.iter() {
+                    csv.push_str(&format!(
+                        "{},{},{}\n",
+                        kind,
+                        csv_field(original),
+                        csv_field(anonymized)
+                    ));
+                }
+                csv
+            }
+        };
+        if let Some(parent) = output
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            create_dir_all(parent)?;
+        }
+        fs::write(output, content)?;
+        warn!(
+            "Re-identification table with {} entries written to {}, keep it away from the output",
+            entries.len(),
+            output.display()
+        );
+        Ok(())
+    }
+}
+
+pub fn dicom_anon(anon_command: AnonCommand, run_options: RunOptions) -> Result<()> {
+    let AnonCommand {
+        prefix: anon_prefix,
+        prefix_lookup,
+        key_tag,
+        profile: profile_path,
+        standard_profile,
+        standard_options,
+        date_shift,
+        id_mode,
+        private_tags,
+        private_allowlist,
+        mapping_out,
+        mapping_db,
+        source: source_path,
+        destination: destination_path,
+    } = anon_command;
     info!(
         "Anonymizing the data for >> SOURCE: {} | DESTINATION: {} | ANON PREFIX: {}",
         source_path.display(),
@@ -28,137 +147,564 @@ pub fn dicom_anon(
         &anon_prefix
     );
 
-    // Set up required variables
-    let (all_files, total_len, pb) = preprocessing_setup(&source_path, &destination_path)?;
-    let failed_case: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    let non_dcm_cases: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    let anon_id_tracker: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
-    let wg = WaitGroup::new();
-
-    // Main Loop
-    all_files
-        .par_iter()
-        .enumerate()
-        .for_each(|(_index, working_path)| {
-            if let Ok(dcm_obj) = open_file(working_path.path()) {
-                let anon_id_clone = Arc::clone(&anon_id_tracker);
-                anon_each_dcm_file(
-                    &dcm_obj,
-                    &destination_path,
-                    anon_id_clone,
-                    &anon_prefix,
-                    wg.clone(),
-                )
-                .unwrap_or_else(|_| {
-                    let mut map = failed_case.lock().expect("Failed to lock mutex");
-                    *map += 1;
-                    error!(
-                        "Can't ANON {:#?} Copying to FAILED_CASES directory",
-                        &working_path.file_name()
-                    );
-                    failed_case_copy(&working_path.clone().into_path(), &destination_path)
-                        .expect("Failed to copy file to FAILED_CASES directory");
-                });
-            } else {
-                let nwg = wg.clone();
-                let mut map = non_dcm_cases.lock().expect("Failed to lock mutex");
-                *map += 1;
-                copy_non_dicom_files(&working_path, &destination_path).unwrap_or_else(|_| {
-                    error!("Can't copy non dicom file {:#?}", &working_path.file_name())
-                });
-                drop(nwg);
-            }
-            pb.inc(1);
-        });
-    pb.finish();
-    print_status(
-        total_len,
-        *failed_case.lock().expect("Failed to lock mutex"),
-        *non_dcm_cases.lock().expect("Failed to lock mutex"),
-        "Anon".to_string(),
+    let prefix = AnonPrefix::new(&anon_prefix, prefix_lookup.as_deref()).unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(1)
+    });
+
+    let (key, _) = extract_tag_vr_from_str(&key_tag).unwrap_or_else(|_| {
+        error!("Key tag is not a valid tag: {}", key_tag);
+        exit(1)
+    });
+    // The shards own the patients of the key tag, the ones the ANON IDs are given to
+    let run_options = RunOptions {
+        shard: run_options.shard.map(|shard| shard.keyed_on(key)),
+        ..run_options
+    };
+
+    let site_profile = profile_path.as_ref().map(|profile_path| {
+        AnonProfile::from_file(profile_path).unwrap_or_else(|e| {
+            error!("{}", e);
+            exit(1)
+        })
+    });
+
+    let standard_profile = standard_profile.then(|| {
+        StandardProfile::new(standard_options, run_options.uid_mapper.clone()).unwrap_or_else(|e| {
+            error!("{}", e);
+            exit(1)
+        })
+    });
+
+    let private_allowlist = match (private_tags, &private_allowlist) {
+        (PrivateTagPolicy::Allowlist, Some(allowlist_path)) => Some(
+            PrivateAllowlist::from_file(allowlist_path).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            }),
+        ),
+        (PrivateTagPolicy::Allowlist, None) => {
+            error!("--private-tags allowlist needs --private-allowlist <FILE>");
+            exit(1)
+        }
+        (_, Some(_)) => {
+            error!("--private-allowlist is only used with --private-tags allowlist");
+            exit(1)
+        }
+        (_, None) => None,
+    };
+    if private_tags == PrivateTagPolicy::Keep {
+        warn!("Private tags are kept, vendor elements can hold patient information");
+    }
+
+    if id_mode == IdMode::Hash && run_options.uid_mapper.secret_path.is_none() {
+        error!("--id-mode hash needs --uid-secret <FILE>, the ANON IDs are derived from it");
+        exit(1)
+    }
+
+    if date_shift && run_options.date_precision.is_some() {
+        error!("--date-shift and --reduce-date-precision can't be used together");
+        exit(1)
+    }
+
+    let private_summary = match &private_allowlist {
+        Some(private_allowlist) => format!("Allowlist {}", private_allowlist.summary()),
+        None => match private_tags {
+            PrivateTagPolicy::Keep => "Kept".to_string(),
+            _ => "Deleted".to_string(),
+        },
+    };
+    let profile = anon_profile(
+        &run_options,
+        &site_profile,
+        &standard_profile,
+        date_shift,
+        private_summary,
+    );
+    let command = [
+        ("Action".to_string(), "Anon".to_string()),
+        ("Anon prefix".to_string(), anon_prefix.clone()),
+        ("Prefix lookup".to_string(), lookup_summary(&prefix_lookup)),
+        ("Key tag".to_string(), key_tag.clone()),
+        ("ID mode".to_string(), format!("{:?}", id_mode)),
+        ("Anon profile".to_string(), lookup_summary(&profile_path)),
+    ];
+    let config = effective_config(&command, &profile, &run_options);
+    if run_options.print_effective_config {
+        println!("{}", config);
+        return Ok(());
+    }
+
+    let policy = policy_hash(&command, &profile, &run_options);
+    let mut run = FileRun::start(
+        "Anon",
+        &policy,
+        &source_path,
+        &destination_path,
+        &run_options,
     )?;
-    wg.wait();
+    let mut stored_ids = match &mapping_db {
+        Some(mapping_db) => read_mapping_store(mapping_db)?,
+        None => HashMap::new(),
+    };
+    // The ANON IDs given before the resume, the mapping DB keeps its own
+    if let Some(checkpoint) = &run.checkpoint {
+        for (key, anon_id) in &checkpoint.anon_ids {
+            stored_ids
+                .entry(key.clone())
+                .or_insert_with(|| anon_id.clone());
+        }
+    }
+    let reid_table = mapping_out.as_ref().map(|_| ReidTable::default());
+    let anonymizer = Anonymizer::new(AnonConfig {
+        prefix,
+        key_tag: key_tag.clone(),
+        date_precision: run_options.date_precision,
+        date_shift,
+        id_mode,
+        description_map: run_options.description_map.clone(),
+        site_profile,
+        standard_profile,
+        rules: run_options.rules.clone(),
+        study_values: run_options.study_values.clone(),
+        uid_mapper: run_options.uid_mapper.clone(),
+        review_policy: run_options.review_policy.clone(),
+        annotation_text: run_options.annotation_text,
+        downsample: run_options.downsample,
+        split_frames: run_options.split_frames,
+        transcode: run_options.transcode,
+        pixel_mask: run_options.pixel_mask.clone(),
+        date_order: run_options.date_order,
+        fix_vr: run_options.fix_vr,
+        keep_legacy_tags: run_options.keep_legacy_tags,
+        private_tags,
+        private_allowlist,
+    })
+    .with_anon_ids(stored_ids);
+    run.save_checkpoints(&run_options, Some(anonymizer.anon_id_store()));
+    let transform = AnonTransform {
+        anonymizer: &anonymizer,
+        reid_table: &reid_table,
+        mapping_db: &mapping_db,
+        mapping_out: &mapping_out,
+        dry_run: run_options.dry_run,
+    };
+    run.process(&transform, &destination_path, &run_options)?;
+    let FileRun {
+        tracker,
+        total_len,
+        checkpoint_writer,
+        status_writer,
+        failed,
+        non_dicom,
+        ..
+    } = run;
+    tracker.progress.finish();
+    print_slowest_files(&tracker.timings, run_options.slowest)?;
+    tracker.print_series_classes();
+    tracker.print_routing();
+    tracker.print_mixed_patients();
+    scrub_sidecars(&tracker, &destination_path)?;
+    merge_series(&run_options, &tracker, &destination_path)?;
+    if let Some(checkpoint_writer) = checkpoint_writer {
+        checkpoint_writer.finish();
+    }
+    let manifest_path = tracker.write_manifest(&destination_path)?;
+    tracker.run_study_hooks();
+    deliver_archives(&run_options, &tracker, &destination_path)?;
+    write_dicomdir(&run_options, &tracker, &destination_path)?;
+    tracker.print_collisions();
+    tracker.print_long_paths();
+    match run_options.dry_run {
+        true => tracker.write_dry_run_report(run_options.dry_run_report.as_deref())?,
+        false => tracker.write_results(&destination_path)?,
+    }
+    tracker.report_completeness(&destination_path)?;
+    tracker.report_modality_mismatches(&destination_path)?;
+    tracker.report_duplicates(&destination_path)?;
+    tracker.report_tag_changes(&destination_path)?;
+    transform.save()?;
+    if let Some(status_writer) = status_writer {
+        status_writer.finish();
+    }
+    if !run_options.dry_run
+        && (run_options.certificate
+            || run_options.email.is_some()
+            || run_options.run_name.is_some())
+    {
+        let summary = RunSummary {
+            action: "Anon".to_string(),
+            counts: RunSummary::run_counts(total_len, failed, non_dicom, &tracker),
+            tag_changes: tracker.tag_change_lines(),
+            source: source_path,
+            destination: destination_path,
+            profile,
+            options: vec![
+                ("Anon prefix".to_string(), anon_prefix.clone()),
+                ("Prefix lookup".to_string(), lookup_summary(&prefix_lookup)),
+                ("Key tag".to_string(), key_tag.clone()),
+                ("ID mode".to_string(), format!("{:?}", id_mode)),
+                ("Anon profile".to_string(), lookup_summary(&profile_path)),
+                ("Mapping store".to_string(), lookup_summary(&mapping_db)),
+                ("Shard".to_string(), run_options.shard_summary()),
+                (
+                    "Manifest SHA-256".to_string(),
+                    match &manifest_path {
+                        Some(manifest_path) => file_digest(manifest_path),
+                        None => "NA".to_string(),
+                    },
+                ),
+            ],
+            config,
+        };
+        if run_options.certificate {
+            write_certificate(&summary, &run_options)?;
+        }
+        send_run_report(&summary, &run_options, &tracker);
+        record_run(&summary, &run_options)?;
+    }
     info!("DICOM Anon complete!");
     Ok(())
 }
 
+// Path and SHA-256 of the prefix lookup, the anon profile or the mapping store for the certificate
+fn lookup_summary(input_path: &Option<PathBuf>) -> String {
+    match input_path {
+        Some(lookup_path) => format!("{} ({})", lookup_path.display(), file_digest(lookup_path)),
+        None => "None".to_string(),
+    }
+}
+
+// Profile entries of the Anon for the certificate
+fn anon_profile(
+    run_options: &RunOptions,
+    site_profile: &Option<AnonProfile>,
+    standard_profile: &Option<StandardProfile>,
+    date_shift: bool,
+    private_summary: String,
+) -> Vec<(String, String)> {
+    let site_entry = (
+        "Site profile".to_string(),
+        match site_profile {
+            Some(site_profile) => site_profile.name.clone(),
+            None => "None".to_string(),
+        },
+    );
+    if let Some(standard_profile) = standard_profile {
+        return vec![
+            (
+                "Identifiers".to_string(),
+                "PatientID and PatientName replaced by the AnonID".to_string(),
+            ),
+            ("PS3.15 profile".to_string(), standard_profile.summary()),
+            (
+                "Downsample".to_string(),
+                match run_options.downsample {
+                    Some(size) => format!("{}x{}", size.rows, size.columns),
+                    None => "No".to_string(),
+                },
+            ),
+            (
+                "Split frames".to_string(),
+                run_options.split_frames.to_string(),
+            ),
+            (
+                "Transcode".to_string(),
+                format!("{:?}", run_options.transcode),
+            ),
+            (
+                "Pixel mask".to_string(),
+                lookup_summary(
+                    &run_options
+                        .pixel_mask
+                        .as_ref()
+                        .map(|mask| mask.path.clone()),
+                ),
+            ),
+            site_entry,
+        ];
+    }
+    vec![
+        (
+            "Identifiers".to_string(),
+            "PatientID, PatientName, PN VRs and IDs replaced by the AnonID".to_string(),
+        ),
+        (
+            "Dates and times".to_string(),
+            match (run_options.date_precision, date_shift) {
+                (Some(precision), _) => {
+                    format!(
+                        "DA/DT truncated to the {:?}, TM set to {}",
+                        precision, DUMMY_TIME
+                    )
+                }
+                (None, true) => format!(
+                    "DA/DT shifted back 1 to {} days per patient, TM kept",
+                    MAX_DATE_SHIFT_DAYS
+                ),
+                (None, false) => format!("DA/TM/DT set to {} {}", DUMMY_DATE, DUMMY_TIME),
+            },
+        ),
+        (
+            "Demographics".to_string(),
+            "PatientAge 099Y, PatientSex O".to_string(),
+        ),
+        (
+            "Downsample".to_string(),
+            match run_options.downsample {
+                Some(size) => format!("{}x{}", size.rows, size.columns),
+                None => "No".to_string(),
+            },
+        ),
+        (
+            "Split frames".to_string(),
+            run_options.split_frames.to_string(),
+        ),
+        (
+            "Transcode".to_string(),
+            format!("{:?}", run_options.transcode),
+        ),
+        (
+            "Pixel mask".to_string(),
+            lookup_summary(
+                &run_options
+                    .pixel_mask
+                    .as_ref()
+                    .map(|mask| mask.path.clone()),
+            ),
+        ),
+        (
+            "Annotation text".to_string(),
+            format!("{:?}", run_options.annotation_text),
+        ),
+        (
+            "Date order".to_string(),
+            format!("{:?}", run_options.date_order),
+        ),
+        (
+            "VR fixes".to_string(),
+            match run_options.fix_vr {
+                true => "Enabled".to_string(),
+                false => "Disabled".to_string(),
+            },
+        ),
+        ("Private tags".to_string(), private_summary),
+        (
+            "Legacy tags".to_string(),
+            match run_options.keep_legacy_tags {
+                true => "Kept".to_string(),
+                false => {
+                    "Curves, overlays, results group and retired identifying attributes deleted"
+                        .to_string()
+                }
+            },
+        ),
+        (
+            "UIDs".to_string(),
+            "Remapped to 2.25 UIDs from the HMAC-SHA256 of the originals".to_string(),
+        ),
+        site_entry,
+    ]
+}
+
+// Anon of each file, the ANON IDs and the re-identification table are saved at the end of the
+// run or when it is stopped
+struct AnonTransform<'a> {
+    anonymizer: &'a Anonymizer,
+    reid_table: &'a Option<ReidTable>,
+    mapping_db: &'a Option<PathBuf>,
+    mapping_out: &'a Option<PathBuf>,
+    dry_run: bool,
+}
+
+impl AnonTransform<'_> {
+    // Write the ANON IDs given to the mapping store and the re-identification table, a dry run
+    // writes neither
+    fn save(&self) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        if let Some(mapping_db) = self.mapping_db {
+            write_mapping_store(mapping_db, &self.anonymizer.anon_ids())?;
+        }
+        if let (Some(mapping_out), Some(reid_table)) = (self.mapping_out, self.reid_table) {
+            reid_table.write(mapping_out)?;
+        }
+        Ok(())
+    }
+}
+
+impl FileTransform for AnonTransform<'_> {
+    fn action(&self) -> &str {
+        "Anon"
+    }
+
+    fn transform(
+        &self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        destination_path: &Path,
+        timing: FileTiming,
+        tracker: RunTracker,
+        wg: WaitGroup,
+    ) -> Result<()> {
+        anon_each_dcm_file(
+            dcm_obj,
+            destination_path,
+            self.anonymizer,
+            self.reid_table,
+            timing,
+            tracker,
+            wg,
+        )
+    }
+
+    // The ANON IDs given so far
+    fn stopped(&self) -> Result<()> {
+        self.save()
+    }
+}
+
 fn anon_each_dcm_file(
     dcm_obj: &FileDicomObject<InMemDicomObject>,
-    destination_path: &PathBuf,
-    map_clone: Arc<Mutex<HashMap<std::string::String, std::string::String>>>,
-    anon_prefix: &String,
+    destination_path: &Path,
+    anonymizer: &Anonymizer,
+    reid_table: &Option<ReidTable>,
+    mut timing: FileTiming,
+    tracker: RunTracker,
     wg: WaitGroup,
 ) -> Result<()> {
-    let patient_id = dcm_obj.element_by_name("PatientID")?.to_str()?.to_string();
-    let mut map = map_clone.lock().expect("Failed to lock mutex");
-    match map.get(&patient_id) {
-        Some(_) => (),
+    let transform_start = Instant::now();
+    let patient = anonymizer.patient(dcm_obj)?;
+    let new_dicom_object = anonymizer.anonymize_patient(dcm_obj, &patient)?;
+    let anon_config = &anonymizer.config;
+    let dicom_tags_values: HashMap<String, String> = get_sanitized_tag_values(&new_dicom_object)?;
+    tracker.record_series(&dicom_tags_values);
+    let tag_changes = tag_changes(dcm_obj, &new_dicom_object);
+    let unchanged = tag_changes
+        .iter()
+        .filter(|(_, change)| *change == TagChange::Kept)
+        .map(|(tag, _)| *tag)
+        .collect();
+    let changes = match tracker.dry_run {
+        true => changed_tags(&tag_changes),
+        false => vec![],
+    };
+
+    let review_reason = anon_config
+        .rules
+        .as_ref()
+        .and_then(|rules| rules.review_reason(dcm_obj))
+        .or_else(|| {
+            anon_config
+                .pixel_mask
+                .as_ref()
+                .and_then(|pixel_mask| pixel_mask.review_reason(dcm_obj))
+        })
+        .or_else(|| anon_config.review_policy.review_reason(&dicom_tags_values));
+    let (new_dp, review_reason) = match tracker.route_file(review_reason, destination_path) {
+        Some(route) => route,
         None => {
-            let anon_id: String = if anon_prefix.len() == 0 {
-                gen_id()
-            } else {
-                format!("{anon_prefix}_{}", gen_id())
-            };
-            map.insert(patient_id.clone(), anon_id);
-            debug!("New AnonID for: {}", patient_id);
+            let mut result =
+                FileResult::new(&timing.path, "excluded").with_tags(&dicom_tags_values);
+            result.duration = timing.read + transform_start.elapsed();
+            tracker.record_result(result);
+            return Ok(());
         }
+    };
+    tracker.record_tag_changes(&tag_changes);
+    if let Some(reid_table) = reid_table {
+        // The site prefix is kept, the same PatientID at two sites is two subjects
+        reid_table.record(
+            patient.key.trim_start_matches('\\'),
+            &patient.anon_id,
+            dcm_obj,
+            &new_dicom_object,
+        );
     }
-    let patient_anon_id = map
-        .get(&patient_id)
-        .expect("Failed to index Hashmap")
-        .to_string();
-    let mut new_dicom_object = mask_tags_with_id(dcm_obj.clone(), patient_anon_id)?;
-    new_dicom_object = dicom_anon_date_time(new_dicom_object)?;
-    new_dicom_object = delete_private_tags(new_dicom_object)?;
-    new_dicom_object = anon_dicom_uids(new_dicom_object)?;
-    let dicom_tags_values: HashMap<String, String> = get_sanitized_tag_values(&new_dicom_object)?;
-
-    let dcm_obj_clone = new_dicom_object.clone();
-    let new_dp = destination_path.clone();
-    rayon::spawn(move || {
-        let file_name = generate_dicom_file_name(&dicom_tags_values, "ANON".to_string())
-            .expect("Failed to generate file Name");
-        let dir_path = generate_dicom_file_path(dicom_tags_values, &new_dp)
-            .expect("Failed to generate file path");
-        let full_path = check_if_dup_exists(format!("{}/{}", dir_path, file_name));
-        debug!("Saving file: {} to: {}", file_name, dir_path);
-        let dcm_buffer = File::create(full_path).expect("Failed to create file");
-        dcm_obj_clone
-            .write_all(dcm_buffer)
-            .expect("Failed to add dcm value to buffer");
+    let expected_instances = study_related_instances(dcm_obj);
+    let instances = output_instances(
+        dcm_obj,
+        OutputInstance {
+            dcm_obj: new_dicom_object,
+            tags_values: dicom_tags_values,
+            unchanged,
+        },
+        anon_config.split_frames,
+    )?;
+    timing.transform = transform_start.elapsed();
+    tracker.progress.transformed.inc(1);
+    let series_affinity = tracker.series_affinity;
+    let destination_path = destination_path.to_path_buf();
+    spawn_write(series_affinity, move || {
+        let write_start = Instant::now();
+        let written = (|| -> Result<Vec<FileResult>> {
+            let mut results = vec![];
+            for instance in instances {
+                let dicom_tags_values = instance.tags_values;
+                let file_name = tracker.output_file_name(
+                    &dicom_tags_values,
+                    &instance.dcm_obj,
+                    "ANON".to_string(),
+                )?;
+                let study_uid = dicom_tags_values
+                    .get("StudyInstanceUID")
+                    .cloned()
+                    .unwrap_or_default();
+                let sop_uid = sop_instance_uid(&instance.dcm_obj);
+                let mut result = FileResult::new(
+                    &timing.path,
+                    match review_reason {
+                        Some(_) => "review",
+                        None => "processed",
+                    },
+                )
+                .with_tags(&dicom_tags_values);
+                result.changes = changes.clone();
+                let dir_path = tracker.output_dir(dicom_tags_values, &instance.dcm_obj, &new_dp)?;
+                let full_path = tracker.output_path(
+                    format!("{}/{}", dir_path, file_name),
+                    &sop_uid,
+                    &mut result,
+                );
+                debug!("Saving file: {} to: {}", file_name, dir_path);
+                let digest = tracker.write_instance(
+                    &full_path,
+                    &timing.path,
+                    &instance.dcm_obj,
+                    &instance.unchanged,
+                )?;
+                tracker.record_checksum(&full_path, digest);
+                match &review_reason {
+                    Some(_) if tracker.dry_run => (),
+                    Some(reason) => write_review_note(&full_path, reason)?,
+                    None => tracker.file_written(&full_path, &study_uid, expected_instances),
+                }
+                tracker.progress.written.inc(1);
+                result.output = full_path;
+                results.push(result);
+            }
+            Ok(results)
+        })();
+        timing.write = write_start.elapsed();
+        match written {
+            // One row per written instance, the frames of a split instance share the source
+            Ok(results) => {
+                for mut result in results {
+                    result.duration = timing.total();
+                    tracker.record_result(result);
+                }
+            }
+            Err(e) => {
+                error!(
+                    "Can't write {:#?}: {:#}",
+                    timing.path.file_name().unwrap_or_default(),
+                    e
+                );
+                tracker.record_failed(&timing.path, &destination_path, &e, timing.total());
+            }
+        }
+        tracker.record_timing(timing);
         drop(wg);
     });
     Ok(())
 }
-
-fn dicom_anon_date_time(
-    dcm_obj: FileDicomObject<InMemDicomObject>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    // Setting Up primitives
-
-    let time_str = "090000".to_string();
-    let date_str = "19000101".to_string();
-    let date_time = format!("{date_str}T{time_str}");
-
-    let dicom_date_data = dicom_vr_corrected_value(VR::DA, &date_str)?;
-    let dicom_time_data = dicom_vr_corrected_value(VR::TM, &time_str)?;
-    let dicom_date_time = dicom_vr_corrected_value(VR::DT, &date_time)?;
-
-    let date_deleted_dcm_obj = mask_all_vr(dcm_obj.clone(), VR::DA, dicom_date_data)?;
-    let time_deleted_dcm_obj = mask_all_vr(date_deleted_dcm_obj.clone(), VR::TM, dicom_time_data)?;
-    let mut datetime_deleted_dcm_obj =
-        mask_all_vr(time_deleted_dcm_obj.clone(), VR::DT, dicom_date_time)?;
-
-    datetime_deleted_dcm_obj.put(DataElement::new(
-        tags::PATIENT_AGE,
-        VR::AS,
-        dicom_value!(Strs, ["099Y".to_string()]),
-    ));
-    datetime_deleted_dcm_obj.put(DataElement::new(
-        tags::PATIENT_SEX,
-        VR::CS,
-        dicom_value!(Strs, ["O".to_string()]),
-    ));
-
-    Ok(datetime_deleted_dcm_obj)
-}
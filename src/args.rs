@@ -1,4 +1,14 @@
 use clap::{Args, Parser, Subcommand};
+use dcmrig_rs::dimse::{IpRange, DEFAULT_CALLING_AET};
+use dcmrig_rs::{
+    checkpoint::DEFAULT_CHECKPOINT_INTERVAL,
+    confidentiality::StandardOption,
+    filter::{FilterAction, FilterExpr},
+    AnnotationText, ByteSize, DateOrder, DatePrecision, DeliveryUnit, DuplicateSuffix, IdMode,
+    IncompleteAction, InventoryLevel, LargeFileAction, MatrixSize, NonDicomAction, OutputFormat,
+    OutputTemplate, PrivateTagPolicy, ReportFormat, ReviewAction, Shard, TranscodeTarget,
+    DEFAULT_STATUS_INTERVAL,
+};
 use std::path::PathBuf;
 
 #[derive(Debug, Parser)]
@@ -9,10 +19,255 @@ use std::path::PathBuf;
 )]
 pub struct ArgsParser {
     #[clap(subcommand)]
-    pub action_type: EntityType,
+    pub action_type: Option<EntityType>,
+    /// List the transfer syntaxes this build can read, decode and encode
+    #[arg(long = "list-codecs")]
+    pub list_codecs: bool,
     /// Verbose output
     #[arg(short = 'v', long = "verbose")]
     pub verbose: bool,
+    /// Run at the lowest CPU and IO priority on a quarter of the cores, for shared workstations
+    #[arg(long = "background", global = true)]
+    pub background: bool,
+    /// Number of slowest files to list at the end of the run, 0 to disable
+    #[arg(long = "slowest", global = true, default_value_t = 5)]
+    pub slowest: usize,
+    /// Zero byte and truncated files: copy (to INCOMPLETE in the destination) or skip (only listed in results.csv)
+    #[arg(long = "incomplete", global = true, default_value = "copy")]
+    pub incomplete: IncompleteAction,
+    /// Seconds to wait for a truncated file to grow before it is incomplete, for sources still being copied
+    #[arg(long = "incomplete-wait", global = true, default_value_t = 0)]
+    pub incomplete_wait: u64,
+    /// TOML email config of the job, the run summary is emailed through its SMTP relay at the end of the run.
+    /// The mail is sent in clear text without STARTTLS or authentication, a relay other than localhost
+    /// needs plaintext_relay = true in the config
+    #[arg(long = "email", global = true)]
+    pub email: Option<PathBuf>,
+    /// Write the progress, rates, last error and ETA of the run to this JSON file for external monitors
+    #[arg(long = "status-file", global = true)]
+    pub status_file: Option<PathBuf>,
+    /// Seconds between two writes of the status file
+    #[arg(long = "status-interval", global = true, default_value_t = DEFAULT_STATUS_INTERVAL)]
+    pub status_interval: u64,
+    /// Pack the written files of deid and anon into one ZIP per study or patient under DELIVERY, with a manifest inside
+    #[arg(long = "deliver", global = true)]
+    pub deliver: Option<DeliveryUnit>,
+    /// age recipients file, each delivery ZIP is encrypted to these public keys with the age CLI
+    #[arg(long = "deliver-recipients", global = true, requires = "deliver")]
+    pub deliver_recipients: Option<PathBuf>,
+    /// Open and transform every file, then report the output path and the changed tags of each
+    /// without writing anything to the destination. review approve|reject only list the decisions
+    #[arg(long = "dry-run", global = true)]
+    pub dry_run: bool,
+    /// Write the dry run report to this CSV instead of printing it
+    #[arg(long = "dry-run-report", global = true, requires = "dry_run")]
+    pub dry_run_report: Option<PathBuf>,
+    /// Name of the run, eg 2024-oncology-export, recorded with its summary in the runs registry
+    #[arg(long = "run-name", global = true)]
+    pub run_name: Option<String>,
+    /// Runs registry (CSV) of the named runs, Default ~/.dcmrig/runs.csv
+    #[arg(long = "runs-db", global = true)]
+    pub runs_db: Option<PathBuf>,
+    /// Write a DICOMDIR at the root of the deid and anon destination, with the files linked under DICOM for media
+    #[arg(long = "dicomdir", global = true)]
+    pub dicomdir: bool,
+    /// Only keep the instances with pixel data, SRs, presentation states, raw data and other instances without images are excluded
+    #[arg(long = "images-only", global = true)]
+    pub images_only: bool,
+    /// Skip the instances whose SOPInstanceUID was already read in the run, the duplicates are listed in duplicate_instances.csv
+    #[arg(long = "dedup", global = true)]
+    pub dedup: bool,
+    /// Checkpoint of a sort, deid or anon run, started when it doesn't exist. Running again with the same checkpoint, eg after a crash or Ctrl-C, skips the files it lists. A checkpoint started with another profile, options or version is refused. It holds the PatientIDs and the random UID secret, only readable by its owner on Unix, Windows needs --uid-secret
+    #[arg(
+        long = "resume",
+        global = true,
+        conflicts_with_all = ["output_format", "merge_frames", "dry_run", "wado_url"]
+    )]
+    pub resume: Option<PathBuf>,
+    /// Seconds between two saves of the checkpoint of --resume
+    #[arg(long = "checkpoint-interval", global = true, default_value_t = DEFAULT_CHECKPOINT_INTERVAL, requires = "resume")]
+    pub checkpoint_interval: u64,
+    /// Only process the files whose values match the expression, eg "Modality=MR && StudyDate>=20230101"
+    #[arg(long = "filter", global = true)]
+    pub filter: Option<FilterExpr>,
+    /// Files not matched by --filter: exclude (only listed in results.csv) or copy (to FILTERED_OUT in the destination as they are)
+    #[arg(long = "filtered-out", global = true, default_value = "exclude")]
+    pub filtered_out: FilterAction,
+    /// Directories of the output instances under the destination, from the tag values, eg "{PatientID}/{StudyDate}_{StudyDescription}/{SeriesNumber}_{SeriesDescription}", {Tag:fallback} for a missing tag
+    #[arg(long = "path-template", global = true)]
+    pub path_template: Option<OutputTemplate>,
+    /// File name of the output instances, from the tag values, eg "{Modality}_{SOPInstanceUID}", .dcm is added
+    #[arg(long = "name-template", global = true)]
+    pub name_template: Option<OutputTemplate>,
+    /// Process the instances of a series on one worker in InstanceNumber order, so the writes of a series are sequential and the post file hook runs in order
+    #[arg(long = "series-affinity", global = true)]
+    pub series_affinity: bool,
+    /// Log the PatientIDs, accession numbers, mapping table lines and other identifier values as they are, they are hashed by default
+    #[arg(long = "log-phi", global = true)]
+    pub log_phi: bool,
+    /// Hide FAILED_CASES, REVIEW_REQUIRED and the other directories with data that is not deidentified from Finder and Explorer
+    #[arg(long = "hide-phi-dirs", global = true)]
+    pub hide_phi_dirs: bool,
+    /// Write the instances of sort, deid and anon into one zip or tar archive in the destination instead of a directory tree, with the same paths inside
+    #[arg(
+        long = "output-format",
+        global = true,
+        conflicts_with_all = ["send_to", "stow_url", "merge_frames", "deliver", "dicomdir", "post_file", "post_study"]
+    )]
+    pub output_format: Option<OutputFormat>,
+    /// Send the written instances of deid and anon to a storage SCP, eg a PACS, with C-STORE: AET@host:port
+    #[arg(long = "send-to", global = true)]
+    pub send_to: Option<String>,
+    /// Only write the instances that could not be sent to the destination
+    #[arg(
+        long = "send-only",
+        global = true,
+        requires = "send_to",
+        conflicts_with_all = ["deliver", "dicomdir", "post_file", "post_study"]
+    )]
+    pub send_only: bool,
+    /// AE title of dcmrig in the associations of --send-to and send
+    #[arg(long = "calling-aet", global = true, default_value = DEFAULT_CALLING_AET)]
+    pub calling_aet: String,
+    /// Upload the written instances of deid and anon to a DICOMweb server with STOW-RS instead of writing them, eg https://server/dicomweb/studies. Needs curl
+    #[arg(
+        long = "stow-url",
+        global = true,
+        conflicts_with_all = ["send_to", "merge_frames", "deliver", "dicomdir", "post_file", "post_study"]
+    )]
+    pub stow_url: Option<String>,
+    /// File with the bearer token of the STOW-RS server
+    #[arg(long = "stow-token", global = true, requires = "stow_url")]
+    pub stow_token: Option<PathBuf>,
+    /// File with the user:password of the STOW-RS server, for basic authentication
+    #[arg(
+        long = "stow-user",
+        global = true,
+        requires = "stow_url",
+        conflicts_with = "stow_token"
+    )]
+    pub stow_user: Option<PathBuf>,
+    /// Read the source of sort, deid and anon from a DICOMweb server with QIDO-RS and WADO-RS, eg https://server/dicomweb. The source path is where the instances are retrieved to. Needs curl
+    #[arg(long = "wado-url", global = true)]
+    pub wado_url: Option<String>,
+    /// QIDO-RS filter of the studies of --wado-url, eg PatientID=12345 or StudyDate=20240101-20241231, repeat for several
+    #[arg(long = "wado-filter", global = true, requires = "wado_url")]
+    pub wado_filters: Vec<String>,
+    /// File with the bearer token of the --wado-url server
+    #[arg(long = "wado-token", global = true, requires = "wado_url")]
+    pub wado_token: Option<PathBuf>,
+    /// File with the user:password of the --wado-url server, for basic authentication
+    #[arg(
+        long = "wado-user",
+        global = true,
+        requires = "wado_url",
+        conflicts_with = "wado_token"
+    )]
+    pub wado_user: Option<PathBuf>,
+    /// Only process the patients of shard i out of n, eg 0/4. PatientIDs, or the --key-tag of anon, are hashed to pick the shard
+    #[arg(long = "shard", global = true)]
+    pub shard: Option<Shard>,
+    /// Write a deidentification certificate (HTML and PDF) to the destination at the end of the run
+    #[arg(long = "certificate", global = true)]
+    pub certificate: bool,
+    /// Operator named in the certificate, Default current user
+    #[arg(long = "operator", global = true)]
+    pub operator: Option<String>,
+    /// Write MANIFEST.sha256 with the SHA-256 of each written file to the destination
+    #[arg(long = "manifest", global = true)]
+    pub manifest: bool,
+    /// Read the DICOM files inside the ISO9660 images (.iso) of the source
+    #[arg(long = "read-iso", global = true)]
+    pub read_iso: bool,
+    /// Downsample the frames of deid and anon to fit in ROWSxCOLUMNS, eg 256x256, for quick look datasets
+    #[arg(long = "downsample", global = true)]
+    pub downsample: Option<MatrixSize>,
+    /// Write the frames of the enhanced CT, MR, PET, XA and XRF instances of deid and anon as classic single-frame instances, for legacy tools
+    #[arg(long = "split-frames", global = true)]
+    pub split_frames: bool,
+    /// Merge the classic CT, MR and PET instances written by deid and anon into one Legacy Converted Enhanced instance per series, for archive storage
+    #[arg(
+        long = "merge-frames",
+        global = true,
+        conflicts_with_all = ["split_frames", "send_to", "post_file"]
+    )]
+    pub merge_frames: bool,
+    /// Transfer syntax of the written pixel data: explicit-le (decoded), jpeg-lossless or keep. JPEG, JPEG Lossless and RLE sources are decoded by this build
+    #[arg(long = "transcode", global = true, default_value = "keep")]
+    pub transcode: TranscodeTarget,
+    /// TOML blackout regions for the burned in annotations of deid and anon, per modality, SOP class and matrix
+    #[arg(long = "pixel-mask", global = true)]
+    pub pixel_mask: Option<PathBuf>,
+    /// Suffix of the output files whose name is taken: tilde (name~), dup (name_dupNN.dcm) or sop-uid (name_SOPInstanceUID.dcm)
+    #[arg(long = "dup-suffix", global = true, default_value = "tilde")]
+    pub dup_suffix: DuplicateSuffix,
+    /// Command run with the path of each written file appended, through sh, or cmd /C on Windows
+    #[arg(long = "post-file", global = true)]
+    pub post_file: Option<String>,
+    /// Command run at the end of the run for each study with the paths of its written files appended
+    #[arg(long = "post-study", global = true)]
+    pub post_study: Option<String>,
+    /// Sign the certificate with HMAC-SHA256 using the key in this file
+    #[arg(long = "sign-key", global = true)]
+    pub sign_key: Option<PathBuf>,
+    /// Truncate DA/DT values to the year or month instead of replacing them, year or month
+    #[arg(long = "reduce-date-precision", global = true)]
+    pub reduce_date_precision: Option<DatePrecision>,
+    /// Order of day, month and year in malformed dates like 05.01.2023: ymd, dmy or mdy
+    #[arg(long = "date-order", global = true, default_value = "ymd")]
+    pub date_order: DateOrder,
+    /// Truncate over-length values and fix wrong VRs so strict parsers accept the output
+    #[arg(long = "fix-vr", global = true)]
+    pub fix_vr: bool,
+    /// Keep the curves, overlay data and comments, the retired results group and the retired
+    /// identifying attributes that PS3.15 removes
+    #[arg(long = "keep-legacy-tags", global = true)]
+    pub keep_legacy_tags: bool,
+    /// Print the effective configuration of the DeID/Anon run as canonical JSON and exit
+    #[arg(long = "print-effective-config", global = true)]
+    pub print_effective_config: bool,
+    /// Files larger than this size, eg 2G or 512M, are processed without reading the pixel data
+    /// into memory
+    #[arg(
+        long = "stream-above",
+        alias = "large-file-limit",
+        global = true,
+        default_value = "2G"
+    )]
+    pub stream_above: ByteSize,
+    /// Files over the limit: stream (only the header is read) or quarantine (copied to LARGE_FILES)
+    #[arg(long = "large-files", global = true, default_value = "stream")]
+    pub large_files: LargeFileAction,
+    /// Non DICOM files of deid and anon: copy (to NON_DICOM), quarantine (under REVIEW_REQUIRED) or scrub (text files copied with the identifiers of the run replaced)
+    #[arg(long = "non-dicom", global = true, default_value = "copy")]
+    pub non_dicom: NonDicomAction,
+    /// TOML file of rules that rewrite StudyDescription/SeriesDescription to a controlled vocabulary
+    #[arg(long = "description-map", global = true)]
+    pub description_map: Option<PathBuf>,
+    /// TOML file of condition > action rules evaluated on each file: route, delete or set tags
+    #[arg(long = "rules", global = true)]
+    pub rules: Option<PathBuf>,
+    /// CSV of per study values set in the files of deid and anon, keyed by AccessionNumber or StudyInstanceUID in its first column, eg a timepoint or trial arm
+    #[arg(long = "study-values", global = true)]
+    pub study_values: Option<PathBuf>,
+    /// DICOMDIR or CSV (StudyInstanceUID or AccessionNumber, then the instance count) of the instances expected per study, the incomplete studies are reported at the end of the run
+    #[arg(long = "expected-counts", global = true)]
+    pub expected_counts: Option<PathBuf>,
+    /// File with the secret the new UIDs of anon are derived from, the same secret gives the same UIDs in every run
+    #[arg(long = "uid-secret", global = true)]
+    pub uid_secret: Option<PathBuf>,
+    /// Dose screen secondary captures: keep, review (write under REVIEW_REQUIRED) or exclude
+    #[arg(long = "dose-screens", global = true, default_value = "review")]
+    pub dose_screens: ReviewAction,
+    /// VL and ophthalmic photographs: keep, review (write under REVIEW_REQUIRED) or exclude
+    #[arg(long = "photos", global = true, default_value = "review")]
+    pub photos: ReviewAction,
+    /// LABEL and OVERVIEW images of whole slide microscopy: keep, review (write under REVIEW_REQUIRED) or exclude
+    #[arg(long = "slide-labels", global = true, default_value = "review")]
+    pub slide_labels: ReviewAction,
+    /// Free text of presentation state annotations: keep, redact (replace the identifiers) or remove
+    #[arg(long = "annotation-text", global = true, default_value = "remove")]
+    pub annotation_text: AnnotationText,
 }
 
 #[derive(Debug, Subcommand)]
@@ -23,11 +278,29 @@ pub enum EntityType {
     Anon(AnonCommand),
     /// Deidentify the given source based on a mapping table
     Deid(DeidCommand),
-    /// [NON FUNCTIONAL] Generate a report for a sorted dataset
+    /// List the SOP classes and transfer syntaxes of the source with their counts and support
+    Scan(ScanCommand),
+    /// Inventory of the patients, studies and series of a source as a table, CSV or JSON
     Report(ReportCommand),
+    /// Clusters of the PatientIDs of a source that are probably one patient, from their names, birth dates and sexes
+    Duplicates(DuplicatesCommand),
+    /// Find the files of a source with the SOPInstanceUID of another file and move the copies out
+    Dedup(DedupCommand),
+    /// Run a DeID cookbook against fixture DICOMs and compare the results to golden headers
+    TestProfile(TestProfileCommand),
+    /// Merge or compare the mapping tables of parallel or partial runs
+    Mapping(MappingCommand),
+    /// List, approve or reject the files routed to REVIEW_REQUIRED
+    Review(ReviewCommand),
+    /// List the named runs of the runs registry or show one of them
+    Runs(RunsCommand),
+    /// Send the DICOM files of the source to a storage SCP, eg a PACS, with C-STORE
+    Send(SendCommand),
+    /// Run a storage SCP and process the received instances with sort, anon or deid
+    Receive(ReceiveCommand),
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 pub struct SortCommand {
     /// Sort order can be any combination of I=PatientID, N=PatientName, and M=Modality
     #[clap(short, long, default_value = "I")]
@@ -38,18 +311,59 @@ pub struct SortCommand {
     pub destination: PathBuf,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 pub struct AnonCommand {
-    /// Prefix for the ANON ID, Default Blank
+    /// Prefix for the ANON ID, Default Blank. {TagName} tokens are resolved from each file eg '{InstitutionName}'
     #[clap(short, long, default_value = "")]
     pub prefix: String,
+    /// Lookup table for the prefix tokens in the following order seperated by line value,code eg St Mary,SMH
+    #[clap(long = "prefix-lookup")]
+    pub prefix_lookup: Option<PathBuf>,
+    /// Tag the ANON IDs are keyed on, eg AccessionNumber or StudyInstanceUID when the PatientID is already scrambled per study
+    #[clap(long = "key-tag", default_value = "PatientID")]
+    pub key_tag: String,
+    /// TOML anon profile of the site: tags to remove, keep, hash or replace on top of the defaults
+    #[clap(long = "profile")]
+    pub profile: Option<PathBuf>,
+    /// Apply the PS3.15 Basic Application Level Confidentiality Profile instead of the default masking
+    #[clap(long = "standard-profile")]
+    pub standard_profile: bool,
+    /// Options of the standard profile, eg retain-uids,retain-modified-dates,clean-descriptors
+    #[clap(
+        long = "standard-option",
+        value_delimiter = ',',
+        requires = "standard_profile"
+    )]
+    pub standard_options: Vec<StandardOption>,
+    /// Shift the dates of each patient back by a stable random offset instead of replacing them, keeping their order and intervals
+    #[clap(long = "date-shift", conflicts_with = "standard_profile")]
+    pub date_shift: bool,
+    /// ANON IDs: random, or hash to derive them from the key value with the --uid-secret so sites sharing the secret agree
+    #[clap(long = "id-mode", default_value = "random")]
+    pub id_mode: IdMode,
+    /// Private elements: remove, keep, or allowlist to keep only those of --private-allowlist
+    #[clap(
+        long = "private-tags",
+        default_value = "remove",
+        conflicts_with = "standard_profile"
+    )]
+    pub private_tags: PrivateTagPolicy,
+    /// TOML allowlist of vendor private elements to keep with --private-tags allowlist
+    #[clap(long = "private-allowlist")]
+    pub private_allowlist: Option<PathBuf>,
+    /// Write the original and anonymized PatientIDs, AccessionNumbers and UIDs to this CSV or JSON (.json) file for re-identification
+    #[clap(long = "mapping-out")]
+    pub mapping_out: Option<PathBuf>,
+    /// Mapping table file of the ANON IDs (plain text, not SQLite), read at the start and updated at the end so repeated runs give the same patients the same IDs
+    #[clap(long = "mapping-db")]
+    pub mapping_db: Option<PathBuf>,
     /// Source data path, All files will be recursively indexed
     pub source: PathBuf,
     /// Destination data path, the paths will be recursively created
     pub destination: PathBuf,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 pub struct DeidCommand {
     /// Mapping table in the following order seperated by line DEID,PatientID eg DEID_001,U012345
     #[clap(short, long)]
@@ -60,10 +374,215 @@ pub struct DeidCommand {
     pub destination: PathBuf,
 }
 
+#[derive(Debug, Args)]
+pub struct ScanCommand {
+    /// Write the list as CSV to this file too
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+    /// Source data path, All files will be recursively indexed
+    pub source: PathBuf,
+}
+
 #[derive(Debug, Args)]
 pub struct ReportCommand {
+    /// Write the inventory to this file instead of printing it
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+    /// Format of the inventory: table, csv or json
+    #[clap(short, long, default_value = "table")]
+    pub format: ReportFormat,
+    /// One row per series or per study
+    #[clap(long, default_value = "series")]
+    pub level: InventoryLevel,
     /// Source data path, All files will be recursively indexed
     pub source: PathBuf,
-    /// Destination data path for the csv file
+}
+
+#[derive(Debug, Args)]
+pub struct DuplicatesCommand {
+    /// Write the candidate clusters as CSV to this file, with the PatientIDs, names and birth dates
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+    /// Lowest match score, from 0 to 1, of two PatientIDs put in one cluster
+    #[clap(long, default_value_t = 0.85)]
+    pub threshold: f64,
+    /// Source data path, All files will be recursively indexed
+    pub source: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct DedupCommand {
+    /// Write the duplicates as CSV to this file instead of printing them
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+    /// Move the duplicates with the same content as the kept file to this directory, with their path in the source
+    #[clap(long)]
+    pub move_to: Option<PathBuf>,
+    /// Source data path, All files will be recursively indexed
+    pub source: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct TestProfileCommand {
+    /// Mapping table for the fixtures, Default <FIXTURES>/mapping_table.txt
+    #[clap(short, long)]
+    pub mapping_table: Option<PathBuf>,
+    /// Write the current output as the golden headers instead of comparing
+    #[clap(short, long)]
+    pub bless: bool,
+    /// Cookbook toml file to test
+    pub profile: PathBuf,
+    /// Fixtures path, each DICOM file is compared to its <FILE>.golden.toml
+    pub fixtures: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct MergeMappingsCommand {
+    /// Merged mapping table to write
+    #[clap(short, long)]
+    pub output: PathBuf,
+    /// Mapping tables to merge, DEID,PatientID per line
+    #[clap(required = true)]
+    pub mappings: Vec<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct MappingCommand {
+    #[command(subcommand)]
+    pub action: MappingAction,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MappingAction {
+    /// Merge mapping tables into one canonical mapping table, conflicts are reported and nothing is written
+    Merge(MergeMappingsCommand),
+    /// Compare two mapping tables, exits with 1 if they differ
+    Diff(DiffMappingsCommand),
+    /// Rebuild the mapping store of anon from a destination it wrote, when the --mapping-db is lost
+    Rebuild(RebuildMappingCommand),
+}
+
+#[derive(Debug, Args)]
+pub struct DiffMappingsCommand {
+    /// First mapping table, DEID,PatientID per line
+    pub first: PathBuf,
+    /// Second mapping table, DEID,PatientID per line
+    pub second: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct RebuildMappingCommand {
+    /// Mapping store to write, ANON_ID,PatientID per line as --mapping-db. Its entries are kept
+    #[clap(short, long)]
+    pub output: PathBuf,
+    /// Original files to match when the sources of results.csv are gone, with the --uid-secret of the run
+    #[clap(long)]
+    pub source: Option<PathBuf>,
+    /// Tag the ANON IDs were keyed on
+    #[clap(long = "key-tag", default_value = "PatientID")]
+    pub key_tag: String,
+    /// CSV of the ANON ID, patient and UIDs of each instance of the destination
+    #[clap(long)]
+    pub linkage: Option<PathBuf>,
+    /// Destination written by anon
+    pub destination: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct ReviewCommand {
+    #[command(subcommand)]
+    pub action: ReviewQueueAction,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ReviewQueueAction {
+    /// List the files that need a manual review with the reason they were flagged
+    List(ReviewListCommand),
+    /// Move the files to the main output, results.csv and MANIFEST.sha256 are updated
+    Approve(ReviewDecisionCommand),
+    /// Delete the files, they are marked rejected in results.csv and removed from MANIFEST.sha256
+    Reject(ReviewDecisionCommand),
+}
+
+#[derive(Debug, Args)]
+pub struct ReviewListCommand {
+    /// Destination of the run
+    pub destination: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct ReviewDecisionCommand {
+    /// Every file that needs a review
+    #[clap(long)]
+    pub all: bool,
+    /// Destination of the run
     pub destination: PathBuf,
+    /// Files as listed by review list, relative to the destination
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct RunsCommand {
+    #[command(subcommand)]
+    pub action: RunsAction,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RunsAction {
+    /// List the named runs, oldest first
+    List,
+    /// Show everything recorded about a named run
+    Show(RunsShowCommand),
+}
+
+#[derive(Debug, Args)]
+pub struct RunsShowCommand {
+    /// Name given with --run-name
+    pub name: String,
+}
+
+#[derive(Debug, Args)]
+pub struct SendCommand {
+    /// Source data path, All files will be recursively indexed
+    pub source: PathBuf,
+    /// Storage SCP as AET@host:port
+    pub address: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ReceiveCommand {
+    /// Port the storage SCP listens on
+    #[clap(short, long, default_value_t = 11112)]
+    pub port: u16,
+    /// AE title of the storage SCP, the called AE title of the callers is not checked
+    #[clap(long = "ae-title", default_value = DEFAULT_CALLING_AET)]
+    pub ae_title: String,
+    /// Calling AE titles accepted, comma separated, the associations of other AE titles are rejected. Any AE title without it
+    #[clap(long = "allow-aet", value_delimiter = ',')]
+    pub allow_aet: Vec<String>,
+    /// IP addresses or ranges accepted, eg 10.0.0.0/8, comma separated, the connections from other addresses are closed. Any address without it
+    #[clap(long = "allow-ip", value_delimiter = ',')]
+    pub allow_ip: Vec<IpRange>,
+    /// Longest association in seconds, a caller still sending after it is aborted
+    #[clap(long = "max-association-time")]
+    pub max_association_time: Option<u64>,
+    /// Most instances of an association, the association of a caller sending more is aborted
+    #[clap(long = "max-instances")]
+    pub max_instances: Option<u64>,
+    /// Most associations received at once, the callers over it are rejected until one ends
+    #[clap(long = "max-associations", default_value_t = 16, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_associations: u64,
+    /// Pipeline of the received instances, its source is the staging directory of the SCP
+    #[command(subcommand)]
+    pub pipeline: ReceivePipeline,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ReceivePipeline {
+    /// Sort the received instances
+    Sort(SortCommand),
+    /// Anonymize the received instances
+    Anon(AnonCommand),
+    /// Deidentify the received instances based on a mapping table
+    Deid(DeidCommand),
 }
@@ -0,0 +1,472 @@
+use anyhow::Result;
+use dcmrig_rs::{hmac_sha256, json_string, sha256, to_hex, tracker::RunTracker, RunOptions};
+use dicom::core::chrono::Local;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::atomic::Ordering,
+};
+use tracing::{error, info};
+
+// Everything the certificate states about a finished run
+pub struct RunSummary {
+    pub action: String,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    // Profile used, label > value
+    pub profile: Vec<(String, String)>,
+    // Options applied, label > value
+    pub options: Vec<(String, String)>,
+    // File counts, label > count
+    pub counts: Vec<(String, u64)>,
+    // Instances each standard tag was removed, replaced or added in, keyword > counts
+    pub tag_changes: Vec<(String, String)>,
+    // Canonical JSON of the configuration in force
+    pub config: String,
+}
+
+impl RunSummary {
+    // File counts of the run as shown in the final status
+    pub fn run_counts(
+        total_len: u64,
+        failed: u64,
+        non_dcm: u64,
+        tracker: &RunTracker,
+    ) -> Vec<(String, u64)> {
+        let written = tracker.progress.written.position();
+        let skipped = tracker.skipped_count();
+        let incomplete = tracker.incomplete_count();
+        let mut counts = vec![
+            ("Total files".to_string(), total_len),
+            ("DICOM files written".to_string(), written),
+            ("Failed files".to_string(), failed),
+            ("Non DICOM files".to_string(), non_dcm),
+            ("Other shard files".to_string(), skipped),
+            ("Incomplete files".to_string(), incomplete),
+            (
+                "Quarantined large files".to_string(),
+                tracker.quarantined_count(),
+            ),
+            (
+                "Files routed to review".to_string(),
+                tracker.reviewed.load(Ordering::Relaxed),
+            ),
+            (
+                "Files excluded".to_string(),
+                tracker.excluded.load(Ordering::Relaxed),
+            ),
+            (
+                "PatientIDs shared by different patients".to_string(),
+                tracker.mixed_patients().len() as u64,
+            ),
+            (
+                "DICOM files not written".to_string(),
+                total_len.saturating_sub(written + failed + non_dcm + skipped + incomplete),
+            ),
+        ];
+        let tag_change_instances = tracker.tag_change_instances.load(Ordering::Relaxed);
+        if tag_change_instances > 0 {
+            counts.push((
+                "Instances with private tags removed".to_string(),
+                tracker.private_tags_removed.load(Ordering::Relaxed),
+            ));
+        }
+        if tracker.kept_instances.is_some() {
+            counts.push((
+                "Duplicate instances skipped".to_string(),
+                tracker.duplicate_count(),
+            ));
+        }
+        if tracker.expected_counts.is_some() {
+            counts.push((
+                "Studies not complete".to_string(),
+                tracker
+                    .study_completeness()
+                    .iter()
+                    .filter(|study| matches!(study.status(), "missing" | "incomplete" | "over"))
+                    .count() as u64,
+            ));
+        }
+        counts
+    }
+
+    // Tool, run, profile, options and counts, one "label: value" per line
+    pub fn report_lines(&self, run_options: &RunOptions) -> Vec<String> {
+        let mut lines = vec![
+            format!("Tool: dcmrig {}", env!("CARGO_PKG_VERSION")),
+            format!("Date: {}", Local::now().format("%Y-%m-%dT%H:%M:%S%:z")),
+            format!("Operator: {}", operator_name(run_options)),
+        ];
+        if let Some(run_name) = &run_options.run_name {
+            lines.push(format!("Run name: {}", run_name));
+        }
+        lines.extend([
+            format!("Action: {}", self.action),
+            format!("Source: {}", self.source.display()),
+            format!("Destination: {}", self.destination.display()),
+        ]);
+        lines.extend(
+            self.profile
+                .iter()
+                .map(|(label, value)| format!("Profile {}: {}", label, value)),
+        );
+        lines.extend(
+            self.options
+                .iter()
+                .map(|(label, value)| format!("Option {}: {}", label, value)),
+        );
+        lines.extend(
+            self.counts
+                .iter()
+                .map(|(label, count)| format!("{}: {}", label, count)),
+        );
+        lines.extend(
+            self.tag_changes
+                .iter()
+                .map(|(keyword, counts)| format!("Tag {}: {}", keyword, counts)),
+        );
+        lines
+    }
+
+    // Count of the run with this label, 0 when it isn't counted
+    pub fn count(&self, label: &str) -> u64 {
+        self.counts
+            .iter()
+            .find(|(name, _)| name == label)
+            .map_or(0, |(_, count)| *count)
+    }
+}
+
+// SHA-256 of a file for the certificate, so the exact profile or table can be verified later
+pub fn file_digest(path: &Path) -> String {
+    match fs::read(path) {
+        Ok(content) => to_hex(&sha256(&content)),
+        Err(_) => "NA".to_string(),
+    }
+}
+
+// Operator of the run, defaults to the current user
+pub fn operator_name(run_options: &RunOptions) -> String {
+    run_options.operator.clone().unwrap_or_else(|| {
+        env::var("USER")
+            .or_else(|_| env::var("USERNAME"))
+            .unwrap_or_else(|_| "unknown".to_string())
+    })
+}
+
+// Merge the CLI flags, the environment and the profile into a canonical JSON document
+// Keys are sorted and there is no whitespace, so the same configuration always gives the same text
+pub fn effective_config(
+    command: &[(String, String)],
+    profile: &[(String, String)],
+    run_options: &RunOptions,
+) -> String {
+    let environment = [
+        (
+            "Home".to_string(),
+            env::var("HOME").unwrap_or_else(|_| "NA".to_string()),
+        ),
+        ("Operator".to_string(), operator_name(run_options)),
+        ("Version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+    ];
+    let options = run_options.effective_options();
+    config_json(&[
+        ("command", command),
+        ("environment", &environment[..]),
+        ("options", &options[..]),
+        ("profile", profile),
+    ])
+}
+
+// Options that don't change what a run writes, a resumed run may change them
+const RUN_ONLY_OPTIONS: [&str; 5] = [
+    "Run name",
+    "Resume",
+    "Checkpoint interval",
+    "Series affinity",
+    "Slowest",
+];
+
+// SHA-256 of the policy of the run: the effective configuration without the environment and
+// the options of RUN_ONLY_OPTIONS, with the version. The checkpoint of --resume keeps it so
+// an output is never written under two policies
+pub fn policy_hash(
+    command: &[(String, String)],
+    profile: &[(String, String)],
+    run_options: &RunOptions,
+) -> String {
+    let version = [("Version".to_string(), env!("CARGO_PKG_VERSION").to_string())];
+    let options: Vec<(String, String)> = run_options
+        .effective_options()
+        .into_iter()
+        .filter(|(label, _)| !RUN_ONLY_OPTIONS.contains(&label.as_str()))
+        .collect();
+    let policy = config_json(&[
+        ("command", command),
+        ("options", &options[..]),
+        ("profile", profile),
+        ("version", &version[..]),
+    ]);
+    to_hex(&sha256(policy.as_bytes()))
+}
+
+// Sections of label > value as canonical JSON
+fn config_json(sections: &[(&str, &[(String, String)])]) -> String {
+    let sections: BTreeMap<&str, BTreeMap<&str, &str>> = sections
+        .iter()
+        .map(|(section, entries)| {
+            (
+                *section,
+                entries
+                    .iter()
+                    .map(|(label, value)| (label.as_str(), value.as_str()))
+                    .collect(),
+            )
+        })
+        .collect();
+    let mut json = String::from("{");
+    for (index, (section, entries)) in sections.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        json.push_str(&format!("{}:{{", json_string(section)));
+        let fields: Vec<String> = entries
+            .iter()
+            .map(|(label, value)| format!("{}:{}", json_string(label), json_string(value)))
+            .collect();
+        json.push_str(&fields.join(","));
+        json.push('}');
+    }
+    json.push('}');
+    json
+}
+
+// Write the deidentification certificate of the run to the destination
+// The certificate is an HTML document, the signed content is embedded as plain text so the
+// HMAC-SHA256 signature can be recomputed from the document alone. A PDF copy is written next
+// to it for printing and the release packages
+pub fn write_certificate(summary: &RunSummary, run_options: &RunOptions) -> Result<()> {
+    let mut lines = vec!["DCMRig deidentification certificate".to_string()];
+    lines.extend(summary.report_lines(run_options));
+    lines.push(format!("Effective config: {}", summary.config));
+    let signed_content = lines.join("\n");
+
+    let signature = match &run_options.sign_key {
+        Some(key_path) => {
+            let key = fs::read_to_string(key_path).unwrap_or_else(|_| {
+                error!("Can't read the signing key: {}", key_path.display());
+                exit(1)
+            });
+            let key = key.trim().as_bytes();
+            Some((
+                to_hex(&hmac_sha256(key, signed_content.as_bytes())),
+                to_hex(&sha256(key))[..16].to_string(),
+            ))
+        }
+        None => None,
+    };
+
+    let certificate_path = summary
+        .destination
+        .join(format!("{}_CERTIFICATE.html", summary.action));
+    fs::write(
+        &certificate_path,
+        certificate_html(summary, &lines, &signed_content, signature.as_ref()),
+    )?;
+    info!("Certificate written: {}", certificate_path.display());
+
+    let pdf_path = certificate_path.with_extension("pdf");
+    fs::write(&pdf_path, certificate_pdf(&lines, signature.as_ref()))?;
+    info!("Certificate written: {}", pdf_path.display());
+    Ok(())
+}
+
+fn certificate_html(
+    summary: &RunSummary,
+    lines: &[String],
+    signed_content: &str,
+    signature: Option<&(String, String)>,
+) -> String {
+    let mut rows = String::new();
+    for line in &lines[1..] {
+        let (label, value) = line.split_once(": ").unwrap_or((line, ""));
+        rows.push_str(&format!(
+            "<tr><th>{}</th><td>{}</td></tr>\n",
+            escape_html(label),
+            escape_html(value)
+        ));
+    }
+    let signature_html = match signature {
+        Some((signature, key_id)) => format!(
+            "<p>Signature (HMAC-SHA256 of the signed content): <code>{}</code><br>\
+             Key fingerprint (first 16 hex of SHA-256): <code>{}</code></p>",
+            signature, key_id
+        ),
+        None => "<p>This certificate is not signed.</p>".to_string(),
+    };
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>DCMRig {action} certificate</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; }}
+th, td {{ border: 1px solid #999; padding: 4px 8px; text-align: left; }}
+pre {{ background: #eee; padding: 1em; }}
+</style>
+</head>
+<body>
+<h1>Deidentification certificate</h1>
+<table>
+{rows}</table>
+<h2>Signed content</h2>
+<pre id=\"signed-content\">{content}</pre>
+{signature_html}
+</body>
+</html>
+",
+        action = escape_html(&summary.action),
+        rows = rows,
+        content = escape_html(signed_content),
+        signature_html = signature_html
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Characters of a line of the PDF copy, with the indent of a wrapped line the A4 width between
+// the margins holds 91 characters of 9 pt Courier
+const PDF_LINE_CHARS: usize = 88;
+// Lines of a page of the PDF copy
+const PDF_PAGE_LINES: usize = 64;
+
+// PDF copy of the certificate: the signed lines and the signature in Courier on A4 pages, the
+// long lines are wrapped. Only the PDF base fonts are used so nothing is embedded, the
+// characters they don't have are shown as ?
+fn certificate_pdf(lines: &[String], signature: Option<&(String, String)>) -> Vec<u8> {
+    let mut text: Vec<String> = Vec::new();
+    for line in lines.iter().skip(1) {
+        // Wrapped at the last space that fits, the long words such as the config JSON are cut
+        let mut rest: Vec<char> = line.chars().collect();
+        let mut indent = "";
+        while rest.len() > PDF_LINE_CHARS {
+            let cut = rest[..=PDF_LINE_CHARS]
+                .iter()
+                .rposition(|&c| c == ' ')
+                .filter(|&space| space > 0);
+            let part: String = rest[..cut.unwrap_or(PDF_LINE_CHARS)].iter().collect();
+            text.push(format!("{}{}", indent, part));
+            rest.drain(..cut.map_or(PDF_LINE_CHARS, |space| space + 1));
+            indent = "  ";
+        }
+        text.push(format!("{}{}", indent, rest.iter().collect::<String>()));
+    }
+    text.push(String::new());
+    match signature {
+        Some((signature, key_id)) => {
+            text.push(
+                "Signature (HMAC-SHA256 of the signed content, see the HTML certificate):"
+                    .to_string(),
+            );
+            text.push(signature.clone());
+            text.push(format!(
+                "Key fingerprint (first 16 hex of SHA-256): {}",
+                key_id
+            ));
+        }
+        None => text.push("This certificate is not signed.".to_string()),
+    }
+
+    let pages: Vec<&[String]> = text.chunks(PDF_PAGE_LINES).collect();
+    // Catalog, page tree and the two fonts, then a page and its content stream per page
+    let mut objects = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".as_bytes().to_vec(),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            (0..pages.len())
+                .map(|page| format!("{} 0 R", 5 + 2 * page))
+                .collect::<Vec<_>>()
+                .join(" "),
+            pages.len()
+        )
+        .into_bytes(),
+        b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>"
+            .to_vec(),
+        b"<< /Type /Font /Subtype /Type1 /BaseFont /Courier /Encoding /WinAnsiEncoding >>".to_vec(),
+    ];
+    for (page, page_lines) in pages.iter().enumerate() {
+        let mut stream = Vec::new();
+        if page == 0 {
+            stream.extend_from_slice(b"BT /F1 16 Tf 50 792 Td (");
+            stream.extend_from_slice(&pdf_string(&lines[0]));
+            stream.extend_from_slice(b") Tj ET\n");
+        }
+        stream.extend_from_slice(b"BT /F2 9 Tf 11 TL 50 762 Td\n");
+        for line in page_lines.iter() {
+            stream.push(b'(');
+            stream.extend_from_slice(&pdf_string(line));
+            stream.extend_from_slice(b") Tj T*\n");
+        }
+        stream.extend_from_slice(b"ET");
+        objects.push(
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 595 842] \
+                 /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                6 + 2 * page
+            )
+            .into_bytes(),
+        );
+        let mut content = format!("<< /Length {} >>\nstream\n", stream.len()).into_bytes();
+        content.extend_from_slice(&stream);
+        content.extend_from_slice(b"\nendstream");
+        objects.push(content);
+    }
+
+    let mut pdf = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (number, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n", number + 1).as_bytes());
+        pdf.extend_from_slice(object);
+        pdf.extend_from_slice(b"\nendobj\n");
+    }
+    let xref = pdf.len();
+    pdf.extend_from_slice(
+        format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes(),
+    );
+    for offset in offsets {
+        pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    pdf.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref
+        )
+        .as_bytes(),
+    );
+    pdf
+}
+
+// Text of a PDF string in the WinAnsi encoding of the base fonts, Latin-1 is kept
+fn pdf_string(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => bytes.extend_from_slice(&[b'\\', c as u8]),
+            ' '..='~' => bytes.push(c as u8),
+            '\u{a0}'..='\u{ff}' => bytes.push(c as u8),
+            _ => bytes.push(b'?'),
+        }
+    }
+    bytes
+}
@@ -0,0 +1,457 @@
+use crate::{csv_field, csv_fields, tracker::RunTracker, uid_mapper::UidMapper, FileResult};
+use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, create_dir_all},
+    io::Write as _,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+// Seconds between two saves of the checkpoint of --resume
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 30;
+
+// First field of the first line of a checkpoint, followed by the version, action and destination
+const CHECKPOINT_HEADER: &str = "dcmrig-checkpoint";
+
+// Status of the results in results.csv and the checkpoints
+const RESULT_STATUSES: [&str; 11] = [
+    "processed",
+    "review",
+    "rejected",
+    "excluded",
+    "unmapped",
+    "failed",
+    "non-DICOM",
+    "skipped",
+    "duplicate",
+    "incomplete",
+    "quarantined",
+];
+
+// Records of a checkpoint, one per line with its kind first, none when it doesn't exist. The
+// last line is left out when the write of a save was cut, it ends with a line break otherwise
+pub(crate) fn read_checkpoint_records(path: &Path) -> Result<Vec<Vec<String>>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let content = fs::read_to_string(path)?;
+    let mut lines: Vec<&str> = content.split('\n').collect();
+    // Empty after the last line break, or a cut line
+    lines.pop();
+    Ok(lines
+        .into_iter()
+        .filter(|line| !line.is_empty())
+        .map(csv_fields)
+        .collect())
+}
+
+/// Checkpoint of a run with --resume: the UID secret, the ANON IDs, the manifest checksums and
+/// the result of each file done, appended as the run goes by a CheckpointWriter. A run with the
+/// checkpoint of an earlier run of the same action, destination and policy skips the files it
+/// lists
+pub struct Checkpoint {
+    path: PathBuf,
+    // The checkpoint was read from an earlier run
+    pub resumed: bool,
+    pub results: Vec<FileResult>,
+    // Source files with a result
+    pub done: HashSet<PathBuf>,
+    // Patient key > ANON ID
+    pub anon_ids: HashMap<String, String>,
+    // Output path > SHA-256
+    pub checksums: Vec<(String, String)>,
+}
+
+impl Checkpoint {
+    // The policy is the hash of the configuration that changes the output, a checkpoint started
+    // under another policy is refused
+    pub fn open(
+        path: &Path,
+        action: &str,
+        destination_path: &Path,
+        policy: &str,
+        uid_mapper: &UidMapper,
+    ) -> Result<Self> {
+        let mut checkpoint = Checkpoint {
+            path: path.to_path_buf(),
+            resumed: false,
+            results: vec![],
+            done: HashSet::new(),
+            anon_ids: HashMap::new(),
+            checksums: vec![],
+        };
+        let header = [
+            CHECKPOINT_HEADER.to_string(),
+            "2".to_string(),
+            action.to_string(),
+            destination_path.display().to_string(),
+            policy.to_string(),
+        ];
+        let records = read_checkpoint_records(path)?;
+        let Some(first) = records.first() else {
+            // Only Unix makes the new file readable by its owner alone, elsewhere it gets the
+            // permissions of its directory and the random secret is kept out of it
+            if cfg!(not(unix)) && uid_mapper.secret_path.is_none() {
+                return Err(anyhow::anyhow!(
+                    "--resume needs --uid-secret on this platform, the checkpoint {} can't be made readable by its owner only to hold the random UID secret",
+                    path.display()
+                ));
+            }
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                create_dir_all(parent)?;
+            }
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                // The secret and the PatientIDs are in it
+                options.mode(0o600);
+            }
+            let mut file = options.open(path)?;
+            let header: Vec<String> = header.iter().map(|field| csv_field(field)).collect();
+            write!(
+                file,
+                "{}\n{}\n",
+                header.join(","),
+                uid_mapper.checkpoint_record()
+            )?;
+            file.sync_all()?;
+            warn!(
+                "Checkpoint started: {}, it holds the UID secret and PatientIDs of the run, keep it away from the output",
+                path.display()
+            );
+            if cfg!(not(unix)) {
+                warn!(
+                    "The checkpoint {} has the permissions of its directory, keep it in a directory only you can read",
+                    path.display()
+                );
+            }
+            return Ok(checkpoint);
+        };
+        if first.len() == header.len() && first[..4] == header[..4] && first[4] != header[4] {
+            return Err(anyhow::anyhow!(
+                "The checkpoint {} was started with another profile, options or version, resume it with the ones it started with or remove it to start over",
+                path.display()
+            ));
+        }
+        if first[..] != header[..] {
+            return Err(anyhow::anyhow!(
+                "The checkpoint {} is not of this {} run to {}: {}",
+                path.display(),
+                action,
+                destination_path.display(),
+                first[1..].join(" ")
+            ));
+        }
+        checkpoint.resumed = true;
+        // The cut line of the last save is dropped, the next save starts on a line of its own
+        let content = fs::read(path)?;
+        if !content.ends_with(b"\n") {
+            let saved = content
+                .iter()
+                .rposition(|byte| *byte == b'\n')
+                .map_or(0, |end| end + 1);
+            fs::OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(saved as u64)?;
+        }
+        for record in &records[1..] {
+            match (record[0].as_str(), record.len()) {
+                ("id", 3) => {
+                    checkpoint
+                        .anon_ids
+                        .insert(record[2].clone(), record[1].clone());
+                }
+                ("checksum", 3) => checkpoint
+                    .checksums
+                    .push((record[1].clone(), record[2].clone())),
+                ("result", 9) => {
+                    let Some(status) = RESULT_STATUSES.iter().find(|status| **status == record[2])
+                    else {
+                        continue;
+                    };
+                    let mut result = FileResult::new(Path::new(&record[1]), status);
+                    result.output = record[3].clone();
+                    result.renamed_from = record[4].clone();
+                    result.patient_id = record[5].clone();
+                    result.study_uid = record[6].clone();
+                    result.series_uid = record[7].clone();
+                    result.error = record[8].clone();
+                    checkpoint.done.insert(result.source.clone());
+                    checkpoint.results.push(result);
+                }
+                _ => (),
+            }
+        }
+        info!(
+            "Resuming from the checkpoint {}: {} files done, {} ANON IDs",
+            path.display(),
+            checkpoint.done.len(),
+            checkpoint.anon_ids.len()
+        );
+        Ok(checkpoint)
+    }
+
+    // Files of the source without a result in the checkpoint
+    pub fn pending(&self, all_files: Vec<PathBuf>) -> Vec<PathBuf> {
+        all_files
+            .into_iter()
+            .filter(|item| !self.done.contains(item))
+            .collect()
+    }
+
+    // Source files done with this status
+    pub fn status_count(&self, status: &str) -> u64 {
+        self.results
+            .iter()
+            .filter(|result| result.status == status)
+            .map(|result| &result.source)
+            .collect::<HashSet<_>>()
+            .len() as u64
+    }
+}
+
+// Appends the results, manifest checksums and ANON IDs of the run to its checkpoint at a fixed
+// interval, and once more when the run is done or stopped
+pub struct CheckpointWriter {
+    stop: mpsc::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl CheckpointWriter {
+    pub fn start(
+        checkpoint: &Checkpoint,
+        interval: Duration,
+        tracker: RunTracker,
+        anon_ids: Option<Arc<Mutex<HashMap<String, String>>>>,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let path = checkpoint.path.clone();
+        // The records already in the checkpoint
+        let mut saved_results = tracker.results.lock().expect("Failed to lock mutex").len();
+        let mut saved_checksums = tracker.checksums.as_ref().map_or(0, |checksums| {
+            checksums.lock().expect("Failed to lock mutex").len()
+        });
+        let mut saved_ids: HashSet<String> = checkpoint.anon_ids.keys().cloned().collect();
+        debug!(
+            "Checkpoint saved to {} every {} seconds",
+            path.display(),
+            interval.as_secs()
+        );
+        let handle = thread::spawn(move || loop {
+            // Saved once more when stopped
+            let stopping = !matches!(
+                stopped.recv_timeout(interval),
+                Err(RecvTimeoutError::Timeout)
+            );
+            // Results first, the ANON ID and the checksum of a file are there before its result
+            let results: Vec<FileResult> = {
+                let results = tracker.results.lock().expect("Failed to lock mutex");
+                let new = results[saved_results..].to_vec();
+                saved_results = results.len();
+                new
+            };
+            let checksums: Vec<(String, String)> = match &tracker.checksums {
+                Some(checksums) => {
+                    let checksums = checksums.lock().expect("Failed to lock mutex");
+                    let new = checksums[saved_checksums..].to_vec();
+                    saved_checksums = checksums.len();
+                    new
+                }
+                None => vec![],
+            };
+            let mut records = String::new();
+            if let Some(anon_ids) = &anon_ids {
+                for (key, anon_id) in anon_ids.lock().expect("Failed to lock mutex").iter() {
+                    if saved_ids.insert(key.clone()) {
+                        records.push_str(&format!(
+                            "id,{},{}\n",
+                            csv_field(anon_id),
+                            csv_field(key)
+                        ));
+                    }
+                }
+            }
+            for (output, digest) in checksums {
+                records.push_str(&format!("checksum,{},{}\n", csv_field(&output), digest));
+            }
+            for result in results {
+                records.push_str(&format!(
+                    "result,{},{},{},{},{},{},{},{}\n",
+                    csv_field(&result.source.display().to_string()),
+                    result.status,
+                    csv_field(&result.output),
+                    csv_field(&result.renamed_from),
+                    csv_field(&result.patient_id),
+                    csv_field(&result.study_uid),
+                    csv_field(&result.series_uid),
+                    csv_field(&result.error)
+                ));
+            }
+            if !records.is_empty() {
+                fs::OpenOptions::new()
+                    .append(true)
+                    .open(&path)
+                    .and_then(|mut file| {
+                        file.write_all(records.as_bytes())?;
+                        file.sync_data()
+                    })
+                    .unwrap_or_else(|e| {
+                        warn!("Can't save the checkpoint {}: {}", path.display(), e)
+                    });
+            }
+            if stopping {
+                break;
+            }
+        });
+        CheckpointWriter { stop, handle }
+    }
+
+    // Save the last results and stop the writer, before results.csv sorts them
+    pub fn finish(self) {
+        let _ = self.stop.send(());
+        if self.handle.join().is_err() {
+            warn!("The checkpoint writer stopped unexpectedly");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_checkpoint_records, Checkpoint};
+    use crate::{gen_id, uid_mapper::UidMapper};
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    const HEADER: &str = "dcmrig-checkpoint,2,Anon,/dest,policy\nsecret,random,abc\n";
+
+    // Checkpoint file with the given content in a new directory
+    fn checkpoint_file(content: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dcmrig_test_{}", gen_id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("checkpoint.csv");
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn open(path: &Path, policy: &str) -> anyhow::Result<Checkpoint> {
+        Checkpoint::open(
+            path,
+            "Anon",
+            Path::new("/dest"),
+            policy,
+            &UidMapper::default(),
+        )
+    }
+
+    #[test]
+    fn resume_reads_the_records() {
+        let path = checkpoint_file(&format!(
+            "{}id,ANON1,P1\nchecksum,P1/a.dcm,0123\nresult,/src/a.dcm,processed,/dest/P1/a.dcm,,P1,1.2,1.2.3,\nresult,/src/b.dcm,failed,,,,,,\"Can't read, truncated\"\n",
+            HEADER
+        ));
+        let checkpoint = open(&path, "policy").unwrap();
+        assert!(checkpoint.resumed);
+        assert_eq!(checkpoint.anon_ids["P1"], "ANON1");
+        assert_eq!(
+            checkpoint.checksums,
+            [("P1/a.dcm".to_string(), "0123".to_string())]
+        );
+        assert_eq!(checkpoint.results.len(), 2);
+        assert_eq!(checkpoint.results[0].output, "/dest/P1/a.dcm");
+        assert_eq!(checkpoint.results[1].error, "Can't read, truncated");
+        assert_eq!(checkpoint.status_count("failed"), 1);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    // A save cut by a crash leaves a line without its line break, it is dropped from the
+    // records and from the file so the next save starts on a line of its own
+    #[test]
+    fn resume_drops_the_cut_last_line() {
+        let path = checkpoint_file(&format!(
+            "{}result,/src/a.dcm,processed,,,,,,\nresult,/src/b.dcm,proc",
+            HEADER
+        ));
+        assert_eq!(read_checkpoint_records(&path).unwrap().len(), 3);
+        let checkpoint = open(&path, "policy").unwrap();
+        assert_eq!(
+            checkpoint.done,
+            [PathBuf::from("/src/a.dcm")].into_iter().collect()
+        );
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}result,/src/a.dcm,processed,,,,,,\n", HEADER)
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn pending_leaves_out_the_files_done() {
+        let path = checkpoint_file(&format!(
+            "{}result,/src/b.dcm,processed,,,,,,\nresult,/src/d.dcm,skipped,,,,,,\n",
+            HEADER
+        ));
+        let checkpoint = open(&path, "policy").unwrap();
+        let all_files = ["/src/a.dcm", "/src/b.dcm", "/src/c.dcm", "/src/d.dcm"]
+            .map(PathBuf::from)
+            .to_vec();
+        assert_eq!(
+            checkpoint.pending(all_files),
+            [PathBuf::from("/src/a.dcm"), PathBuf::from("/src/c.dcm")]
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    // The frames of a split instance each have a result, the source file is counted once
+    #[test]
+    fn status_count_counts_source_files() {
+        let path = checkpoint_file(&format!(
+            "{}result,/src/a.dcm,processed,/dest/1.dcm,,,,,\nresult,/src/a.dcm,processed,/dest/2.dcm,,,,,\nresult,/src/b.dcm,processed,,,,,,\n",
+            HEADER
+        ));
+        let checkpoint = open(&path, "policy").unwrap();
+        assert_eq!(checkpoint.status_count("processed"), 2);
+        assert_eq!(checkpoint.status_count("failed"), 0);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn refuses_another_run() {
+        let path = checkpoint_file(HEADER);
+        let error = open(&path, "other policy").err().unwrap().to_string();
+        assert!(
+            error.contains("another profile, options or version"),
+            "{}",
+            error
+        );
+        let other_destination = Checkpoint::open(
+            &path,
+            "Anon",
+            Path::new("/other"),
+            "policy",
+            &UidMapper::default(),
+        );
+        assert!(other_destination.is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn no_records_without_a_checkpoint() {
+        let path = std::env::temp_dir().join(format!("dcmrig_test_{}.csv", gen_id()));
+        assert!(read_checkpoint_records(&path).unwrap().is_empty());
+    }
+}
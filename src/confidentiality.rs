@@ -0,0 +1,685 @@
+use crate::{
+    fit_to_vr_length, identifying_values, is_legacy_group, scrub_annotation_text, scrub_sr_content,
+    shift_date_value, uid_mapper::UidMapper, vr_dummy_value, with_multiplicity, AnnotationText,
+    RETIRED_IDENTIFYING_TAGS,
+};
+use anyhow::Result;
+use dicom::{
+    core::{header::Header, value::DataSetSequence, DataElement, PrimitiveValue, VR},
+    dicom_value,
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject, Tag},
+};
+use std::str::FromStr;
+
+// Action of the Basic Profile on an attribute, PS3.15 Table E.1-1
+// The combined actions of the table use the least identifying one that keeps the IOD valid:
+// X/Z, Z/D and X/Z/D are Z, X/D is D and X/Z/U* is U
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    // K, sequences are cleaned item by item
+    Keep,
+    // X
+    Remove,
+    // Z, empty value. Dates and times get the dummy value so the output paths stay readable
+    Zero,
+    // D, dummy value of the VR, the ANON ID for the text VRs
+    Dummy,
+    // C, dates are shifted and text is redacted
+    Clean,
+    // U, UIDs are replaced by the UidMapper of the run, the same in every file
+    Uid,
+}
+
+// Option column an attribute falls in, the U attributes are all in the Retain UIDs column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    None,
+    Device,
+    Institution,
+    Characteristics,
+    Temporal,
+    Descriptor,
+    StructuredContent,
+    Graphics,
+}
+
+// Options of the profile selectable with --standard-option
+// Clean Pixel Data, Clean Recognizable Visual Features and Retain Safe Private are not implemented
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StandardOption {
+    RetainUids,
+    RetainDeviceIdentity,
+    RetainInstitutionIdentity,
+    RetainPatientCharacteristics,
+    RetainFullDates,
+    RetainModifiedDates,
+    CleanDescriptors,
+    CleanStructuredContent,
+    CleanGraphics,
+}
+
+impl FromStr for StandardOption {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "retain-uids" => Ok(StandardOption::RetainUids),
+            "retain-device-identity" => Ok(StandardOption::RetainDeviceIdentity),
+            "retain-institution-identity" => Ok(StandardOption::RetainInstitutionIdentity),
+            "retain-patient-characteristics" => Ok(StandardOption::RetainPatientCharacteristics),
+            "retain-full-dates" => Ok(StandardOption::RetainFullDates),
+            "retain-modified-dates" => Ok(StandardOption::RetainModifiedDates),
+            "clean-descriptors" => Ok(StandardOption::CleanDescriptors),
+            "clean-structured-content" => Ok(StandardOption::CleanStructuredContent),
+            "clean-graphics" => Ok(StandardOption::CleanGraphics),
+            "retain-safe-private" | "clean-pixel-data" | "clean-recognizable-visual-features" => {
+                Err(anyhow::anyhow!("Option not implemented yet: {}", value))
+            }
+            _ => Err(anyhow::anyhow!(
+                "Should be one of retain-uids, retain-device-identity, retain-institution-identity, \
+                retain-patient-characteristics, retain-full-dates, retain-modified-dates, \
+                clean-descriptors, clean-structured-content or clean-graphics: {}",
+                value
+            )),
+        }
+    }
+}
+
+impl StandardOption {
+    // Code of the option in DeidentificationMethodCodeSequence, CID 7050
+    fn code(&self) -> (&'static str, &'static str) {
+        match self {
+            StandardOption::CleanGraphics => ("113103", "Clean Graphics Option"),
+            StandardOption::CleanStructuredContent => ("113104", "Clean Structured Content Option"),
+            StandardOption::CleanDescriptors => ("113105", "Clean Descriptors Option"),
+            StandardOption::RetainFullDates => (
+                "113106",
+                "Retain Longitudinal Temporal Information Full Dates Option",
+            ),
+            StandardOption::RetainModifiedDates => (
+                "113107",
+                "Retain Longitudinal Temporal Information Modified Dates Option",
+            ),
+            StandardOption::RetainPatientCharacteristics => {
+                ("113108", "Retain Patient Characteristics Option")
+            }
+            StandardOption::RetainDeviceIdentity => ("113109", "Retain Device Identity Option"),
+            StandardOption::RetainUids => ("113110", "Retain UIDs Option"),
+            StandardOption::RetainInstitutionIdentity => {
+                ("113112", "Retain Institution Identity Option")
+            }
+        }
+    }
+}
+
+use Action::*;
+use Column as C;
+
+// Attributes of PS3.15 Table E.1-1 with their basic action and option column
+// Private attributes, overlay data and comments and curves are removed separately
+const BASIC_PROFILE: &[(Tag, Action, Column)] = &[
+    // Patient
+    (tags::PATIENT_NAME, Zero, C::None),
+    (tags::PATIENT_ID, Zero, C::None),
+    (tags::ISSUER_OF_PATIENT_ID, Remove, C::None),
+    (tags::PATIENT_BIRTH_DATE, Zero, C::None),
+    (tags::PATIENT_BIRTH_TIME, Remove, C::None),
+    (tags::PATIENT_SEX, Zero, C::Characteristics),
+    (tags::PATIENT_INSURANCE_PLAN_CODE_SEQUENCE, Remove, C::None),
+    (
+        tags::PATIENT_PRIMARY_LANGUAGE_CODE_SEQUENCE,
+        Remove,
+        C::None,
+    ),
+    // OtherPatientIDs, retired
+    (Tag(0x0010, 0x1000), Remove, C::None),
+    (tags::OTHER_PATIENT_NAMES, Remove, C::None),
+    (tags::OTHER_PATIENT_I_DS_SEQUENCE, Remove, C::None),
+    (tags::PATIENT_BIRTH_NAME, Remove, C::None),
+    (tags::PATIENT_AGE, Remove, C::Characteristics),
+    (tags::PATIENT_SIZE, Remove, C::Characteristics),
+    (tags::PATIENT_WEIGHT, Remove, C::Characteristics),
+    (tags::PATIENT_ADDRESS, Remove, C::None),
+    // InsurancePlanIdentification, retired
+    (Tag(0x0010, 0x1050), Remove, C::None),
+    (tags::PATIENT_MOTHER_BIRTH_NAME, Remove, C::None),
+    (tags::MILITARY_RANK, Remove, C::None),
+    (tags::BRANCH_OF_SERVICE, Remove, C::None),
+    // MedicalRecordLocator, retired
+    (Tag(0x0010, 0x1090), Remove, C::None),
+    (tags::REFERENCED_PATIENT_PHOTO_SEQUENCE, Remove, C::None),
+    (tags::MEDICAL_ALERTS, Remove, C::Characteristics),
+    (tags::ALLERGIES, Remove, C::Characteristics),
+    (tags::COUNTRY_OF_RESIDENCE, Remove, C::None),
+    (tags::REGION_OF_RESIDENCE, Remove, C::None),
+    (tags::PATIENT_TELEPHONE_NUMBERS, Remove, C::None),
+    (tags::ETHNIC_GROUP, Remove, C::Characteristics),
+    (tags::OCCUPATION, Remove, C::Descriptor),
+    (tags::SMOKING_STATUS, Remove, C::Characteristics),
+    (tags::ADDITIONAL_PATIENT_HISTORY, Remove, C::Descriptor),
+    (tags::PREGNANCY_STATUS, Remove, C::Characteristics),
+    (tags::LAST_MENSTRUAL_DATE, Remove, C::Temporal),
+    (tags::PATIENT_RELIGIOUS_PREFERENCE, Remove, C::None),
+    (tags::PATIENT_SEX_NEUTERED, Zero, C::Characteristics),
+    (tags::RESPONSIBLE_PERSON, Remove, C::None),
+    (tags::RESPONSIBLE_ORGANIZATION, Remove, C::None),
+    (tags::PATIENT_COMMENTS, Remove, C::Descriptor),
+    (tags::REFERENCED_PATIENT_SEQUENCE, Remove, C::None),
+    (tags::PATIENT_TRANSPORT_ARRANGEMENTS, Remove, C::None),
+    (tags::SPECIAL_NEEDS, Remove, C::Characteristics),
+    (tags::PATIENT_STATE, Remove, C::Characteristics),
+    (
+        tags::CONFIDENTIALITY_CONSTRAINT_ON_PATIENT_DATA_DESCRIPTION,
+        Remove,
+        C::None,
+    ),
+    // Study and visit
+    (tags::INSTANCE_CREATION_DATE, Dummy, C::Temporal),
+    (tags::INSTANCE_CREATION_TIME, Zero, C::Temporal),
+    (tags::INSTANCE_CREATOR_UID, Uid, C::None),
+    (tags::SOP_INSTANCE_UID, Uid, C::None),
+    (tags::STUDY_DATE, Zero, C::Temporal),
+    (tags::SERIES_DATE, Dummy, C::Temporal),
+    (tags::ACQUISITION_DATE, Zero, C::Temporal),
+    (tags::CONTENT_DATE, Zero, C::Temporal),
+    // OverlayDate, retired
+    (Tag(0x0008, 0x0024), Remove, C::Temporal),
+    // CurveDate, retired
+    (Tag(0x0008, 0x0025), Remove, C::Temporal),
+    (tags::ACQUISITION_DATE_TIME, Zero, C::Temporal),
+    (tags::STUDY_TIME, Zero, C::Temporal),
+    (tags::SERIES_TIME, Dummy, C::Temporal),
+    (tags::ACQUISITION_TIME, Zero, C::Temporal),
+    (tags::CONTENT_TIME, Zero, C::Temporal),
+    // OverlayTime, retired
+    (Tag(0x0008, 0x0034), Remove, C::Temporal),
+    // CurveTime, retired
+    (Tag(0x0008, 0x0035), Remove, C::Temporal),
+    (tags::ACCESSION_NUMBER, Zero, C::None),
+    (tags::INSTITUTION_NAME, Zero, C::Institution),
+    (tags::INSTITUTION_ADDRESS, Remove, C::Institution),
+    (tags::INSTITUTION_CODE_SEQUENCE, Zero, C::Institution),
+    (tags::REFERRING_PHYSICIAN_NAME, Zero, C::None),
+    (tags::REFERRING_PHYSICIAN_ADDRESS, Remove, C::None),
+    (tags::REFERRING_PHYSICIAN_TELEPHONE_NUMBERS, Remove, C::None),
+    (
+        tags::REFERRING_PHYSICIAN_IDENTIFICATION_SEQUENCE,
+        Remove,
+        C::None,
+    ),
+    (tags::CONSULTING_PHYSICIAN_NAME, Remove, C::None),
+    (tags::STATION_NAME, Zero, C::Device),
+    (tags::STUDY_DESCRIPTION, Remove, C::Descriptor),
+    (tags::INSTITUTIONAL_DEPARTMENT_NAME, Remove, C::Institution),
+    (
+        tags::INSTITUTIONAL_DEPARTMENT_TYPE_CODE_SEQUENCE,
+        Remove,
+        C::Institution,
+    ),
+    (tags::SERIES_DESCRIPTION, Remove, C::Descriptor),
+    (tags::PHYSICIANS_OF_RECORD, Remove, C::None),
+    (tags::PERFORMING_PHYSICIAN_NAME, Remove, C::None),
+    (tags::NAME_OF_PHYSICIANS_READING_STUDY, Remove, C::None),
+    (tags::OPERATORS_NAME, Zero, C::None),
+    (tags::OPERATOR_IDENTIFICATION_SEQUENCE, Dummy, C::None),
+    (tags::ADMITTING_DIAGNOSES_DESCRIPTION, Remove, C::Descriptor),
+    (tags::REFERENCED_STUDY_SEQUENCE, Zero, C::None),
+    (
+        tags::REFERENCED_PERFORMED_PROCEDURE_STEP_SEQUENCE,
+        Zero,
+        C::None,
+    ),
+    (tags::REFERENCED_IMAGE_SEQUENCE, Uid, C::None),
+    (tags::REFERENCED_SOP_INSTANCE_UID, Uid, C::None),
+    (tags::TRANSACTION_UID, Uid, C::None),
+    (tags::DERIVATION_DESCRIPTION, Remove, C::Descriptor),
+    (tags::SOURCE_IMAGE_SEQUENCE, Uid, C::None),
+    (tags::IRRADIATION_EVENT_UID, Uid, C::None),
+    (tags::PYRAMID_UID, Uid, C::None),
+    // Acquisition and equipment
+    (tags::CONTRAST_BOLUS_AGENT, Dummy, C::Descriptor),
+    (tags::DEVICE_SERIAL_NUMBER, Zero, C::Device),
+    (tags::DEVICE_UID, Uid, C::Device),
+    (tags::PLATE_ID, Remove, C::Device),
+    (tags::GENERATOR_ID, Remove, C::Device),
+    (tags::CASSETTE_ID, Remove, C::Device),
+    (tags::GANTRY_ID, Remove, C::Device),
+    (tags::PROTOCOL_NAME, Dummy, C::Descriptor),
+    // AcquisitionComments, retired
+    (Tag(0x0018, 0x4000), Remove, C::Descriptor),
+    (tags::DETECTOR_ID, Dummy, C::Device),
+    (tags::TARGET_UID, Uid, C::None),
+    (tags::FRAME_ACQUISITION_DATE_TIME, Remove, C::Temporal),
+    (tags::FRAME_REFERENCE_DATE_TIME, Remove, C::Temporal),
+    (tags::STUDY_ID, Zero, C::None),
+    (tags::STUDY_INSTANCE_UID, Uid, C::None),
+    (tags::SERIES_INSTANCE_UID, Uid, C::None),
+    (tags::FRAME_OF_REFERENCE_UID, Uid, C::None),
+    (tags::SYNCHRONIZATION_FRAME_OF_REFERENCE_UID, Uid, C::None),
+    (tags::IMAGE_COMMENTS, Remove, C::Descriptor),
+    (tags::FRAME_COMMENTS, Remove, C::Descriptor),
+    (tags::CONCATENATION_UID, Uid, C::None),
+    (tags::DIMENSION_ORGANIZATION_UID, Uid, C::None),
+    // LargePaletteColorLookupTableUID, retired
+    (Tag(0x0028, 0x1214), Uid, C::None),
+    (tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE, Uid, C::None),
+    (tags::STORAGE_MEDIA_FILE_SET_UID, Uid, C::None),
+    (tags::REQUESTING_PHYSICIAN, Remove, C::None),
+    (tags::REQUESTING_SERVICE, Remove, C::None),
+    // ReasonForStudy, retired
+    (Tag(0x0032, 0x1030), Remove, C::Descriptor),
+    (tags::REQUESTED_PROCEDURE_DESCRIPTION, Remove, C::Descriptor),
+    // StudyComments, retired
+    (Tag(0x0032, 0x4000), Remove, C::Descriptor),
+    (tags::ADMISSION_ID, Remove, C::None),
+    (tags::ISSUER_OF_ADMISSION_ID_SEQUENCE, Remove, C::None),
+    (tags::ADMITTING_DATE, Remove, C::Temporal),
+    (tags::ADMITTING_TIME, Remove, C::Temporal),
+    // DischargeDiagnosisDescription, retired
+    (Tag(0x0038, 0x0040), Remove, C::Descriptor),
+    (tags::SERVICE_EPISODE_ID, Remove, C::None),
+    (tags::SERVICE_EPISODE_DESCRIPTION, Remove, C::Descriptor),
+    (tags::CURRENT_PATIENT_LOCATION, Remove, C::None),
+    (tags::PATIENT_INSTITUTION_RESIDENCE, Remove, C::None),
+    (tags::VISIT_COMMENTS, Remove, C::Descriptor),
+    // Procedure steps and requests
+    (tags::SCHEDULED_STATION_AE_TITLE, Remove, C::Device),
+    (
+        tags::SCHEDULED_PROCEDURE_STEP_START_DATE,
+        Remove,
+        C::Temporal,
+    ),
+    (
+        tags::SCHEDULED_PROCEDURE_STEP_START_TIME,
+        Remove,
+        C::Temporal,
+    ),
+    (tags::SCHEDULED_PROCEDURE_STEP_END_DATE, Remove, C::Temporal),
+    (tags::SCHEDULED_PROCEDURE_STEP_END_TIME, Remove, C::Temporal),
+    (tags::SCHEDULED_PERFORMING_PHYSICIAN_NAME, Remove, C::None),
+    (
+        tags::SCHEDULED_PROCEDURE_STEP_DESCRIPTION,
+        Remove,
+        C::Descriptor,
+    ),
+    (tags::SCHEDULED_PROCEDURE_STEP_ID, Remove, C::None),
+    (tags::SCHEDULED_STATION_NAME, Remove, C::Device),
+    (tags::SCHEDULED_PROCEDURE_STEP_LOCATION, Remove, C::Device),
+    (tags::PRE_MEDICATION, Remove, C::Descriptor),
+    (tags::PERFORMED_STATION_AE_TITLE, Remove, C::Device),
+    (tags::PERFORMED_STATION_NAME, Remove, C::Device),
+    (tags::PERFORMED_LOCATION, Remove, C::Device),
+    (
+        tags::PERFORMED_PROCEDURE_STEP_START_DATE,
+        Remove,
+        C::Temporal,
+    ),
+    (
+        tags::PERFORMED_PROCEDURE_STEP_START_TIME,
+        Remove,
+        C::Temporal,
+    ),
+    (tags::PERFORMED_PROCEDURE_STEP_END_DATE, Remove, C::Temporal),
+    (tags::PERFORMED_PROCEDURE_STEP_END_TIME, Remove, C::Temporal),
+    (tags::PERFORMED_PROCEDURE_STEP_ID, Remove, C::None),
+    (
+        tags::PERFORMED_PROCEDURE_STEP_DESCRIPTION,
+        Remove,
+        C::Descriptor,
+    ),
+    (tags::REQUEST_ATTRIBUTES_SEQUENCE, Remove, C::None),
+    (
+        tags::COMMENTS_ON_THE_PERFORMED_PROCEDURE_STEP,
+        Remove,
+        C::Descriptor,
+    ),
+    (tags::ACQUISITION_CONTEXT_SEQUENCE, Remove, C::None),
+    (tags::REQUESTED_PROCEDURE_ID, Remove, C::None),
+    (
+        tags::REASON_FOR_THE_REQUESTED_PROCEDURE,
+        Remove,
+        C::Descriptor,
+    ),
+    (tags::REQUESTED_PROCEDURE_COMMENTS, Remove, C::Descriptor),
+    (tags::ORDER_ENTERED_BY, Remove, C::None),
+    (tags::ORDER_ENTERER_LOCATION, Remove, C::None),
+    (tags::ORDER_CALLBACK_PHONE_NUMBER, Remove, C::None),
+    (
+        tags::PLACER_ORDER_NUMBER_IMAGING_SERVICE_REQUEST,
+        Zero,
+        C::None,
+    ),
+    (
+        tags::FILLER_ORDER_NUMBER_IMAGING_SERVICE_REQUEST,
+        Zero,
+        C::None,
+    ),
+    (
+        tags::IMAGING_SERVICE_REQUEST_COMMENTS,
+        Remove,
+        C::Descriptor,
+    ),
+    // Specimens
+    (tags::CONTAINER_IDENTIFIER, Zero, C::None),
+    (tags::CONTAINER_DESCRIPTION, Remove, C::Descriptor),
+    (tags::SPECIMEN_IDENTIFIER, Zero, C::None),
+    (tags::SPECIMEN_UID, Uid, C::None),
+    (tags::SPECIMEN_SHORT_DESCRIPTION, Remove, C::Descriptor),
+    (tags::SPECIMEN_DETAILED_DESCRIPTION, Remove, C::Descriptor),
+    // People and observers
+    (tags::PERSON_IDENTIFICATION_CODE_SEQUENCE, Dummy, C::None),
+    (tags::PERSON_ADDRESS, Remove, C::None),
+    (tags::PERSON_TELEPHONE_NUMBERS, Remove, C::None),
+    (tags::VERIFYING_ORGANIZATION, Remove, C::None),
+    (tags::VERIFICATION_DATE_TIME, Dummy, C::Temporal),
+    (tags::VERIFYING_OBSERVER_SEQUENCE, Dummy, C::None),
+    (tags::VERIFYING_OBSERVER_NAME, Dummy, C::None),
+    (tags::AUTHOR_OBSERVER_SEQUENCE, Remove, C::None),
+    (tags::PARTICIPANT_SEQUENCE, Remove, C::None),
+    (tags::CUSTODIAL_ORGANIZATION_SEQUENCE, Remove, C::None),
+    (
+        tags::VERIFYING_OBSERVER_IDENTIFICATION_CODE_SEQUENCE,
+        Zero,
+        C::None,
+    ),
+    (tags::PERSON_NAME, Dummy, C::None),
+    (tags::UID, Uid, C::None),
+    (tags::CONTENT_SEQUENCE, Remove, C::StructuredContent),
+    // Presentation, devices and RT
+    (tags::GRAPHIC_ANNOTATION_SEQUENCE, Dummy, C::Graphics),
+    (tags::CONTENT_CREATOR_NAME, Zero, C::None),
+    (
+        tags::CONTENT_CREATOR_IDENTIFICATION_CODE_SEQUENCE,
+        Remove,
+        C::None,
+    ),
+    (tags::FIDUCIAL_UID, Uid, C::None),
+    (tags::PRESENTATION_DISPLAY_COLLECTION_UID, Uid, C::None),
+    (tags::PRESENTATION_SEQUENCE_COLLECTION_UID, Uid, C::None),
+    (tags::DEVICE_SEQUENCE, Remove, C::Device),
+    (tags::DEVICE_DESCRIPTION, Remove, C::Device),
+    (tags::DOSE_REFERENCE_UID, Uid, C::None),
+    (tags::REFERENCED_FRAME_OF_REFERENCE_UID, Uid, C::None),
+    // RelatedFrameOfReferenceUID, retired
+    (Tag(0x3006, 0x00C2), Uid, C::None),
+    (tags::SOURCE_SERIAL_NUMBER, Remove, C::Device),
+    (tags::REVIEWER_NAME, Zero, C::None),
+    // Audit and signatures
+    (tags::ENCRYPTED_ATTRIBUTES_SEQUENCE, Remove, C::None),
+    (tags::MODIFIED_ATTRIBUTES_SEQUENCE, Remove, C::None),
+    (tags::ORIGINAL_ATTRIBUTES_SEQUENCE, Remove, C::None),
+    (tags::MAC_PARAMETERS_SEQUENCE, Remove, C::None),
+    (tags::DIGITAL_SIGNATURES_SEQUENCE, Remove, C::None),
+    (tags::DATA_SET_TRAILING_PADDING, Remove, C::None),
+];
+
+// PS3.15 Annex E Basic Application Level Confidentiality Profile with the selected options
+// Replaces the masking, date, private tag and UID steps of anon. The table is applied to every
+// item of the kept sequences, PatientName and PatientID get the ANON ID as their dummy value
+#[derive(Debug, Clone)]
+pub struct StandardProfile {
+    options: Vec<StandardOption>,
+    uid_mapper: UidMapper,
+}
+
+// Attributes of the basic profile, the identifying attributes the tag changes of a run are
+// reported for even when no instance had them
+pub fn basic_profile_tags() -> Vec<Tag> {
+    BASIC_PROFILE.iter().map(|(tag, _, _)| *tag).collect()
+}
+
+impl StandardProfile {
+    pub fn new(mut options: Vec<StandardOption>, uid_mapper: UidMapper) -> Result<Self> {
+        options.sort();
+        options.dedup();
+        if options.contains(&StandardOption::RetainFullDates)
+            && options.contains(&StandardOption::RetainModifiedDates)
+        {
+            return Err(anyhow::anyhow!(
+                "retain-full-dates and retain-modified-dates can't be used together"
+            ));
+        }
+        Ok(StandardProfile {
+            options,
+            uid_mapper,
+        })
+    }
+
+    // Options for the certificate and DeidentificationMethod
+    pub fn summary(&self) -> String {
+        let mut summary = vec!["Basic Application Confidentiality Profile"];
+        summary.extend(self.options.iter().map(|option| option.code().1));
+        summary.join(", ")
+    }
+
+    fn has(&self, option: StandardOption) -> bool {
+        self.options.contains(&option)
+    }
+
+    // Action on the attribute with the selected options
+    fn action_of(&self, tag: Tag) -> Option<Action> {
+        let (_, action, column) = BASIC_PROFILE.iter().find(|(t, _, _)| *t == tag)?;
+        if *action == Uid && self.has(StandardOption::RetainUids) {
+            return Some(Keep);
+        }
+        let option_action = match column {
+            C::Device if self.has(StandardOption::RetainDeviceIdentity) => Some(Keep),
+            C::Institution if self.has(StandardOption::RetainInstitutionIdentity) => Some(Keep),
+            C::Characteristics if self.has(StandardOption::RetainPatientCharacteristics) => {
+                Some(Keep)
+            }
+            C::Temporal if self.has(StandardOption::RetainFullDates) => Some(Keep),
+            C::Temporal if self.has(StandardOption::RetainModifiedDates) => Some(Clean),
+            C::Descriptor if self.has(StandardOption::CleanDescriptors) => Some(Clean),
+            C::StructuredContent if self.has(StandardOption::CleanStructuredContent) => Some(Clean),
+            C::Graphics if self.has(StandardOption::CleanGraphics) => Some(Clean),
+            _ => None,
+        };
+        Some(option_action.unwrap_or(*action))
+    }
+
+    pub fn apply(
+        &self,
+        dcm_obj: &mut FileDicomObject<InMemDicomObject>,
+        anon_id: &str,
+    ) -> Result<()> {
+        let cleaner = Cleaner {
+            profile: self,
+            anon_id,
+            identifiers: identifying_values(dcm_obj),
+            date_shift: self.uid_mapper.date_shift_days(anon_id),
+        };
+        // The identifiers of the text are read before the table masks them
+        if self.has(StandardOption::CleanStructuredContent) {
+            scrub_sr_content(dcm_obj, anon_id);
+        }
+        scrub_annotation_text(
+            dcm_obj,
+            match self.has(StandardOption::CleanGraphics) {
+                true => AnnotationText::Redact,
+                false => AnnotationText::Remove,
+            },
+            anon_id,
+        );
+        cleaner.clean(dcm_obj);
+
+        for tag in [tags::PATIENT_NAME, tags::PATIENT_ID] {
+            let vr = if tag == tags::PATIENT_NAME {
+                VR::PN
+            } else {
+                VR::LO
+            };
+            dcm_obj.put(DataElement::new(tag, vr, vr_dummy_value(vr, anon_id)));
+        }
+        self.put_method(dcm_obj);
+        let sop_instance_uid = dcm_obj
+            .element(tags::SOP_INSTANCE_UID)?
+            .to_str()?
+            .to_string();
+        let meta = dcm_obj.meta_mut();
+        meta.media_storage_sop_instance_uid = sop_instance_uid;
+        meta.update_information_group_length();
+        Ok(())
+    }
+
+    // PatientIdentityRemoved, DeidentificationMethod and its code sequence
+    fn put_method(&self, dcm_obj: &mut InMemDicomObject) {
+        put_deidentification_method(dcm_obj, &self.summary());
+        let codes = std::iter::once(("113100", "Basic Application Confidentiality Profile"))
+            .chain(self.options.iter().map(|option| option.code()));
+        let items: Vec<InMemDicomObject> = codes
+            .map(|(value, meaning)| {
+                InMemDicomObject::from_element_iter([
+                    DataElement::new(tags::CODE_VALUE, VR::SH, dicom_value!(Str, value)),
+                    DataElement::new(
+                        tags::CODING_SCHEME_DESIGNATOR,
+                        VR::SH,
+                        dicom_value!(Str, "DCM"),
+                    ),
+                    DataElement::new(tags::CODE_MEANING, VR::LO, dicom_value!(Str, meaning)),
+                ])
+            })
+            .collect();
+        dcm_obj.put(DataElement::new(
+            tags::DEIDENTIFICATION_METHOD_CODE_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(items),
+        ));
+        let temporal = if self.has(StandardOption::RetainFullDates) {
+            "UNMODIFIED"
+        } else if self.has(StandardOption::RetainModifiedDates) {
+            "MODIFIED"
+        } else {
+            "REMOVED"
+        };
+        dcm_obj.put(DataElement::new(
+            tags::LONGITUDINAL_TEMPORAL_INFORMATION_MODIFIED,
+            VR::CS,
+            dicom_value!(Strs, [temporal.to_string()]),
+        ));
+    }
+}
+
+// State of the profile for one file
+struct Cleaner<'a> {
+    profile: &'a StandardProfile,
+    anon_id: &'a str,
+    // Identifiers of the source, redacted from the cleaned text
+    identifiers: Vec<String>,
+    // Days the dates are shifted back by for retain-modified-dates
+    date_shift: u64,
+}
+
+impl Cleaner<'_> {
+    // Apply the table to the object and the items of its kept sequences
+    fn clean(&self, dcm_obj: &mut InMemDicomObject) {
+        let elements: Vec<(Tag, VR)> = dcm_obj.iter().map(|e| (e.tag(), e.vr())).collect();
+        for (tag, vr) in elements {
+            let action = if is_removed_group(tag) {
+                Remove
+            } else {
+                self.profile.action_of(tag).unwrap_or_else(|| {
+                    match RETIRED_IDENTIFYING_TAGS.contains(&tag) {
+                        true => Remove,
+                        false => Keep,
+                    }
+                })
+            };
+            match (action, vr) {
+                (Remove, _) => {
+                    dcm_obj.remove_element(tag);
+                }
+                (Zero, VR::SQ) => {
+                    dcm_obj.put(DataElement::new(
+                        tag,
+                        vr,
+                        DataSetSequence::from(Vec::<InMemDicomObject>::new()),
+                    ));
+                }
+                (Zero, VR::DA | VR::TM | VR::DT) | (Dummy, _) if vr != VR::SQ => {
+                    let multiplicity = dcm_obj
+                        .get(tag)
+                        .map_or(1, |element| element.value().multiplicity());
+                    dcm_obj.put(DataElement::new(
+                        tag,
+                        vr,
+                        with_multiplicity(vr_dummy_value(vr, self.anon_id), vr, multiplicity),
+                    ));
+                }
+                (Zero, _) => {
+                    dcm_obj.put(DataElement::new(tag, vr, PrimitiveValue::Empty));
+                }
+                (Uid, VR::UI) => {
+                    self.update_strs(dcm_obj, tag, |uid| self.profile.uid_mapper.new_uid(uid))
+                }
+                (Clean, VR::DA | VR::DT) => self.update_strs(dcm_obj, tag, |value| {
+                    shift_date_value(vr, value, self.date_shift)
+                }),
+                (Clean, VR::SH | VR::LO | VR::ST | VR::LT | VR::UT | VR::UC | VR::PN) => {
+                    self.update_strs(dcm_obj, tag, |text| self.redact(text))
+                }
+                (_, VR::SQ) => {
+                    dcm_obj.update_value(tag, |value| {
+                        for item in value.items_mut().into_iter().flatten() {
+                            self.clean(item);
+                        }
+                    });
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // Replace each value of a text element
+    fn update_strs(&self, dcm_obj: &mut InMemDicomObject, tag: Tag, f: impl Fn(&str) -> String) {
+        let element = match dcm_obj.get(tag) {
+            Some(element) => element,
+            None => return,
+        };
+        let vr = element.vr();
+        let values: Vec<String> = match element.to_multi_str() {
+            Ok(values) => values
+                .iter()
+                .map(|value| f(value.trim_end_matches(['\0', ' '])))
+                .collect(),
+            Err(_) => return,
+        };
+        dcm_obj.put(DataElement::new(
+            tag,
+            vr,
+            PrimitiveValue::Strs(values.into()),
+        ));
+    }
+
+    fn redact(&self, text: &str) -> String {
+        self.identifiers
+            .iter()
+            .fold(text.to_string(), |text, identifier| {
+                text.replace(identifier.as_str(), self.anon_id)
+            })
+    }
+}
+
+// Private attributes and the legacy groups are always removed
+fn is_removed_group(tag: Tag) -> bool {
+    tag.group() % 2 == 1 || is_legacy_group(tag)
+}
+
+/// PatientIdentityRemoved YES and the DeidentificationMethod DCMRIG <method>, for every path that
+/// deidentifies an instance
+pub fn put_deidentification_method(dcm_obj: &mut InMemDicomObject, method: &str) {
+    dcm_obj.put(DataElement::new(
+        tags::PATIENT_IDENTITY_REMOVED,
+        VR::CS,
+        dicom_value!(Strs, ["YES".to_string()]),
+    ));
+    dcm_obj.put(DataElement::new(
+        tags::DEIDENTIFICATION_METHOD,
+        VR::LO,
+        dicom_value!(
+            Strs,
+            [fit_to_vr_length(VR::LO, &format!("DCMRIG {}", method))]
+        ),
+    ));
+}
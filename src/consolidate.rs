@@ -0,0 +1,182 @@
+use anyhow::Result;
+use dcmrig_rs::{
+    frames::{legacy_converted_class, merge_frames},
+    get_sanitized_tag_values, open_source_file,
+    tracker::RunTracker,
+    unique_output_path, write_dicom_file, RunOptions,
+};
+use dicom::{
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject, Tag},
+};
+use rayon::prelude::*;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::Path,
+};
+use tracing::{debug, info, warn};
+
+// Tags the instances of a merged series must share, with the series and the SOP class
+const MERGE_KEY_TAGS: [Tag; 9] = [
+    tags::SERIES_INSTANCE_UID,
+    tags::SOP_CLASS_UID,
+    tags::ROWS,
+    tags::COLUMNS,
+    tags::SAMPLES_PER_PIXEL,
+    tags::PHOTOMETRIC_INTERPRETATION,
+    tags::BITS_ALLOCATED,
+    tags::BITS_STORED,
+    tags::PIXEL_REPRESENTATION,
+];
+
+/// Merge the classic CT, MR and PET instances written by the run into one Legacy Converted
+/// Enhanced instance per series, see merge_frames. The merged file replaces the classic files in
+/// the destination, results.csv, the manifest and the post study hook
+/// Instances of a series with another matrix or pixel format are merged apart, a series that
+/// can't be merged keeps its classic instances
+pub fn merge_series(
+    run_options: &RunOptions,
+    tracker: &RunTracker,
+    destination_path: &Path,
+) -> Result<()> {
+    if !run_options.merge_frames || tracker.dry_run {
+        return Ok(());
+    }
+    let mut outputs: Vec<String> = tracker
+        .results
+        .lock()
+        .expect("Failed to lock mutex")
+        .iter()
+        .filter(|result| result.status == "processed" && !result.output.is_empty())
+        .map(|result| result.output.clone())
+        .collect();
+    outputs.sort();
+    outputs.dedup();
+    let mut series: BTreeMap<Vec<String>, Vec<String>> = BTreeMap::new();
+    for file in outputs {
+        let dcm_obj = match open_source_file(Path::new(&file), true) {
+            Ok(dcm_obj) => dcm_obj,
+            Err(e) => {
+                warn!("Not merged, can't read {}: {}", file, e);
+                continue;
+            }
+        };
+        if legacy_converted_class(&value(&dcm_obj, tags::SOP_CLASS_UID)).is_none() {
+            continue;
+        }
+        let mut key: Vec<String> = MERGE_KEY_TAGS
+            .iter()
+            .map(|tag| value(&dcm_obj, *tag))
+            .collect();
+        key.push(dcm_obj.meta().transfer_syntax().to_string());
+        series.entry(key).or_default().push(file);
+    }
+
+    let mut merged_series = 0;
+    let mut merged_instances = 0;
+    for (key, files) in series.iter().filter(|(_, files)| files.len() > 1) {
+        match merge_files(tracker, files) {
+            Ok(merged_path) => {
+                debug!("{} instances merged into {}", files.len(), merged_path);
+                merged_series += 1;
+                merged_instances += files.len();
+            }
+            Err(e) => warn!(
+                "Series {} is kept as {} classic instances: {}",
+                key[0],
+                files.len(),
+                e
+            ),
+        }
+    }
+    info!(
+        "{} classic instances merged into {} enhanced instances in {}",
+        merged_instances,
+        merged_series,
+        destination_path.display()
+    );
+    Ok(())
+}
+
+// Write the merged instance next to the classic files, then replace them with it
+fn merge_files(tracker: &RunTracker, files: &[String]) -> Result<String> {
+    let instances = files
+        .par_iter()
+        .map(|file| open_source_file(Path::new(file), false))
+        .collect::<Result<Vec<_>>>()?;
+    let merged = merge_frames(instances)?;
+    let first = Path::new(&files[0]);
+    let dir_path = first.parent().unwrap_or(Path::new("."));
+    // ANON or DeID, as the classic files
+    let prefix = first
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split('_').next())
+        .unwrap_or("DCM")
+        .to_string();
+    let file_name =
+        tracker.output_file_name(&get_sanitized_tag_values(&merged)?, &merged, prefix)?;
+    // Written under a temporary name, the final one is usually taken by the first classic file
+    let merging_path = dir_path.join(format!("{}.merging", file_name));
+    let digest =
+        tracker
+            .sink
+            .write_instance(&merging_path, tracker.checksums.is_some(), &mut |to| {
+                write_dicom_file(first, None, &merged, &HashSet::new(), to)
+            })?;
+    for file in files {
+        fs::remove_file(file)?;
+    }
+    let merged_path = unique_output_path(
+        dir_path.join(file_name).display().to_string(),
+        tracker.duplicate_suffix,
+        &value(&merged, tags::SOP_INSTANCE_UID),
+    );
+    fs::rename(&merging_path, &merged_path)?;
+
+    let merged_files: HashSet<&String> = files.iter().collect();
+    for result in tracker
+        .results
+        .lock()
+        .expect("Failed to lock mutex")
+        .iter_mut()
+        .filter(|result| merged_files.contains(&result.output))
+    {
+        result.output = merged_path.clone();
+    }
+    if let Some(checksums) = &tracker.checksums {
+        checksums
+            .lock()
+            .expect("Failed to lock mutex")
+            .retain(|(full_path, _)| !merged_files.contains(full_path));
+    }
+    tracker.record_checksum(&merged_path, digest);
+    // One file now stands for the instances of the study it replaces
+    for study in tracker
+        .study_files
+        .lock()
+        .expect("Failed to lock mutex")
+        .values_mut()
+    {
+        let count = study.files.len();
+        study.files.retain(|file| !merged_files.contains(file));
+        let removed = count - study.files.len();
+        if removed > 0 {
+            study.files.push(merged_path.clone());
+            study.expected_instances = study
+                .expected_instances
+                .map(|expected| expected.saturating_sub(removed - 1));
+        }
+    }
+    Ok(merged_path)
+}
+
+fn value(dcm_obj: &FileDicomObject<InMemDicomObject>, tag: Tag) -> String {
+    dcm_obj
+        .element(tag)
+        .ok()
+        .and_then(|element| element.to_str().ok())
+        .map(|value| value.trim_end_matches('\0').trim().to_string())
+        .unwrap_or_default()
+}
@@ -1,4 +1,8 @@
 use anyhow::Result;
+use dcmrig_rs::{
+    pixel_mask::PixelMask, rules::RuleSet, AnnotationText, DateOrder, DatePrecision,
+    DescriptionMap, MatrixSize, ReviewPolicy, StudyValues, TagPath, TranscodeTarget,
+};
 use dicom::core::dictionary::DataDictionaryEntryRef;
 use dicom::core::{DataDictionary, VR};
 use dicom::object::StandardDataDictionary;
@@ -9,9 +13,9 @@ use std::str::FromStr;
 use std::{
     collections::HashMap,
     fs::{self, canonicalize, create_dir_all, File},
+    path::{Path, PathBuf},
     process::exit,
 };
-use toml;
 use tracing::{error, info, warn};
 
 #[derive(Debug, Deserialize)]
@@ -20,6 +24,12 @@ struct CookBook {
     mask: Option<MaskTags>,
     delete: Option<DelTags>,
     add: Option<AddTags>,
+    dates: Option<DateOptions>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DateOptions {
+    precision: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -88,6 +98,7 @@ tags = ["PatientID", "PatientName", "InstitutionName", "InstitutionAddress", "St
 vrs = ["PN"]
 
 # List of tags that will be deleted
+# Elements in sequences are addressed with a path, eg "RequestAttributesSequence[*].ScheduledProcedureStepID"
 [delete]
 tags = []
 private_tags = false
@@ -106,15 +117,21 @@ tags.ClinicalTrialTimePointID = "PatientID_StudyDateTStudyTime_Modality"
 "#;
     let mut file_to_save =
         File::create(cookbook_file_path).expect("Failed to create cookbook path");
-    write!(file_to_save, "{}", default_cookbook_raw.to_string())?;
+    write!(file_to_save, "{}", default_cookbook_raw)?;
     info!("Default cookbook created: {}", cookbook_file_path);
     Ok(default_cookbook_raw.to_string())
 }
 
+// Path of the cookbook in the users home dir
+pub fn home_cookbook_path() -> PathBuf {
+    let home_path = home_dir().expect("Home path not found");
+    home_path.join(".dcmrig").join("cookbook.toml")
+}
+
 fn check_for_cookbook() -> Result<String> {
     let home_path = home_dir().expect("Home path not found");
     let cookbook_home = format!("{}/.dcmrig", home_path.display());
-    let cookbook_file_path = format!("{}/cookbook.toml", cookbook_home);
+    let cookbook_file_path = home_cookbook_path().display().to_string();
 
     match canonicalize(&cookbook_file_path) {
         Ok(_) => (),
@@ -138,12 +155,12 @@ fn check_for_cookbook() -> Result<String> {
     Ok(file_content)
 }
 
-fn check_valid_tag_vec(tag_vec: Vec<String>) -> Vec<DataDictionaryEntryRef<'static>> {
+fn check_valid_tag_vec(tag_vec: Vec<String>) -> Vec<TagPath> {
     let mut std_tag_list = Vec::new();
     for each in tag_vec {
-        match DataDictionary::by_name(&StandardDataDictionary, &each) {
-            Some(tag) => std_tag_list.push(tag.to_owned()),
-            None => warn!("Tag {} is not valid", each),
+        match TagPath::from_str(&each) {
+            Ok(tag_path) => std_tag_list.push(tag_path),
+            Err(e) => warn!("Tag {} is not valid: {}", each, e),
         }
     }
     // tags_vec
@@ -165,31 +182,27 @@ fn check_valid_vr_vec(vrs_vec: Vec<String>) -> Vec<VR> {
 fn check_valid_tag_hashmap(tag_hash: HashMap<String, String>) -> HashMap<String, String> {
     let mut tags_hash_m = tag_hash.clone();
     for each in tag_hash {
-        match DataDictionary::by_name(&StandardDataDictionary, &each.0) {
-            Some(_) => (),
-            None => {
-                tags_hash_m.remove(&each.0);
-                warn!("Tag {} is not valid", each.0)
-            }
+        if let Err(e) = TagPath::from_str(&each.0) {
+            tags_hash_m.remove(&each.0);
+            warn!("Tag {} is not valid: {}", each.0, e)
         }
     }
     tags_hash_m
 }
 
-fn check_tag_list(action: &str, tag_list: Vec<String>) -> Vec<DataDictionaryEntryRef<'static>> {
+fn check_tag_list(action: &str, tag_list: Vec<String>) -> Vec<TagPath> {
     match tag_list.is_empty() {
         true => {
             warn!("The {} cookbook is empty or corrupted", action);
-            return vec![];
+            vec![]
         }
         false => {
             info!("Checking Mask list");
-            let tag_list: Vec<DataDictionaryEntryRef<'_>> = check_valid_tag_vec(tag_list);
+            let tag_list: Vec<TagPath> = check_valid_tag_vec(tag_list);
             tag_list
                 .iter()
-                .enumerate()
-                .for_each(|(_i, v)| info!("Tags to {} {}", action, v.alias));
-            return tag_list;
+                .for_each(|v| info!("Tags to {} {}", action, v));
+            tag_list
         }
     }
 }
@@ -198,60 +211,94 @@ fn check_vr_list(vr_list: Vec<String>) -> Vec<VR> {
     match vr_list.is_empty() {
         true => {
             warn!("The Mask VR cookbook is empty or corrupted");
-            return vec![];
+            vec![]
         }
         false => {
             info!("Checking Mask list");
             let vr_list = check_valid_vr_vec(vr_list);
             // info!("Tags to mask {:?}", mask_list);
+            vr_list.iter().for_each(|v| info!("VR to mask {}", v));
             vr_list
-                .iter()
-                .enumerate()
-                .for_each(|(_i, v)| info!("VR to mask {}", v));
-            return vr_list;
         }
     }
 }
 
-pub fn parse_toml_cookbook() -> Result<(
-    DataDictionaryEntryRef<'static>,
-    Vec<DataDictionaryEntryRef<'static>>,
-    Vec<VR>,
-    HashMap<String, String>,
-    Vec<DataDictionaryEntryRef<'static>>,
-    bool,
-)> {
+// Validated cookbook values used by the DeID
+#[derive(Debug, Clone)]
+pub struct CookBookConfig {
+    pub match_id: DataDictionaryEntryRef<'static>,
+    pub mask_tags: Vec<TagPath>,
+    pub mask_vrs: Vec<VR>,
+    pub add_tags: HashMap<String, String>,
+    pub delete_tags: Vec<TagPath>,
+    pub delete_private_tags: bool,
+    pub date_precision: Option<DatePrecision>,
+    pub description_map: Option<DescriptionMap>,
+    pub rules: Option<RuleSet>,
+    pub study_values: Option<StudyValues>,
+    pub review_policy: ReviewPolicy,
+    pub annotation_text: AnnotationText,
+    pub downsample: Option<MatrixSize>,
+    pub split_frames: bool,
+    pub transcode: TranscodeTarget,
+    pub pixel_mask: Option<PixelMask>,
+    pub date_order: DateOrder,
+    pub fix_vr: bool,
+    pub keep_legacy_tags: bool,
+}
+
+// Read the cookbook from the users home dir, a default one is created if not found
+pub fn parse_toml_cookbook() -> Result<CookBookConfig> {
     let file_content = check_for_cookbook()?;
+    parse_cookbook_content(&file_content)
+}
+
+// Read the cookbook from the given path
+pub fn parse_cookbook_file(cookbook_path: &Path) -> Result<CookBookConfig> {
+    let file_content = fs::read_to_string(cookbook_path).unwrap_or_else(|_| {
+        error!("Can't read the cookbook file: {}", cookbook_path.display());
+        exit(1)
+    });
+    info!(
+        "Reading from the cookbook toml file at {}",
+        cookbook_path.display()
+    );
+    parse_cookbook_content(&file_content)
+}
+
+fn parse_cookbook_content(file_content: &str) -> Result<CookBookConfig> {
     let toml_des: CookBook =
-        toml::from_str(&file_content).expect("Failed to deserialize Cargo.toml");
+        toml::from_str(file_content).expect("Failed to deserialize cookbook toml");
 
     // Setting up variables
     let matchid = toml_des.matchid.unwrap_or_else(|| MatchIDTag {
         tag: "PatientID".to_string(),
     });
-    let mask_list = toml_des
-        .mask
-        .clone()
-        .unwrap_or_else(|| MaskTags::default())
-        .tags;
-    let mask_vrs_list = toml_des
-        .mask
-        .clone()
-        .unwrap_or_else(|| MaskTags::default())
-        .vrs;
+    let mask_list = toml_des.mask.clone().unwrap_or_else(MaskTags::default).tags;
+    let mask_vrs_list = toml_des.mask.clone().unwrap_or_else(MaskTags::default).vrs;
 
-    let add_list = toml_des.add.unwrap_or_else(|| AddTags::default()).tags;
+    let add_list = toml_des.add.unwrap_or_else(AddTags::default).tags;
 
     let delete_list = toml_des
         .delete
         .clone()
-        .unwrap_or_else(|| DelTags::default())
+        .unwrap_or_else(DelTags::default)
         .tags;
     let private_tags_del = toml_des
         .delete
-        .unwrap_or_else(|| DelTags::default())
+        .unwrap_or_else(DelTags::default)
         .private_tags;
 
+    let date_precision = toml_des
+        .dates
+        .and_then(|dates| dates.precision)
+        .map(|precision| {
+            DatePrecision::from_str(&precision).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        });
+
     // Validating the lists
     info!("Checking MatchID tag");
     let matchid = match matchid.tag.as_str() {
@@ -283,18 +330,30 @@ pub fn parse_toml_cookbook() -> Result<(
             // info!("Tags to add {:?}", add_list);
             add_list
                 .iter()
-                .enumerate()
-                .for_each(|(_i, v)| info!("Tags to add {} > {}", v.0, v.1));
+                .for_each(|v| info!("Tags to add {} > {}", v.0, v.1));
             add_list
         }
     };
 
-    Ok((
-        matchid.to_owned(),
-        mask_tag_list,
-        mask_vr_list,
-        add_list,
-        delete_tag_list,
-        private_tags_del,
-    ))
+    Ok(CookBookConfig {
+        match_id: matchid.to_owned(),
+        mask_tags: mask_tag_list,
+        mask_vrs: mask_vr_list,
+        add_tags: add_list,
+        delete_tags: delete_tag_list,
+        delete_private_tags: private_tags_del,
+        date_precision,
+        description_map: None,
+        rules: None,
+        study_values: None,
+        review_policy: ReviewPolicy::default(),
+        annotation_text: AnnotationText::default(),
+        downsample: None,
+        split_frames: false,
+        transcode: TranscodeTarget::Keep,
+        pixel_mask: None,
+        date_order: DateOrder::default(),
+        fix_vr: false,
+        keep_legacy_tags: false,
+    })
 }
@@ -0,0 +1,178 @@
+use anyhow::Result;
+use dcmrig_rs::{
+    duplicate_instances_csv, same_file_content, sop_instance_uid,
+    source::{DirectorySource, InstanceSource},
+    ByteSize, DuplicateInstance, RunOptions,
+};
+use rayon::prelude::*;
+use std::{
+    collections::BTreeMap,
+    fs::{self, canonicalize, create_dir_all},
+    path::{Path, PathBuf},
+    process::exit,
+};
+use tracing::{debug, error, info, warn};
+
+/// Files of the source with the SOPInstanceUID of another file, eg a study exported twice into
+/// different folders. The first path of each SOPInstanceUID is kept, the others are listed, and
+/// moved to move_to with their path in the source when they have the same content as the kept
+/// file. Written as CSV to the output file, or printed
+pub fn dicom_dedup(
+    source_path: PathBuf,
+    output: Option<PathBuf>,
+    move_to: Option<PathBuf>,
+    run_options: RunOptions,
+) -> Result<()> {
+    let source_root = canonicalize(&source_path).unwrap_or_else(|e| {
+        error!(
+            "Given source Path doesnot exist: {}\n{}",
+            source_path.display(),
+            e
+        );
+        exit(1)
+    });
+    if let Some(move_to) = &move_to {
+        if std::path::absolute(move_to)?.starts_with(&source_root)
+            || move_to.starts_with(&source_path)
+        {
+            error!(
+                "The duplicates can't be moved inside the source: {}",
+                move_to.display()
+            );
+            exit(1)
+        }
+    }
+    let source = DirectorySource::new(&source_path, run_options.read_iso);
+    info!("Indexing files from: {}", source.describe());
+    let all_files = source.items()?;
+    // SOPInstanceUID > files, files without one are left out
+    let instances: BTreeMap<String, Vec<PathBuf>> = all_files
+        .par_iter()
+        .filter_map(|item| {
+            let dcm_obj = source.open_dataset(item, true).ok()?;
+            let sop_uid = sop_instance_uid(&dcm_obj);
+            (!sop_uid.is_empty()).then(|| (sop_uid, item.clone()))
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .fold(BTreeMap::new(), |mut instances, (sop_uid, item)| {
+            instances.entry(sop_uid).or_insert_with(Vec::new).push(item);
+            instances
+        });
+
+    let duplicates: Vec<DuplicateInstance> = instances
+        .par_iter()
+        .filter(|(_, files)| files.len() > 1)
+        .flat_map_iter(|(sop_uid, files)| {
+            let mut files = files.clone();
+            files.sort();
+            let kept = files.remove(0);
+            files.into_iter().map(move |duplicate| DuplicateInstance {
+                sop_uid: sop_uid.clone(),
+                identical: same_file_content(&kept, &duplicate),
+                kept: kept.clone(),
+                duplicate,
+            })
+        })
+        .collect();
+    let bytes: u64 = duplicates
+        .iter()
+        .map(|duplicate| fs::metadata(&duplicate.duplicate).map_or(0, |metadata| metadata.len()))
+        .sum();
+    let different = duplicates
+        .iter()
+        .filter(|duplicate| !duplicate.identical)
+        .count();
+    info!(
+        "{} instances, {} duplicates ({}) of {} of them",
+        instances.len(),
+        duplicates.len(),
+        ByteSize(bytes),
+        instances.values().filter(|files| files.len() > 1).count()
+    );
+    if different > 0 {
+        warn!(
+            "{} duplicates have the SOPInstanceUID of another file but not its content, they are never moved",
+            different
+        );
+    }
+
+    let content = duplicate_instances_csv(&duplicates);
+    match &output {
+        Some(output) => {
+            fs::write(output, content)?;
+            info!("Duplicates written to {}", output.display());
+        }
+        None => print!("{}", content),
+    }
+    if let Some(move_to) = &move_to {
+        move_duplicates(&duplicates, &source_path, move_to, run_options.dry_run);
+    }
+    source.finalize()?;
+    Ok(())
+}
+
+// Move the identical duplicates to move_to, with their path in the source. The files extracted
+// from the ISO images are not in the source and stay where they are
+fn move_duplicates(
+    duplicates: &[DuplicateInstance],
+    source_path: &Path,
+    move_to: &Path,
+    dry_run: bool,
+) {
+    let mut moved = 0;
+    for duplicate in duplicates.iter().filter(|duplicate| duplicate.identical) {
+        let Ok(relative) = duplicate.duplicate.strip_prefix(source_path) else {
+            warn!(
+                "Not in the source, not moved: {}",
+                duplicate.duplicate.display()
+            );
+            continue;
+        };
+        let target = move_to.join(relative);
+        if dry_run {
+            info!(
+                "Would move {} to {}",
+                duplicate.duplicate.display(),
+                target.display()
+            );
+            moved += 1;
+            continue;
+        }
+        if target.exists() {
+            warn!("Already taken, not moved: {}", target.display());
+            continue;
+        }
+        match move_file(&duplicate.duplicate, &target) {
+            Ok(_) => {
+                debug!(
+                    "Moved {} to {}",
+                    duplicate.duplicate.display(),
+                    target.display()
+                );
+                moved += 1;
+            }
+            Err(e) => error!("Can't move {}: {}", duplicate.duplicate.display(), e),
+        }
+    }
+    match dry_run {
+        true => info!(
+            "{} duplicates would be moved to {}",
+            moved,
+            move_to.display()
+        ),
+        false => info!("{} duplicates moved to {}", moved, move_to.display()),
+    }
+}
+
+// Rename the file, or copy then remove it across filesystems
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
@@ -1,21 +1,31 @@
-use crate::cookbook_parser::parse_toml_cookbook;
+use crate::certificate::{
+    effective_config, file_digest, policy_hash, write_certificate, RunSummary,
+};
+use crate::consolidate::merge_series;
+use crate::cookbook_parser::{home_cookbook_path, parse_toml_cookbook, CookBookConfig};
+use crate::dicomdir::write_dicomdir;
+use crate::notify::send_run_report;
+use crate::runs::record_run;
+use crate::sidecar::scrub_sidecars;
 use anyhow::Result;
 use crossbeam::sync::WaitGroup;
+use dcmrig_rs::delivery::deliver_archives;
+use dcmrig_rs::pipeline::{FileRun, FileTransform};
 use dcmrig_rs::*;
-
-use dicom::{
-    core::{dictionary::DataDictionaryEntryRef, VR},
-    object::{FileDicomObject, InMemDicomObject},
+use dcmrig_rs::{
+    frames::{output_instances, OutputInstance},
+    tracker::RunTracker,
 };
 
-use rayon::prelude::*;
+use dicom::object::{FileDicomObject, InMemDicomObject};
+
 use std::{
     collections::HashMap,
     fs::File,
     io::{BufRead, BufReader},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::exit,
-    sync::{Arc, Mutex},
+    time::Instant,
 };
 use tracing::{debug, error, info, warn};
 
@@ -23,6 +33,7 @@ pub fn dicom_deid(
     source_path: PathBuf,
     destination_path: PathBuf,
     mapping_table: PathBuf,
+    run_options: RunOptions,
 ) -> Result<()> {
     info!(
         "Deidentifying the data for >> SOURCE: {} | DESTINATION: {} | MappingTable: {}",
@@ -32,179 +43,471 @@ pub fn dicom_deid(
     );
 
     // Get cookbook configs
-    let (
-        match_id,
-        mask_tag_config,
-        mask_vr_config,
-        add_config,
-        delete_tag_config,
-        private_tags_del,
-    ) = parse_toml_cookbook()?;
-
-    // Set up required variables
-    let (all_files, total_len, pb) = preprocessing_setup(&source_path, &destination_path)?;
-    let failed_case: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    let non_dcm_cases: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
+    let mut cookbook = parse_toml_cookbook()?;
+    if run_options.date_precision.is_some() {
+        cookbook.date_precision = run_options.date_precision;
+    }
+    cookbook.description_map = run_options.description_map.clone();
+    cookbook.rules = run_options.rules.clone();
+    cookbook.study_values = run_options.study_values.clone();
+    cookbook.review_policy = run_options.review_policy.clone();
+    cookbook.annotation_text = run_options.annotation_text;
+    cookbook.downsample = run_options.downsample;
+    cookbook.split_frames = run_options.split_frames;
+    cookbook.transcode = run_options.transcode;
+    cookbook.pixel_mask = run_options.pixel_mask.clone();
+    cookbook.date_order = run_options.date_order;
+    cookbook.fix_vr = run_options.fix_vr;
+    cookbook.keep_legacy_tags = run_options.keep_legacy_tags;
+    let cookbook_path = home_cookbook_path();
+    let command = [
+        ("Action".to_string(), "DeID".to_string()),
+        (
+            "Mapping table".to_string(),
+            mapping_table.display().to_string(),
+        ),
+        (
+            "Mapping table SHA-256".to_string(),
+            file_digest(&mapping_table),
+        ),
+    ];
+    let profile = cookbook_summary(&cookbook, &cookbook_path);
+    let config = effective_config(&command, &profile, &run_options);
+    if run_options.print_effective_config {
+        println!("{}", config);
+        return Ok(());
+    }
+
     let mapping_dict = generate_mapping_dict(&mapping_table).unwrap_or_else(|_| {
         error!("Can't open the mapping table: {}", mapping_table.display());
         exit(1);
     });
-    let wg = WaitGroup::new();
-
-    // Main Loop
-    all_files
-        .par_iter()
-        .enumerate()
-        .for_each(|(_index, working_path)| {
-            if let Ok(dcm_obj) = dicom::object::OpenFileOptions::new()
-                .read_all()
-                .open_file(working_path.path())
-            {
-                deid_each_dcm_file(
-                    &dcm_obj,
-                    &destination_path,
-                    mapping_dict.clone(),
-                    match_id.clone(),
-                    mask_tag_config.clone(),
-                    mask_vr_config.clone(),
-                    delete_tag_config.clone(),
-                    add_config.clone(),
-                    private_tags_del.clone(),
-                    wg.clone(),
-                )
-                .unwrap_or_else(|_| {
-                    let mut map = failed_case.lock().expect("Failed to lock mutex");
-                    *map += 1;
-                    error!(
-                        "Can't DeID {:#?} Copying to FAILED_CASES directory",
-                        &working_path.file_name()
-                    );
-                    failed_case_copy(&working_path.clone().into_path(), &destination_path)
-                        .expect("Failed to copy file to FAILED_CASES directory");
-                });
-            } else {
-                let nwg = wg.clone();
-                let mut map = non_dcm_cases.lock().expect("Failed to lock mutex");
-                *map += 1;
-                copy_non_dicom_files(&working_path, &destination_path).unwrap_or_else(|_| {
-                    error!("Can't copy non dicom file {:#?}", &working_path.file_name());
-                });
-                drop(nwg);
-            }
-            pb.inc(1);
-        });
-    pb.finish();
-    print_status(
-        total_len,
-        *failed_case.lock().expect("Failed to lock mutex"),
-        *non_dcm_cases.lock().expect("Failed to lock mutex"),
-        "DeID".to_string(),
+    let policy = policy_hash(&command, &profile, &run_options);
+    let mut run = FileRun::start(
+        "DeID",
+        &policy,
+        &source_path,
+        &destination_path,
+        &run_options,
     )?;
-    info!("Waiting for all threads to complete");
-    wg.wait();
+    run.save_checkpoints(&run_options, None);
+    run.process(
+        &DeidTransform {
+            mapping_dict: &mapping_dict,
+            cookbook: &cookbook,
+        },
+        &destination_path,
+        &run_options,
+    )?;
+    let FileRun {
+        tracker,
+        total_len,
+        checkpoint_writer,
+        status_writer,
+        failed,
+        non_dicom,
+        ..
+    } = run;
+    tracker.progress.finish();
+    print_slowest_files(&tracker.timings, run_options.slowest)?;
+    tracker.print_series_classes();
+    tracker.print_routing();
+    tracker.print_mixed_patients();
+    scrub_sidecars(&tracker, &destination_path)?;
+    merge_series(&run_options, &tracker, &destination_path)?;
+    if let Some(checkpoint_writer) = checkpoint_writer {
+        checkpoint_writer.finish();
+    }
+    let manifest_path = tracker.write_manifest(&destination_path)?;
+    tracker.run_study_hooks();
+    deliver_archives(&run_options, &tracker, &destination_path)?;
+    write_dicomdir(&run_options, &tracker, &destination_path)?;
+    tracker.print_collisions();
+    tracker.print_long_paths();
+    match run_options.dry_run {
+        true => tracker.write_dry_run_report(run_options.dry_run_report.as_deref())?,
+        false => tracker.write_results(&destination_path)?,
+    }
+    tracker.report_completeness(&destination_path)?;
+    tracker.report_modality_mismatches(&destination_path)?;
+    tracker.report_duplicates(&destination_path)?;
+    tracker.report_tag_changes(&destination_path)?;
+    if let Some(status_writer) = status_writer {
+        status_writer.finish();
+    }
+    if !run_options.dry_run
+        && (run_options.certificate
+            || run_options.email.is_some()
+            || run_options.run_name.is_some())
+    {
+        let summary = RunSummary {
+            action: "DeID".to_string(),
+            counts: RunSummary::run_counts(total_len, failed, non_dicom, &tracker),
+            tag_changes: tracker.tag_change_lines(),
+            source: source_path,
+            destination: destination_path,
+            profile: cookbook_summary(&cookbook, &cookbook_path),
+            options: vec![
+                (
+                    "Mapping table".to_string(),
+                    mapping_table.display().to_string(),
+                ),
+                (
+                    "Mapping table SHA-256".to_string(),
+                    file_digest(&mapping_table),
+                ),
+                ("Shard".to_string(), run_options.shard_summary()),
+                (
+                    "Manifest SHA-256".to_string(),
+                    match &manifest_path {
+                        Some(manifest_path) => file_digest(manifest_path),
+                        None => "NA".to_string(),
+                    },
+                ),
+            ],
+            config,
+        };
+        if run_options.certificate {
+            write_certificate(&summary, &run_options)?;
+        }
+        send_run_report(&summary, &run_options, &tracker);
+        record_run(&summary, &run_options)?;
+    }
     info!("DICOM DeID complete!");
     Ok(())
 }
 
+// DeID of each file with the mapping table and the cookbook
+struct DeidTransform<'a> {
+    mapping_dict: &'a HashMap<String, String>,
+    cookbook: &'a CookBookConfig,
+}
+
+impl FileTransform for DeidTransform<'_> {
+    fn action(&self) -> &str {
+        "DeID"
+    }
+
+    fn transform(
+        &self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        destination_path: &Path,
+        timing: FileTiming,
+        tracker: RunTracker,
+        wg: WaitGroup,
+    ) -> Result<()> {
+        deid_each_dcm_file(
+            dcm_obj,
+            destination_path,
+            self.mapping_dict,
+            self.cookbook,
+            timing,
+            tracker,
+            wg,
+        )
+    }
+}
+
 /// Deidentify each file based on the mapping dict
 /// Generate filename and path based on DICOM tags
 /// Save the file to the necessary directory
 /// All Destination directories will be created recursively
 fn deid_each_dcm_file(
     dcm_obj: &FileDicomObject<InMemDicomObject>,
-    destination_path: &PathBuf,
-    mapping_dict: HashMap<String, String>,
-    match_id: DataDictionaryEntryRef<'static>,
-    mask_tag_config_list: Vec<DataDictionaryEntryRef<'static>>,
-    mask_vr_config_list: Vec<VR>,
-    delete_tag_config_list: Vec<DataDictionaryEntryRef<'static>>,
-    add_config_list: HashMap<String, String>,
-    private_tags_del: bool,
+    destination_path: &Path,
+    mapping_dict: &HashMap<String, String>,
+    cookbook: &CookBookConfig,
+    mut timing: FileTiming,
+    tracker: RunTracker,
     wg: WaitGroup,
 ) -> Result<()> {
-    let tag_to_match = dcm_obj.element(match_id.tag.inner())?.to_str()?.to_string();
+    let transform_start = Instant::now();
+    let new_dicom_object = match deid_dcm_object(dcm_obj, mapping_dict, cookbook)? {
+        Some(obj) => obj,
+        None => {
+            let mut result = FileResult::new(&timing.path, "unmapped");
+            result.duration = timing.read + transform_start.elapsed();
+            tracker.record_result(result);
+            return Ok(());
+        }
+    };
+
+    let dicom_tags_values = get_sanitized_tag_values(&new_dicom_object)?;
+    tracker.record_series(&dicom_tags_values);
+    let tag_changes = tag_changes(dcm_obj, &new_dicom_object);
+    let unchanged = tag_changes
+        .iter()
+        .filter(|(_, change)| *change == TagChange::Kept)
+        .map(|(tag, _)| *tag)
+        .collect();
+    let changes = match tracker.dry_run {
+        true => changed_tags(&tag_changes),
+        false => vec![],
+    };
+
+    let review_reason = cookbook
+        .rules
+        .as_ref()
+        .and_then(|rules| rules.review_reason(dcm_obj))
+        .or_else(|| {
+            cookbook
+                .pixel_mask
+                .as_ref()
+                .and_then(|pixel_mask| pixel_mask.review_reason(dcm_obj))
+        })
+        .or_else(|| cookbook.review_policy.review_reason(&dicom_tags_values));
+    let (new_dp, review_reason) = match tracker.route_file(review_reason, destination_path) {
+        Some(route) => route,
+        None => {
+            let mut result =
+                FileResult::new(&timing.path, "excluded").with_tags(&dicom_tags_values);
+            result.duration = timing.read + transform_start.elapsed();
+            tracker.record_result(result);
+            return Ok(());
+        }
+    };
+    tracker.record_tag_changes(&tag_changes);
+    let expected_instances = study_related_instances(dcm_obj);
+    let instances = output_instances(
+        dcm_obj,
+        OutputInstance {
+            dcm_obj: new_dicom_object,
+            tags_values: dicom_tags_values,
+            unchanged,
+        },
+        cookbook.split_frames,
+    )?;
+    timing.transform = transform_start.elapsed();
+    tracker.progress.transformed.inc(1);
+    let series_affinity = tracker.series_affinity;
+    let destination_path = destination_path.to_path_buf();
+    spawn_write(series_affinity, move || {
+        let write_start = Instant::now();
+        let written = (|| -> Result<Vec<FileResult>> {
+            let mut results = vec![];
+            for instance in instances {
+                let dicom_tags_values = instance.tags_values;
+                let file_name = tracker.output_file_name(
+                    &dicom_tags_values,
+                    &instance.dcm_obj,
+                    "DeID".to_string(),
+                )?;
+                let study_uid = dicom_tags_values
+                    .get("StudyInstanceUID")
+                    .cloned()
+                    .unwrap_or_default();
+                let sop_uid = sop_instance_uid(&instance.dcm_obj);
+                let mut result = FileResult::new(
+                    &timing.path,
+                    match review_reason {
+                        Some(_) => "review",
+                        None => "processed",
+                    },
+                )
+                .with_tags(&dicom_tags_values);
+                result.changes = changes.clone();
+                let dir_path = tracker.output_dir(dicom_tags_values, &instance.dcm_obj, &new_dp)?;
+                let full_path = tracker.output_path(
+                    format!("{}/{}", dir_path, file_name),
+                    &sop_uid,
+                    &mut result,
+                );
+                debug!("Saving file: {} to: {}", file_name, dir_path);
+                let digest = tracker.write_instance(
+                    &full_path,
+                    &timing.path,
+                    &instance.dcm_obj,
+                    &instance.unchanged,
+                )?;
+                tracker.record_checksum(&full_path, digest);
+                match &review_reason {
+                    Some(_) if tracker.dry_run => (),
+                    Some(reason) => write_review_note(&full_path, reason)?,
+                    None => tracker.file_written(&full_path, &study_uid, expected_instances),
+                }
+                tracker.progress.written.inc(1);
+                result.output = full_path;
+                results.push(result);
+            }
+            Ok(results)
+        })();
+        timing.write = write_start.elapsed();
+        match written {
+            // One row per written instance, the frames of a split instance share the source
+            Ok(results) => {
+                for mut result in results {
+                    result.duration = timing.total();
+                    tracker.record_result(result);
+                }
+            }
+            Err(e) => {
+                error!(
+                    "Can't write {:#?}: {:#}",
+                    timing.path.file_name().unwrap_or_default(),
+                    e
+                );
+                tracker.record_failed(&timing.path, &destination_path, &e, timing.total());
+            }
+        }
+        tracker.record_timing(timing);
+        drop(wg);
+    });
+    Ok(())
+}
+
+/// Apply the cookbook to a single DICOM object
+/// Returns None if the match tag value is not present in the mapping dict
+pub fn deid_dcm_object(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    mapping_dict: &HashMap<String, String>,
+    cookbook: &CookBookConfig,
+) -> Result<Option<FileDicomObject<InMemDicomObject>>> {
+    let tag_to_match = dcm_obj
+        .element(cookbook.match_id.tag.inner())?
+        .to_str()?
+        .to_string();
     let patient_deid = match mapping_dict.get(&tag_to_match) {
         Some(deid) => deid.to_string(),
         None => "".to_string(),
     };
 
     if patient_deid.is_empty() {
-        debug!("DeID for {tag_to_match} is not found");
-        return Ok(());
+        debug!("DeID for {} is not found", phi(&tag_to_match));
+        return Ok(None);
     }
 
     let mut new_dicom_object = dcm_obj.clone();
+    normalize_dates(&mut new_dicom_object, cookbook.date_order);
+    scrub_sr_content(&mut new_dicom_object, &patient_deid);
+    delete_patient_photo_references(&mut new_dicom_object, &cookbook.review_policy);
+    scrub_annotation_text(
+        &mut new_dicom_object,
+        cookbook.annotation_text,
+        &patient_deid,
+    );
 
-    if private_tags_del {
+    if cookbook.delete_private_tags {
         new_dicom_object = delete_private_tags(new_dicom_object)?
     }
+    if !cookbook.keep_legacy_tags {
+        delete_legacy_tags(&mut new_dicom_object);
+    }
 
-    let new_dicom_object = match mask_tag_config_list.is_empty() {
+    let new_dicom_object = match cookbook.mask_tags.is_empty() {
         true => new_dicom_object,
         false => tags_to_mask(
             new_dicom_object.clone(),
             patient_deid.clone(),
-            mask_tag_config_list,
+            cookbook.mask_tags.clone(),
         )?,
     };
 
-    let new_dicom_object = match mask_vr_config_list.is_empty() {
+    let new_dicom_object = match cookbook.mask_vrs.is_empty() {
         true => new_dicom_object,
-        false => mask_vr(new_dicom_object, mask_vr_config_list, patient_deid.clone())?,
+        false => mask_vr(
+            new_dicom_object,
+            cookbook.mask_vrs.clone(),
+            patient_deid.clone(),
+        )?,
     };
 
-    let new_dicom_object = match add_config_list.is_empty() {
+    let new_dicom_object = match cookbook.date_precision {
+        Some(precision) => {
+            let mut new_dicom_object = new_dicom_object;
+            reduce_dates(&mut new_dicom_object, precision);
+            new_dicom_object
+        }
+        None => new_dicom_object,
+    };
+
+    let new_dicom_object = match &cookbook.description_map {
+        Some(description_map) => {
+            let mut new_dicom_object = new_dicom_object;
+            description_map.apply(&mut new_dicom_object);
+            new_dicom_object
+        }
+        None => new_dicom_object,
+    };
+
+    let new_dicom_object = match cookbook.add_tags.is_empty() {
         true => new_dicom_object,
-        false => tags_to_add(new_dicom_object.clone(), add_config_list)?,
+        false => tags_to_add(new_dicom_object.clone(), cookbook.add_tags.clone())?,
     };
 
-    let new_dicom_object = match delete_tag_config_list.is_empty() {
+    let new_dicom_object = match cookbook.delete_tags.is_empty() {
         true => new_dicom_object,
-        false => tags_to_delete(new_dicom_object.clone(), delete_tag_config_list)?,
+        false => tags_to_delete(new_dicom_object.clone(), cookbook.delete_tags.clone())?,
     };
 
-    let dicom_tags_values = get_sanitized_tag_values(&new_dicom_object)?;
+    let new_dicom_object = match &cookbook.rules {
+        Some(rules) => {
+            let mut new_dicom_object = new_dicom_object;
+            rules.apply(dcm_obj, &mut new_dicom_object);
+            new_dicom_object
+        }
+        None => new_dicom_object,
+    };
 
-    let new_dp = destination_path.clone();
-    let dcm_obj_clone = new_dicom_object.clone();
-
-    rayon::spawn(move || {
-        let file_name = generate_dicom_file_name(&dicom_tags_values, "DeID".to_string())
-            .expect("Failed to generate file name");
-        let dir_path = generate_dicom_file_path(dicom_tags_values, &new_dp)
-            .expect("Failed to generate DIR path");
-
-        let full_path = check_if_dup_exists(format!("{}/{}", dir_path, file_name));
-        debug!("Saving file: {} to: {}", file_name, dir_path);
-        let dcm_buffer = File::create(full_path).expect("Failed to create file");
-        dcm_obj_clone
-            .write_all(dcm_buffer)
-            .expect("Failed to add dcm value to buffer");
-        drop(wg);
-    });
-    Ok(())
+    let new_dicom_object = match &cookbook.study_values {
+        Some(study_values) => {
+            let mut new_dicom_object = new_dicom_object;
+            study_values.apply(dcm_obj, &mut new_dicom_object);
+            new_dicom_object
+        }
+        None => new_dicom_object,
+    };
+
+    let new_dicom_object = match &cookbook.pixel_mask {
+        Some(pixel_mask) => {
+            let mut new_dicom_object = new_dicom_object;
+            pixel_mask.apply(dcm_obj, &mut new_dicom_object)?;
+            new_dicom_object
+        }
+        None => new_dicom_object,
+    };
+
+    let new_dicom_object = match cookbook.downsample {
+        Some(size) => {
+            let mut new_dicom_object = new_dicom_object;
+            downsample_pixels(&mut new_dicom_object, size)?;
+            new_dicom_object
+        }
+        None => new_dicom_object,
+    };
+
+    let new_dicom_object = match cookbook.fix_vr {
+        true => {
+            let mut new_dicom_object = new_dicom_object;
+            fix_value_violations(&mut new_dicom_object);
+            new_dicom_object
+        }
+        false => new_dicom_object,
+    };
+
+    let mut new_dicom_object = new_dicom_object;
+    transcode_pixels(&mut new_dicom_object, cookbook.transcode)?;
+
+    Ok(Some(new_dicom_object))
 }
 
 /// Generate a dictionary based on the Mapping table
 /// Eg DeID001,U012345 >> {"U012345"; "DeID001"}
 /// All lines that dont follow DeID,PatientID pattern will be ignored
-fn generate_mapping_dict(mapping_table: &PathBuf) -> Result<HashMap<String, String>> {
+pub fn generate_mapping_dict(mapping_table: &Path) -> Result<HashMap<String, String>> {
     let mut data_map: HashMap<String, String> = HashMap::new();
-    if let Ok(file) = File::open(&mapping_table) {
+    if let Ok(file) = File::open(mapping_table) {
         let reader = BufReader::new(file);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                let parts: Vec<&str> = line.split(',').collect();
-                if parts.len() == 2 {
-                    if parts[0].is_empty() || parts[1].is_empty() {
-                        continue;
-                    }
-                    let key = parts[1].trim().to_string();
-                    let value = parts[0].trim().to_string();
-                    data_map.insert(key, value);
-                } else {
-                    warn!("Invalid line: {}", line);
+        for line in reader.lines().map_while(Result::ok) {
+            let parts: Vec<&str> = line.split(',').collect();
+            if parts.len() == 2 {
+                if parts[0].is_empty() || parts[1].is_empty() {
+                    continue;
                 }
+                let key = parts[1].trim().to_string();
+                let value = parts[0].trim().to_string();
+                data_map.insert(key, value);
+            } else {
+                warn!("Invalid line: {}", phi(&line));
             }
         }
     } else {
@@ -213,3 +516,92 @@ fn generate_mapping_dict(mapping_table: &PathBuf) -> Result<HashMap<String, Stri
     }
     Ok(data_map)
 }
+
+// Profile entries of the cookbook for the certificate
+fn cookbook_summary(cookbook: &CookBookConfig, cookbook_path: &Path) -> Vec<(String, String)> {
+    let aliases = |entries: &Vec<TagPath>| {
+        entries
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut added: Vec<String> = cookbook
+        .add_tags
+        .iter()
+        .map(|(tag, value)| format!("{}={}", tag, value))
+        .collect();
+    added.sort();
+    vec![
+        ("Cookbook".to_string(), cookbook_path.display().to_string()),
+        ("Cookbook SHA-256".to_string(), file_digest(cookbook_path)),
+        ("Match tag".to_string(), cookbook.match_id.alias.to_string()),
+        ("Masked tags".to_string(), aliases(&cookbook.mask_tags)),
+        (
+            "Masked VRs".to_string(),
+            cookbook
+                .mask_vrs
+                .iter()
+                .map(|vr| vr.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        ("Deleted tags".to_string(), aliases(&cookbook.delete_tags)),
+        (
+            "Delete private tags".to_string(),
+            cookbook.delete_private_tags.to_string(),
+        ),
+        (
+            "Date precision".to_string(),
+            match cookbook.date_precision {
+                Some(precision) => format!("{:?}", precision),
+                None => "Unchanged".to_string(),
+            },
+        ),
+        (
+            "Downsample".to_string(),
+            match cookbook.downsample {
+                Some(size) => format!("{}x{}", size.rows, size.columns),
+                None => "No".to_string(),
+            },
+        ),
+        (
+            "Split frames".to_string(),
+            cookbook.split_frames.to_string(),
+        ),
+        ("Transcode".to_string(), format!("{:?}", cookbook.transcode)),
+        (
+            "Pixel mask".to_string(),
+            match &cookbook.pixel_mask {
+                Some(pixel_mask) => pixel_mask.path.display().to_string(),
+                None => "None".to_string(),
+            },
+        ),
+        (
+            "Annotation text".to_string(),
+            format!("{:?}", cookbook.annotation_text),
+        ),
+        (
+            "Date order".to_string(),
+            format!("{:?}", cookbook.date_order),
+        ),
+        (
+            "VR fixes".to_string(),
+            match cookbook.fix_vr {
+                true => "Enabled".to_string(),
+                false => "Disabled".to_string(),
+            },
+        ),
+        (
+            "Legacy tags".to_string(),
+            match cookbook.keep_legacy_tags {
+                true => "Kept".to_string(),
+                false => {
+                    "Curves, overlays, results group and retired identifying attributes deleted"
+                        .to_string()
+                }
+            },
+        ),
+        ("Added tags".to_string(), added.join(", ")),
+    ]
+}
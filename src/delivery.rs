@@ -0,0 +1,667 @@
+use crate::{
+    sha256,
+    sink::{FileSystemSink, OutputSink},
+    to_hex,
+    tracker::RunTracker,
+    unique_output_path, DeliveryUnit, DuplicateSuffix, OutputFormat, RunOptions, Sha256,
+    DELIVERY_DIR, MANIFEST_FILE, REVIEW_REQUIRED_DIR,
+};
+use anyhow::Result;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, canonicalize, create_dir_all, File},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+// Entry of the central directory
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+// The age CLI encrypts the archives, check it runs before the files are processed
+pub fn check_age(recipients: &Path) -> Result<()> {
+    if !recipients.is_file() {
+        return Err(anyhow::anyhow!(
+            "No recipients file: {}",
+            recipients.display()
+        ));
+    }
+    match Command::new("age").arg("--version").output() {
+        Ok(output) if output.status.success() => Ok(()),
+        _ => Err(anyhow::anyhow!(
+            "The age command is needed to encrypt the delivery archives, see https://age-encryption.org"
+        )),
+    }
+}
+
+/// Pack the processed files of each study or patient into DELIVERY/<name>.zip with a
+/// MANIFEST.sha256 of its files inside, encrypted to the age recipients when given
+/// Files routed to review or excluded are not delivered
+pub fn deliver_archives(
+    run_options: &RunOptions,
+    tracker: &RunTracker,
+    destination_path: &Path,
+) -> Result<()> {
+    let unit = match run_options.delivery {
+        Some(unit) if !tracker.dry_run => unit,
+        _ => return Ok(()),
+    };
+    let mut groups: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for result in tracker
+        .results
+        .lock()
+        .expect("Failed to lock mutex")
+        .iter()
+        .filter(|result| result.status == "processed" && !result.output.is_empty())
+    {
+        let name = match unit {
+            DeliveryUnit::Study => format!("{}_{}", result.patient_id, result.study_uid),
+            DeliveryUnit::Patient => result.patient_id.clone(),
+        };
+        groups
+            .entry(archive_name(&name))
+            .or_default()
+            .push(PathBuf::from(&result.output));
+    }
+    let delivery_dir = destination_path.join(DELIVERY_DIR);
+    create_dir_all(&delivery_dir)?;
+    for (name, mut files) in groups {
+        files.sort();
+        files.dedup();
+        let zip_path = delivery_dir.join(format!("{}.zip", name));
+        write_zip(&zip_path, &files, destination_path)?;
+        match &run_options.delivery_recipients {
+            Some(recipients) => {
+                let encrypted = delivery_dir.join(format!("{}.zip.age", name));
+                let status = Command::new("age")
+                    .arg("-R")
+                    .arg(recipients)
+                    .arg("-o")
+                    .arg(&encrypted)
+                    .arg(&zip_path)
+                    .status()?;
+                fs::remove_file(&zip_path)?;
+                if !status.success() {
+                    return Err(anyhow::anyhow!(
+                        "age failed to encrypt {}: {}",
+                        zip_path.display(),
+                        status
+                    ));
+                }
+                info!("Delivery of {} files: {}", files.len(), encrypted.display());
+            }
+            None => info!("Delivery of {} files: {}", files.len(), zip_path.display()),
+        }
+    }
+    Ok(())
+}
+
+// File name of an archive, the IDs of the output may hold any character
+fn archive_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || "._-".contains(c) {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    match name.trim_matches('_').is_empty() {
+        true => "UNKNOWN".to_string(),
+        false => name,
+    }
+}
+
+// Store only ZIP, the DICOM files are mostly compressed already or do not gain enough for it
+// Entries are named by their path relative to the destination, MANIFEST.sha256 comes last
+fn write_zip(zip_path: &Path, files: &[PathBuf], destination_path: &Path) -> Result<()> {
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(zip_path)?));
+    let mut manifest = String::new();
+    for file in files {
+        let name = file
+            .strip_prefix(destination_path)
+            .unwrap_or(file)
+            .display()
+            .to_string();
+        // First pass for the checksums of the local header
+        let (crc, size, digest) = checksums(file)?;
+        manifest.push_str(&format!("{}  {}\n", digest, name));
+        zip.add(name, crc, size, &mut File::open(file)?)?;
+    }
+    zip.add(
+        MANIFEST_FILE.to_string(),
+        crc32(0, manifest.as_bytes()),
+        manifest.len() as u64,
+        &mut manifest.as_bytes(),
+    )?;
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+// Sizes, offsets and counts from this value on are in the Zip64 records
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+
+// Store only ZIP, entries are written one after the other with their CRC-32 and size known
+// Archives over 4 GiB or with more than 65535 entries get the Zip64 records
+pub struct ZipWriter<W: Write> {
+    writer: W,
+    offset: u64,
+    entries: Vec<ZipEntry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(writer: W) -> Self {
+        ZipWriter {
+            writer,
+            offset: 0,
+            entries: vec![],
+        }
+    }
+
+    // Add an entry, its data is copied from the reader and has to match the size
+    pub fn add(&mut self, name: String, crc: u32, size: u64, data: &mut impl Read) -> Result<()> {
+        let entry = ZipEntry {
+            name,
+            crc,
+            size,
+            offset: self.offset,
+        };
+        self.offset += write_local_header(&mut self.writer, &entry)?;
+        let copied = std::io::copy(data, &mut self.writer)?;
+        if copied != size {
+            return Err(anyhow::anyhow!("{} changed while archived", entry.name));
+        }
+        self.offset += copied;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    // Write the central directory and the end records, returns the writer to flush
+    pub fn finish(mut self) -> Result<W> {
+        let directory_offset = self.offset;
+        let mut directory_size: u64 = 0;
+        for entry in &self.entries {
+            directory_size += write_central_header(&mut self.writer, entry)?;
+        }
+        let count = self.entries.len() as u64;
+        let zip = &mut self.writer;
+        if count >= 0xFFFF || directory_offset >= ZIP64_LIMIT || directory_size >= ZIP64_LIMIT {
+            // Zip64 end of central directory record, then its locator
+            zip.write_all(&0x06064b50u32.to_le_bytes())?;
+            zip.write_all(&44u64.to_le_bytes())?;
+            zip.write_all(&0x032Du16.to_le_bytes())?;
+            zip.write_all(&45u16.to_le_bytes())?;
+            zip.write_all(&[0; 8])?;
+            zip.write_all(&count.to_le_bytes())?;
+            zip.write_all(&count.to_le_bytes())?;
+            zip.write_all(&directory_size.to_le_bytes())?;
+            zip.write_all(&directory_offset.to_le_bytes())?;
+            zip.write_all(&0x07064b50u32.to_le_bytes())?;
+            zip.write_all(&[0; 4])?;
+            zip.write_all(&(directory_offset + directory_size).to_le_bytes())?;
+            zip.write_all(&1u32.to_le_bytes())?;
+        }
+        // End of central directory record
+        zip.write_all(&0x06054b50u32.to_le_bytes())?;
+        zip.write_all(&[0; 4])?;
+        zip.write_all(&(count.min(0xFFFF) as u16).to_le_bytes())?;
+        zip.write_all(&(count.min(0xFFFF) as u16).to_le_bytes())?;
+        zip.write_all(&(directory_size.min(ZIP64_LIMIT) as u32).to_le_bytes())?;
+        zip.write_all(&(directory_offset.min(ZIP64_LIMIT) as u32).to_le_bytes())?;
+        zip.write_all(&[0; 2])?;
+        Ok(self.writer)
+    }
+}
+
+// Zip64 extra field with the given sizes and offset, empty when none is needed
+fn zip64_extra(values: &[u64]) -> Vec<u8> {
+    if values.is_empty() {
+        return vec![];
+    }
+    let mut extra = 1u16.to_le_bytes().to_vec();
+    extra.extend_from_slice(&(values.len() as u16 * 8).to_le_bytes());
+    for value in values {
+        extra.extend_from_slice(&value.to_le_bytes());
+    }
+    extra
+}
+
+// Version needed, UTF-8 names, stored without compression and no timestamp
+fn common_fields(entry: &ZipEntry, extra: &[u8]) -> Vec<u8> {
+    let size = entry.size.min(ZIP64_LIMIT) as u32;
+    let mut fields = vec![];
+    match extra.is_empty() {
+        true => fields.extend_from_slice(&20u16.to_le_bytes()),
+        false => fields.extend_from_slice(&45u16.to_le_bytes()),
+    }
+    fields.extend_from_slice(&0x0800u16.to_le_bytes());
+    fields.extend_from_slice(&0u16.to_le_bytes());
+    fields.extend_from_slice(&0u16.to_le_bytes());
+    fields.extend_from_slice(&0x0021u16.to_le_bytes());
+    fields.extend_from_slice(&entry.crc.to_le_bytes());
+    fields.extend_from_slice(&size.to_le_bytes());
+    fields.extend_from_slice(&size.to_le_bytes());
+    fields.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+    fields.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+    fields
+}
+
+fn write_local_header(zip: &mut impl Write, entry: &ZipEntry) -> Result<u64> {
+    let extra = match entry.size >= ZIP64_LIMIT {
+        true => zip64_extra(&[entry.size, entry.size]),
+        false => vec![],
+    };
+    let mut header = 0x04034b50u32.to_le_bytes().to_vec();
+    header.extend(common_fields(entry, &extra));
+    header.extend_from_slice(entry.name.as_bytes());
+    header.extend(extra);
+    zip.write_all(&header)?;
+    Ok(header.len() as u64)
+}
+
+fn write_central_header(zip: &mut impl Write, entry: &ZipEntry) -> Result<u64> {
+    let mut values = vec![];
+    if entry.size >= ZIP64_LIMIT {
+        values.extend([entry.size, entry.size]);
+    }
+    if entry.offset >= ZIP64_LIMIT {
+        values.push(entry.offset);
+    }
+    let extra = zip64_extra(&values);
+    let mut header = 0x02014b50u32.to_le_bytes().to_vec();
+    // Made by Unix
+    header.extend_from_slice(&0x0314u16.to_le_bytes());
+    header.extend(common_fields(entry, &extra));
+    // Comment length, disk, internal attributes
+    header.extend_from_slice(&[0; 6]);
+    // rw-r--r--
+    header.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+    header.extend_from_slice(&(entry.offset.min(ZIP64_LIMIT) as u32).to_le_bytes());
+    header.extend_from_slice(entry.name.as_bytes());
+    header.extend(extra);
+    zip.write_all(&header)?;
+    Ok(header.len() as u64)
+}
+
+// POSIX tar, the names over 100 bytes are given in a pax extended header
+pub struct TarWriter<W: Write> {
+    writer: W,
+    mtime: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(writer: W) -> Self {
+        TarWriter {
+            writer,
+            mtime: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        if name.len() > 100 {
+            let record = pax_record("path", name);
+            let pax_name = format!("PaxHeaders/{}", short_name(name, 89));
+            self.write_entry(&pax_name, b'x', record.as_bytes())?;
+        }
+        self.write_entry(&short_name(name, 100), b'0', data)
+    }
+
+    fn write_entry(&mut self, name: &str, typeflag: u8, data: &[u8]) -> Result<()> {
+        self.writer
+            .write_all(&tar_header(name, typeflag, data.len() as u64, self.mtime))?;
+        self.writer.write_all(data)?;
+        let padding = (512 - data.len() % 512) % 512;
+        self.writer.write_all(&vec![0; padding])?;
+        Ok(())
+    }
+
+    // Two empty blocks end the archive, returns the writer to flush
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.write_all(&[0; 1024])?;
+        Ok(self.writer)
+    }
+}
+
+// The last bytes of a name that fit in the header, the full name is in the pax header
+fn short_name(name: &str, length: usize) -> String {
+    let mut start = name.len().saturating_sub(length);
+    while !name.is_char_boundary(start) {
+        start += 1;
+    }
+    name[start..].to_string()
+}
+
+// "<length> <key>=<value>\n", the length counts its own digits
+fn pax_record(key: &str, value: &str) -> String {
+    let content = format!(" {}={}\n", key, value);
+    let mut length = content.len() + 1;
+    while length != content.len() + length.to_string().len() {
+        length = content.len() + length.to_string().len();
+    }
+    format!("{}{}", length, content)
+}
+
+// ustar header, octal numbers, sizes over 8 GiB in base-256
+fn tar_header(name: &str, typeflag: u8, size: u64, mtime: u64) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    let mut put = |pos: usize, value: &[u8]| header[pos..pos + value.len()].copy_from_slice(value);
+    put(0, name.as_bytes());
+    put(100, b"0000644\0");
+    put(108, b"0000000\0");
+    put(116, b"0000000\0");
+    match size < 0o77777777777 {
+        true => put(124, format!("{:011o}\0", size).as_bytes()),
+        false => {
+            let mut field = [0u8; 12];
+            field[4..].copy_from_slice(&size.to_be_bytes());
+            field[0] = 0x80;
+            put(124, &field);
+        }
+    }
+    put(136, format!("{:011o}\0", mtime).as_bytes());
+    put(148, b"        ");
+    put(156, &[typeflag]);
+    put(257, b"ustar\0");
+    put(263, b"00");
+    let checksum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+enum ArchiveWriter {
+    Zip(ZipWriter<BufWriter<File>>),
+    Tar(TarWriter<BufWriter<File>>),
+}
+
+// Archive being written and the names of its entries
+struct OpenArchive {
+    writer: ArchiveWriter,
+    names: HashSet<String>,
+}
+
+// The instances of the run are written into one ZIP or tar archive in the destination instead
+// of a file each, named by their path relative to the destination. The archive is written as
+// .part and renamed at the end of the run. The files routed to REVIEW_REQUIRED are still
+// written as files, so they are reviewed and not shipped
+pub struct ArchiveSink {
+    pub path: PathBuf,
+    destination_path: PathBuf,
+    archive: Mutex<Option<OpenArchive>>,
+}
+
+impl ArchiveSink {
+    pub fn new(destination_path: &Path, format: OutputFormat) -> Result<Self> {
+        let name = canonicalize(destination_path)?
+            .file_name()
+            .map(|name| archive_name(&name.to_string_lossy()))
+            .unwrap_or_else(|| "OUTPUT".to_string());
+        let path = PathBuf::from(unique_output_path(
+            destination_path
+                .join(format!("{}.{}", name, format.extension()))
+                .display()
+                .to_string(),
+            DuplicateSuffix::Counter,
+            "",
+        ));
+        let file = BufWriter::new(File::create(part_path(&path))?);
+        info!("Instances are written to {}", path.display());
+        Ok(ArchiveSink {
+            path,
+            destination_path: destination_path.to_path_buf(),
+            archive: Mutex::new(Some(OpenArchive {
+                writer: match format {
+                    OutputFormat::Zip => ArchiveWriter::Zip(ZipWriter::new(file)),
+                    OutputFormat::Tar => ArchiveWriter::Tar(TarWriter::new(file)),
+                },
+                names: HashSet::new(),
+            })),
+        })
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.part", path.display()))
+}
+
+impl OutputSink for ArchiveSink {
+    fn write_instance(
+        &self,
+        path: &Path,
+        hashing: bool,
+        encode: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+    ) -> Result<Option<String>> {
+        if path.starts_with(self.destination_path.join(REVIEW_REQUIRED_DIR)) {
+            return FileSystemSink.write_instance(path, hashing, encode);
+        }
+        // Encoded apart, the archive is only locked to append the entry
+        let mut bytes = vec![];
+        encode(&mut bytes)?;
+        let mut name = path
+            .strip_prefix(&self.destination_path)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let mut archive = self.archive.lock().expect("Failed to lock mutex");
+        let archive = archive
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("{} is already closed", self.path.display()))?;
+        // Output paths are made unique on the filesystem, an entry written twice gets a ~
+        while !archive.names.insert(name.clone()) {
+            name.push('~');
+        }
+        match &mut archive.writer {
+            ArchiveWriter::Zip(zip) => zip.add(
+                name,
+                crc32(0, &bytes),
+                bytes.len() as u64,
+                &mut bytes.as_slice(),
+            )?,
+            ArchiveWriter::Tar(tar) => tar.add(&name, &bytes)?,
+        }
+        Ok(hashing.then(|| to_hex(&sha256(&bytes))))
+    }
+
+    fn finalize(&self) -> Result<()> {
+        let Some(archive) = self.archive.lock().expect("Failed to lock mutex").take() else {
+            return Ok(());
+        };
+        match archive.writer {
+            ArchiveWriter::Zip(zip) => zip.finish()?.flush()?,
+            ArchiveWriter::Tar(tar) => tar.finish()?.flush()?,
+        }
+        fs::rename(part_path(&self.path), &self.path)?;
+        info!(
+            "{} instances written to {}",
+            archive.names.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+// CRC-32, size and SHA-256 of a file
+fn checksums(file: &Path) -> Result<(u32, u64, String)> {
+    let mut reader = File::open(file)?;
+    let mut buffer = vec![0; 1 << 16];
+    let mut crc = 0;
+    let mut size = 0;
+    let mut sha256 = Sha256::default();
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        crc = crc32(crc, &buffer[..read]);
+        sha256.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((crc, size, to_hex(&sha256.finalize())))
+}
+
+// CRC-32 of ZIP, reflected polynomial 0xEDB88320
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB88320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, write_central_header, TarWriter, ZipEntry, ZipWriter, ZIP64_LIMIT};
+    use crate::{
+        gen_id,
+        source::{extract_tar, extract_zip, zip64_fields, zip_directory},
+    };
+    use std::{fs, path::PathBuf};
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dcmrig_test_{}", gen_id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // The check value of CRC-32/ISO-HDLC
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF43926);
+        assert_eq!(crc32(0, b""), 0);
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        let dir = scratch_dir();
+        let entries: [(&str, &[u8]); 3] = [
+            ("P1/ST1/SE1/IM1.dcm", b"DICM first instance"),
+            ("P1/ST1/SE1/Ünïcode.dcm", &[0, 1, 2, 0xFF]),
+            ("empty.dcm", b""),
+        ];
+        let mut zip = ZipWriter::new(vec![]);
+        for (name, data) in entries {
+            zip.add(name.to_string(), crc32(0, data), data.len() as u64, &mut {
+                data
+            })
+            .unwrap();
+        }
+        let bytes = zip.finish().unwrap();
+        // Stored, UTF-8 names and no Zip64 records for a small archive
+        assert_eq!(&bytes[..4], &0x04034b50u32.to_le_bytes());
+        assert_eq!(&bytes[4..10], &[20, 0, 0x00, 0x08, 0, 0]);
+        assert_eq!(
+            &bytes[bytes.len() - 22..][..4],
+            &0x06054b50u32.to_le_bytes()
+        );
+
+        let archive = dir.join("out.zip");
+        fs::write(&archive, &bytes).unwrap();
+        let staging = dir.join("staging");
+        let extracted = extract_zip(fs::File::open(&archive).unwrap(), &staging).unwrap();
+        assert_eq!(extracted, 3);
+        for (name, data) in entries {
+            assert_eq!(fs::read(staging.join(name)).unwrap(), data);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // More than 65534 entries need the Zip64 end records
+    #[test]
+    fn zip64_end_records() {
+        let dir = scratch_dir();
+        let mut zip = ZipWriter::new(vec![]);
+        for i in 0..0xFFFF {
+            zip.add(format!("{}.dcm", i), 0, 0, &mut std::io::empty())
+                .unwrap();
+        }
+        let bytes = zip.finish().unwrap();
+        let archive = dir.join("many.zip");
+        fs::write(&archive, &bytes).unwrap();
+        let (entries, directory_size, directory_offset) =
+            zip_directory(&mut fs::File::open(&archive).unwrap()).unwrap();
+        assert_eq!(entries, 0xFFFF);
+        // The Zip64 record, its locator and the end record follow the directory
+        assert_eq!(
+            directory_offset + directory_size,
+            bytes.len() as u64 - 56 - 20 - 22
+        );
+        assert_eq!(
+            &bytes[directory_offset as usize..][..4],
+            &0x02014b50u32.to_le_bytes()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Sizes and offsets past 4 GiB are in the Zip64 extra field of the central header
+    #[test]
+    fn zip64_extra_field() {
+        let entry = ZipEntry {
+            name: "large.dcm".to_string(),
+            crc: 0,
+            size: ZIP64_LIMIT + 10,
+            offset: ZIP64_LIMIT + 20,
+        };
+        let mut header = vec![];
+        write_central_header(&mut header, &entry).unwrap();
+        // Version needed 4.5, the 32-bit sizes and offset are 0xFFFFFFFF
+        assert_eq!(&header[6..8], &45u16.to_le_bytes());
+        for pos in [20, 24, 42] {
+            assert_eq!(&header[pos..pos + 4], &[0xFF; 4]);
+        }
+        let mut size = 0xFFFF_FFFF;
+        let mut compressed_size = 0xFFFF_FFFF;
+        let mut offset = 0xFFFF_FFFF;
+        zip64_fields(
+            &header[46 + entry.name.len()..],
+            [&mut size, &mut compressed_size, &mut offset],
+        );
+        assert_eq!(
+            (size, compressed_size, offset),
+            (entry.size, entry.size, entry.offset)
+        );
+    }
+
+    #[test]
+    fn tar_round_trip() {
+        let dir = scratch_dir();
+        // Over 100 bytes, the name is given in a pax header
+        let long_name = format!("{}/IM1.dcm", "LongSeriesDescription".repeat(8));
+        let entries: [(&str, Vec<u8>); 3] = [
+            ("P1/ST1/SE1/IM1.dcm", b"DICM first instance".to_vec()),
+            (&long_name, vec![7; 1000]),
+            ("empty.dcm", vec![]),
+        ];
+        let mut tar = TarWriter::new(vec![]);
+        for (name, data) in &entries {
+            tar.add(name, data).unwrap();
+        }
+        let bytes = tar.finish().unwrap();
+        assert_eq!(bytes.len() % 512, 0);
+        assert_eq!(&bytes[257..263], b"ustar\0");
+        // The pax header of the long name follows the first entry and its data block
+        assert_eq!(bytes[512 * 2 + 156], b'x');
+
+        let staging = dir.join("staging");
+        let extracted = extract_tar(bytes.as_slice(), &staging).unwrap();
+        assert_eq!(extracted, 3);
+        for (name, data) in &entries {
+            assert_eq!(&fs::read(staging.join(name)).unwrap(), data);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,400 @@
+use anyhow::Result;
+use dcmrig_rs::{open_source_file, sha256, tracker::RunTracker, RunOptions};
+use dicom::{
+    core::Tag,
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject, IMPLEMENTATION_CLASS_UID},
+};
+use std::{
+    collections::BTreeMap,
+    fs::{self, create_dir_all},
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+const DICOMDIR_FILE: &str = "DICOMDIR";
+// Root of the media tree the DICOMDIR references
+const MEDIA_DIR: &str = "DICOM";
+const MEDIA_STORAGE_DIRECTORY: &str = "1.2.840.10008.1.3.10";
+const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+
+// Directory record with its lower level records
+struct Record {
+    // Encoded item, with zero offsets
+    item: Vec<u8>,
+    children: Vec<Record>,
+}
+
+// Written instance with the keys of its records
+struct Instance {
+    file: PathBuf,
+    dcm_obj: FileDicomObject<InMemDicomObject>,
+}
+
+/// Write a DICOMDIR at the destination root for the processed files of the run
+/// File IDs are limited to 8 upper case characters, so the files are linked (or copied when
+/// the filesystem can't link) to DICOM/Pnnnnnnn/Snnnnnnn/Rnnnnnnn/Innnnnnn, the main output is
+/// left as it is. Burn DICOMDIR and DICOM to the media
+pub fn write_dicomdir(
+    run_options: &RunOptions,
+    tracker: &RunTracker,
+    destination_path: &Path,
+) -> Result<()> {
+    if !run_options.dicomdir || tracker.dry_run {
+        return Ok(());
+    }
+    let media_path = destination_path.join(MEDIA_DIR);
+    if media_path.exists() {
+        return Err(anyhow::anyhow!(
+            "{} already exists, remove it to write the DICOMDIR again",
+            media_path.display()
+        ));
+    }
+    let mut outputs: Vec<PathBuf> = tracker
+        .results
+        .lock()
+        .expect("Failed to lock mutex")
+        .iter()
+        .filter(|result| result.status == "processed" && !result.output.is_empty())
+        .map(|result| PathBuf::from(&result.output))
+        .collect();
+    // Instances merged by --merge-frames share their output
+    outputs.sort();
+    outputs.dedup();
+    // PatientID > StudyInstanceUID > SeriesInstanceUID > instances
+    let mut patients: BTreeMap<String, BTreeMap<String, BTreeMap<String, Vec<Instance>>>> =
+        BTreeMap::new();
+    for file in outputs {
+        let dcm_obj = match open_source_file(&file, true) {
+            Ok(dcm_obj) => dcm_obj,
+            Err(e) => {
+                warn!("Not in the DICOMDIR, can't read {}: {}", file.display(), e);
+                continue;
+            }
+        };
+        patients
+            .entry(value(&dcm_obj, tags::PATIENT_ID))
+            .or_default()
+            .entry(value(&dcm_obj, tags::STUDY_INSTANCE_UID))
+            .or_default()
+            .entry(value(&dcm_obj, tags::SERIES_INSTANCE_UID))
+            .or_default()
+            .push(Instance { file, dcm_obj });
+    }
+
+    let mut root = vec![];
+    let mut instance_uids = String::new();
+    let mut count = 0;
+    for (p, studies) in patients.values().enumerate() {
+        let first = first_instance(studies.values().flat_map(|series| series.values()));
+        let mut patient = Record {
+            item: record_item(
+                "PATIENT",
+                &[
+                    key(first, tags::SPECIFIC_CHARACTER_SET, "CS", ""),
+                    key(first, tags::PATIENT_NAME, "PN", ""),
+                    key(first, tags::PATIENT_ID, "LO", ""),
+                ],
+            ),
+            children: vec![],
+        };
+        for (s, all_series) in studies.values().enumerate() {
+            let first = first_instance(all_series.values());
+            let mut study = Record {
+                item: record_item(
+                    "STUDY",
+                    &[
+                        key(first, tags::SPECIFIC_CHARACTER_SET, "CS", ""),
+                        key(first, tags::STUDY_DATE, "DA", "19000101"),
+                        key(first, tags::STUDY_TIME, "TM", "000000"),
+                        key(first, tags::ACCESSION_NUMBER, "SH", ""),
+                        key(first, tags::STUDY_DESCRIPTION, "LO", ""),
+                        key(first, tags::STUDY_INSTANCE_UID, "UI", ""),
+                        key(first, tags::STUDY_ID, "SH", "1"),
+                    ],
+                ),
+                children: vec![],
+            };
+            for (r, instances) in all_series.values().enumerate() {
+                let first = &instances[0];
+                let mut series = Record {
+                    item: record_item(
+                        "SERIES",
+                        &[
+                            key(first, tags::SPECIFIC_CHARACTER_SET, "CS", ""),
+                            key(first, tags::MODALITY, "CS", "OT"),
+                            key(first, tags::SERIES_INSTANCE_UID, "UI", ""),
+                            key(first, tags::SERIES_NUMBER, "IS", "1"),
+                        ],
+                    ),
+                    children: vec![],
+                };
+                for (i, instance) in instances.iter().enumerate() {
+                    let file_id = [
+                        MEDIA_DIR.to_string(),
+                        format!("P{:07}", p + 1),
+                        format!("S{:07}", s + 1),
+                        format!("R{:07}", r + 1),
+                        format!("I{:07}", i + 1),
+                    ];
+                    let media_file = file_id
+                        .iter()
+                        .fold(destination_path.to_path_buf(), |path, id| path.join(id));
+                    link_or_copy(&instance.file, &media_file)?;
+                    let meta = instance.dcm_obj.meta();
+                    let sop_instance_uid =
+                        meta.media_storage_sop_instance_uid().trim_end_matches('\0');
+                    instance_uids.push_str(sop_instance_uid);
+                    series.children.push(Record {
+                        item: record_item(
+                            "IMAGE",
+                            &[
+                                (tags::REFERENCED_FILE_ID, "CS", file_id.join("\\")),
+                                (
+                                    tags::REFERENCED_SOP_CLASS_UID_IN_FILE,
+                                    "UI",
+                                    meta.media_storage_sop_class_uid()
+                                        .trim_end_matches('\0')
+                                        .to_string(),
+                                ),
+                                (
+                                    tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE,
+                                    "UI",
+                                    sop_instance_uid.to_string(),
+                                ),
+                                (
+                                    tags::REFERENCED_TRANSFER_SYNTAX_UID_IN_FILE,
+                                    "UI",
+                                    meta.transfer_syntax().trim_end_matches('\0').to_string(),
+                                ),
+                                key(instance, tags::SPECIFIC_CHARACTER_SET, "CS", ""),
+                                key(instance, tags::INSTANCE_NUMBER, "IS", "1"),
+                            ],
+                        ),
+                        children: vec![],
+                    });
+                    count += 1;
+                }
+                study.children.push(series);
+            }
+            patient.children.push(study);
+        }
+        root.push(patient);
+    }
+
+    let dicomdir_path = destination_path.join(DICOMDIR_FILE);
+    fs::write(&dicomdir_path, encode_dicomdir(&root, &instance_uids))?;
+    info!(
+        "DICOMDIR of {} patients and {} instances written: {}",
+        root.len(),
+        count,
+        dicomdir_path.display()
+    );
+    Ok(())
+}
+
+fn first_instance<'a>(mut instances: impl Iterator<Item = &'a Vec<Instance>>) -> &'a Instance {
+    &instances.next().expect("Records have instances")[0]
+}
+
+// Trimmed value of a top level element, empty when absent
+fn value(dcm_obj: &FileDicomObject<InMemDicomObject>, tag: Tag) -> String {
+    dcm_obj
+        .element(tag)
+        .ok()
+        .and_then(|element| element.to_str().ok())
+        .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+        .unwrap_or_default()
+}
+
+// Record key from the instance, the default fills the type 1 keys missing in the instance
+// Specific Character Set is left out of the record when the instance has none
+fn key(
+    instance: &Instance,
+    tag: Tag,
+    vr: &'static str,
+    default: &str,
+) -> (Tag, &'static str, String) {
+    let value = value(&instance.dcm_obj, tag);
+    match value.is_empty() {
+        true => (tag, vr, default.to_string()),
+        false => (tag, vr, value),
+    }
+}
+
+fn link_or_copy(file: &Path, media_file: &Path) -> Result<()> {
+    if let Some(parent) = media_file.parent() {
+        create_dir_all(parent)?;
+    }
+    if fs::hard_link(file, media_file).is_err() {
+        fs::copy(file, media_file)?;
+    }
+    Ok(())
+}
+
+// Explicit VR little endian element, values are padded to an even length
+fn element(tag: Tag, vr: &str, value: &[u8]) -> Vec<u8> {
+    let mut value = value.to_vec();
+    if value.len() % 2 == 1 {
+        value.push(if vr == "UI" { 0 } else { b' ' });
+    }
+    let mut bytes = vec![];
+    bytes.extend_from_slice(&tag.group().to_le_bytes());
+    bytes.extend_from_slice(&tag.element().to_le_bytes());
+    bytes.extend_from_slice(vr.as_bytes());
+    match vr {
+        "OB" | "SQ" => {
+            bytes.extend_from_slice(&[0, 0]);
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        }
+        _ => bytes.extend_from_slice(&(value.len() as u16).to_le_bytes()),
+    }
+    bytes.extend(value);
+    bytes
+}
+
+// Item of a directory record, the offsets are filled in when the file is encoded
+// The keys are in tag order after the record type
+fn record_item(record_type: &str, keys: &[(Tag, &str, String)]) -> Vec<u8> {
+    let mut content = vec![];
+    content.extend(element(
+        tags::OFFSET_OF_THE_NEXT_DIRECTORY_RECORD,
+        "UL",
+        &[0; 4],
+    ));
+    content.extend(element(
+        tags::RECORD_IN_USE_FLAG,
+        "US",
+        &0xFFFFu16.to_le_bytes(),
+    ));
+    content.extend(element(
+        tags::OFFSET_OF_REFERENCED_LOWER_LEVEL_DIRECTORY_ENTITY,
+        "UL",
+        &[0; 4],
+    ));
+    content.extend(element(
+        tags::DIRECTORY_RECORD_TYPE,
+        "CS",
+        record_type.as_bytes(),
+    ));
+    let mut keys: Vec<&(Tag, &str, String)> = keys
+        .iter()
+        .filter(|(tag, _, value)| *tag != tags::SPECIFIC_CHARACTER_SET || !value.is_empty())
+        .collect();
+    keys.sort_by_key(|(tag, _, _)| *tag);
+    for (tag, vr, value) in keys {
+        content.extend(element(*tag, vr, value.as_bytes()));
+    }
+    let mut item = vec![];
+    item.extend_from_slice(&0xFFFEu16.to_le_bytes());
+    item.extend_from_slice(&0xE000u16.to_le_bytes());
+    item.extend_from_slice(&(content.len() as u32).to_le_bytes());
+    item.extend(content);
+    item
+}
+
+// Offset of the next record value in a record item: item header, then the tag, VR and length
+const NEXT_OFFSET_AT: usize = 8 + 8;
+// Offset of the lower level record value: after the next record offset and the in use flag
+const LOWER_OFFSET_AT: usize = 8 + 12 + 10 + 8;
+
+// Items of the records in the order of the sequence, depth first, with the offsets of the next
+// record and of the first lower level record filled in. Returns the offsets of the records of
+// this level in the file and the end of the last one
+fn layout(records: &[Record], start: usize, items: &mut Vec<Vec<u8>>) -> (Vec<usize>, usize) {
+    let mut offsets = vec![];
+    let mut indexes = vec![];
+    let mut position = start;
+    for record in records {
+        let index = items.len();
+        offsets.push(position);
+        indexes.push(index);
+        items.push(record.item.clone());
+        position += record.item.len();
+        let (children, end) = layout(&record.children, position, items);
+        if let Some(first_child) = children.first() {
+            set_offset(items.get_mut(index), LOWER_OFFSET_AT, *first_child);
+        }
+        position = end;
+    }
+    for (index, next) in indexes.iter().zip(offsets.iter().skip(1)) {
+        set_offset(items.get_mut(*index), NEXT_OFFSET_AT, *next);
+    }
+    (offsets, position)
+}
+
+fn set_offset(item: Option<&mut Vec<u8>>, at: usize, offset: usize) {
+    if let Some(item) = item {
+        item[at..at + 4].copy_from_slice(&(offset as u32).to_le_bytes());
+    }
+}
+
+// DICOMDIR file: preamble, file meta group and the directory records
+fn encode_dicomdir(root: &[Record], instance_uids: &str) -> Vec<u8> {
+    let mut digest = [0u8; 16];
+    digest.copy_from_slice(&sha256(instance_uids.as_bytes())[..16]);
+    let media_uid = format!("2.25.{}", u128::from_be_bytes(digest));
+    let mut meta = vec![];
+    meta.extend(element(tags::FILE_META_INFORMATION_VERSION, "OB", &[0, 1]));
+    meta.extend(element(
+        tags::MEDIA_STORAGE_SOP_CLASS_UID,
+        "UI",
+        MEDIA_STORAGE_DIRECTORY.as_bytes(),
+    ));
+    meta.extend(element(
+        tags::MEDIA_STORAGE_SOP_INSTANCE_UID,
+        "UI",
+        media_uid.as_bytes(),
+    ));
+    meta.extend(element(
+        tags::TRANSFER_SYNTAX_UID,
+        "UI",
+        EXPLICIT_VR_LITTLE_ENDIAN.as_bytes(),
+    ));
+    meta.extend(element(
+        tags::IMPLEMENTATION_CLASS_UID,
+        "UI",
+        IMPLEMENTATION_CLASS_UID.as_bytes(),
+    ));
+    meta.extend(element(
+        tags::IMPLEMENTATION_VERSION_NAME,
+        "SH",
+        format!("DCMRIG {}", env!("CARGO_PKG_VERSION")).as_bytes(),
+    ));
+    let mut file = vec![0u8; 128];
+    file.extend_from_slice(b"DICM");
+    file.extend(element(
+        tags::FILE_META_INFORMATION_GROUP_LENGTH,
+        "UL",
+        &(meta.len() as u32).to_le_bytes(),
+    ));
+    file.extend(meta);
+
+    let file_set_id = element(tags::FILE_SET_ID, "CS", b"DCMRIG");
+    // File set ID, first and last record offsets, consistency flag and the sequence header
+    let records_start = file.len() + file_set_id.len() + 12 + 12 + 10 + 12;
+    let mut items = vec![];
+    let (offsets, _) = layout(root, records_start, &mut items);
+    file.extend(file_set_id);
+    file.extend(element(
+        tags::OFFSET_OF_THE_FIRST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY,
+        "UL",
+        &(offsets.first().copied().unwrap_or(0) as u32).to_le_bytes(),
+    ));
+    file.extend(element(
+        tags::OFFSET_OF_THE_LAST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY,
+        "UL",
+        &(offsets.last().copied().unwrap_or(0) as u32).to_le_bytes(),
+    ));
+    file.extend(element(
+        tags::FILE_SET_CONSISTENCY_FLAG,
+        "US",
+        &0u16.to_le_bytes(),
+    ));
+    file.extend(element(
+        tags::DIRECTORY_RECORD_SEQUENCE,
+        "SQ",
+        &items.concat(),
+    ));
+    file
+}
@@ -0,0 +1,639 @@
+use crate::{
+    check_given_path_exists, create_target_dir, gen_id, phi, preprocessing_setup,
+    sink::{HashingWriter, OutputSink},
+    source::InstanceSource,
+    source_setup,
+    tracker::RunTracker,
+    ExpectedCounts, RunOptions,
+};
+use anyhow::{anyhow, Result};
+use dicom::dictionary_std::tags;
+use regex::Regex;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tracing::{debug, error, info, warn};
+
+// Seconds curl is given for one upload or retrieve
+const STOW_TIMEOUT: &str = "300";
+// Results asked for in each QIDO-RS page
+const QIDO_PAGE: usize = 1000;
+// Bytes searched for the part headers at the start and the closing boundary at the end of a
+// WADO-RS response
+const MULTIPART_SEARCH: u64 = 64 * 1024;
+const MULTIPART_DICOM: &str = "multipart/related; type=\"application/dicom\"; transfer-syntax=*";
+
+// curl config with the Authorization header or the user, kept out of the command line
+struct CurlAuth {
+    config: Option<PathBuf>,
+}
+
+impl CurlAuth {
+    fn new(token: Option<&Path>, user: Option<&Path>) -> Result<Self> {
+        let setting = match (token, user) {
+            (Some(token), _) => Some(format!(
+                "header = \"Authorization: Bearer {}\"",
+                config_escape(&read_secret(token)?)
+            )),
+            (None, Some(user)) => Some(format!(
+                "user = \"{}\"",
+                config_escape(&read_basic_user(user)?)
+            )),
+            (None, None) => None,
+        };
+        let config = match setting {
+            Some(setting) => {
+                let config_dir = std::env::temp_dir().join(format!("dcmrig_curl_{}", gen_id()));
+                private_dir(&config_dir)?;
+                let config = config_dir.join("curlrc");
+                fs::write(&config, format!("{}\n", setting))?;
+                Some(config)
+            }
+            None => None,
+        };
+        Ok(CurlAuth { config })
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new("curl");
+        command
+            .args(["--silent", "--show-error"])
+            .args(["--max-time", STOW_TIMEOUT]);
+        if let Some(config) = &self.config {
+            command.arg("--config").arg(config);
+        }
+        command
+    }
+
+    // GET a URL, the body is returned or written to the output file
+    // Returns the body, the HTTP status and the content type
+    fn get(
+        &self,
+        url: &str,
+        accept: &str,
+        output: Option<&Path>,
+    ) -> Result<(String, String, String)> {
+        let mut command = self.command();
+        command
+            .arg("--header")
+            .arg(format!("Accept: {}", accept))
+            .args(["--write-out", "\n%{http_code} %{content_type}"]);
+        if let Some(output) = output {
+            command.arg("--output").arg(output);
+        }
+        let output = command.arg(url).stdin(Stdio::null()).output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "curl failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let (body, write_out) = stdout.rsplit_once('\n').unwrap_or(("", &stdout));
+        let (status, content_type) = write_out.split_once(' ').unwrap_or((write_out, ""));
+        Ok((
+            body.to_string(),
+            status.trim().to_string(),
+            content_type.trim().to_string(),
+        ))
+    }
+}
+
+// The secrets don't outlive the run
+impl Drop for CurlAuth {
+    fn drop(&mut self) {
+        if let Some(config_dir) = self.config.as_ref().and_then(|config| config.parent()) {
+            let _ = fs::remove_dir_all(config_dir);
+        }
+    }
+}
+
+/// STOW-RS client uploading each written instance to a DICOMweb server, eg
+/// https://server/dicomweb/studies, with curl. One request per instance, the instances that
+/// could not be uploaded are written to the destination instead
+pub struct StowClient {
+    url: String,
+    auth: CurlAuth,
+    uploaded: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl StowClient {
+    pub fn new(url: &str, token: Option<&Path>, user: Option<&Path>) -> Result<Self> {
+        Ok(StowClient {
+            url: url.to_string(),
+            auth: CurlAuth::new(token, user)?,
+            uploaded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        })
+    }
+
+    // Upload an encoded DICOM file as the single part of a multipart/related request
+    pub fn upload(&self, bytes: &[u8]) -> Result<()> {
+        let boundary = format!("DCMRIG{}", gen_id());
+        let mut child = self
+            .auth
+            .command()
+            .args(["--request", "POST"])
+            .arg("--header")
+            .arg(format!(
+                "Content-Type: multipart/related; type=\"application/dicom\"; boundary={}",
+                boundary
+            ))
+            .args(["--header", "Accept: application/dicom+json"])
+            .args(["--data-binary", "@-"])
+            .args(["--write-out", "\n%{http_code}"])
+            .arg(&self.url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("Piped stdin");
+        let written = write!(
+            stdin,
+            "--{}\r\nContent-Type: application/dicom\r\n\r\n",
+            boundary
+        )
+        .and_then(|_| stdin.write_all(bytes))
+        .and_then(|_| write!(stdin, "\r\n--{}--\r\n", boundary));
+        drop(stdin);
+        let output = child.wait_with_output()?;
+        // A failed connection closes the input early, the error of curl says why
+        if !output.status.success() {
+            return Err(anyhow!(
+                "curl failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        written?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let (response, status) = stdout.rsplit_once('\n').unwrap_or(("", &stdout));
+        match status.trim() {
+            "200" => Ok(()),
+            // Stored with warnings or coercions
+            "202" => {
+                warn!("Stored with warnings by {}: {}", self.url, response.trim());
+                Ok(())
+            }
+            status => Err(anyhow!(
+                "STOW-RS failed with HTTP {}: {}",
+                status,
+                response.trim().chars().take(200).collect::<String>()
+            )),
+        }
+    }
+}
+
+impl OutputSink for StowClient {
+    fn write_instance(
+        &self,
+        path: &Path,
+        hashing: bool,
+        encode: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+    ) -> Result<Option<String>> {
+        let mut bytes = vec![];
+        encode(&mut bytes)?;
+        match self.upload(&bytes) {
+            Ok(()) => {
+                self.uploaded.fetch_add(1, Ordering::Relaxed);
+                return Ok(None);
+            }
+            Err(e) => {
+                error!("Can't upload {} to {}: {}", path.display(), self.url, e);
+                self.failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let Some(parent) = path.parent() {
+            create_target_dir(&parent.display().to_string())?;
+        }
+        let mut writer = HashingWriter::new(File::create(path)?, hashing);
+        writer.write_all(&bytes)?;
+        writer.finish()
+    }
+
+    fn finalize(&self) -> Result<()> {
+        info!(
+            "Instances uploaded to {}: {}",
+            self.url,
+            self.uploaded.load(Ordering::Relaxed)
+        );
+        let failed = self.failed.load(Ordering::Relaxed);
+        if failed > 0 {
+            warn!(
+                "Instances that could not be uploaded to {}: {}, they are written to the destination",
+                self.url, failed
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Index the source of a sort, anon or deid run: the instances of the --wado-url server, retrieved
+/// to the source path, or the files of the source path
+pub fn setup_source(
+    source_path: &PathBuf,
+    destination_path: &PathBuf,
+    run_options: &RunOptions,
+) -> Result<(Vec<PathBuf>, u64, RunTracker)> {
+    let url = match &run_options.wado_url {
+        Some(url) => url,
+        None => {
+            return preprocessing_setup(
+                source_path,
+                destination_path,
+                run_options.read_iso,
+                run_options.dry_run,
+            )
+        }
+    };
+    fs::create_dir_all(source_path)?;
+    check_given_path_exists(source_path, destination_path, run_options.dry_run)?;
+    source_setup(Arc::new(WadoSource::new(
+        url,
+        &run_options.wado_filters,
+        source_path,
+        run_options.wado_token.as_deref(),
+        run_options.wado_user.as_deref(),
+    )?))
+    .inspect_err(|e| error!("Can't search {}: {}", url, e))
+}
+
+/// Instances of a DICOMweb server, eg https://server/dicomweb, found with QIDO-RS: the studies
+/// matching the filters, then their instances. Each item is retrieved with WADO-RS to
+/// <staging>/<study>/<series>/<instance>.dcm when a worker first takes it, the retrieved files
+/// are removed at the end of the run
+pub struct WadoSource {
+    url: String,
+    filters: Vec<(String, String)>,
+    staging: PathBuf,
+    auth: CurlAuth,
+    retrieved: Mutex<Vec<PathBuf>>,
+    // StudyInstanceUID > NumberOfStudyRelatedInstances, or the instances found without it
+    expected: Mutex<BTreeMap<String, usize>>,
+}
+
+impl WadoSource {
+    pub fn new(
+        url: &str,
+        filters: &[(String, String)],
+        staging: &Path,
+        token: Option<&Path>,
+        user: Option<&Path>,
+    ) -> Result<Self> {
+        Ok(WadoSource {
+            url: url.trim_end_matches('/').to_string(),
+            filters: filters.to_vec(),
+            staging: staging.to_path_buf(),
+            auth: CurlAuth::new(token, user)?,
+            retrieved: Mutex::new(vec![]),
+            expected: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    // JSON objects of all the pages of a QIDO-RS search
+    fn search(&self, resource: &str, query: &[(String, String)]) -> Result<Vec<String>> {
+        let mut seen = HashSet::new();
+        let mut results = vec![];
+        loop {
+            let mut query = query.to_vec();
+            query.push(("limit".to_string(), QIDO_PAGE.to_string()));
+            query.push(("offset".to_string(), results.len().to_string()));
+            let query: Vec<String> = query
+                .iter()
+                .map(|(key, value)| format!("{}={}", query_escape(key), query_escape(value)))
+                .collect();
+            let url = format!("{}/{}?{}", self.url, resource, query.join("&"));
+            // The filters can hold a PatientID or a name
+            debug!(
+                "QIDO-RS {}/{}?{}",
+                self.url,
+                resource,
+                phi(&query.join("&"))
+            );
+            let (body, status, _) = self.auth.get(&url, "application/dicom+json", None)?;
+            match status.as_str() {
+                "200" => (),
+                // No match
+                "204" => break,
+                status => {
+                    return Err(anyhow!(
+                        "QIDO-RS {} failed with HTTP {}: {}",
+                        url,
+                        status,
+                        body.trim().chars().take(200).collect::<String>()
+                    ))
+                }
+            }
+            let page: Vec<String> = json_objects(&body)
+                .into_iter()
+                .filter(|object| seen.insert(object.to_string()))
+                .map(|object| object.to_string())
+                .collect();
+            // The last page is empty, or repeats the results when the server ignores the offset
+            if page.is_empty() {
+                break;
+            }
+            results.extend(page);
+        }
+        Ok(results)
+    }
+}
+
+impl InstanceSource for WadoSource {
+    fn describe(&self) -> String {
+        let filters: Vec<String> = self
+            .filters
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        format!("{} [{}]", self.url, filters.join(" "))
+    }
+
+    fn items(&self) -> Result<Vec<PathBuf>> {
+        let study_uid = uid_value(0x0020_000D);
+        let series_uid = uid_value(0x0020_000E);
+        let sop_uid = uid_value(0x0008_0018);
+        let related_instances = uid_value(0x0020_1208);
+        let studies = self.search("studies", &self.filters)?;
+        let mut items = vec![];
+        let mut expected = self.expected.lock().expect("Failed to lock mutex");
+        for study in &studies {
+            let study_uid = match json_uid(&study_uid, study) {
+                Some(study_uid) => study_uid,
+                None => continue,
+            };
+            let instances = self.search(&format!("studies/{}/instances", study_uid), &[])?;
+            expected.insert(
+                study_uid.clone(),
+                json_uid(&related_instances, study)
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or(instances.len()),
+            );
+            for instance in instances {
+                if let (Some(series_uid), Some(sop_uid)) = (
+                    json_uid(&series_uid, &instance),
+                    json_uid(&sop_uid, &instance),
+                ) {
+                    items.push(
+                        self.staging
+                            .join(&study_uid)
+                            .join(series_uid)
+                            .join(format!("{}.dcm", sop_uid)),
+                    );
+                }
+            }
+        }
+        info!(
+            "{} instances of {} studies found on {}",
+            items.len(),
+            studies.len(),
+            self.url
+        );
+        Ok(items)
+    }
+
+    fn expected_counts(&self) -> Option<ExpectedCounts> {
+        Some(ExpectedCounts {
+            origin: self.url.clone(),
+            key_tag: tags::STUDY_INSTANCE_UID,
+            studies: self.expected.lock().expect("Failed to lock mutex").clone(),
+        })
+    }
+
+    // Retrieve the instance with WADO-RS, unless an earlier call did
+    fn fetch(&self, item: &Path) -> Result<()> {
+        if item.exists() {
+            return Ok(());
+        }
+        // The UIDs are the names of the path, <study>/<series>/<instance>.dcm
+        let uid = |path: Option<&Path>| {
+            path.and_then(|path| path.file_name())
+                .map(|uid| uid.to_string_lossy().trim_end_matches(".dcm").to_string())
+                .ok_or_else(|| anyhow!("Not a retrieved instance: {}", item.display()))
+        };
+        let series = item.parent();
+        let (study_uid, series_uid, sop_uid) = (
+            uid(series.and_then(|series| series.parent()))?,
+            uid(series)?,
+            uid(Some(item))?,
+        );
+        let url = format!(
+            "{}/studies/{}/series/{}/instances/{}",
+            self.url, study_uid, series_uid, sop_uid
+        );
+        create_target_dir(&series.unwrap_or(&self.staging).display().to_string())?;
+        let response = item.with_extension("part");
+        let retrieved = self
+            .auth
+            .get(&url, MULTIPART_DICOM, Some(&response))
+            .and_then(|(_, status, content_type)| match status.as_str() {
+                "200" => match content_type.starts_with("multipart/related") {
+                    true => extract_part(&response, item, &content_type),
+                    false => Ok(fs::rename(&response, item)?),
+                },
+                status => Err(anyhow!("WADO-RS {} failed with HTTP {}", url, status)),
+            });
+        let _ = fs::remove_file(&response);
+        retrieved?;
+        self.retrieved
+            .lock()
+            .expect("Failed to lock mutex")
+            .push(item.to_path_buf());
+        Ok(())
+    }
+
+    // Remove the retrieved instances, then their empty series and study directories
+    fn finalize(&self) -> Result<()> {
+        let retrieved = std::mem::take(&mut *self.retrieved.lock().expect("Failed to lock mutex"));
+        for item in &retrieved {
+            fs::remove_file(item)
+                .unwrap_or_else(|e| warn!("Can't remove the retrieved {}: {}", item.display(), e));
+            let series = item.parent();
+            for dir in [series, series.and_then(|series| series.parent())]
+                .into_iter()
+                .flatten()
+            {
+                let _ = fs::remove_dir(dir);
+            }
+        }
+        info!("{} instances retrieved from {}", retrieved.len(), self.url);
+        Ok(())
+    }
+}
+
+// Copy the body of the first part of a multipart/related WADO-RS response to the item
+fn extract_part(response: &Path, item: &Path, content_type: &str) -> Result<()> {
+    let boundary = content_type
+        .split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+        .map(|(_, boundary)| boundary.trim_matches('"'))
+        .ok_or_else(|| anyhow!("No boundary in the content type {}", content_type))?;
+    let delimiter = format!("--{}", boundary);
+    let mut file = File::open(response)?;
+    let len = file.metadata()?.len();
+    let mut head = vec![0; len.min(MULTIPART_SEARCH) as usize];
+    file.read_exact(&mut head)?;
+    let start = find(&head, delimiter.as_bytes())
+        .and_then(|start| find(&head[start..], b"\r\n\r\n").map(|headers| start + headers + 4))
+        .ok_or_else(|| anyhow!("No part in the WADO-RS response"))? as u64;
+    let tail_start = len.saturating_sub(MULTIPART_SEARCH).max(start);
+    let mut tail = vec![0; (len - tail_start) as usize];
+    file.seek(SeekFrom::Start(tail_start))?;
+    file.read_exact(&mut tail)?;
+    let end = tail
+        .windows(delimiter.len() + 2)
+        .rposition(|window| window[..2] == *b"\r\n" && window[2..] == *delimiter.as_bytes())
+        .ok_or_else(|| anyhow!("The WADO-RS response is truncated"))? as u64
+        + tail_start;
+    file.seek(SeekFrom::Start(start))?;
+    let part = item.with_extension("dcm.tmp");
+    io::copy(&mut file.take(end - start), &mut File::create(&part)?)?;
+    fs::rename(&part, item)?;
+    Ok(())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// Top level objects of a JSON array, the datasets of a QIDO-RS response
+fn json_objects(text: &str) -> Vec<&str> {
+    let mut objects = vec![];
+    let (mut depth, mut start, mut in_string, mut escaped) = (0, 0, false, false);
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => {
+                if depth == 0 {
+                    start = i;
+                }
+                depth += 1;
+            }
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    objects.push(&text[start..=i]);
+                }
+            }
+            _ => (),
+        }
+    }
+    objects
+}
+
+// First value of a UI or IS element of a DICOM JSON dataset, eg "0020000D": {"vr": "UI", "Value": [..]}
+// IS values are JSON numbers, some servers quote them
+fn uid_value(tag: u32) -> Regex {
+    Regex::new(&format!(
+        r#"(?i)"{:08X}"\s*:\s*\{{[^{{}}]*?"Value"\s*:\s*\[\s*"?([0-9.]+)"#,
+        tag
+    ))
+    .expect("Invalid UID pattern")
+}
+
+fn json_uid(pattern: &Regex, object: &str) -> Option<String> {
+    pattern
+        .captures(object)
+        .map(|captures| captures[1].to_string())
+}
+
+// Percent encoding of a query parameter, the ranges and wildcards are kept
+fn query_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'*' | b',' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Check the URL, the secrets and that curl runs before the files are processed
+pub fn check_stow(url: &str, token: Option<&Path>, user: Option<&Path>) -> Result<()> {
+    check_curl("STOW-RS", url, token, user)
+}
+
+pub fn check_wado(url: &str, token: Option<&Path>, user: Option<&Path>) -> Result<()> {
+    check_curl("QIDO-RS and WADO-RS", url, token, user)
+}
+
+fn check_curl(service: &str, url: &str, token: Option<&Path>, user: Option<&Path>) -> Result<()> {
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        return Err(anyhow!(
+            "The {} URL should start with https://: {}",
+            service,
+            url
+        ));
+    }
+    if let Some(token) = token {
+        read_secret(token)?;
+    }
+    if let Some(user) = user {
+        read_basic_user(user)?;
+    }
+    match Command::new("curl").arg("--version").output() {
+        Ok(output) if output.status.success() => Ok(()),
+        _ => Err(anyhow!(
+            "The curl command is needed for {}, see https://curl.se",
+            service
+        )),
+    }
+}
+
+fn read_secret(path: &Path) -> Result<String> {
+    let secret = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Can't read {}: {}", path.display(), e))?
+        .trim()
+        .to_string();
+    match secret.is_empty() || secret.contains(['\r', '\n']) {
+        true => Err(anyhow!("{} should hold a single line", path.display())),
+        false => Ok(secret),
+    }
+}
+
+// user:password of HTTP basic authentication
+fn read_basic_user(path: &Path) -> Result<String> {
+    let user = read_secret(path)?;
+    match user.contains(':') {
+        true => Ok(user),
+        false => Err(anyhow!("{} should hold user:password", path.display())),
+    }
+}
+
+fn config_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Only readable by the user on Unix
+fn private_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        fs::DirBuilder::new().mode(0o700).create(path)?;
+    }
+    #[cfg(not(unix))]
+    fs::create_dir(path)?;
+    Ok(())
+}
@@ -0,0 +1,595 @@
+use crate::{
+    create_target_dir,
+    sink::{HashingWriter, OutputSink},
+    source::{DirectorySource, InstanceSource},
+};
+use anyhow::{anyhow, Result};
+use dicom::{
+    core::{dicom_value, DataElement, VR},
+    dictionary_std::{
+        tags,
+        uids::{
+            EXPLICIT_VR_LITTLE_ENDIAN, IMPLICIT_VR_LITTLE_ENDIAN, MEDIA_STORAGE_DIRECTORY_STORAGE,
+            VERIFICATION,
+        },
+    },
+    encoding::{TransferSyntax, TransferSyntaxIndex},
+    object::{FileDicomObject, FileMetaTable, InMemDicomObject},
+    pixeldata::Transcode,
+    transfer_syntax::TransferSyntaxRegistry,
+    ul::{
+        pdu::{PDataValue, PDataValueType, PresentationContextResultReason},
+        ClientAssociation, ClientAssociationOptions, Pdu,
+    },
+};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    net::IpAddr,
+    path::Path,
+    process::exit,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU16, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tracing::{debug, error, info, warn};
+
+// AE title of dcmrig when --calling-aet is not given
+pub const DEFAULT_CALLING_AET: &str = "DCMRIG";
+pub const DIMSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// C-STORE-RQ and C-ECHO-RQ command fields
+pub const C_STORE_RQ: u16 = 0x0001;
+pub const C_ECHO_RQ: u16 = 0x0030;
+// Command Data Set Type of the commands with and without a data set
+const DATA_SET_PRESENT: u16 = 0x0000;
+pub const NO_DATA_SET: u16 = 0x0101;
+
+// Association of the storage SCU with the presentation context accepted for one SOP class
+struct StoreAssociation {
+    association: ClientAssociation,
+    context_id: u8,
+    transfer_syntax: String,
+}
+
+/// Storage SCU sending each written instance to a storage SCP, eg a PACS, given as AET@host:port
+/// The associations are kept open per SOP class and transfer syntax, each worker takes its own
+/// The instances are also written to the destination, or only the instances that failed to send
+/// when send_only is set
+pub struct StoreScu {
+    address: String,
+    calling_aet: String,
+    send_only: bool,
+    associations: Mutex<HashMap<(String, String), Vec<StoreAssociation>>>,
+    message_id: AtomicU16,
+    sent: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl StoreScu {
+    pub fn new(address: &str, calling_aet: &str, send_only: bool) -> Self {
+        StoreScu {
+            address: address.to_string(),
+            calling_aet: calling_aet.to_string(),
+            send_only,
+            associations: Mutex::new(HashMap::new()),
+            message_id: AtomicU16::new(1),
+            sent: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        }
+    }
+
+    // Send an encoded DICOM file, with or without its preamble
+    pub fn send(&self, bytes: &[u8]) -> Result<()> {
+        let start = match bytes.get(128..132) {
+            Some(b"DICM") => 128,
+            _ => 0,
+        };
+        let meta = FileMetaTable::from_reader(&bytes[start..])?;
+        let dataset_start = meta_group_end(bytes, start)?;
+        let sop_class = trim_uid(&meta.media_storage_sop_class_uid);
+        let sop_instance = trim_uid(&meta.media_storage_sop_instance_uid);
+        let ts = trim_uid(&meta.transfer_syntax);
+        let key = (sop_class.to_string(), ts.to_string());
+
+        let pooled = self
+            .associations
+            .lock()
+            .expect("Failed to lock mutex")
+            .get_mut(&key)
+            .and_then(|pool| pool.pop());
+        let mut store = match pooled {
+            Some(store) => store,
+            None => self.associate(sop_class, ts)?,
+        };
+        // The file is re-encoded when the SCP only accepted one of the uncompressed fallbacks
+        let dataset: Cow<[u8]> = match store.transfer_syntax == ts {
+            true => Cow::Borrowed(&bytes[dataset_start..]),
+            false => {
+                let accepted = TransferSyntaxRegistry
+                    .get(&store.transfer_syntax)
+                    .ok_or_else(|| anyhow!("Unknown transfer syntax {}", store.transfer_syntax))?;
+                let mut obj = FileDicomObject::from_reader(&bytes[start..])?;
+                obj.transcode(accepted)?;
+                let mut dataset = vec![];
+                obj.write_dataset_with_ts(&mut dataset, accepted)?;
+                Cow::Owned(dataset)
+            }
+        };
+        let command = InMemDicomObject::command_from_element_iter([
+            DataElement::new(
+                tags::AFFECTED_SOP_CLASS_UID,
+                VR::UI,
+                dicom_value!(Str, padded_uid(sop_class)),
+            ),
+            DataElement::new(tags::COMMAND_FIELD, VR::US, dicom_value!(U16, [C_STORE_RQ])),
+            DataElement::new(
+                tags::MESSAGE_ID,
+                VR::US,
+                dicom_value!(U16, [self.next_id()]),
+            ),
+            DataElement::new(tags::PRIORITY, VR::US, dicom_value!(U16, [0])),
+            DataElement::new(
+                tags::COMMAND_DATA_SET_TYPE,
+                VR::US,
+                dicom_value!(U16, [DATA_SET_PRESENT]),
+            ),
+            DataElement::new(
+                tags::AFFECTED_SOP_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, padded_uid(sop_instance)),
+            ),
+        ]);
+        let sent = send_command(&mut store.association, store.context_id, &command)
+            .and_then(|_| {
+                let mut writer = store.association.send_pdata(store.context_id);
+                writer.write_all(&dataset)?;
+                writer.finish()?;
+                Ok(())
+            })
+            .and_then(|_| receive_status(&mut store.association));
+        match sent {
+            Ok(status) => {
+                self.associations
+                    .lock()
+                    .expect("Failed to lock mutex")
+                    .entry(key)
+                    .or_default()
+                    .push(store);
+                check_status(status, sop_instance)
+            }
+            // The association may be out of step after a failure, it is not reused
+            Err(e) => {
+                let _ = store.association.abort();
+                Err(e)
+            }
+        }
+    }
+
+    // The file's own transfer syntax is proposed first, the uncompressed ones as fallbacks
+    fn associate(&self, sop_class: &str, ts: &str) -> Result<StoreAssociation> {
+        let mut transfer_syntaxes = vec![ts.to_string()];
+        for fallback in [EXPLICIT_VR_LITTLE_ENDIAN, IMPLICIT_VR_LITTLE_ENDIAN] {
+            if fallback != ts {
+                transfer_syntaxes.push(fallback.to_string());
+            }
+        }
+        let mut association = ClientAssociationOptions::new()
+            .calling_ae_title(self.calling_aet.clone())
+            .with_presentation_context(sop_class.to_string(), transfer_syntaxes)
+            .establish_with(&self.address)
+            .map_err(|e| anyhow!("Can't associate with {}: {}", self.address, e))?;
+        association
+            .inner_stream()
+            .set_read_timeout(Some(DIMSE_TIMEOUT))?;
+        association
+            .inner_stream()
+            .set_write_timeout(Some(DIMSE_TIMEOUT))?;
+        // Each message waits for its response, Nagle would hold the end of every data set
+        association.inner_stream().set_nodelay(true)?;
+        let context = association
+            .presentation_contexts()
+            .iter()
+            .find(|context| context.reason == PresentationContextResultReason::Acceptance)
+            .map(|context| (context.id, trim_uid(&context.transfer_syntax).to_string()));
+        match context {
+            Some((context_id, transfer_syntax)) => {
+                debug!(
+                    "Association with {} for {} in {}",
+                    self.address, sop_class, transfer_syntax
+                );
+                Ok(StoreAssociation {
+                    association,
+                    context_id,
+                    transfer_syntax,
+                })
+            }
+            None => {
+                let _ = association.abort();
+                Err(anyhow!(
+                    "{} does not accept the SOP class {}",
+                    self.address,
+                    sop_class
+                ))
+            }
+        }
+    }
+
+    fn next_id(&self) -> u16 {
+        self.message_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn release_associations(&self) {
+        for (_, pool) in self
+            .associations
+            .lock()
+            .expect("Failed to lock mutex")
+            .drain()
+        {
+            for store in pool {
+                store.association.release().unwrap_or_else(|e| {
+                    warn!("Can't release the association with {}: {}", self.address, e)
+                });
+            }
+        }
+    }
+}
+
+impl OutputSink for StoreScu {
+    fn write_instance(
+        &self,
+        path: &Path,
+        hashing: bool,
+        encode: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+    ) -> Result<Option<String>> {
+        let mut bytes = vec![];
+        encode(&mut bytes)?;
+        // A failed send is only logged, the instance is still written to the destination
+        let sent = match self.send(&bytes) {
+            Ok(()) => {
+                self.sent.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(e) => {
+                error!("Can't send {} to {}: {}", path.display(), self.address, e);
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        };
+        if sent && self.send_only {
+            return Ok(None);
+        }
+        if let Some(parent) = path.parent() {
+            create_target_dir(&parent.display().to_string())?;
+        }
+        let mut writer = HashingWriter::new(File::create(path)?, hashing);
+        writer.write_all(&bytes)?;
+        writer.finish()
+    }
+
+    // Release the associations and log the counts of the run
+    fn finalize(&self) -> Result<()> {
+        self.release_associations();
+        info!(
+            "Instances sent to {}: {}",
+            self.address,
+            self.sent.load(Ordering::Relaxed)
+        );
+        let failed = self.failed.load(Ordering::Relaxed);
+        if failed > 0 {
+            warn!(
+                "Instances that could not be sent to {}: {}, they are written to the destination",
+                self.address, failed
+            );
+        }
+        Ok(())
+    }
+}
+
+// C-ECHO the SCP before the files are processed, so a wrong address or AE title stops the run early
+pub fn check_scp(address: &str, calling_aet: &str) -> Result<()> {
+    let mut association = ClientAssociationOptions::new()
+        .calling_ae_title(calling_aet)
+        .with_abstract_syntax(VERIFICATION)
+        .establish_with(address)
+        .map_err(|e| anyhow!("Can't associate with {}: {}", address, e))?;
+    association
+        .inner_stream()
+        .set_read_timeout(Some(DIMSE_TIMEOUT))?;
+    let context_id = association
+        .presentation_contexts()
+        .iter()
+        .find(|context| context.reason == PresentationContextResultReason::Acceptance)
+        .map(|context| context.id)
+        .ok_or_else(|| anyhow!("{} does not accept the verification SOP class", address))?;
+    let command = InMemDicomObject::command_from_element_iter([
+        DataElement::new(
+            tags::AFFECTED_SOP_CLASS_UID,
+            VR::UI,
+            dicom_value!(Str, padded_uid(VERIFICATION)),
+        ),
+        DataElement::new(tags::COMMAND_FIELD, VR::US, dicom_value!(U16, [C_ECHO_RQ])),
+        DataElement::new(tags::MESSAGE_ID, VR::US, dicom_value!(U16, [1])),
+        DataElement::new(
+            tags::COMMAND_DATA_SET_TYPE,
+            VR::US,
+            dicom_value!(U16, [NO_DATA_SET]),
+        ),
+    ]);
+    send_command(&mut association, context_id, &command)?;
+    let status = receive_status(&mut association)?;
+    association.release()?;
+    match status {
+        0 => Ok(()),
+        status => Err(anyhow!(
+            "C-ECHO of {} failed with status {:04X}",
+            address,
+            status
+        )),
+    }
+}
+
+/// Send the DICOM files of the source to the SCP, without processing them
+pub fn dicom_send(
+    source_path: &Path,
+    address: &str,
+    calling_aet: &str,
+    read_iso: bool,
+) -> Result<()> {
+    check_scp(address, calling_aet).unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(1)
+    });
+    let source = DirectorySource::new(source_path, read_iso);
+    let all_files = source.items()?;
+    info!("Sending {} files to {}", all_files.len(), address);
+    let scu = StoreScu::new(address, calling_aet, true);
+    let skipped = AtomicU64::new(0);
+    all_files.par_iter().for_each(|file| {
+        let bytes = match fs::read(file) {
+            Ok(bytes) if is_dicom_file(&bytes) => bytes,
+            _ => {
+                debug!("Not a DICOM file, not sent: {}", file.display());
+                skipped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        match scu.send(&bytes) {
+            Ok(()) => {
+                scu.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                error!("Can't send {} to {}: {}", file.display(), address, e);
+                scu.failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+    scu.release_associations();
+    info!(
+        "Instances sent to {}: {}",
+        address,
+        scu.sent.load(Ordering::Relaxed)
+    );
+    info!("Files skipped: {}", skipped.load(Ordering::Relaxed));
+    source.finalize()?;
+    let failed = scu.failed.load(Ordering::Relaxed);
+    if failed > 0 {
+        error!("Files that could not be sent: {}", failed);
+        exit(1);
+    }
+    Ok(())
+}
+
+// End of the file meta group as encoded, FileMetaTable recomputes the group length from the
+// elements it keeps and adds those that were missing
+fn meta_group_end(bytes: &[u8], start: usize) -> Result<usize> {
+    // Magic code, then the group length element
+    match bytes.get(start + 4..start + 16) {
+        Some(element) if element[..8] == [0x02, 0x00, 0x00, 0x00, b'U', b'L', 0x04, 0x00] => {
+            let length = u32::from_le_bytes([element[8], element[9], element[10], element[11]]);
+            Ok(start + 16 + length as usize)
+        }
+        _ => Err(anyhow!("The file meta group has no group length")),
+    }
+}
+
+// DICOM files with a file meta group, the DICOMDIR is not an instance to store
+fn is_dicom_file(bytes: &[u8]) -> bool {
+    bytes.get(128..132) == Some(b"DICM")
+        && FileMetaTable::from_reader(&bytes[128..]).is_ok_and(|meta| {
+            trim_uid(&meta.media_storage_sop_class_uid) != MEDIA_STORAGE_DIRECTORY_STORAGE
+        })
+}
+
+// Commands are always encoded in Implicit VR Little Endian, in a single PDV
+pub fn command_pdu(context_id: u8, command: &InMemDicomObject) -> Result<Pdu> {
+    let mut data = vec![];
+    command.write_dataset_with_ts(&mut data, implicit_vr_le())?;
+    Ok(Pdu::PData {
+        data: vec![PDataValue {
+            presentation_context_id: context_id,
+            value_type: PDataValueType::Command,
+            is_last: true,
+            data,
+        }],
+    })
+}
+
+pub fn read_command(data: &[u8]) -> Result<InMemDicomObject> {
+    Ok(InMemDicomObject::read_dataset_with_ts(
+        data,
+        implicit_vr_le(),
+    )?)
+}
+
+fn implicit_vr_le() -> &'static TransferSyntax {
+    TransferSyntaxRegistry
+        .get(IMPLICIT_VR_LITTLE_ENDIAN)
+        .expect("Implicit VR Little Endian is always registered")
+}
+
+fn send_command(
+    association: &mut ClientAssociation,
+    context_id: u8,
+    command: &InMemDicomObject,
+) -> Result<()> {
+    association.send(&command_pdu(context_id, command)?)?;
+    Ok(())
+}
+
+// Read the response command and return its Status
+fn receive_status(association: &mut ClientAssociation) -> Result<u16> {
+    let mut data = vec![];
+    loop {
+        match association.receive()? {
+            Pdu::PData { data: values } => {
+                let mut last = false;
+                for value in values {
+                    if value.value_type == PDataValueType::Command {
+                        data.extend(value.data);
+                        last |= value.is_last;
+                    }
+                }
+                if last {
+                    break;
+                }
+            }
+            Pdu::AbortRQ { source } => return Err(anyhow!("Aborted by the SCP: {:?}", source)),
+            pdu => return Err(anyhow!("Unexpected response: {:?}", pdu)),
+        }
+    }
+    let response = read_command(&data)?;
+    Ok(response.element(tags::STATUS)?.to_int::<u16>()?)
+}
+
+// Success, or one of the warnings of the storage service class (PS3.4 B.2.3)
+fn check_status(status: u16, sop_instance: &str) -> Result<()> {
+    match status {
+        0x0000 => Ok(()),
+        0x0001 | 0x0107 | 0x0116 | 0xB000 | 0xB006 | 0xB007 => {
+            warn!(
+                "Stored {} with the warning status {:04X}",
+                sop_instance, status
+            );
+            Ok(())
+        }
+        status => Err(anyhow!("C-STORE failed with status {:04X}", status)),
+    }
+}
+
+pub fn trim_uid(uid: &str) -> &str {
+    uid.trim_end_matches(['\0', ' '])
+}
+
+// UIDs are padded to an even length with a null byte
+pub fn padded_uid(uid: &str) -> String {
+    match uid.len() % 2 {
+        0 => uid.to_string(),
+        _ => format!("{}\0", uid),
+    }
+}
+
+// Address or CIDR range of the callers of a storage SCP, eg 10.1.2.3 or 10.0.0.0/8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    pub network: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // An IPv4 caller of a dual stack listener is seen as ::ffff:a.b.c.d
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Parse the range from an address, with a prefix length for a range
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid IP address: {}", address))?;
+        let max_prefix = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("Invalid prefix length: {}", value))?,
+            None => max_prefix,
+        };
+        Ok(IpRange { network, prefix })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpRange;
+
+    fn contains(range: &str, address: &str) -> bool {
+        range
+            .parse::<IpRange>()
+            .unwrap()
+            .contains(address.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        assert!(contains("10.0.0.0/8", "10.200.3.4"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.7", "192.168.1.7"));
+        assert!(!contains("192.168.1.7", "192.168.1.8"));
+        assert!(contains("0.0.0.0/0", "8.8.8.8"));
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        assert!(contains("fd00::/8", "fd12:3456::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains("::1", "::1"));
+    }
+
+    #[test]
+    fn ipv4_caller_of_dual_stack_listener() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("fd00::/8", "10.1.2.3"));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        for range in ["10.0.0.0/33", "::/129", "10.0.0/8", "host", "10.0.0.0/x"] {
+            assert!(range.parse::<IpRange>().is_err(), "{}", range);
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use anyhow::Result;
+use dcmrig_rs::{
+    csv_field, phi,
+    source::{DirectorySource, InstanceSource},
+    RunOptions,
+};
+use dicom::{
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject, Tag},
+};
+use rayon::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, canonicalize},
+    path::PathBuf,
+    process::exit,
+};
+use tracing::{error, info, warn};
+
+// Weights of the name and the birth date in the match score
+const NAME_WEIGHT: f64 = 0.6;
+const BIRTH_DATE_WEIGHT: f64 = 0.4;
+// Birth date score when one of the records has none, neither a match nor a mismatch
+const UNKNOWN_BIRTH_DATE: f64 = 0.5;
+// Birth dates one typo apart, or with the day and month swapped
+const BIRTH_DATE_TYPO: f64 = 0.8;
+// Bonus of PatientIDs that only differ in padding, eg 00123 and 123
+const SAME_ID_BONUS: f64 = 0.1;
+// Penalty of records with a different PatientSex, M and F
+const OTHER_SEX_PENALTY: f64 = 0.15;
+// Letters of the name components the candidate pairs are blocked on
+const NAME_BLOCK: usize = 3;
+
+// PatientID, PatientName, PatientBirthDate and PatientSex seen together
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct PatientRecord {
+    patient_id: String,
+    name: String,
+    birth_date: String,
+    sex: String,
+}
+
+// Studies and instances of a record
+#[derive(Default)]
+struct RecordCounts {
+    studies: BTreeSet<String>,
+    instances: u64,
+}
+
+type PatientRecords = HashMap<PatientRecord, RecordCounts>;
+
+// Best link of a record to another PatientID of its cluster
+struct RecordMatch {
+    score: f64,
+    reason: String,
+}
+
+/// Clusters of the PatientIDs of the source that are probably one patient, from a fuzzy match
+/// of the PatientName (Jaro-Winkler, the family and given names may be swapped), the
+/// PatientBirthDate (one typo or swapped day and month), the PatientSex and the PatientIDs that
+/// only differ in padding. Printed with the PatientIDs hashed as in the logs, and written as CSV
+/// with the values, for the curators to give the PatientIDs of a patient one DeID or ANON ID
+pub fn dicom_duplicates(
+    source_path: PathBuf,
+    output: Option<PathBuf>,
+    threshold: f64,
+    run_options: RunOptions,
+) -> Result<()> {
+    if let Err(e) = canonicalize(&source_path) {
+        error!(
+            "Given source Path doesnot exist: {}\n{}",
+            source_path.display(),
+            e
+        );
+        exit(1)
+    }
+    let source = DirectorySource::new(&source_path, run_options.read_iso);
+    info!("Indexing files from: {}", source.describe());
+    let all_files = source.items()?;
+    let records: PatientRecords = all_files
+        .par_iter()
+        .fold(HashMap::new, |mut records: PatientRecords, item| {
+            if let Ok(dcm_obj) = source.open_dataset(item, true) {
+                let (record, study_uid) = patient_record(&dcm_obj);
+                let counts = records.entry(record).or_default();
+                counts.studies.insert(study_uid);
+                counts.instances += 1;
+            }
+            records
+        })
+        .reduce(HashMap::new, |mut records, other| {
+            for (record, other_counts) in other {
+                let counts = records.entry(record).or_default();
+                counts.studies.extend(other_counts.studies);
+                counts.instances += other_counts.instances;
+            }
+            records
+        });
+    source.finalize()?;
+
+    let mut patients: Vec<PatientRecord> = records.keys().cloned().collect();
+    patients.sort();
+    let patient_ids: BTreeSet<&String> = patients.iter().map(|record| &record.patient_id).collect();
+    info!(
+        "{} PatientIDs with {} name, birth date and sex combinations",
+        patient_ids.len(),
+        patients.len()
+    );
+
+    // Only the records sharing a block are compared
+    let mut blocks: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, record) in patients.iter().enumerate() {
+        for key in block_keys(record) {
+            blocks.entry(key).or_default().push(index);
+        }
+    }
+    let mut parents: Vec<usize> = (0..patients.len()).collect();
+    let mut matches: HashMap<usize, RecordMatch> = HashMap::new();
+    let mut compared: BTreeSet<(usize, usize)> = BTreeSet::new();
+    for members in blocks.values() {
+        for (position, &first) in members.iter().enumerate() {
+            for &second in &members[position + 1..] {
+                if patients[first].patient_id == patients[second].patient_id
+                    || !compared.insert((first, second))
+                {
+                    continue;
+                }
+                let (score, reason) = match_score(&patients[first], &patients[second]);
+                if score < threshold {
+                    continue;
+                }
+                union(&mut parents, first, second);
+                for index in [first, second] {
+                    if matches.get(&index).is_none_or(|best| best.score < score) {
+                        matches.insert(
+                            index,
+                            RecordMatch {
+                                score,
+                                reason: reason.clone(),
+                            },
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Records of the clusters with more than one PatientID, by their first record
+    let mut clusters: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for index in matches.keys() {
+        clusters
+            .entry(root(&mut parents, *index))
+            .or_default()
+            .push(*index);
+    }
+    let mut clusters: Vec<Vec<usize>> = clusters.into_values().collect();
+    for members in clusters.iter_mut() {
+        members.sort();
+    }
+    clusters.sort();
+
+    for (number, members) in clusters.iter().enumerate() {
+        let ids: BTreeSet<String> = members
+            .iter()
+            .map(|index| phi(&patients[*index].patient_id))
+            .collect();
+        let best = members
+            .iter()
+            .filter_map(|index| matches.get(index))
+            .max_by(|a, b| a.score.total_cmp(&b.score));
+        println!(
+            "Cluster {}: {} ({:.2}, {})",
+            number + 1,
+            ids.into_iter().collect::<Vec<_>>().join(", "),
+            best.map_or(0.0, |best| best.score),
+            best.map_or("", |best| best.reason.as_str())
+        );
+    }
+    match clusters.is_empty() {
+        true => info!("No PatientIDs look like the same patient"),
+        false => warn!(
+            "{} clusters of PatientIDs that may be one patient",
+            clusters.len()
+        ),
+    }
+    if let Some(output) = &output {
+        let mut content = String::from(
+            "cluster,PatientID,PatientName,PatientBirthDate,PatientSex,studies,instances,score,reason\n",
+        );
+        for (number, members) in clusters.iter().enumerate() {
+            for index in members {
+                let record = &patients[*index];
+                let counts = &records[record];
+                let best = &matches[index];
+                content.push_str(&format!(
+                    "{},{},{},{},{},{},{},{:.2},{}\n",
+                    number + 1,
+                    csv_field(&record.patient_id),
+                    csv_field(&record.name),
+                    csv_field(&record.birth_date),
+                    csv_field(&record.sex),
+                    counts.studies.len(),
+                    counts.instances,
+                    best.score,
+                    csv_field(&best.reason)
+                ));
+            }
+        }
+        fs::write(output, content)?;
+        info!("Candidate clusters written to {}", output.display());
+    }
+    Ok(())
+}
+
+// Record of the instance and its StudyInstanceUID
+fn patient_record(dcm_obj: &FileDicomObject<InMemDicomObject>) -> (PatientRecord, String) {
+    let value = |tag: Tag| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_str().ok())
+            .map(|value| value.trim_end_matches('\0').trim().to_string())
+            .unwrap_or_default()
+    };
+    let record = PatientRecord {
+        patient_id: value(tags::PATIENT_ID),
+        // DOE^JOHN and doe^john^^ are the same name
+        name: value(tags::PATIENT_NAME)
+            .trim_end_matches(['^', ' '])
+            .to_uppercase(),
+        birth_date: value(tags::PATIENT_BIRTH_DATE),
+        sex: value(tags::PATIENT_SEX).to_uppercase(),
+    };
+    (record, value(tags::STUDY_INSTANCE_UID))
+}
+
+// Family and given name, letters only. A name without ^ is split on its first space
+fn name_components(name: &str) -> (String, String) {
+    let letters = |part: &str| -> String { part.chars().filter(|c| c.is_alphabetic()).collect() };
+    let mut parts = match name.contains('^') {
+        true => name.split('^'),
+        false => name.split(' '),
+    };
+    let family = letters(parts.next().unwrap_or_default());
+    let given = letters(&parts.collect::<Vec<_>>().join(" "));
+    (family, given)
+}
+
+// PatientID without the padding and separators
+fn normalized_id(patient_id: &str) -> String {
+    let id: String = patient_id
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_uppercase();
+    id.trim_start_matches('0').to_string()
+}
+
+// The birth date, the start of each name component and the normalized PatientID
+fn block_keys(record: &PatientRecord) -> Vec<String> {
+    let (family, given) = name_components(&record.name);
+    let mut keys = vec![];
+    if !record.birth_date.is_empty() {
+        keys.push(format!("D{}", record.birth_date));
+    }
+    // Family and given names share the blocks, they may be swapped
+    for component in [family, given] {
+        if !component.is_empty() {
+            keys.push(format!(
+                "N{}",
+                component.chars().take(NAME_BLOCK).collect::<String>()
+            ));
+        }
+    }
+    let id = normalized_id(&record.patient_id);
+    if !id.is_empty() {
+        keys.push(format!("I{}", id));
+    }
+    keys
+}
+
+// Score from 0 to 1 that the records are one patient, and why
+fn match_score(first: &PatientRecord, second: &PatientRecord) -> (f64, String) {
+    let (first_family, first_given) = name_components(&first.name);
+    let (second_family, second_given) = name_components(&second.name);
+    let in_order = (jaro_winkler(&first_family, &second_family)
+        + jaro_winkler(&first_given, &second_given))
+        / 2.0;
+    let swapped = (jaro_winkler(&first_family, &second_given)
+        + jaro_winkler(&first_given, &second_family))
+        / 2.0;
+    let name = in_order.max(swapped);
+    let mut reasons = vec![match swapped > in_order {
+        true => format!("names {:.2} swapped", name),
+        false => format!("names {:.2}", name),
+    }];
+    let birth_date = match birth_date_score(&first.birth_date, &second.birth_date) {
+        Some(score) => {
+            reasons.push(
+                if score == 1.0 {
+                    "same birth date"
+                } else if score == 0.0 {
+                    "other birth date"
+                } else {
+                    "birth date typo"
+                }
+                .to_string(),
+            );
+            score
+        }
+        None => {
+            reasons.push("no birth date".to_string());
+            UNKNOWN_BIRTH_DATE
+        }
+    };
+    let mut score = NAME_WEIGHT * name + BIRTH_DATE_WEIGHT * birth_date;
+    let id = normalized_id(&first.patient_id);
+    if !id.is_empty() && id == normalized_id(&second.patient_id) {
+        score += SAME_ID_BONUS;
+        reasons.push("PatientIDs differ in padding".to_string());
+    }
+    let sexes = [first.sex.as_str(), second.sex.as_str()];
+    if sexes.iter().all(|sex| matches!(*sex, "M" | "F")) && sexes[0] != sexes[1] {
+        score -= OTHER_SEX_PENALTY;
+        reasons.push("other sex".to_string());
+    }
+    (score.clamp(0.0, 1.0), reasons.join(", "))
+}
+
+// 1 for the same date, BIRTH_DATE_TYPO one typo apart or with the day and month swapped, None
+// when a date is missing
+fn birth_date_score(first: &str, second: &str) -> Option<f64> {
+    if first.is_empty() || second.is_empty() {
+        return None;
+    }
+    if first == second {
+        return Some(1.0);
+    }
+    let (a, b) = (first.as_bytes(), second.as_bytes());
+    if a.len() != 8 || b.len() != 8 {
+        return Some(0.0);
+    }
+    let differences: Vec<usize> = (0..8).filter(|i| a[*i] != b[*i]).collect();
+    let typo = match differences.as_slice() {
+        [_] => true,
+        // Adjacent digits transposed
+        [i, j] => *j == i + 1 && a[*i] == b[*j] && a[*j] == b[*i],
+        _ => false,
+    };
+    let day_month_swapped = a[..4] == b[..4] && a[4..6] == b[6..8] && a[6..8] == b[4..6];
+    match typo || day_month_swapped {
+        true => Some(BIRTH_DATE_TYPO),
+        false => Some(0.0),
+    }
+}
+
+// Jaro-Winkler similarity of two names, 0 when one is empty
+fn jaro_winkler(first: &str, second: &str) -> f64 {
+    let a: Vec<char> = first.chars().collect();
+    let b: Vec<char> = second.chars().collect();
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    if a == b {
+        return 1.0;
+    }
+    let window = (a.len().max(b.len()) / 2).saturating_sub(1);
+    let mut a_matched = vec![false; a.len()];
+    let mut b_matched = vec![false; b.len()];
+    let mut matching = 0;
+    for (i, c) in a.iter().enumerate() {
+        let start = i.saturating_sub(window);
+        let end = (i + window + 1).min(b.len());
+        for j in start..end {
+            if !b_matched[j] && b[j] == *c {
+                a_matched[i] = true;
+                b_matched[j] = true;
+                matching += 1;
+                break;
+            }
+        }
+    }
+    if matching == 0 {
+        return 0.0;
+    }
+    let a_order = a
+        .iter()
+        .zip(&a_matched)
+        .filter(|(_, m)| **m)
+        .map(|(c, _)| c);
+    let b_order = b
+        .iter()
+        .zip(&b_matched)
+        .filter(|(_, m)| **m)
+        .map(|(c, _)| c);
+    let transpositions = a_order.zip(b_order).filter(|(x, y)| x != y).count() as f64 / 2.0;
+    let m = matching as f64;
+    let jaro = (m / a.len() as f64 + m / b.len() as f64 + (m - transpositions) / m) / 3.0;
+    // Common prefix of up to 4 letters
+    let prefix = a.iter().zip(&b).take(4).take_while(|(x, y)| x == y).count();
+    jaro + prefix as f64 * 0.1 * (1.0 - jaro)
+}
+
+fn root(parents: &mut [usize], index: usize) -> usize {
+    let mut root = index;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[index] = root;
+    root
+}
+
+fn union(parents: &mut [usize], first: usize, second: usize) {
+    let (first, second) = (root(parents, first), root(parents, second));
+    parents[first.max(second)] = first.min(second);
+}
@@ -0,0 +1,433 @@
+use crate::TagPath;
+use anyhow::Result;
+use dicom::object::InMemDicomObject;
+use regex::Regex;
+use std::str::FromStr;
+
+// Source files not matched by --filter, copied as they are with --filtered-out copy
+pub const FILTERED_OUT_DIR: &str = "FILTERED_OUT";
+
+// What to do with the files not matched by --filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterAction {
+    // Only list in results.csv
+    #[default]
+    Exclude,
+    // Copy to FILTERED_OUT in the destination as they are
+    Copy,
+}
+
+impl FromStr for FilterAction {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "exclude" => Ok(FilterAction::Exclude),
+            "copy" => Ok(FilterAction::Copy),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of exclude or copy: {}",
+                value
+            )),
+        }
+    }
+}
+
+// Selection of the source files by their values, eg Modality=MR && StudyDate>=20230101
+// Comparisons of a tag path and a value joined with &&, || and !, in brackets when needed.
+// = and != take the * and ? wildcards, ~ and !~ a regular expression searched in the value,
+// < <= > >= compare numbers, or the text when one side is not a number, so dates compare too.
+// A path to the items of a sequence or a multi-valued element matches when any of its values
+// does, a missing tag never matches except with != and !~
+#[derive(Debug, Clone)]
+pub struct FilterExpr {
+    expression: String,
+    node: FilterNode,
+}
+
+#[derive(Debug, Clone)]
+enum FilterNode {
+    And(Box<FilterNode>, Box<FilterNode>),
+    Or(Box<FilterNode>, Box<FilterNode>),
+    Not(Box<FilterNode>),
+    Compare(TagPath, FilterTest),
+}
+
+#[derive(Debug, Clone)]
+enum FilterTest {
+    // = and ~, negated for != and !~
+    Pattern {
+        pattern: Regex,
+        negated: bool,
+    },
+    // < <= > >=, the orderings of a value to the bound that match
+    Order {
+        bound: String,
+        orderings: Vec<std::cmp::Ordering>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FilterToken {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Operator(&'static str),
+    Word(String),
+}
+
+impl FromStr for FilterExpr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = |e: anyhow::Error| anyhow::anyhow!("Invalid filter {}: {}", value, e);
+        let mut parser = FilterParser {
+            tokens: filter_tokens(value).map_err(invalid)?,
+            position: 0,
+        };
+        let node = parser.or().map_err(invalid)?;
+        if let Some(token) = parser.next() {
+            return Err(invalid(anyhow::anyhow!("{:?} is not expected", token)));
+        }
+        Ok(FilterExpr {
+            expression: value.trim().to_string(),
+            node,
+        })
+    }
+}
+
+impl std::fmt::Display for FilterExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl FilterExpr {
+    pub fn matches(&self, dcm_obj: &InMemDicomObject) -> bool {
+        self.node.matches(dcm_obj)
+    }
+}
+
+impl FilterNode {
+    fn matches(&self, dcm_obj: &InMemDicomObject) -> bool {
+        match self {
+            FilterNode::And(left, right) => left.matches(dcm_obj) && right.matches(dcm_obj),
+            FilterNode::Or(left, right) => left.matches(dcm_obj) || right.matches(dcm_obj),
+            FilterNode::Not(node) => !node.matches(dcm_obj),
+            FilterNode::Compare(tag_path, test) => {
+                let values = tag_path.values(dcm_obj);
+                let mut values = values.iter().flat_map(|value| value.split('\\'));
+                match test {
+                    FilterTest::Pattern { pattern, negated } => {
+                        values.any(|value| pattern.is_match(value.trim())) != *negated
+                    }
+                    FilterTest::Order { bound, orderings } => values
+                        .any(|value| orderings.contains(&filter_ordering(value.trim(), bound))),
+                }
+            }
+        }
+    }
+}
+
+// Numbers are compared as numbers, anything else as text
+fn filter_ordering(value: &str, bound: &str) -> std::cmp::Ordering {
+    match (value.parse::<f64>(), bound.parse::<f64>()) {
+        (Ok(value), Ok(bound)) => value
+            .partial_cmp(&bound)
+            .unwrap_or(std::cmp::Ordering::Equal),
+        _ => value.cmp(bound),
+    }
+}
+
+// Words are tag paths and values, quoted with " or ' when they hold spaces or operators
+fn filter_tokens(expression: &str) -> Result<Vec<FilterToken>> {
+    let mut tokens = vec![];
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        let next = chars.peek().copied();
+        let token = match (c, next) {
+            (c, _) if c.is_whitespace() => continue,
+            ('(', _) => FilterToken::Open,
+            (')', _) => FilterToken::Close,
+            ('&', Some('&')) | ('|', Some('|')) => {
+                chars.next();
+                match c {
+                    '&' => FilterToken::And,
+                    _ => FilterToken::Or,
+                }
+            }
+            ('&' | '|', _) => return Err(anyhow::anyhow!("{} should be {}{}", c, c, c)),
+            ('!' | '=' | '<' | '>', Some('=')) => {
+                chars.next();
+                FilterToken::Operator(match c {
+                    '!' => "!=",
+                    '=' => "=",
+                    '<' => "<=",
+                    _ => ">=",
+                })
+            }
+            ('!', Some('~')) => {
+                chars.next();
+                FilterToken::Operator("!~")
+            }
+            ('!', _) => FilterToken::Not,
+            ('=', _) => FilterToken::Operator("="),
+            ('~', _) => FilterToken::Operator("~"),
+            ('<', _) => FilterToken::Operator("<"),
+            ('>', _) => FilterToken::Operator(">"),
+            ('"' | '\'', _) => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(quote) if quote == c => break,
+                        Some(each) => word.push(each),
+                        None => return Err(anyhow::anyhow!("{} is not closed", c)),
+                    }
+                }
+                FilterToken::Word(word)
+            }
+            _ => {
+                let mut word = c.to_string();
+                while let Some(each) =
+                    chars.next_if(|each| !each.is_whitespace() && !"()&|!=<>~\"'".contains(*each))
+                {
+                    word.push(each);
+                }
+                FilterToken::Word(word)
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// || binds looser than &&, ! applies to the comparison or bracket that follows
+struct FilterParser {
+    tokens: Vec<FilterToken>,
+    position: usize,
+}
+
+impl FilterParser {
+    fn next(&mut self) -> Option<FilterToken> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_is(&mut self, token: &FilterToken) -> bool {
+        let found = self.tokens.get(self.position) == Some(token);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<FilterNode> {
+        let mut node = self.and()?;
+        while self.next_is(&FilterToken::Or) {
+            node = FilterNode::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<FilterNode> {
+        let mut node = self.unary()?;
+        while self.next_is(&FilterToken::And) {
+            node = FilterNode::And(Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<FilterNode> {
+        match self.next() {
+            Some(FilterToken::Not) => Ok(FilterNode::Not(Box::new(self.unary()?))),
+            Some(FilterToken::Open) => {
+                let node = self.or()?;
+                match self.next() {
+                    Some(FilterToken::Close) => Ok(node),
+                    _ => Err(anyhow::anyhow!("( is not closed")),
+                }
+            }
+            Some(FilterToken::Word(path)) => {
+                let tag_path = TagPath::from_str(&path)?;
+                let operator = match self.next() {
+                    Some(FilterToken::Operator(operator)) => operator,
+                    _ => return Err(anyhow::anyhow!("{} needs a comparison, eg =", path)),
+                };
+                let value = match self.next() {
+                    Some(FilterToken::Word(value)) => value,
+                    _ => return Err(anyhow::anyhow!("{} {} needs a value", path, operator)),
+                };
+                let test = match operator {
+                    "=" | "!=" => FilterTest::Pattern {
+                        pattern: wildcard_pattern(&value),
+                        negated: operator == "!=",
+                    },
+                    "~" | "!~" => FilterTest::Pattern {
+                        pattern: Regex::new(&value)?,
+                        negated: operator == "!~",
+                    },
+                    _ => FilterTest::Order {
+                        bound: value,
+                        orderings: match operator {
+                            "<" => vec![std::cmp::Ordering::Less],
+                            "<=" => vec![std::cmp::Ordering::Less, std::cmp::Ordering::Equal],
+                            ">" => vec![std::cmp::Ordering::Greater],
+                            _ => vec![std::cmp::Ordering::Greater, std::cmp::Ordering::Equal],
+                        },
+                    },
+                };
+                Ok(FilterNode::Compare(tag_path, test))
+            }
+            Some(token) => Err(anyhow::anyhow!("{:?} is not expected", token)),
+            None => Err(anyhow::anyhow!("A comparison is missing at the end")),
+        }
+    }
+}
+
+// Whole value match, * for any characters and ? for one
+fn wildcard_pattern(value: &str) -> Regex {
+    let pattern = regex::escape(value)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    Regex::new(&format!("^{}$", pattern)).expect("Invalid wildcard pattern")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FilterExpr;
+    use dicom::{
+        core::{DataElement, PrimitiveValue, VR},
+        dictionary_std::tags,
+        object::{InMemDicomObject, Tag},
+    };
+
+    fn dataset() -> InMemDicomObject {
+        let mut dcm_obj = InMemDicomObject::new_empty();
+        let mut put = |tag: Tag, vr: VR, value: &str| {
+            dcm_obj.put(DataElement::new(tag, vr, PrimitiveValue::from(value)))
+        };
+        put(tags::MODALITY, VR::CS, "CT");
+        put(tags::STUDY_DATE, VR::DA, "20230615");
+        put(tags::SERIES_NUMBER, VR::IS, "10");
+        put(tags::SERIES_DESCRIPTION, VR::LO, "Dose Report (a.b)");
+        put(tags::IMAGE_TYPE, VR::CS, "ORIGINAL\\PRIMARY\\LOCALIZER");
+        dcm_obj
+    }
+
+    fn matches(expression: &str) -> bool {
+        expression
+            .parse::<FilterExpr>()
+            .unwrap_or_else(|e| panic!("{}: {}", expression, e))
+            .matches(&dataset())
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert!(matches("Modality=CT || Modality=MR && StudyDate>20990101"));
+        assert!(!matches(
+            "(Modality=CT || Modality=MR) && StudyDate>20990101"
+        ));
+        assert!(matches("Modality=MR && StudyDate>20990101 || Modality=CT"));
+        assert!(!matches(
+            "Modality=MR && (StudyDate>20990101 || Modality=CT)"
+        ));
+    }
+
+    #[test]
+    fn not_applies_to_what_follows() {
+        assert!(matches("!Modality=MR"));
+        assert!(!matches("!Modality=CT && Modality=CT"));
+        assert!(!matches("!(Modality=CT || Modality=MR)"));
+        assert!(matches("!!Modality=CT"));
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert!(matches("SeriesDescription=\"Dose Report (a.b)\""));
+        assert!(matches("SeriesDescription='Dose Report (a.b)'"));
+        assert!(matches("SeriesDescription = 'Dose*' && Modality = CT"));
+        assert!(!matches("SeriesDescription=\"Modality=CT || x\""));
+    }
+
+    #[test]
+    fn wildcards_match_the_whole_value() {
+        assert!(matches("Modality=C?"));
+        assert!(matches("SeriesDescription=*Report*"));
+        assert!(!matches("SeriesDescription=Dose"));
+        assert!(!matches("Modality=?"));
+        // The other regular expression characters are literal
+        assert!(!matches("SeriesDescription='Dose Report (axb)'"));
+    }
+
+    #[test]
+    fn regular_expressions_search_the_value() {
+        assert!(matches("SeriesDescription~^Dose"));
+        assert!(matches(
+            "SeriesDescription~'report' || SeriesDescription~Rep"
+        ));
+        assert!(matches("SeriesDescription!~^Report"));
+        assert!(!matches("SeriesDescription~'^Report'"));
+    }
+
+    #[test]
+    fn orderings_of_numbers_and_text() {
+        // 10 > 9 as numbers, "10" < "9" as text
+        assert!(matches("SeriesNumber>9"));
+        assert!(matches("SeriesNumber<=10 && SeriesNumber>=10"));
+        assert!(!matches("SeriesNumber<10"));
+        assert!(matches("StudyDate>=20230101 && StudyDate<20240101"));
+        assert!(matches("Modality<MR"));
+    }
+
+    #[test]
+    fn any_value_of_a_multi_valued_element() {
+        assert!(matches("ImageType=LOCALIZER"));
+        // != and !~ hold when none of the values match
+        assert!(!matches("ImageType!=LOCALIZER"));
+        assert!(matches("ImageType!=DERIVED"));
+        assert!(!matches("ImageType=DERIVED"));
+    }
+
+    #[test]
+    fn missing_tags_only_match_negations() {
+        assert!(!matches("AccessionNumber=*"));
+        assert!(!matches("AccessionNumber<1"));
+        assert!(matches("AccessionNumber!=A1"));
+        assert!(matches("AccessionNumber!~."));
+    }
+
+    #[test]
+    fn display_is_the_expression() {
+        let filter: FilterExpr = "  Modality=CT && StudyDate>20230101 ".parse().unwrap();
+        assert_eq!(filter.to_string(), "Modality=CT && StudyDate>20230101");
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        for expression in [
+            "",
+            "Modality",
+            "Modality=",
+            "=CT",
+            "Modality=CT &&",
+            "&& Modality=CT",
+            "(Modality=CT",
+            "Modality=CT)",
+            "Modality=CT Modality=MR",
+            "Modality=CT & StudyDate>1",
+            "Modality=CT | StudyDate>1",
+            "Modality=\"CT",
+            "NotATag=1",
+            "Modality.Item=1",
+            "SeriesDescription~'('",
+        ] {
+            assert!(
+                expression.parse::<FilterExpr>().is_err(),
+                "{} should be invalid",
+                expression
+            );
+        }
+    }
+}
@@ -0,0 +1,754 @@
+use crate::{fnv1a_hash, get_sanitized_tag_values, unchanged_tags};
+use anyhow::Result;
+use dicom::{
+    core::{
+        header::Header,
+        value::{DataSetSequence, PixelFragmentSequence},
+        DataElement, PrimitiveValue, VR,
+    },
+    dicom_value,
+    dictionary_std::tags,
+    encoding::{Endianness, TransferSyntaxIndex},
+    object::{mem::InMemElement, FileDicomObject, InMemDicomObject, Tag},
+    transfer_syntax::TransferSyntaxRegistry,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use tracing::debug;
+
+// Classic single-frame SOP class of each enhanced multi-frame SOP class that can be split
+const ENHANCED_SOP_CLASSES: [(&str, &str); 8] = [
+    // Enhanced and Legacy Converted Enhanced CT
+    ("1.2.840.10008.5.1.4.1.1.2.1", "1.2.840.10008.5.1.4.1.1.2"),
+    ("1.2.840.10008.5.1.4.1.1.2.2", "1.2.840.10008.5.1.4.1.1.2"),
+    // Enhanced and Legacy Converted Enhanced MR
+    ("1.2.840.10008.5.1.4.1.1.4.1", "1.2.840.10008.5.1.4.1.1.4"),
+    ("1.2.840.10008.5.1.4.1.1.4.4", "1.2.840.10008.5.1.4.1.1.4"),
+    // Enhanced and Legacy Converted Enhanced PET
+    ("1.2.840.10008.5.1.4.1.1.130", "1.2.840.10008.5.1.4.1.1.128"),
+    (
+        "1.2.840.10008.5.1.4.1.1.128.1",
+        "1.2.840.10008.5.1.4.1.1.128",
+    ),
+    // Enhanced XA and XRF
+    (
+        "1.2.840.10008.5.1.4.1.1.12.1.1",
+        "1.2.840.10008.5.1.4.1.1.12.1",
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.12.2.1",
+        "1.2.840.10008.5.1.4.1.1.12.2",
+    ),
+];
+
+// Functional group sequences that are attributes of the classic images as they are, the first
+// item of the other functional group sequences is merged into the top level
+const KEPT_FUNCTIONAL_GROUPS: [Tag; 3] = [
+    tags::REFERENCED_IMAGE_SEQUENCE,
+    tags::DERIVATION_IMAGE_SEQUENCE,
+    tags::REAL_WORLD_VALUE_MAPPING_SEQUENCE,
+];
+
+// Top level tags of the enhanced instances without a meaning in the classic ones
+const ENHANCED_ONLY_TAGS: [Tag; 7] = [
+    tags::NUMBER_OF_FRAMES,
+    tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE,
+    tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+    tags::DIMENSION_ORGANIZATION_SEQUENCE,
+    tags::DIMENSION_INDEX_SEQUENCE,
+    tags::DIMENSION_ORGANIZATION_TYPE,
+    tags::PIXEL_DATA,
+];
+
+// Instance to write with its tag values and the tags still holding the value of the source
+pub struct OutputInstance {
+    pub dcm_obj: FileDicomObject<InMemDicomObject>,
+    pub tags_values: HashMap<String, String>,
+    pub unchanged: HashSet<Tag>,
+}
+
+// The transformed instance, or the classic instances of its frames when enhanced instances are split
+pub fn output_instances(
+    source_obj: &FileDicomObject<InMemDicomObject>,
+    instance: OutputInstance,
+    split: bool,
+) -> Result<Vec<OutputInstance>> {
+    let frames = match split {
+        true => split_frames(&instance.dcm_obj)?,
+        false => None,
+    };
+    match frames {
+        Some(frames) => frames
+            .into_iter()
+            .map(|frame| {
+                Ok(OutputInstance {
+                    tags_values: get_sanitized_tag_values(&frame)?,
+                    unchanged: unchanged_tags(source_obj, &frame),
+                    dcm_obj: frame,
+                })
+            })
+            .collect(),
+        None => Ok(vec![instance]),
+    }
+}
+
+// Split an enhanced multi-frame instance into classic single-frame instances, for the tools that
+// can't read the enhanced IODs. The shared then the per-frame functional group values become top
+// level tags, eg the PlanePositionSequence gives the ImagePositionPatient and the FrameType the
+// ImageType. Each frame gets a SOPInstanceUID derived from the enhanced one, its frame number as
+// InstanceNumber and references the enhanced instance in the SourceImageSequence
+// Returns None for the other instances, they are written as they are
+pub fn split_frames(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+) -> Result<Option<Vec<FileDicomObject<InMemDicomObject>>>> {
+    let value_of = |tag: Tag| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_str().ok())
+            .map(|value| value.trim_end_matches('\0').trim().to_string())
+            .unwrap_or_default()
+    };
+    let sop_class_uid = value_of(tags::SOP_CLASS_UID);
+    let classic_class = match ENHANCED_SOP_CLASSES
+        .iter()
+        .find(|(enhanced, _)| *enhanced == sop_class_uid)
+    {
+        Some((_, classic)) => classic.to_string(),
+        None => return Ok(None),
+    };
+    let frames = dcm_obj
+        .element(tags::NUMBER_OF_FRAMES)
+        .ok()
+        .and_then(|element| element.to_int::<u32>().ok())
+        .unwrap_or(1)
+        .max(1) as usize;
+    let pixels = frame_pixels(dcm_obj, frames)?;
+    let shared = dcm_obj
+        .get(tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE)
+        .and_then(|element| element.items())
+        .and_then(|items| items.first());
+    let per_frame = dcm_obj
+        .get(tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE)
+        .and_then(|element| element.items())
+        .unwrap_or(&[]);
+    if !per_frame.is_empty() && per_frame.len() != frames {
+        return Err(anyhow::anyhow!(
+            "{} per-frame functional groups for {} frames",
+            per_frame.len(),
+            frames
+        ));
+    }
+    let sop_instance_uid = value_of(tags::SOP_INSTANCE_UID);
+
+    // The frames share everything but the functional groups and the pixel data
+    let mut template = dcm_obj.clone();
+    for tag in ENHANCED_ONLY_TAGS {
+        template.remove_element(tag);
+    }
+    let mut instances = Vec::with_capacity(frames);
+    for (index, pixel_data) in pixels.into_iter().enumerate() {
+        let frame_number = index + 1;
+        let mut instance = template.clone();
+        for groups in shared.into_iter().chain(per_frame.get(index)) {
+            merge_functional_groups(&mut instance, groups);
+        }
+        instance.put(pixel_data);
+
+        let frame_uid = format!(
+            "2.25.{}",
+            fnv1a_hash(format!("{}/frame {}", sop_instance_uid, frame_number).as_bytes())
+        );
+        instance.put(DataElement::new(
+            tags::SOP_CLASS_UID,
+            VR::UI,
+            dicom_value!(Str, classic_class.clone()),
+        ));
+        instance.put(DataElement::new(
+            tags::SOP_INSTANCE_UID,
+            VR::UI,
+            dicom_value!(Str, frame_uid.clone()),
+        ));
+        instance.put(DataElement::new(
+            tags::INSTANCE_NUMBER,
+            VR::IS,
+            dicom_value!(Str, frame_number.to_string()),
+        ));
+        let mut source_image = InMemDicomObject::new_empty();
+        source_image.put(DataElement::new(
+            tags::REFERENCED_SOP_CLASS_UID,
+            VR::UI,
+            dicom_value!(Str, sop_class_uid.clone()),
+        ));
+        source_image.put(DataElement::new(
+            tags::REFERENCED_SOP_INSTANCE_UID,
+            VR::UI,
+            dicom_value!(Str, sop_instance_uid.clone()),
+        ));
+        source_image.put(DataElement::new(
+            tags::REFERENCED_FRAME_NUMBER,
+            VR::IS,
+            dicom_value!(Str, frame_number.to_string()),
+        ));
+        let mut source_images: Vec<InMemDicomObject> = instance
+            .get(tags::SOURCE_IMAGE_SEQUENCE)
+            .and_then(|element| element.items())
+            .map(|items| items.to_vec())
+            .unwrap_or_default();
+        source_images.push(source_image);
+        instance.put(DataElement::new(
+            tags::SOURCE_IMAGE_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(source_images),
+        ));
+
+        let meta = instance.meta_mut();
+        meta.media_storage_sop_class_uid = classic_class.clone();
+        meta.media_storage_sop_instance_uid = frame_uid;
+        meta.update_information_group_length();
+        instances.push(instance);
+    }
+    debug!("{} split into {} frames", sop_instance_uid, frames);
+    Ok(Some(instances))
+}
+
+// Copy one item of the functional groups to the top level of a classic instance
+fn merge_functional_groups(instance: &mut InMemDicomObject, groups: &InMemDicomObject) {
+    for group in groups.iter() {
+        let item = group.items().and_then(|items| items.first());
+        match item {
+            Some(item) if !KEPT_FUNCTIONAL_GROUPS.contains(&group.tag()) => {
+                for element in item.iter() {
+                    match element.tag() {
+                        tags::FRAME_TYPE => instance.put(DataElement::new(
+                            tags::IMAGE_TYPE,
+                            VR::CS,
+                            element.value().clone(),
+                        )),
+                        _ => instance.put(element.clone()),
+                    };
+                }
+            }
+            _ => {
+                instance.put(group.clone());
+            }
+        }
+    }
+}
+
+// Pixel data element of each frame. Native frames are cut by their length, encapsulated frames
+// are their fragments, one per frame or grouped by the basic offset table
+fn frame_pixels(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    frames: usize,
+) -> Result<Vec<InMemElement>> {
+    let pixel_element = dcm_obj
+        .element_opt(tags::PIXEL_DATA)?
+        .ok_or_else(|| anyhow::anyhow!("No pixel data to split, streamed files can't be split"))?;
+    let pixel_vr = pixel_element.vr();
+    let value = pixel_element.value();
+    if let Some(fragments) = value.fragments() {
+        let offset_table = value.offset_table().unwrap_or(&[]);
+        let groups: Vec<Vec<Vec<u8>>> = if fragments.len() == frames {
+            fragments
+                .iter()
+                .map(|fragment| vec![fragment.clone()])
+                .collect()
+        } else if offset_table.len() == frames {
+            // Offsets are from the first fragment item, each item has an 8 bytes header
+            let mut groups = vec![vec![]; frames];
+            let mut position = 0;
+            for fragment in fragments {
+                let frame = offset_table
+                    .iter()
+                    .rposition(|offset| u64::from(*offset) <= position)
+                    .unwrap_or(0);
+                groups[frame].push(fragment.clone());
+                position += 8 + fragment.len() as u64;
+            }
+            groups
+        } else {
+            return Err(anyhow::anyhow!(
+                "{} fragments for {} frames and no offset table to group them",
+                fragments.len(),
+                frames
+            ));
+        };
+        return Ok(groups
+            .into_iter()
+            .map(|fragments| {
+                InMemElement::new(
+                    tags::PIXEL_DATA,
+                    pixel_vr,
+                    PixelFragmentSequence::new(Vec::new(), fragments),
+                )
+            })
+            .collect());
+    }
+
+    let little_endian = TransferSyntaxRegistry
+        .get(dcm_obj.meta().transfer_syntax())
+        .is_some_and(|ts| ts.endianness() == Endianness::Little);
+    let pixel_bytes = match (little_endian, value.primitive()) {
+        (true, Some(value)) => value.to_bytes(),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Only little endian pixel data can be split"
+            ))
+        }
+    };
+    let int_of = |tag: Tag, default: u32| -> usize {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_int::<u32>().ok())
+            .unwrap_or(default) as usize
+    };
+    let bits_allocated = int_of(tags::BITS_ALLOCATED, 16);
+    if bits_allocated % 8 != 0 {
+        return Err(anyhow::anyhow!(
+            "Frames of {} bits allocated can't be split",
+            bits_allocated
+        ));
+    }
+    let frame_length = int_of(tags::ROWS, 0)
+        * int_of(tags::COLUMNS, 0)
+        * int_of(tags::SAMPLES_PER_PIXEL, 1)
+        * bits_allocated
+        / 8;
+    if frame_length == 0 || pixel_bytes.len() < frame_length * frames {
+        return Err(anyhow::anyhow!(
+            "Pixel data of {} bytes is shorter than {} frames",
+            pixel_bytes.len(),
+            frames
+        ));
+    }
+    Ok(pixel_bytes
+        .chunks(frame_length)
+        .take(frames)
+        .map(|frame| {
+            let mut frame = frame.to_vec();
+            if frame.len() % 2 == 1 {
+                frame.push(0);
+            }
+            DataElement::new(tags::PIXEL_DATA, pixel_vr, PrimitiveValue::U8(frame.into()))
+        })
+        .collect())
+}
+
+// Legacy Converted Enhanced SOP class the classic single-frame instances of a SOP class are merged into
+const LEGACY_CONVERTED_SOP_CLASSES: [(&str, &str); 3] = [
+    // CT, MR and PET
+    ("1.2.840.10008.5.1.4.1.1.2", "1.2.840.10008.5.1.4.1.1.2.2"),
+    ("1.2.840.10008.5.1.4.1.1.4", "1.2.840.10008.5.1.4.1.1.4.4"),
+    (
+        "1.2.840.10008.5.1.4.1.1.128",
+        "1.2.840.10008.5.1.4.1.1.128.1",
+    ),
+];
+
+// Functional groups of the merged instances with the top level tags of the classic images they hold
+const CONVERTED_FUNCTIONAL_GROUPS: [(Tag, &[Tag]); 5] = [
+    (
+        tags::PIXEL_MEASURES_SEQUENCE,
+        &[
+            tags::PIXEL_SPACING,
+            tags::SLICE_THICKNESS,
+            tags::SPACING_BETWEEN_SLICES,
+        ],
+    ),
+    (
+        tags::PLANE_POSITION_SEQUENCE,
+        &[tags::IMAGE_POSITION_PATIENT],
+    ),
+    (
+        tags::PLANE_ORIENTATION_SEQUENCE,
+        &[tags::IMAGE_ORIENTATION_PATIENT],
+    ),
+    (
+        tags::FRAME_VOILUT_SEQUENCE,
+        &[
+            tags::WINDOW_CENTER,
+            tags::WINDOW_WIDTH,
+            tags::WINDOW_CENTER_WIDTH_EXPLANATION,
+        ],
+    ),
+    (
+        tags::PIXEL_VALUE_TRANSFORMATION_SEQUENCE,
+        &[
+            tags::RESCALE_INTERCEPT,
+            tags::RESCALE_SLOPE,
+            tags::RESCALE_TYPE,
+        ],
+    ),
+];
+
+// SOP class the classic instances of a SOP class are merged into, None when they aren't merged
+pub fn legacy_converted_class(sop_class_uid: &str) -> Option<&'static str> {
+    LEGACY_CONVERTED_SOP_CLASSES
+        .iter()
+        .find(|(classic, _)| *classic == sop_class_uid)
+        .map(|(_, converted)| *converted)
+}
+
+// Merge the classic single-frame instances of a series into one Legacy Converted Enhanced
+// instance, the reverse of split_frames. The frames are ordered along the slice normal, or by
+// InstanceNumber when the instances don't share an orientation. The geometry, window and rescale
+// of the frames become shared or per-frame functional groups, the other tags that differ between
+// the frames go to the UnassignedPerFrameConvertedAttributesSequence and each frame references
+// its classic instance in the ConversionSourceAttributesSequence
+// The instances must share the matrix, pixel format and transfer syntax. Native frames are
+// concatenated, encapsulated frames keep their fragments with a new basic offset table
+pub fn merge_frames(
+    mut instances: Vec<FileDicomObject<InMemDicomObject>>,
+) -> Result<FileDicomObject<InMemDicomObject>> {
+    let value_of = |dcm_obj: &InMemDicomObject, tag: Tag| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_str().ok())
+            .map(|value| value.trim_end_matches('\0').trim().to_string())
+            .unwrap_or_default()
+    };
+    let numbers_of = |dcm_obj: &InMemDicomObject, tag: Tag| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_multi_float64().ok())
+            .unwrap_or_default()
+    };
+    let first = instances
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("No instances to merge"))?;
+    let sop_class_uid = value_of(first, tags::SOP_CLASS_UID);
+    let converted_class = legacy_converted_class(&sop_class_uid)
+        .ok_or_else(|| anyhow::anyhow!("Instances of {} can't be merged", sop_class_uid))?;
+    let ts_uid = first
+        .meta()
+        .transfer_syntax()
+        .trim_end_matches('\0')
+        .to_string();
+    if instances.iter().any(|instance| {
+        value_of(instance, tags::SOP_CLASS_UID) != sop_class_uid
+            || instance.meta().transfer_syntax().trim_end_matches('\0') != ts_uid
+    }) {
+        return Err(anyhow::anyhow!(
+            "The instances don't share the SOP class and transfer syntax"
+        ));
+    }
+
+    let orientation = numbers_of(first, tags::IMAGE_ORIENTATION_PATIENT);
+    let stacked = orientation.len() == 6
+        && instances.iter().all(|instance| {
+            numbers_of(instance, tags::IMAGE_ORIENTATION_PATIENT) == orientation
+                && numbers_of(instance, tags::IMAGE_POSITION_PATIENT).len() == 3
+        });
+    match stacked {
+        true => {
+            let normal = [
+                orientation[1] * orientation[5] - orientation[2] * orientation[4],
+                orientation[2] * orientation[3] - orientation[0] * orientation[5],
+                orientation[0] * orientation[4] - orientation[1] * orientation[3],
+            ];
+            let distance = |instance: &InMemDicomObject| -> f64 {
+                numbers_of(instance, tags::IMAGE_POSITION_PATIENT)
+                    .iter()
+                    .zip(normal)
+                    .map(|(position, normal)| position * normal)
+                    .sum()
+            };
+            instances.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+        }
+        false => instances.sort_by_key(|instance| {
+            instance
+                .element(tags::INSTANCE_NUMBER)
+                .ok()
+                .and_then(|element| element.to_int::<i64>().ok())
+                .unwrap_or(0)
+        }),
+    }
+    let pixel_data = merged_pixels(&instances)?;
+    let source_uids: Vec<String> = instances
+        .iter()
+        .map(|instance| value_of(instance, tags::SOP_INSTANCE_UID))
+        .collect();
+    let merged_uid = format!("2.25.{}", fnv1a_hash(source_uids.join("/").as_bytes()));
+
+    // Tags of the functional groups, and the other tags with a value per frame
+    let group_tags: Vec<Tag> = CONVERTED_FUNCTIONAL_GROUPS
+        .iter()
+        .flat_map(|(_, group_tags)| group_tags.iter().copied())
+        .chain([
+            tags::SOP_INSTANCE_UID,
+            tags::INSTANCE_NUMBER,
+            tags::PIXEL_DATA,
+        ])
+        .collect();
+    let all_tags: BTreeSet<Tag> = instances
+        .iter()
+        .flat_map(|instance| instance.iter().map(|element| element.tag()))
+        .filter(|tag| !group_tags.contains(tag))
+        .collect();
+    let unassigned: Vec<Tag> = all_tags
+        .into_iter()
+        .filter(|tag| {
+            instances
+                .iter()
+                .any(|instance| instance.get(*tag) != instances[0].get(*tag))
+        })
+        .collect();
+
+    let mut shared = InMemDicomObject::new_empty();
+    let mut per_frame: Vec<InMemDicomObject> = instances
+        .iter()
+        .map(|_| InMemDicomObject::new_empty())
+        .collect();
+    for (group, group_tags) in CONVERTED_FUNCTIONAL_GROUPS {
+        let items: Vec<InMemDicomObject> = instances
+            .iter()
+            .map(|instance| {
+                InMemDicomObject::from_element_iter(
+                    group_tags
+                        .iter()
+                        .filter_map(|tag| instance.get(*tag).cloned()),
+                )
+            })
+            .collect();
+        if items.iter().all(|item| item.iter().next().is_none()) {
+            continue;
+        }
+        if items.iter().all(|item| *item == items[0]) {
+            shared.put(DataElement::new(
+                group,
+                VR::SQ,
+                DataSetSequence::from(vec![items[0].clone()]),
+            ));
+            continue;
+        }
+        for (frame, item) in per_frame.iter_mut().zip(items) {
+            frame.put(DataElement::new(
+                group,
+                VR::SQ,
+                DataSetSequence::from(vec![item]),
+            ));
+        }
+    }
+    for (index, (frame, instance)) in per_frame.iter_mut().zip(&instances).enumerate() {
+        let position = index as u32 + 1;
+        let frame_content = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::STACK_ID, VR::SH, dicom_value!(Str, "1")),
+            DataElement::new(
+                tags::IN_STACK_POSITION_NUMBER,
+                VR::UL,
+                dicom_value!(U32, [position]),
+            ),
+            DataElement::new(
+                tags::DIMENSION_INDEX_VALUES,
+                VR::UL,
+                dicom_value!(U32, [position]),
+            ),
+        ]);
+        frame.put(DataElement::new(
+            tags::FRAME_CONTENT_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(vec![frame_content]),
+        ));
+        let conversion_source = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::REFERENCED_SOP_CLASS_UID,
+                VR::UI,
+                dicom_value!(Str, sop_class_uid.clone()),
+            ),
+            DataElement::new(
+                tags::REFERENCED_SOP_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, source_uids[index].clone()),
+            ),
+        ]);
+        frame.put(DataElement::new(
+            tags::CONVERSION_SOURCE_ATTRIBUTES_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(vec![conversion_source]),
+        ));
+        if !unassigned.is_empty() {
+            let unassigned_item = InMemDicomObject::from_element_iter(
+                unassigned
+                    .iter()
+                    .filter_map(|tag| instance.get(*tag).cloned()),
+            );
+            frame.put(DataElement::new(
+                tags::UNASSIGNED_PER_FRAME_CONVERTED_ATTRIBUTES_SEQUENCE,
+                VR::SQ,
+                DataSetSequence::from(vec![unassigned_item]),
+            ));
+        }
+    }
+
+    let frames = instances.len();
+    let mut merged = instances.swap_remove(0);
+    for tag in group_tags.iter().chain(&unassigned) {
+        merged.remove_element(*tag);
+    }
+    // The frames are indexed by their position in the stack
+    let organization_uid = format!(
+        "2.25.{}",
+        fnv1a_hash(format!("{}/dimensions", merged_uid).as_bytes())
+    );
+    let organization = InMemDicomObject::from_element_iter([DataElement::new(
+        tags::DIMENSION_ORGANIZATION_UID,
+        VR::UI,
+        dicom_value!(Str, organization_uid.clone()),
+    )]);
+    let dimension_index = InMemDicomObject::from_element_iter([
+        DataElement::new(
+            tags::DIMENSION_ORGANIZATION_UID,
+            VR::UI,
+            dicom_value!(Str, organization_uid),
+        ),
+        DataElement::new(
+            tags::DIMENSION_INDEX_POINTER,
+            VR::AT,
+            dicom_value!(Tags, [tags::IN_STACK_POSITION_NUMBER]),
+        ),
+        DataElement::new(
+            tags::FUNCTIONAL_GROUP_POINTER,
+            VR::AT,
+            dicom_value!(Tags, [tags::FRAME_CONTENT_SEQUENCE]),
+        ),
+    ]);
+    merged.put(DataElement::new(
+        tags::SOP_CLASS_UID,
+        VR::UI,
+        dicom_value!(Str, converted_class),
+    ));
+    merged.put(DataElement::new(
+        tags::SOP_INSTANCE_UID,
+        VR::UI,
+        dicom_value!(Str, merged_uid.clone()),
+    ));
+    merged.put(DataElement::new(
+        tags::INSTANCE_NUMBER,
+        VR::IS,
+        dicom_value!(Str, "1"),
+    ));
+    merged.put(DataElement::new(
+        tags::NUMBER_OF_FRAMES,
+        VR::IS,
+        dicom_value!(Str, frames.to_string()),
+    ));
+    merged.put(DataElement::new(
+        tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE,
+        VR::SQ,
+        DataSetSequence::from(vec![shared]),
+    ));
+    merged.put(DataElement::new(
+        tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+        VR::SQ,
+        DataSetSequence::from(per_frame),
+    ));
+    merged.put(DataElement::new(
+        tags::DIMENSION_ORGANIZATION_SEQUENCE,
+        VR::SQ,
+        DataSetSequence::from(vec![organization]),
+    ));
+    merged.put(DataElement::new(
+        tags::DIMENSION_INDEX_SEQUENCE,
+        VR::SQ,
+        DataSetSequence::from(vec![dimension_index]),
+    ));
+    merged.put(pixel_data);
+    let meta = merged.meta_mut();
+    meta.media_storage_sop_class_uid = converted_class.to_string();
+    meta.media_storage_sop_instance_uid = merged_uid.clone();
+    meta.update_information_group_length();
+    debug!("{} frames merged into {}", frames, merged_uid);
+    Ok(merged)
+}
+
+// Pixel data of the merged instance, in the order of the instances
+fn merged_pixels(instances: &[FileDicomObject<InMemDicomObject>]) -> Result<InMemElement> {
+    let elements = instances
+        .iter()
+        .map(|instance| {
+            instance
+                .element_opt(tags::PIXEL_DATA)?
+                .ok_or_else(|| anyhow::anyhow!("No pixel data to merge"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let pixel_vr = elements[0].vr();
+    if elements[0].value().fragments().is_some() {
+        // Offsets are from the first fragment item, each item has an 8 bytes header
+        let mut offset_table = Vec::with_capacity(elements.len());
+        let mut fragments = vec![];
+        let mut position: u64 = 0;
+        for element in &elements {
+            let frame_fragments = element
+                .value()
+                .fragments()
+                .ok_or_else(|| anyhow::anyhow!("Native and encapsulated frames can't be merged"))?;
+            offset_table.push(u32::try_from(position)?);
+            for fragment in frame_fragments {
+                position += 8 + fragment.len() as u64;
+                fragments.push(fragment.clone());
+            }
+        }
+        return Ok(InMemElement::new(
+            tags::PIXEL_DATA,
+            pixel_vr,
+            PixelFragmentSequence::new(offset_table, fragments),
+        ));
+    }
+
+    let little_endian = TransferSyntaxRegistry
+        .get(instances[0].meta().transfer_syntax())
+        .is_some_and(|ts| ts.endianness() == Endianness::Little);
+    if !little_endian {
+        return Err(anyhow::anyhow!(
+            "Only little endian pixel data can be merged"
+        ));
+    }
+    let int_of = |tag: Tag, default: u32| -> usize {
+        instances[0]
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_int::<u32>().ok())
+            .unwrap_or(default) as usize
+    };
+    let bits_allocated = int_of(tags::BITS_ALLOCATED, 16);
+    if bits_allocated % 8 != 0 {
+        return Err(anyhow::anyhow!(
+            "Frames of {} bits allocated can't be merged",
+            bits_allocated
+        ));
+    }
+    let frame_length = int_of(tags::ROWS, 0)
+        * int_of(tags::COLUMNS, 0)
+        * int_of(tags::SAMPLES_PER_PIXEL, 1)
+        * bits_allocated
+        / 8;
+    if frame_length == 0 || frame_length * elements.len() >= u32::MAX as usize {
+        return Err(anyhow::anyhow!(
+            "{} frames of {} bytes don't fit in one pixel data element",
+            elements.len(),
+            frame_length
+        ));
+    }
+    let mut pixel_bytes = Vec::with_capacity(frame_length * elements.len() + 1);
+    for element in &elements {
+        let frame = element.value().to_bytes()?;
+        if frame.len() < frame_length {
+            return Err(anyhow::anyhow!(
+                "Pixel data of {} bytes is shorter than its frame",
+                frame.len()
+            ));
+        }
+        pixel_bytes.extend_from_slice(&frame[..frame_length]);
+    }
+    if pixel_bytes.len() % 2 == 1 {
+        pixel_bytes.push(0);
+    }
+    Ok(DataElement::new(
+        tags::PIXEL_DATA,
+        pixel_vr,
+        PrimitiveValue::U8(pixel_bytes.into()),
+    ))
+}
@@ -0,0 +1,434 @@
+use crate::FrameLayout;
+use anyhow::Result;
+use dicom::{
+    core::{value::PixelFragmentSequence, DataElement, PrimitiveValue, VR},
+    dictionary_std::{tags, uids::JPEG_LOSSLESS_SV1},
+    encoding::TransferSyntaxIndex,
+    object::{FileDicomObject, InMemDicomObject, Tag},
+    transfer_syntax::TransferSyntaxRegistry,
+};
+use rayon::{iter::ParallelIterator, slice::ParallelSlice};
+
+impl FrameLayout {
+    // JPEG Lossless SV1 of the frame: each sample is predicted by the one on its left, or the one
+    // above at the start of a row, and the differences are Huffman coded with a table fitted to
+    // the frame. The components of a pixel are interleaved in the scan
+    fn encode_jpeg_lossless(&self, frame: &[u8]) -> Vec<u8> {
+        let precision = self.sample_bytes * 8;
+        let samples: Vec<i32> = match self.sample_bytes {
+            1 => frame.iter().map(|&b| b as i32).collect(),
+            _ => frame
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as i32)
+                .collect(),
+        };
+        let at = |row: usize, column: usize, component: usize| match self.planar {
+            true => samples[(component * self.rows + row) * self.columns + column],
+            false => samples[(row * self.columns + column) * self.samples + component],
+        };
+        let mut differences = Vec::with_capacity(samples.len());
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                for component in 0..self.samples {
+                    let prediction = match (row, column) {
+                        (0, 0) => 1 << (precision - 1),
+                        (_, 0) => at(row - 1, column, component),
+                        _ => at(row, column - 1, component),
+                    };
+                    // Differences are modulo 2^16
+                    let mut difference =
+                        (at(row, column, component) - prediction).rem_euclid(65536);
+                    if difference > 32768 {
+                        difference -= 65536;
+                    }
+                    differences.push(difference);
+                }
+            }
+        }
+        let category = |difference: i32| (32 - difference.unsigned_abs().leading_zeros()) as usize;
+        let mut frequencies = [0u64; 17];
+        for difference in &differences {
+            frequencies[category(*difference)] += 1;
+        }
+        let (bits, values) = huffman_table(&frequencies);
+        let codes = huffman_codes(&bits, &values);
+
+        let mut jpeg = vec![0xFF, 0xD8];
+        // Lossless start of frame, the components are not subsampled
+        let mut segment = vec![precision as u8];
+        segment.extend_from_slice(&(self.rows as u16).to_be_bytes());
+        segment.extend_from_slice(&(self.columns as u16).to_be_bytes());
+        segment.push(self.samples as u8);
+        for component in 0..self.samples {
+            segment.extend_from_slice(&[component as u8 + 1, 0x11, 0]);
+        }
+        jpeg_segment(&mut jpeg, 0xC3, &segment);
+        let mut segment = vec![0];
+        segment.extend_from_slice(&bits);
+        segment.extend_from_slice(&values);
+        jpeg_segment(&mut jpeg, 0xC4, &segment);
+        // Start of scan with the first predictor and no point transform
+        let mut segment = vec![self.samples as u8];
+        for component in 0..self.samples {
+            segment.extend_from_slice(&[component as u8 + 1, 0]);
+        }
+        segment.extend_from_slice(&[1, 0, 0]);
+        jpeg_segment(&mut jpeg, 0xDA, &segment);
+        let mut writer = BitWriter::default();
+        for difference in differences {
+            let size = category(difference);
+            let (code, length) = codes[size];
+            writer.put(code, length);
+            // The category 16 is 32768 alone, without additional bits
+            if size > 0 && size < 16 {
+                let bits = match difference < 0 {
+                    true => difference - 1,
+                    false => difference,
+                };
+                writer.put(bits as u32, size);
+            }
+        }
+        jpeg.extend(writer.finish());
+        jpeg.extend_from_slice(&[0xFF, 0xD9]);
+        if jpeg.len() % 2 == 1 {
+            jpeg.push(0);
+        }
+        jpeg
+    }
+}
+
+// Marker segment of a JPEG stream, the length counts itself
+fn jpeg_segment(jpeg: &mut Vec<u8>, marker: u8, segment: &[u8]) {
+    jpeg.extend_from_slice(&[0xFF, marker]);
+    jpeg.extend_from_slice(&((segment.len() + 2) as u16).to_be_bytes());
+    jpeg.extend_from_slice(segment);
+}
+
+// Entropy coded bits of a JPEG scan, each 0xFF byte is followed by a stuffed 0x00
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    count: usize,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, length: usize) {
+        self.buffer = (self.buffer << length) | u64::from(value & ((1u32 << length) - 1));
+        self.count += length;
+        while self.count >= 8 {
+            self.count -= 8;
+            let byte = (self.buffer >> self.count) as u8;
+            self.bytes.push(byte);
+            if byte == 0xFF {
+                self.bytes.push(0);
+            }
+        }
+        self.buffer &= (1 << self.count) - 1;
+    }
+
+    // The last byte is padded with 1 bits
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            let padding = 8 - self.count;
+            self.put((1 << padding) - 1, padding);
+        }
+        self.bytes
+    }
+}
+
+// Huffman table of the difference categories from their frequencies, as the BITS count of codes
+// of each length and the HUFFVAL categories in code order. JPEG Annex K.2 with a reserved
+// category so no code is all 1 bits, and the code lengths limited to 16
+fn huffman_table(frequencies: &[u64; 17]) -> ([u8; 16], Vec<u8>) {
+    let mut frequencies = frequencies.to_vec();
+    frequencies.push(1);
+    let count = frequencies.len();
+    let mut code_sizes = vec![0usize; count];
+    let mut others: Vec<Option<usize>> = vec![None; count];
+    loop {
+        // Least frequent, the highest category on ties, then the next least frequent
+        let least = |skip: Option<usize>, frequencies: &[u64]| {
+            (0..count)
+                .filter(|i| frequencies[*i] > 0 && Some(*i) != skip)
+                .fold(None, |least: Option<usize>, i| match least {
+                    Some(least) if frequencies[least] < frequencies[i] => Some(least),
+                    _ => Some(i),
+                })
+        };
+        let (first, second) = match least(None, &frequencies) {
+            Some(first) => match least(Some(first), &frequencies) {
+                Some(second) => (first, second),
+                None => break,
+            },
+            None => break,
+        };
+        frequencies[first] += frequencies[second];
+        frequencies[second] = 0;
+        let mut branch = first;
+        code_sizes[branch] += 1;
+        while let Some(other) = others[branch] {
+            branch = other;
+            code_sizes[branch] += 1;
+        }
+        others[branch] = Some(second);
+        let mut branch = second;
+        code_sizes[branch] += 1;
+        while let Some(other) = others[branch] {
+            branch = other;
+            code_sizes[branch] += 1;
+        }
+    }
+    let mut bits = [0usize; 33];
+    for size in &code_sizes {
+        if *size > 0 {
+            bits[*size] += 1;
+        }
+    }
+    for length in (17..33).rev() {
+        while bits[length] > 0 {
+            let mut shorter = length - 2;
+            while bits[shorter] == 0 {
+                shorter -= 1;
+            }
+            bits[length] -= 2;
+            bits[length - 1] += 1;
+            bits[shorter + 1] += 2;
+            bits[shorter] -= 1;
+        }
+    }
+    // The reserved category has the longest code
+    if let Some(length) = (1..17).rev().find(|length| bits[*length] > 0) {
+        bits[length] -= 1;
+    }
+    // Categories by code size, the reserved one is left out
+    let mut values: Vec<u8> = (0..17u8)
+        .filter(|category| code_sizes[*category as usize] > 0)
+        .collect();
+    values.sort_by_key(|category| code_sizes[*category as usize]);
+    let mut table = [0u8; 16];
+    for (length, count) in table.iter_mut().enumerate() {
+        *count = bits[length + 1] as u8;
+    }
+    (table, values)
+}
+
+// Code and length of each category, the codes of a length follow each other
+fn huffman_codes(bits: &[u8; 16], values: &[u8]) -> [(u32, usize); 17] {
+    let mut codes = [(0, 0); 17];
+    let mut code = 0;
+    let mut categories = values.iter();
+    for (length, count) in bits.iter().enumerate() {
+        for _ in 0..*count {
+            if let Some(category) = categories.next() {
+                codes[*category as usize] = (code, length + 1);
+            }
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+// Encode native little endian pixel data of 8 or 16 bits as JPEG Lossless SV1, one fragment per
+// frame. The frames are encoded in parallel
+pub(crate) fn encode_jpeg_lossless(dcm_obj: &mut FileDicomObject<InMemDicomObject>) -> Result<()> {
+    let int_of = |tag: Tag, default: u32| -> usize {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_int::<u32>().ok())
+            .unwrap_or(default) as usize
+    };
+    let sample_bytes = match int_of(tags::BITS_ALLOCATED, 16) {
+        8 => 1,
+        16 => 2,
+        bits_allocated => {
+            return Err(anyhow::anyhow!(
+                "JPEG Lossless of {} bits allocated is not supported",
+                bits_allocated
+            ))
+        }
+    };
+    let frame = FrameLayout {
+        rows: int_of(tags::ROWS, 0),
+        columns: int_of(tags::COLUMNS, 0),
+        samples: int_of(tags::SAMPLES_PER_PIXEL, 1),
+        sample_bytes,
+        signed: false,
+        planar: int_of(tags::PLANAR_CONFIGURATION, 0) == 1,
+    };
+    let frames = int_of(tags::NUMBER_OF_FRAMES, 1).max(1);
+    let pixel_bytes = dcm_obj
+        .element(tags::PIXEL_DATA)?
+        .value()
+        .primitive()
+        .map(|value| value.to_bytes().to_vec())
+        .ok_or_else(|| anyhow::anyhow!("Pixel data is not native"))?;
+    let frame_length = frame.rows * frame.columns * frame.samples * sample_bytes;
+    if frame_length == 0 || pixel_bytes.len() < frame_length * frames {
+        return Err(anyhow::anyhow!(
+            "Pixel data of {} bytes is shorter than {} frames",
+            pixel_bytes.len(),
+            frames
+        ));
+    }
+    let fragments: Vec<Vec<u8>> = pixel_bytes[..frame_length * frames]
+        .par_chunks(frame_length)
+        .map(|pixels| frame.encode_jpeg_lossless(pixels))
+        .collect();
+    // Offsets of the fragments from the first one, each item has an 8 bytes header
+    let mut offset_table = Vec::with_capacity(frames);
+    let mut offset = 0;
+    for fragment in &fragments {
+        offset_table.push(offset);
+        offset += 8 + fragment.len() as u32;
+    }
+    dcm_obj.put(DataElement::new(
+        tags::PIXEL_DATA,
+        VR::OB,
+        PixelFragmentSequence::new(offset_table, fragments),
+    ));
+    if frame.samples > 1 {
+        dcm_obj.put(DataElement::new(
+            tags::PLANAR_CONFIGURATION,
+            VR::US,
+            PrimitiveValue::from(0_u16),
+        ));
+    }
+    let jpeg_lossless = TransferSyntaxRegistry
+        .get(JPEG_LOSSLESS_SV1)
+        .ok_or_else(|| anyhow::anyhow!("No JPEG Lossless in this build"))?;
+    dcm_obj.meta_mut().set_transfer_syntax(jpeg_lossless);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode_jpeg_lossless;
+    use dicom::{
+        core::{DataElement, PrimitiveValue, VR},
+        dictionary_std::{
+            tags,
+            uids::{EXPLICIT_VR_LITTLE_ENDIAN, JPEG_LOSSLESS_SV1, SECONDARY_CAPTURE_IMAGE_STORAGE},
+        },
+        object::{FileDicomObject, FileMetaTableBuilder, InMemDicomObject},
+        pixeldata::PixelDecoder,
+    };
+
+    // Native image of the given layout, the pixels are little endian
+    fn native_image(
+        bits: u16,
+        samples: u16,
+        frames: u16,
+        pixels: &[u8],
+    ) -> FileDicomObject<InMemDicomObject> {
+        let mut dcm_obj = InMemDicomObject::new_empty();
+        let mut put_us = |tag, value: u16| {
+            dcm_obj.put(DataElement::new(tag, VR::US, PrimitiveValue::from(value)))
+        };
+        put_us(tags::ROWS, 7);
+        put_us(tags::COLUMNS, 5);
+        put_us(tags::SAMPLES_PER_PIXEL, samples);
+        put_us(tags::BITS_ALLOCATED, bits);
+        put_us(tags::BITS_STORED, bits);
+        put_us(tags::HIGH_BIT, bits - 1);
+        put_us(tags::PIXEL_REPRESENTATION, 0);
+        if samples > 1 {
+            put_us(tags::PLANAR_CONFIGURATION, 0);
+        }
+        let photometric = match samples {
+            1 => "MONOCHROME2",
+            _ => "RGB",
+        };
+        dcm_obj.put(DataElement::new(
+            tags::PHOTOMETRIC_INTERPRETATION,
+            VR::CS,
+            PrimitiveValue::from(photometric),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::NUMBER_OF_FRAMES,
+            VR::IS,
+            PrimitiveValue::from(frames.to_string()),
+        ));
+        let vr = match bits {
+            8 => VR::OB,
+            _ => VR::OW,
+        };
+        dcm_obj.put(DataElement::new(
+            tags::PIXEL_DATA,
+            vr,
+            PrimitiveValue::from(pixels.to_vec()),
+        ));
+        dcm_obj
+            .with_meta(
+                FileMetaTableBuilder::new()
+                    .media_storage_sop_class_uid(SECONDARY_CAPTURE_IMAGE_STORAGE)
+                    .media_storage_sop_instance_uid("2.25.1")
+                    .transfer_syntax(EXPLICIT_VR_LITTLE_ENDIAN),
+            )
+            .unwrap()
+    }
+
+    // Noise with the extremes of the sample range, so every difference category is coded
+    fn test_pixels(length: usize) -> Vec<u8> {
+        let mut state: u32 = 12345;
+        let mut pixels: Vec<u8> = (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        pixels[..4].copy_from_slice(&[0, 0, 0xFF, 0xFF]);
+        pixels
+    }
+
+    // Encode, then decode with the JPEG decoder of dicom-rs, the pixels come back as they were
+    fn assert_round_trip(bits: u16, samples: u16, frames: u16) {
+        let length = 7 * 5 * usize::from(samples * frames * bits / 8);
+        let pixels = test_pixels(length);
+        let mut dcm_obj = native_image(bits, samples, frames, &pixels);
+        encode_jpeg_lossless(&mut dcm_obj).unwrap();
+        assert_eq!(
+            dcm_obj.meta().transfer_syntax.trim_end_matches('\0'),
+            JPEG_LOSSLESS_SV1
+        );
+        let fragments = dcm_obj
+            .element(tags::PIXEL_DATA)
+            .unwrap()
+            .value()
+            .fragments()
+            .map(|fragments| fragments.len());
+        assert_eq!(fragments, Some(usize::from(frames)));
+        let decoded = dcm_obj.decode_pixel_data().unwrap();
+        assert_eq!(decoded.data(), pixels.as_slice());
+    }
+
+    #[test]
+    fn jpeg_lossless_round_trip_8_bits() {
+        assert_round_trip(8, 1, 1);
+        assert_round_trip(8, 1, 3);
+    }
+
+    #[test]
+    fn jpeg_lossless_round_trip_16_bits() {
+        assert_round_trip(16, 1, 1);
+        assert_round_trip(16, 1, 2);
+    }
+
+    #[test]
+    fn jpeg_lossless_round_trip_rgb() {
+        assert_round_trip(8, 3, 2);
+    }
+
+    #[test]
+    fn jpeg_lossless_refuses_12_bits_allocated() {
+        let mut dcm_obj = native_image(16, 1, 1, &test_pixels(7 * 5 * 2));
+        dcm_obj.put(DataElement::new(
+            tags::BITS_ALLOCATED,
+            VR::US,
+            PrimitiveValue::from(12_u16),
+        ));
+        assert!(encode_jpeg_lossless(&mut dcm_obj).is_err());
+    }
+}
@@ -1,33 +1,81 @@
+pub mod checkpoint;
+pub mod confidentiality;
+pub mod delivery;
+pub mod dicomweb;
+pub mod dimse;
+pub mod filter;
+pub mod frames;
+pub mod jpeg;
+pub mod pipeline;
+pub mod pixel_mask;
+pub mod rules;
+pub mod service;
+pub mod sink;
+pub mod source;
+pub mod tracker;
+pub mod uid_mapper;
+use checkpoint::Checkpoint;
+use confidentiality::StandardProfile;
+use filter::{FilterAction, FilterExpr, FILTERED_OUT_DIR};
+use jpeg::encode_jpeg_lossless;
 use nanoid::nanoid;
+use pixel_mask::PixelMask;
+use rules::RuleSet;
+use source::{archive_kind, ArchiveSource, DirectorySource, InstanceSource};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Write,
-    fs::{self, canonicalize, copy, create_dir_all},
-    path::PathBuf,
-    process::exit,
+    fs::{self, canonicalize, create_dir_all},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write as _},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::{exit, Command},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, OnceLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
+use tracker::{RunProgress, RunTracker};
+use uid_mapper::UidMapper;
 
 use anyhow::Result;
+use dicom::dictionary_std::uids::{
+    EXPLICIT_VR_LITTLE_ENDIAN, IMPLICIT_VR_LITTLE_ENDIAN, JPEG_LOSSLESS_SV1,
+};
 use dicom::{
     core::{
-        chrono::NaiveDate,
-        dictionary::DataDictionaryEntryRef,
+        chrono::{Days, Local, NaiveDate},
+        dictionary::{UidDictionary, UidDictionaryEntry, VirtualVr},
         header::Header,
-        value::{DicomDate, DicomDateTime, DicomTime},
+        value::{DataSetSequence, DicomDate, DicomDateTime, DicomTime},
         DataDictionary, DataElement, PrimitiveValue, VR,
     },
     dicom_value,
     dictionary_std::tags::{self, ORIGINAL_ATTRIBUTES_SEQUENCE},
-    object::{FileDicomObject, InMemDicomObject, StandardDataDictionary, Tag},
+    encoding::{
+        text::SpecificCharacterSet, Codec, Endianness, TransferSyntax, TransferSyntaxIndex,
+    },
+    object::{
+        mem::InMemElement, FileDicomObject, FileMetaTable, InMemDicomObject,
+        StandardDataDictionary, Tag,
+    },
+    pixeldata::Transcode,
+    transfer_syntax::TransferSyntaxRegistry,
 };
-use indicatif::{ProgressBar, ProgressStyle};
+use dicom_dictionary_std::StandardSopClassDictionary;
 use rayon::{
     current_num_threads,
-    iter::{ParallelBridge, ParallelIterator},
+    iter::{IntoParallelRefIterator, ParallelIterator},
+    slice::ParallelSlice,
 };
 use regex::Regex;
+use serde::Deserialize;
 use tracing::{debug, error, info, warn};
-use walkdir::{DirEntry, WalkDir};
 
 // Tags to get data for
 static DICOM_TAGS_SANITIZED: [&str; 10] = [
@@ -73,32 +121,1010 @@ pub fn print_logo() {
     println!("{} Ver: {}", art, app_version);
 }
 
+// Options shared by the sort, anon and deid runs
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    // Number of slowest files to list at the end of the run
+    pub slowest: usize,
+    // Only process the patients owned by this shard
+    pub shard: Option<Shard>,
+    // Write a deidentification certificate to the destination at the end of the run
+    pub certificate: bool,
+    // Operator named in the certificate, defaults to the current user
+    pub operator: Option<String>,
+    // Key file to sign the certificate with HMAC-SHA256
+    pub sign_key: Option<PathBuf>,
+    // Truncate dates to this precision instead of replacing them
+    pub date_precision: Option<DatePrecision>,
+    // Rewrite the descriptions to a controlled vocabulary
+    pub description_map: Option<DescriptionMap>,
+    // Files that can't be cleaned by header edits alone
+    pub review_policy: ReviewPolicy,
+    // Free text of presentation state annotations
+    pub annotation_text: AnnotationText,
+    // Write the SHA-256 of each written file to a manifest in the destination
+    pub manifest: bool,
+    // Read the files of the ISO9660 images in the source
+    pub read_iso: bool,
+    // Downsample the frames to fit in this matrix
+    pub downsample: Option<MatrixSize>,
+    // Write the frames of the enhanced multi-frame instances as classic single-frame instances
+    pub split_frames: bool,
+    // Merge the classic instances of each series into one enhanced instance at the end of the run
+    pub merge_frames: bool,
+    // Transfer syntax of the written pixel data
+    pub transcode: TranscodeTarget,
+    // Blackout regions of the burned in annotations
+    pub pixel_mask: Option<PixelMask>,
+    // Suffix of the output files whose name is taken
+    pub duplicate_suffix: DuplicateSuffix,
+    // Directories and file name of the output instances, instead of the default layout
+    pub path_template: Option<OutputTemplate>,
+    pub name_template: Option<OutputTemplate>,
+    // Order of the fields in malformed dates
+    pub date_order: DateOrder,
+    // Truncate over-length values and fix wrong VRs in the output
+    pub fix_vr: bool,
+    // Keep the curves, overlays, results group and retired identifying attributes
+    pub keep_legacy_tags: bool,
+    // Stream the pixel data of the files larger than this many bytes
+    pub stream_above: u64,
+    // Condition > action rules evaluated on each file
+    pub rules: Option<RuleSet>,
+    // Per study values set in the written files, keyed by AccessionNumber or StudyInstanceUID
+    pub study_values: Option<StudyValues>,
+    // Instances expected per study, checked at the end of the run
+    pub expected_counts: Option<ExpectedCounts>,
+    // New UIDs of the anonymized instances
+    pub uid_mapper: UidMapper,
+    // Zero byte and truncated items
+    pub incomplete_action: IncompleteAction,
+    pub incomplete_wait: Duration,
+    // Stream or quarantine the files over stream_above
+    pub large_file_action: LargeFileAction,
+    // Copy, quarantine or scrub the non DICOM files of deid and anon
+    pub non_dicom: NonDicomAction,
+    // Email the run summary at the end of the run
+    pub email: Option<EmailConfig>,
+    // Write the progress of the run to this JSON file for external monitors
+    pub status_file: Option<PathBuf>,
+    pub status_interval: Duration,
+    // Pack the written files into one archive per study or patient at the end of the run
+    pub delivery: Option<DeliveryUnit>,
+    // age recipients file the archives are encrypted to
+    pub delivery_recipients: Option<PathBuf>,
+    // Write a DICOMDIR and its media tree at the destination root at the end of the run
+    pub dicomdir: bool,
+    // Only report what the run would write and change, nothing is written to the destination
+    pub dry_run: bool,
+    // CSV the dry run report is written to, printed when None
+    pub dry_run_report: Option<PathBuf>,
+    // Name of the run in the runs registry
+    pub run_name: Option<String>,
+    pub runs_db: PathBuf,
+    // Exclude the instances without pixel data, eg SRs, presentation states and raw data
+    pub images_only: bool,
+    // Skip the instances whose SOPInstanceUID was already read in the run
+    pub dedup: bool,
+    // Checkpoint the run is saved to and resumed from, and the time between two saves
+    pub resume: Option<PathBuf>,
+    pub checkpoint_interval: Duration,
+    // Only process the files matched by the expression
+    pub filter: Option<FilterExpr>,
+    pub filter_action: FilterAction,
+    // The instances of a series are processed and written by one worker, in instance order
+    pub series_affinity: bool,
+    // Hide FAILED_CASES, REVIEW_REQUIRED and the other directories with data that is not
+    // deidentified
+    pub hide_phi_dirs: bool,
+    // Write the instances into one archive in the destination instead of a directory tree
+    pub output_format: Option<OutputFormat>,
+    // Send the written instances to this storage SCP, AET@host:port
+    pub send_to: Option<String>,
+    // Only write the instances that could not be sent
+    pub send_only: bool,
+    // AE title of dcmrig in the associations
+    pub calling_aet: String,
+    // Upload the written instances to this DICOMweb server with STOW-RS
+    pub stow_url: Option<String>,
+    // Files with the bearer token or the user:password of the STOW-RS server
+    pub stow_token: Option<PathBuf>,
+    pub stow_user: Option<PathBuf>,
+    // Read the source from this DICOMweb server with QIDO-RS and WADO-RS, the source path is the
+    // staging directory of the retrieved instances
+    pub wado_url: Option<String>,
+    // QIDO-RS filters of the studies, eg (StudyDate, 20240101-20241231)
+    pub wado_filters: Vec<(String, String)>,
+    // Files with the bearer token or the user:password of the WADO-RS server
+    pub wado_token: Option<PathBuf>,
+    pub wado_user: Option<PathBuf>,
+    // Print the effective configuration as JSON and exit without processing
+    pub print_effective_config: bool,
+    pub hooks: PostHooks,
+}
+
+impl RunOptions {
+    /// Checkpoint of the run with --resume, read when it exists and started otherwise
+    pub fn open_checkpoint(
+        &self,
+        action: &str,
+        destination_path: &Path,
+        policy: &str,
+    ) -> Result<Option<Checkpoint>> {
+        self.resume
+            .as_ref()
+            .map(|path| Checkpoint::open(path, action, destination_path, policy, &self.uid_mapper))
+            .transpose()
+    }
+
+    // Start writing the status file of the run, when one is given
+    pub fn start_status(&self, action: &str, tracker: &RunTracker) -> Option<StatusWriter> {
+        self.status_file.as_ref().map(|path| {
+            StatusWriter::start(
+                path.clone(),
+                self.status_interval,
+                action,
+                self.run_name.clone(),
+                tracker.clone(),
+            )
+        })
+    }
+
+    // Check if the file belongs to the current shard, all files belong to an unsharded run
+    pub fn owns_file(&self, dcm_obj: &FileDicomObject<InMemDicomObject>) -> bool {
+        match &self.shard {
+            Some(shard) => shard.owns_patient(dcm_obj),
+            None => true,
+        }
+    }
+
+    // Check if the file is matched by --filter, every file is without one
+    pub fn selects(&self, dcm_obj: &FileDicomObject<InMemDicomObject>) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches(dcm_obj))
+    }
+
+    // Description of the shard for reports
+    pub fn shard_summary(&self) -> String {
+        match &self.shard {
+            Some(shard) => format!("{}/{}", shard.index, shard.count),
+            None => "None".to_string(),
+        }
+    }
+
+    // Every option of the run as label > value, for the effective configuration
+    pub fn effective_options(&self) -> Vec<(String, String)> {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "None".to_string());
+        vec![
+            ("Run name".to_string(), optional(self.run_name.clone())),
+            ("Dry run".to_string(), self.dry_run.to_string()),
+            ("Images only".to_string(), self.images_only.to_string()),
+            ("Dedup".to_string(), self.dedup.to_string()),
+            (
+                "Resume".to_string(),
+                optional(self.resume.as_ref().map(|path| path.display().to_string())),
+            ),
+            (
+                "Checkpoint interval".to_string(),
+                self.checkpoint_interval.as_secs().to_string(),
+            ),
+            (
+                "Filter".to_string(),
+                optional(self.filter.as_ref().map(|filter| filter.to_string())),
+            ),
+            (
+                "Filtered out".to_string(),
+                format!("{:?}", self.filter_action),
+            ),
+            (
+                "Series affinity".to_string(),
+                self.series_affinity.to_string(),
+            ),
+            ("Hide PHI dirs".to_string(), self.hide_phi_dirs.to_string()),
+            ("Slowest".to_string(), self.slowest.to_string()),
+            ("Shard".to_string(), self.shard_summary()),
+            ("Certificate".to_string(), self.certificate.to_string()),
+            (
+                "Sign key".to_string(),
+                optional(
+                    self.sign_key
+                        .as_ref()
+                        .map(|path| path.display().to_string()),
+                ),
+            ),
+            (
+                "Date precision".to_string(),
+                optional(
+                    self.date_precision
+                        .map(|precision| format!("{:?}", precision)),
+                ),
+            ),
+            (
+                "Description map".to_string(),
+                optional(
+                    self.description_map
+                        .as_ref()
+                        .map(|map| map.path.display().to_string()),
+                ),
+            ),
+            (
+                "Rules".to_string(),
+                optional(
+                    self.rules
+                        .as_ref()
+                        .map(|rules| rules.path.display().to_string()),
+                ),
+            ),
+            (
+                "Study values".to_string(),
+                optional(
+                    self.study_values
+                        .as_ref()
+                        .map(|study_values| study_values.path.display().to_string()),
+                ),
+            ),
+            (
+                "Expected counts".to_string(),
+                optional(
+                    self.expected_counts
+                        .as_ref()
+                        .map(|expected_counts| expected_counts.origin.clone()),
+                ),
+            ),
+            (
+                "UID secret".to_string(),
+                match &self.uid_mapper.secret_path {
+                    Some(secret_path) => secret_path.display().to_string(),
+                    None => "Random for the run".to_string(),
+                },
+            ),
+            (
+                "Dose screens".to_string(),
+                format!("{:?}", self.review_policy.dose_screens),
+            ),
+            (
+                "Photos".to_string(),
+                format!("{:?}", self.review_policy.photos),
+            ),
+            (
+                "Slide labels".to_string(),
+                format!("{:?}", self.review_policy.slide_labels),
+            ),
+            (
+                "Annotation text".to_string(),
+                format!("{:?}", self.annotation_text),
+            ),
+            ("Manifest".to_string(), self.manifest.to_string()),
+            ("Read ISO".to_string(), self.read_iso.to_string()),
+            (
+                "Downsample".to_string(),
+                optional(
+                    self.downsample
+                        .map(|size| format!("{}x{}", size.rows, size.columns)),
+                ),
+            ),
+            ("Split frames".to_string(), self.split_frames.to_string()),
+            ("Merge frames".to_string(), self.merge_frames.to_string()),
+            ("Transcode".to_string(), format!("{:?}", self.transcode)),
+            (
+                "Pixel mask".to_string(),
+                optional(
+                    self.pixel_mask
+                        .as_ref()
+                        .map(|mask| mask.path.display().to_string()),
+                ),
+            ),
+            (
+                "Duplicate suffix".to_string(),
+                format!("{:?}", self.duplicate_suffix),
+            ),
+            (
+                "Path template".to_string(),
+                optional(self.path_template.as_ref().map(|t| t.to_string())),
+            ),
+            (
+                "Name template".to_string(),
+                optional(self.name_template.as_ref().map(|t| t.to_string())),
+            ),
+            ("Date order".to_string(), format!("{:?}", self.date_order)),
+            ("Fix VR".to_string(), self.fix_vr.to_string()),
+            (
+                "Keep legacy tags".to_string(),
+                self.keep_legacy_tags.to_string(),
+            ),
+            ("Stream above".to_string(), self.stream_above.to_string()),
+            (
+                "Large files".to_string(),
+                format!("{:?}", self.large_file_action),
+            ),
+            ("Non DICOM".to_string(), format!("{:?}", self.non_dicom)),
+            (
+                "Delivery".to_string(),
+                optional(self.delivery.map(|unit| format!("{:?}", unit))),
+            ),
+            (
+                "Delivery recipients".to_string(),
+                optional(
+                    self.delivery_recipients
+                        .as_ref()
+                        .map(|path| path.display().to_string()),
+                ),
+            ),
+            ("DICOMDIR".to_string(), self.dicomdir.to_string()),
+            (
+                "Output format".to_string(),
+                optional(
+                    self.output_format
+                        .map(|output_format| output_format.extension().to_string()),
+                ),
+            ),
+            ("Send to".to_string(), optional(self.send_to.clone())),
+            ("Send only".to_string(), self.send_only.to_string()),
+            ("Calling AET".to_string(), self.calling_aet.clone()),
+            ("STOW URL".to_string(), optional(self.stow_url.clone())),
+            (
+                "STOW token".to_string(),
+                optional(
+                    self.stow_token
+                        .as_ref()
+                        .map(|path| path.display().to_string()),
+                ),
+            ),
+            (
+                "STOW user".to_string(),
+                optional(
+                    self.stow_user
+                        .as_ref()
+                        .map(|path| path.display().to_string()),
+                ),
+            ),
+            ("WADO URL".to_string(), optional(self.wado_url.clone())),
+            (
+                "WADO filters".to_string(),
+                self.wado_filters
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ),
+            (
+                "WADO token".to_string(),
+                optional(
+                    self.wado_token
+                        .as_ref()
+                        .map(|path| path.display().to_string()),
+                ),
+            ),
+            (
+                "WADO user".to_string(),
+                optional(
+                    self.wado_user
+                        .as_ref()
+                        .map(|path| path.display().to_string()),
+                ),
+            ),
+            (
+                "Post file".to_string(),
+                optional(self.hooks.post_file.clone()),
+            ),
+            (
+                "Post study".to_string(),
+                optional(self.hooks.post_study.clone()),
+            ),
+        ]
+    }
+
+    // Non DICOM files are only copied by the first shard
+    pub fn owns_non_dicom(&self) -> bool {
+        match &self.shard {
+            Some(shard) => shard.index == 0,
+            None => true,
+        }
+    }
+}
+
+// Deterministic partition of the source by hashed PatientID, or the key tag of anon
+// Each shard owns complete patients so the mappings of each shard can be merged afterwards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: u64,
+    pub count: u64,
+    // Tag that identifies the patient, PatientID unless anon keys its patients on another one
+    pub key_tag: Tag,
+}
+
+impl Shard {
+    // Same shard keyed on the given tag
+    pub fn keyed_on(self, key_tag: Tag) -> Self {
+        Shard { key_tag, ..self }
+    }
+
+    pub fn owns_key(&self, key: &str) -> bool {
+        fnv1a_hash(key.trim().as_bytes()) % self.count == self.index
+    }
+
+    pub fn owns_patient(&self, dcm_obj: &FileDicomObject<InMemDicomObject>) -> bool {
+        let patient_key = match dcm_obj.element(self.key_tag) {
+            Ok(element) => element.to_str().map(|v| v.to_string()).unwrap_or_default(),
+            Err(_) => "".to_string(),
+        };
+        self.owns_key(&patient_key)
+    }
+}
+
+// Parse the shard from i/n, eg 0/4 is the first of 4 shards
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let (index, count) = value
+            .split_once('/')
+            .ok_or_else(|| format!("Shard should follow the i/n format eg 0/4: {}", value))?;
+        let index: u64 = index
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid shard index: {}", index))?;
+        let count: u64 = count
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid shard count: {}", count))?;
+        if count == 0 || index >= count {
+            return Err(format!(
+                "Shard index should be between 0 and {}: {}",
+                count.saturating_sub(1),
+                value
+            ));
+        }
+        Ok(Shard {
+            index,
+            count,
+            key_tag: tags::PATIENT_ID,
+        })
+    }
+}
+
+// FNV-1a hash, stable across builds and platforms unlike the std hasher
+pub fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for each in bytes {
+        hash ^= *each as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// Incremental SHA-256, FIPS 180-4
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    // Bytes not yet compressed, always less than a block
+    buffer: Vec<u8>,
+    length: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            buffer: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+}
+
+impl Sha256 {
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.length += bytes.len() as u64;
+        if !self.buffer.is_empty() {
+            let take = (64 - self.buffer.len()).min(bytes.len());
+            self.buffer.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+            if self.buffer.len() < 64 {
+                return;
+            }
+            let block = std::mem::take(&mut self.buffer);
+            self.compress(&block);
+            self.buffer = block;
+            self.buffer.clear();
+        }
+        let mut blocks = bytes.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        self.buffer.extend_from_slice(blocks.remainder());
+    }
+
+    pub fn finalize(mut self) -> [u8; 32] {
+        let bit_length = self.length * 8;
+        let mut padding = vec![0x80];
+        while (self.buffer.len() + padding.len()) % 64 != 56 {
+            padding.push(0);
+        }
+        padding.extend_from_slice(&bit_length.to_be_bytes());
+        self.update(&padding);
+
+        let mut digest = [0u8; 32];
+        for (chunk, value) in digest.chunks_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&value.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (each, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *each = each.wrapping_add(value);
+        }
+    }
+}
+
+// SHA-256 digest of a byte slice
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::default();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+// Identifier values are only logged as they are with --log-phi
+static LOG_PHI: AtomicBool = AtomicBool::new(false);
+
+pub fn set_log_phi(log_phi: bool) {
+    LOG_PHI.store(log_phi, Ordering::Relaxed);
+}
+
+/// Identifier value (PatientID, AccessionNumber, a mapping table line...) as it can be logged
+/// Without --log-phi it is replaced by a hash keyed for the run, the lines of a value can be
+/// followed within a run but the value can't be guessed from the log
+pub fn phi(value: &str) -> String {
+    static KEY: OnceLock<String> = OnceLock::new();
+    if LOG_PHI.load(Ordering::Relaxed) {
+        return value.to_string();
+    }
+    let key = KEY.get_or_init(|| nanoid!(32));
+    let digest = sha256(format!("{}\\{}", key, value).as_bytes());
+    format!("<PHI {}>", to_hex(&digest[..4]))
+}
+
+// HMAC-SHA256 of the message with the given key, RFC 2104
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block_key = [0u8; 64];
+    if key.len() > 64 {
+        block_key[..32].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block_key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let mut outer: Vec<u8> = block_key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+// Lowercase hex string of the given bytes
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+// Transfer syntax of a file read from its file meta group only
+fn read_transfer_syntax(file_path: &Path) -> Option<String> {
+    let mut reader = BufReader::new(fs::File::open(file_path).ok()?);
+    reader.seek_relative(128).ok()?;
+    FileMetaTable::from_reader(reader)
+        .ok()
+        .map(|meta| meta.transfer_syntax().to_string())
+}
+
+// File meta group of an item of the source, None for the items without the DICM prefix
+pub fn read_file_meta(source: &dyn InstanceSource, item: &Path) -> Option<FileMetaTable> {
+    let mut reader = source.open_bytes(item).ok()?;
+    std::io::copy(&mut reader.by_ref().take(128), &mut std::io::sink()).ok()?;
+    FileMetaTable::from_reader(reader).ok()
+}
+
+// Count the files of each transfer syntax in the source and warn up front about the ones
+// this build can't read, or can't decode the pixel data of when pixel_access is set. The items
+// a source only fetches when they are processed, eg from a WADO-RS server, are not counted
+pub fn transfer_syntax_precheck(
+    source: &dyn InstanceSource,
+    all_files: &[PathBuf],
+    pixel_access: bool,
+) -> BTreeMap<String, u64> {
+    let stats = all_files
+        .par_iter()
+        .filter_map(|path| read_file_meta(source, path))
+        .map(|meta| meta.transfer_syntax().to_string())
+        .fold(BTreeMap::new, |mut stats: BTreeMap<String, u64>, uid| {
+            *stats.entry(uid).or_default() += 1;
+            stats
+        })
+        .reduce(BTreeMap::new, |mut stats, other| {
+            for (uid, count) in other {
+                *stats.entry(uid).or_default() += count;
+            }
+            stats
+        });
+    if stats.is_empty() {
+        return stats;
+    }
+    info!("Transfer syntaxes in use:");
+    for (uid, count) in &stats {
+        match TransferSyntaxRegistry.get(uid) {
+            Some(ts) if ts.is_unsupported() => {
+                warn!("    {} ({}): {} files", ts.name(), uid, count);
+                warn!(
+                    "    {} files can't be read with this build and will be handled as non DICOM",
+                    count
+                );
+            }
+            Some(ts) if pixel_access && !ts.can_decode_all() => {
+                warn!("    {} ({}): {} files", ts.name(), uid, count);
+                warn!(
+                    "    No pixel data decoder in this build, {} files will fail",
+                    count
+                );
+            }
+            Some(ts) => info!("    {} ({}): {} files", ts.name(), uid, count),
+            None => {
+                warn!("    Unknown ({}): {} files", uid, count);
+                warn!(
+                    "    {} files can't be read with this build and will be handled as non DICOM",
+                    count
+                );
+            }
+        }
+    }
+    stats
+}
+
+// List the transfer syntaxes known to this build and what can be done with each of them
+pub fn print_codecs() {
+    let mut all_ts: Vec<_> = TransferSyntaxRegistry.iter().collect();
+    all_ts.sort_by_key(|ts| ts.uid());
+    println!(
+        "{:<26} {:<8} {:<8} {:<8} NAME",
+        "UID", "DATASET", "DECODE", "ENCODE"
+    );
+    for ts in all_ts {
+        let can_encode = matches!(
+            ts.codec(),
+            Codec::None | Codec::Dataset(Some(_)) | Codec::EncapsulatedPixelData(_, Some(_))
+        );
+        let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+        println!(
+            "{:<26} {:<8} {:<8} {:<8} {}",
+            ts.uid(),
+            yes_no(ts.can_decode_dataset()),
+            yes_no(ts.can_decode_all()),
+            yes_no(can_encode),
+            ts.name()
+        );
+    }
+}
+
 // Initial setup before starting the action
 pub fn preprocessing_setup(
     source_path: &PathBuf,
     destination_path: &PathBuf,
-) -> Result<(Vec<DirEntry>, u64, ProgressBar)> {
-    check_given_path_exists(&source_path, &destination_path)?;
-    info!("Indexing files from: {}", source_path.display());
-    let all_files: Vec<_> = WalkDir::new(source_path)
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-        .par_bridge()
-        .filter(|entry| entry.file_type().is_file())
-        .collect();
+    read_iso: bool,
+    dry_run: bool,
+) -> Result<(Vec<PathBuf>, u64, RunTracker)> {
+    check_given_path_exists(source_path, destination_path, dry_run)?;
+    match archive_kind(source_path) {
+        Some(kind) => source_setup(Arc::new(ArchiveSource::new(source_path, kind))),
+        None => source_setup(Arc::new(DirectorySource::new(source_path, read_iso))),
+    }
+}
+
+// Index the items of the source and set it on the tracker of the run
+pub fn source_setup(source: Arc<dyn InstanceSource>) -> Result<(Vec<PathBuf>, u64, RunTracker)> {
+    info!("Indexing files from: {}", source.describe());
+    let all_files = source.items()?;
     let total_len: u64 = all_files.len() as u64;
-    info!("Total files found: {} | Starting deid", total_len);
-    let pb = ProgressBar::new(total_len);
-    pb.set_style(
-        ProgressStyle::with_template(
-            "{spinner:.green} {percent}% [{elapsed_precise}] [{wide_bar:.cyan/blue}] ({pos}/{len}, ETA {eta})",
-        )?,
-    );
+    info!("Total files found: {}", total_len);
+    let mut tracker = RunTracker::new(RunProgress::new(total_len)?);
+    tracker.source = source;
     info!("Current number of threads: {}", current_num_threads());
-    Ok((all_files, total_len, pb))
+    Ok((all_files, total_len, tracker))
+}
+
+// Batches of items processed in order by one worker each. With series affinity the items of a
+// series form one batch ordered by InstanceNumber, the non DICOM items are batches of their own
+// Without it each item is its own batch
+pub fn series_batches(tracker: &RunTracker, items: &[PathBuf]) -> Vec<Vec<PathBuf>> {
+    if !tracker.series_affinity {
+        return items.iter().map(|item| vec![item.clone()]).collect();
+    }
+    // Only the header is read, the item is opened again by the worker
+    let keys: Vec<Option<(String, i64)>> = items
+        .par_iter()
+        .map(|item| {
+            let dcm_obj = tracker.source.open_dataset(item, true).ok()?;
+            let value = |tag| {
+                dcm_obj
+                    .element(tag)
+                    .ok()
+                    .and_then(|element| element.to_str().ok())
+                    .map(|value| value.trim_end_matches('\0').trim().to_string())
+            };
+            let series_uid = value(tags::SERIES_INSTANCE_UID).filter(|uid| !uid.is_empty())?;
+            let instance_number = value(tags::INSTANCE_NUMBER)
+                .and_then(|number| number.parse().ok())
+                .unwrap_or(i64::MAX);
+            Some((series_uid, instance_number))
+        })
+        .collect();
+    let mut series: BTreeMap<String, Vec<(i64, &PathBuf)>> = BTreeMap::new();
+    let mut others = vec![];
+    for (item, key) in items.iter().zip(keys) {
+        match key {
+            Some((series_uid, instance_number)) => series
+                .entry(series_uid)
+                .or_default()
+                .push((instance_number, item)),
+            None => others.push(vec![item.clone()]),
+        }
+    }
+    info!(
+        "Series affinity: {} series, {} items without a series",
+        series.len(),
+        others.len()
+    );
+    let mut batches: Vec<Vec<PathBuf>> = series
+        .into_values()
+        .map(|mut instances| {
+            instances.sort();
+            instances
+                .into_iter()
+                .map(|(_, item)| item.clone())
+                .collect()
+        })
+        .collect();
+    // The largest series first, so a long series doesn't start last and hold up the run
+    batches.sort_by_key(|batch| std::cmp::Reverse(batch.len()));
+    batches.extend(others);
+    batches
+}
+
+// Run the write of an instance on its own task, or on the current worker in order with series
+// affinity
+pub fn spawn_write(series_affinity: bool, write: impl FnOnce() + Send + 'static) {
+    match series_affinity {
+        true => write(),
+        false => rayon::spawn(write),
+    }
+}
+
+// Share of the cores a --background run uses, a quarter leaves the workstation responsive
+pub const BACKGROUND_CORE_DIVISOR: usize = 4;
+
+// Lower the CPU and IO priority of the process and cap the worker threads, so archive wide jobs
+// can run next to the clinical software of a shared workstation. Must run before the thread pool
+// is first used, the worker threads inherit the priority of the main thread
+pub fn enter_background_mode() -> Result<()> {
+    let cores = std::thread::available_parallelism()
+        .map(|cores| cores.get())
+        .unwrap_or(1);
+    let threads = (cores / BACKGROUND_CORE_DIVISOR).max(1);
+    lower_priority();
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()?;
+    info!(
+        "Background mode: {} of {} cores at the lowest CPU and IO priority",
+        threads, cores
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+fn lower_priority() {
+    // nice 19, the threads created afterwards inherit it
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        warn!(
+            "Can't lower the CPU priority: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    // Idle IO class as with ionice -c 3, only Linux has it
+    #[cfg(target_os = "linux")]
+    {
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        let ioprio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+            warn!(
+                "Can't lower the IO priority: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(windows)]
+fn lower_priority() {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, PROCESS_MODE_BACKGROUND_BEGIN,
+    };
+    // Background mode lowers the CPU, IO and memory priority of the whole process
+    if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+        warn!(
+            "Can't enter the background mode: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lower_priority() {
+    warn!("The priority can't be lowered on this platform, only the threads are capped");
+}
+
+// Seconds between two writes of the status file
+pub const DEFAULT_STATUS_INTERVAL: u64 = 10;
+
+// Writes the progress of the run to a small JSON file at a fixed interval, so monitors and
+// schedulers can follow long runs without parsing the logs
+pub struct StatusWriter {
+    // Final state of the run
+    stop: mpsc::Sender<&'static str>,
+    handle: JoinHandle<()>,
+}
+
+impl StatusWriter {
+    pub fn start(
+        path: PathBuf,
+        interval: Duration,
+        action: &str,
+        run_name: Option<String>,
+        tracker: RunTracker,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let action = action.to_string();
+        let started = Local::now();
+        let clock = Instant::now();
+        info!(
+            "Status written to {} every {} seconds",
+            path.display(),
+            interval.as_secs()
+        );
+        let handle = thread::spawn(move || {
+            let mut state = "running";
+            loop {
+                let status = tracker.status_json(
+                    &action,
+                    run_name.as_deref(),
+                    state,
+                    started,
+                    clock.elapsed(),
+                );
+                write_replacing(&path, &status).unwrap_or_else(|e| {
+                    warn!("Can't write the status file {}: {}", path.display(), e)
+                });
+                if state != "running" {
+                    break;
+                }
+                state = match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => "running",
+                    Ok(state) => state,
+                    // The run ended without finishing the writer, eg on an error
+                    Err(RecvTimeoutError::Disconnected) => "aborted",
+                };
+            }
+        });
+        StatusWriter { stop, handle }
+    }
+
+    // Write the final status and stop the writer
+    pub fn finish(self) {
+        self.end("finished");
+    }
+
+    // Write the status of a run stopped by a signal before all its files were done
+    pub fn stopped(self) {
+        self.end("stopped");
+    }
+
+    fn end(self, state: &'static str) {
+        let _ = self.stop.send(state);
+        if self.handle.join().is_err() {
+            warn!("The status writer stopped unexpectedly");
+        }
+    }
+}
+
+// Replace the file through a temporary file, readers never see a partial write
+fn write_replacing(path: &Path, content: &str) -> Result<()> {
+    let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    fs::write(&temp_path, content)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+// Paths longer than this fail on Windows unless they are in the verbatim \\?\ form
+pub const WINDOWS_MAX_PATH: usize = 260;
+
+// Path without the Windows verbatim prefix: \\?\C:\dest is C:\dest and \\?\UNC\server\share
+// is \\server\share. The output paths are joined with / which Windows doesn't normalize in a
+// verbatim path. std adds the prefix back when it opens or creates a path longer than MAX_PATH,
+// so the deep output paths and the UNC shares are written without it. Unchanged on other platforms
+pub fn simplified_path(path: PathBuf) -> PathBuf {
+    if !cfg!(windows) {
+        return path;
+    }
+    match strip_verbatim_prefix(&path.to_string_lossy()) {
+        Some(simplified) => PathBuf::from(simplified),
+        None => path,
+    }
+}
+
+// Plain form of a verbatim Windows path, None when it has none
+fn strip_verbatim_prefix(text: &str) -> Option<String> {
+    if let Some(share) = text.strip_prefix(r"\\?\UNC\") {
+        return Some(format!(r"\\{}", share));
+    }
+    match text.strip_prefix(r"\\?\") {
+        // Only drive paths, the verbatim volume GUID paths have no simpler form
+        Some(local) if local.as_bytes().get(1) == Some(&b':') => Some(local.to_string()),
+        _ => None,
+    }
 }
 
-fn check_given_path_exists(src_path: &PathBuf, dest_path: &PathBuf) -> Result<()> {
+pub fn check_given_path_exists(
+    src_path: &PathBuf,
+    dest_path: &PathBuf,
+    dry_run: bool,
+) -> Result<()> {
     // Source Path
     match canonicalize(src_path) {
         Ok(_) => (),
@@ -111,9 +1137,10 @@ fn check_given_path_exists(src_path: &PathBuf, dest_path: &PathBuf) -> Result<()
             exit(1)
         }
     }
-    // Destination Path
+    // Destination Path, a dry run doesn't create it
     match canonicalize(dest_path) {
         Ok(_) => (),
+        Err(_) if dry_run => info!("Dry run, {} is not created", dest_path.display()),
         Err(_) => create_dir_all(dest_path).unwrap_or_else(|_| {
             error!("Can't create dir: {}", dest_path.display());
             exit(1)
@@ -123,26 +1150,32 @@ fn check_given_path_exists(src_path: &PathBuf, dest_path: &PathBuf) -> Result<()
 }
 
 // For all non DICOM files, Copy them to a NON_DICOM directory in the destination path
-pub fn copy_non_dicom_files(each_file: &DirEntry, destination_path: &PathBuf) -> Result<()> {
+pub fn copy_non_dicom_files(
+    source: &dyn InstanceSource,
+    each_file: &Path,
+    destination_path: &Path,
+) -> Result<PathBuf> {
     let non_dicom_path: PathBuf =
-        PathBuf::from(format!("{}/NON_DICOM", &destination_path.to_string_lossy()));
+        PathBuf::from(format!("{}/NON_DICOM", &destination_path.to_string_lossy()))
+            .join(source.non_dicom_dir(each_file));
     if !non_dicom_path.exists() {
         create_dir_all(&non_dicom_path)?;
     }
-    let non_dicom_file_path = PathBuf::from(format!(
-        "{}/NON_DICOM/{}",
-        &destination_path.to_string_lossy(),
-        &each_file
+    let non_dicom_file_path = non_dicom_path.join(
+        each_file
             .file_name()
-            .to_str()
-            .expect("Failed to extract filename")
-    ));
-    copy(each_file.clone().into_path(), non_dicom_file_path)?;
-    Ok(())
+            .and_then(|name| name.to_str())
+            .expect("Failed to extract filename"),
+    );
+    std::io::copy(
+        &mut source.open_bytes(each_file)?,
+        &mut fs::File::create(&non_dicom_file_path)?,
+    )?;
+    Ok(non_dicom_file_path)
 }
 
-pub fn failed_case_copy(source_path: &PathBuf, dest_path: &PathBuf) -> Result<()> {
-    let failed_cases_path = format!("{}/FAILED_CASES", dest_path.display());
+pub fn failed_case_copy(source_path: &Path, dest_path: &Path) -> Result<PathBuf> {
+    let failed_cases_path = format!("{}/{}", dest_path.display(), FAILED_CASES_DIR);
     match canonicalize(failed_cases_path.clone()) {
         Ok(_) => (),
         Err(_) => create_dir_all(&failed_cases_path).unwrap_or_else(|_| {
@@ -161,8 +1194,8 @@ pub fn failed_case_copy(source_path: &PathBuf, dest_path: &PathBuf) -> Result<()
     );
 
     let final_failed_path = check_if_dup_exists(failed_cases_full_name);
-    fs::copy(source_path, final_failed_path)?;
-    Ok(())
+    fs::copy(source_path, &final_failed_path)?;
+    Ok(PathBuf::from(final_failed_path))
 }
 // Replace all non_alphanumeric characters with an underscore '_'
 pub fn replace_non_alphanumeric(input: &str) -> String {
@@ -195,18 +1228,103 @@ pub fn get_sanitized_tag_values(
             }
         }
     }
-    dicom_tags_values.insert("ImagePlane".to_string(), determine_plane(&dcm_obj)?);
+    dicom_tags_values.insert("ImagePlane".to_string(), determine_plane(dcm_obj)?);
+    dicom_tags_values.insert("SeriesClass".to_string(), classify_series(dcm_obj));
     Ok(dicom_tags_values)
 }
 
-// Check if the target directory exists and create a new one recursively if it does not exist
+// Create the target directory recursively if it does not exist
+// The directories created by the run are cached, so the files of a series cost no metadata
+// syscalls after the first one, which matters on NFS. Writer threads racing to create the same
+// directory are fine, create_dir_all succeeds when another thread created it first
 pub fn create_target_dir(dir_path: &String) -> Result<()> {
-    if !PathBuf::from(dir_path).exists() {
-        create_dir_all(PathBuf::from(dir_path))?
+    static CREATED_DIRS: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    let created_dirs = CREATED_DIRS.get_or_init(|| Mutex::new(HashSet::new()));
+    if created_dirs
+        .lock()
+        .expect("Failed to lock mutex")
+        .contains(dir_path)
+    {
+        return Ok(());
     }
+    create_dir_all(PathBuf::from(dir_path))?;
+    created_dirs
+        .lock()
+        .expect("Failed to lock mutex")
+        .insert(dir_path.clone());
     Ok(())
 }
 
+// Suffix of an output file whose name is already taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateSuffix {
+    // ~ after the file name, repeated until the name is free
+    #[default]
+    Tilde,
+    // _dupNN before the extension
+    Counter,
+    // _SOPInstanceUID before the extension, then _dupNN if that is taken too
+    SopUid,
+}
+
+impl FromStr for DuplicateSuffix {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "tilde" => Ok(DuplicateSuffix::Tilde),
+            "dup" => Ok(DuplicateSuffix::Counter),
+            "sop-uid" => Ok(DuplicateSuffix::SopUid),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of tilde, dup or sop-uid: {}",
+                value
+            )),
+        }
+    }
+}
+
+// Free output path with the duplicate suffix, the path is returned as it is if not taken
+pub fn unique_output_path(full_path: String, suffix: DuplicateSuffix, sop_uid: &str) -> String {
+    if !Path::new(&full_path).exists() {
+        return full_path;
+    }
+    let (stem, extension) = match full_path.rsplit_once('.') {
+        Some((stem, extension)) if !extension.contains('/') && !stem.ends_with('/') => {
+            (stem.to_string(), format!(".{}", extension))
+        }
+        _ => (full_path.clone(), String::new()),
+    };
+    let counter_path = |stem: &str| {
+        (1..)
+            .map(|count| format!("{}_dup{:02}{}", stem, count, extension))
+            .find(|path| !Path::new(path).exists())
+            .expect("Failed to find a free file name")
+    };
+    match suffix {
+        DuplicateSuffix::Tilde => check_if_dup_exists(full_path),
+        DuplicateSuffix::Counter => counter_path(&stem),
+        DuplicateSuffix::SopUid if sop_uid.is_empty() => counter_path(&stem),
+        DuplicateSuffix::SopUid => {
+            let uid_stem = format!("{}_{}", stem, sop_uid);
+            let uid_path = format!("{}{}", uid_stem, extension);
+            match Path::new(&uid_path).exists() {
+                true => counter_path(&uid_stem),
+                false => uid_path,
+            }
+        }
+    }
+}
+
+// SOPInstanceUID of the object, empty if not found
+pub fn sop_instance_uid(dcm_obj: &FileDicomObject<InMemDicomObject>) -> String {
+    dcm_obj
+        .element(tags::SOP_INSTANCE_UID)
+        .ok()
+        .and_then(|element| element.to_str().ok())
+        .map(|uid| uid.trim_end_matches('\0').trim().to_string())
+        .unwrap_or_default()
+}
+
 // Check if a file already exist and add ~ to end of the file if it does recursively.
 pub fn check_if_dup_exists(full_path: String) -> String {
     let new_path = full_path;
@@ -214,138 +1332,509 @@ pub fn check_if_dup_exists(full_path: String) -> String {
         let change_path = format!("{}~", new_path.clone());
         check_if_dup_exists(change_path)
     } else {
-        return new_path;
+        new_path
     }
 }
 
-// Change certain tags to the given ID and add deidentified tags.
-// Returns a cloned dicom object with modified values
-pub fn mask_tags_with_id(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-    patient_deid: String,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    let p_value = dicom_vr_corrected_value(VR::PN, &patient_deid)?;
-    // Mask all PN values with the given ID
-    dcm_obj = mask_all_vr(dcm_obj.clone(), VR::PN, p_value.clone())?;
+// Tags of the modified object that still hold the same value as in the source object
+pub fn unchanged_tags(
+    source_obj: &FileDicomObject<InMemDicomObject>,
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+) -> HashSet<Tag> {
+    dcm_obj
+        .iter()
+        .filter(|element| source_obj.element(element.tag()).ok() == Some(*element))
+        .map(|element| element.tag())
+        .collect()
+}
 
-    for each_v in DICOM_TAGS_CHANGE {
-        let p_value = dicom_vr_corrected_value(each_v.1, &patient_deid)?;
-        dcm_obj.put(DataElement::new(each_v.0, each_v.1, p_value.clone()));
-    }
-    // Add deidentified Info
-    dcm_obj.put(DataElement::new(
-        tags::DEIDENTIFICATION_METHOD,
-        VR::LO,
-        p_value.clone(),
-    ));
-    dcm_obj.put(DataElement::new(
-        tags::PATIENT_IDENTITY_REMOVED,
-        VR::CS,
-        p_value,
-    ));
-    Ok(dcm_obj)
+// What the transform did to a top level tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagChange {
+    Added,
+    Replaced,
+    Removed,
+    Kept,
 }
 
-pub fn tags_to_mask(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-    patient_deid: String,
-    mask_config_list: Vec<DataDictionaryEntryRef<'static>>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    for each_tag in mask_config_list {
-        let each_tag_tag = each_tag.tag.inner();
-        let each_tag_vr: VR = each_tag.vr.relaxed();
-        let value = dicom_vr_corrected_value(each_tag_vr, &patient_deid)?;
-        match dcm_obj.put(DataElement::new(each_tag_tag, each_tag_vr, value.clone())) {
-            Some(_) => (),
-            None => error!("Mask Tag : Failed to mask tag {:?}", each_tag_tag),
-        }
-
-        fn mask_sq_vrs(
-            data_element: &DataElement<InMemDicomObject>,
-            // dcm_obj: &mut FileDicomObject<InMemDicomObject>,
-            value: PrimitiveValue,
-            tag_to_check: Tag,
-        ) {
-            for each_sq_element in data_element.items().into_iter() {
-                for each_element in each_sq_element.into_iter() {
-                    for sq_inner_element in each_element.to_owned() {
-                        if sq_inner_element.vr() == VR::SQ {
-                            mask_sq_vrs(
-                                &sq_inner_element,
-                                // mut dcm_obj,
-                                value.clone(),
-                                tag_to_check,
-                            );
-                        }
-                        if sq_inner_element.tag() == tag_to_check {
-                            // sq_inner_element.update_value(|e| {
-                            //     e.primitive_mut().unwrap().truncate(0);
-                            // });
-                            // TODO
-                        }
-                    }
-                }
-            }
-        }
+// Instances of a run by what the transform did to a tag
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TagCounts {
+    pub removed: u64,
+    pub replaced: u64,
+    pub added: u64,
+    pub kept: u64,
+}
 
-        for data_element in &dcm_obj {
-            if data_element.vr() == VR::SQ {
-                mask_sq_vrs(data_element, value.clone(), each_tag_tag);
-            }
-        }
+impl TagCounts {
+    pub fn changed(&self) -> u64 {
+        self.removed + self.replaced + self.added
     }
 
-    Ok(dcm_obj)
-}
+    // Instances without the tag in the source, the added ones included
+    pub fn missing(&self, instances: u64) -> u64 {
+        instances.saturating_sub(self.removed + self.replaced + self.kept)
+    }
 
-pub fn tags_to_add(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-    add_config_list: HashMap<String, String>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    for each_element in add_config_list {
-        let config_tag = each_element.0;
-        let config_value = each_element.1;
-        let (each_tag, each_vr) = extract_tag_vr_from_str(&config_tag)?;
-        let value = dicom_vr_corrected_value(each_vr, &config_value)?;
-        dcm_obj.put(DataElement::new(each_tag, each_vr, value));
+    pub fn describe(&self, instances: u64) -> String {
+        format!(
+            "removed {}, replaced {}, added {}, kept {}, missing {}",
+            self.removed,
+            self.replaced,
+            self.added,
+            self.kept,
+            self.missing(instances)
+        )
     }
-    Ok(dcm_obj)
 }
 
-pub fn tags_to_delete(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-    delete_config_list: Vec<DataDictionaryEntryRef<'static>>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    for each_tag in delete_config_list {
-        match dcm_obj.remove_element(each_tag.tag.inner()) {
-            true => (),
-            false => debug!("Delete Tag: {:?} not valid/found", each_tag.tag.inner()),
-        }
+// Keyword of a tag, or its number when it is not in the dictionary
+pub fn tag_keyword(tag: Tag) -> String {
+    match StandardDataDictionary.by_tag(tag) {
+        Some(entry) => entry.alias.to_string(),
+        None => format!("{}", tag),
     }
-    Ok(dcm_obj)
 }
 
-pub fn delete_private_tags(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    fn is_private(tag: Tag) -> bool {
-        return tag.group() % 2 == 1;
-    }
+// Top level tags of the source and the transformed object with what the transform did to them,
+// in tag order. A sequence is replaced as a whole when one of its items changed
+pub fn tag_changes(
+    source_obj: &FileDicomObject<InMemDicomObject>,
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+) -> Vec<(Tag, TagChange)> {
+    let mut changes: Vec<(Tag, TagChange)> = dcm_obj
+        .iter()
+        .map(|element| match source_obj.element(element.tag()) {
+            Ok(source_element) if source_element == element => (element.tag(), TagChange::Kept),
+            Ok(_) => (element.tag(), TagChange::Replaced),
+            Err(_) => (element.tag(), TagChange::Added),
+        })
+        .collect();
+    changes.extend(
+        source_obj
+            .iter()
+            .filter(|element| dcm_obj.element(element.tag()).is_err())
+            .map(|element| (element.tag(), TagChange::Removed)),
+    );
+    changes.sort_by_key(|(tag, _)| *tag);
+    changes
+}
 
-    let mut private_tags: Vec<Tag> = vec![];
-    for each_element in dcm_obj.clone() {
-        collect_tags(each_element, &mut private_tags);
-    }
+// Tags added (+), modified (~) or removed (-) by the transform, named by their keyword
+pub fn changed_tags(changes: &[(Tag, TagChange)]) -> Vec<String> {
+    changes
+        .iter()
+        .filter_map(|(tag, change)| match change {
+            TagChange::Added => Some(format!("+{}", tag_keyword(*tag))),
+            TagChange::Replaced => Some(format!("~{}", tag_keyword(*tag))),
+            TagChange::Removed => Some(format!("-{}", tag_keyword(*tag))),
+            TagChange::Kept => None,
+        })
+        .collect()
+}
 
-    fn collect_tags(data_element: DataElement<InMemDicomObject>, private_tags: &mut Vec<Tag>) {
-        let tag = data_element.tag();
-        if is_private(tag) {
-            private_tags.push(tag);
+// Write the DICOM object to the given writer
+// Unchanged top level elements are copied from the source file bytes as they are, only the
+// modified elements and the file meta group are encoded again
+// Falls back to encoding the whole object when the source transfer syntax can't be spliced
+// The source file is read again when its bytes were not kept
+pub fn write_dicom_file<W: std::io::Write>(
+    source_path: &Path,
+    source_bytes: Option<&[u8]>,
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    unchanged: &HashSet<Tag>,
+    to: W,
+) -> Result<()> {
+    let ts_uid = dcm_obj.meta().transfer_syntax().to_string();
+    // A transcoded object has nothing to splice from the source
+    let transcoded = read_transfer_syntax(source_path).is_some_and(|source_ts| source_ts != ts_uid);
+    let spliced = spliceable_transfer_syntax(&ts_uid)
+        .filter(|_| !transcoded)
+        .and_then(|ts| {
+            let bytes = match source_bytes {
+                Some(bytes) => Cow::Borrowed(bytes),
+                None => Cow::Owned(fs::read(source_path).ok()?),
+            };
+            let explicit_vr = ts_uid != IMPLICIT_VR_LITTLE_ENDIAN;
+            scan_source_elements(&bytes, explicit_vr).map(|ranges| (bytes, ranges, ts))
+        });
+    let (source_bytes, ranges, ts) = match spliced {
+        Some(spliced) => spliced,
+        None => {
+            debug!("Encoding the whole object for {}", source_path.display());
+            dcm_obj.write_all(to)?;
+            return Ok(());
+        }
+    };
+
+    let mut to = BufWriter::new(to);
+    write_spliced_elements(&mut to, dcm_obj, unchanged, &source_bytes, &ranges, ts)?;
+    to.flush()?;
+    Ok(())
+}
+
+// Write the preamble, the file meta group and the dataset elements
+// Unchanged elements are copied from the source bytes, the others are encoded with the transfer syntax
+fn write_spliced_elements<W: std::io::Write>(
+    to: &mut W,
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    unchanged: &HashSet<Tag>,
+    source_bytes: &[u8],
+    ranges: &HashMap<Tag, Range<usize>>,
+    ts: &TransferSyntax,
+) -> Result<()> {
+    // Text of the re-encoded elements follows the charset of the dataset
+    let charset = dcm_obj
+        .element(tags::SPECIFIC_CHARACTER_SET)
+        .ok()
+        .and_then(|element| element.to_str().ok().map(|s| s.to_string()))
+        .and_then(|codes| {
+            codes
+                .split('\\')
+                .map(str::trim)
+                .find(|code| !code.is_empty())
+                .and_then(SpecificCharacterSet::from_code)
+        })
+        .unwrap_or_default();
+    // Groups with modified elements, their retired group length would be stale
+    let modified_groups: HashSet<u16> = dcm_obj
+        .iter()
+        .map(|element| element.tag())
+        .filter(|tag| !unchanged.contains(tag) || !ranges.contains_key(tag))
+        .map(|tag| tag.group())
+        .collect();
+
+    to.write_all(&[0_u8; 128])?;
+    to.write_all(b"DICM")?;
+    dcm_obj.meta().write(&mut *to)?;
+    for element in dcm_obj.iter() {
+        let tag = element.tag();
+        match ranges.get(&tag) {
+            Some(range) if unchanged.contains(&tag) => {
+                if tag.element() == 0 && modified_groups.contains(&tag.group()) {
+                    continue;
+                }
+                to.write_all(&source_bytes[range.clone()])?;
+            }
+            _ => {
+                let mut single = InMemDicomObject::new_empty();
+                single.put(element.clone());
+                single.write_dataset_with_ts_cs(&mut *to, ts, charset.clone())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Write an object read up to the pixel data, eg with open_source_file, to the given writer
+// The header is written like write_dicom_file, the pixel data and the elements after it are
+// copied from the source file in chunks so they are never held in memory
+pub fn write_streamed_dicom_file<W: std::io::Write>(
+    source_path: &Path,
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    unchanged: &HashSet<Tag>,
+    to: W,
+) -> Result<()> {
+    let ts_uid = dcm_obj.meta().transfer_syntax().to_string();
+    let ts = spliceable_transfer_syntax(&ts_uid).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Transfer syntax {} can't be streamed",
+            ts_uid.trim_end_matches('\0')
+        )
+    })?;
+    let explicit_vr = ts_uid != IMPLICIT_VR_LITTLE_ENDIAN;
+    let (header, pixel_data_offset) = read_source_header(source_path, explicit_vr)?;
+    let ranges = scan_source_elements(&header, explicit_vr)
+        .ok_or_else(|| anyhow::anyhow!("Can't walk the header of {}", source_path.display()))?;
+
+    let mut to = BufWriter::new(to);
+    write_spliced_elements(&mut to, dcm_obj, unchanged, &header, &ranges, ts)?;
+    let mut source = fs::File::open(source_path)?;
+    source.seek(SeekFrom::Start(pixel_data_offset as u64))?;
+    std::io::copy(&mut source, &mut to)?;
+    to.flush()?;
+    Ok(())
+}
+
+// The elements of little endian files without a deflated dataset can be walked and spliced
+fn spliceable_transfer_syntax(ts_uid: &str) -> Option<&'static TransferSyntax> {
+    TransferSyntaxRegistry.get(ts_uid).filter(|ts| {
+        ts.endianness() == Endianness::Little && !matches!(ts.codec(), Codec::Dataset(_))
+    })
+}
+
+// Open a source file, streamed files are only read up to the pixel data
+pub fn open_source_file(path: &Path, streamed: bool) -> Result<FileDicomObject<InMemDicomObject>> {
+    let options = dicom::object::OpenFileOptions::new();
+    let dcm_obj = match streamed {
+        true => {
+            debug!("Streaming the pixel data of {}", path.display());
+            options.read_until(tags::PIXEL_DATA).open_file(path)?
+        }
+        false => options.read_all().open_file(path)?,
+    };
+    Ok(dcm_obj)
+}
+
+// Open a source file read whole, its bytes are returned to copy the unchanged elements from
+pub fn read_source_file(path: &Path) -> Result<(FileDicomObject<InMemDicomObject>, SourceBytes)> {
+    let bytes: SourceBytes = fs::read(path)?.into();
+    let dcm_obj = dicom::object::OpenFileOptions::new()
+        .read_all()
+        .from_reader(&bytes[..])?;
+    Ok((dcm_obj, bytes))
+}
+
+// Check if an instance carries pixel data. Streamed datasets are only read up to the pixel data,
+// so the header of their source file is walked to find it
+pub fn has_pixel_data(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    source_path: &Path,
+    streamed: bool,
+) -> bool {
+    let pixel_tags = [
+        tags::PIXEL_DATA,
+        tags::FLOAT_PIXEL_DATA,
+        tags::DOUBLE_FLOAT_PIXEL_DATA,
+    ];
+    if pixel_tags.iter().any(|tag| dcm_obj.element(*tag).is_ok()) {
+        return true;
+    }
+    let explicit_vr = dcm_obj.meta().transfer_syntax() != IMPLICIT_VR_LITTLE_ENDIAN;
+    let file_len = fs::metadata(source_path).map_or(0, |metadata| metadata.len() as usize);
+    streamed
+        && read_source_header(source_path, explicit_vr).is_ok_and(|(_, offset)| offset < file_len)
+}
+
+// Bytes of the source file before the top level pixel data and the offset of the pixel data
+// The file is read in growing chunks until the walk of the header reaches the pixel data
+fn read_source_header(source_path: &Path, explicit_vr: bool) -> Result<(Vec<u8>, usize)> {
+    let file_len = fs::metadata(source_path)?.len() as usize;
+    let mut chunk = 1 << 20;
+    loop {
+        let mut header = Vec::with_capacity(chunk.min(file_len));
+        fs::File::open(source_path)?
+            .take(chunk as u64)
+            .read_to_end(&mut header)?;
+        if let Some(offset) = find_pixel_data(&header, explicit_vr) {
+            header.truncate(offset);
+            return Ok((header, offset));
+        }
+        // Without pixel data the whole file is the header
+        if header.len() >= file_len {
+            let file_len = header.len();
+            return Ok((header, file_len));
+        }
+        chunk *= 4;
+    }
+}
+
+// Offset of the top level pixel data element, None if it isn't within the bytes
+fn find_pixel_data(bytes: &[u8], explicit_vr: bool) -> Option<usize> {
+    if bytes.get(128..132)? != b"DICM" {
+        return None;
+    }
+    let mut pos = 132;
+    while read_tag(bytes, pos)?.group() == 0x0002 {
+        pos = skip_element(bytes, pos, true)?;
+    }
+    loop {
+        if read_tag(bytes, pos)? == tags::PIXEL_DATA {
+            return Some(pos);
+        }
+        pos = skip_element(bytes, pos, explicit_vr)?;
+    }
+}
+
+// Byte ranges of the top level dataset elements of a little endian DICOM file
+// Returns None if the file can't be walked, eg no DICM magic code or a truncated element
+fn scan_source_elements(bytes: &[u8], explicit_vr: bool) -> Option<HashMap<Tag, Range<usize>>> {
+    if bytes.get(128..132)? != b"DICM" {
+        return None;
+    }
+    // The file meta group is always explicit VR little endian
+    let mut pos = 132;
+    while pos < bytes.len() && read_tag(bytes, pos)?.group() == 0x0002 {
+        pos = skip_element(bytes, pos, true)?;
+    }
+    let mut ranges = HashMap::new();
+    while pos < bytes.len() {
+        let tag = read_tag(bytes, pos)?;
+        let end = skip_element(bytes, pos, explicit_vr)?;
+        ranges.insert(tag, pos..end);
+        pos = end;
+    }
+    Some(ranges)
+}
+
+pub(crate) fn read_u16(bytes: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(pos..pos + 2)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn read_u32(bytes: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(pos..pos + 4)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn read_u64(bytes: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(pos..pos + 8)?.try_into().ok()?,
+    ))
+}
+
+fn read_tag(bytes: &[u8], pos: usize) -> Option<Tag> {
+    Some(Tag(read_u16(bytes, pos)?, read_u16(bytes, pos + 2)?))
+}
+
+// Returns the end position of the element starting at pos
+fn skip_element(bytes: &[u8], pos: usize, explicit_vr: bool) -> Option<usize> {
+    const UNDEFINED_LENGTH: u32 = 0xFFFF_FFFF;
+    let (vr, length, value_start) = if explicit_vr {
+        let vr = bytes.get(pos + 4..pos + 6)?;
+        match vr {
+            b"OB" | b"OD" | b"OF" | b"OL" | b"OV" | b"OW" | b"SQ" | b"SV" | b"UC" | b"UN"
+            | b"UR" | b"UT" | b"UV" => (vr, read_u32(bytes, pos + 8)?, pos + 12),
+            _ => (vr, read_u16(bytes, pos + 6)? as u32, pos + 8),
+        }
+    } else {
+        (&b"UN"[..], read_u32(bytes, pos + 4)?, pos + 8)
+    };
+    if length != UNDEFINED_LENGTH {
+        let end = value_start.checked_add(length as usize)?;
+        return (end <= bytes.len()).then_some(end);
+    }
+    // Undefined length sequence or encapsulated pixel data, walk the items
+    // The items of an explicit VR UN sequence are implicit VR
+    let items_explicit_vr = explicit_vr && vr != b"UN";
+    let mut pos = value_start;
+    loop {
+        match read_tag(bytes, pos)? {
+            Tag(0xFFFE, 0xE0DD) => return Some(pos + 8),
+            Tag(0xFFFE, 0xE000) => {
+                let item_length = read_u32(bytes, pos + 4)?;
+                pos += 8;
+                if item_length != UNDEFINED_LENGTH {
+                    pos = pos.checked_add(item_length as usize)?;
+                    continue;
+                }
+                while read_tag(bytes, pos)? != Tag(0xFFFE, 0xE00D) {
+                    pos = skip_element(bytes, pos, items_explicit_vr)?;
+                }
+                pos += 8;
+            }
+            _ => return None,
+        }
+    }
+}
+
+// DeidentificationMethod of the instances masked with the AnonID
+const ANON_MASK_METHOD: &str = "AnonID masking of identifiers, dates and private tags";
+
+// Change certain tags to the given ID and add deidentified tags.
+// Returns a cloned dicom object with modified values
+pub fn mask_tags_with_id(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+    patient_deid: String,
+) -> Result<FileDicomObject<InMemDicomObject>> {
+    let p_value = vr_dummy_value(VR::PN, &patient_deid);
+    // Mask all PN values with the given ID
+    dcm_obj = mask_all_vr(dcm_obj.clone(), VR::PN, p_value)?;
+
+    mask_nested_tags(&mut dcm_obj, &patient_deid);
+    for each_v in DICOM_TAGS_CHANGE {
+        let p_value = vr_dummy_value(each_v.1, &patient_deid);
+        dcm_obj.put(DataElement::new(each_v.0, each_v.1, p_value));
+    }
+    // Add deidentified Info
+    confidentiality::put_deidentification_method(&mut dcm_obj, ANON_MASK_METHOD);
+    Ok(dcm_obj)
+}
+
+// Replace the tags of DICOM_TAGS_CHANGE found in the items of sequences, like the PatientID of
+// OtherPatientIDsSequence. They are only added at the top level
+fn mask_nested_tags(dcm_obj: &mut InMemDicomObject, patient_deid: &str) {
+    let sequences: Vec<Tag> = dcm_obj
+        .iter()
+        .filter(|element| element.vr() == VR::SQ)
+        .map(|element| element.tag())
+        .collect();
+    for tag in sequences {
+        dcm_obj.update_value(tag, |value| {
+            if let Some(items) = value.items_mut() {
+                for item in items.iter_mut() {
+                    for (tag, vr) in DICOM_TAGS_CHANGE {
+                        if item.get(tag).is_some() {
+                            item.put(DataElement::new(tag, vr, vr_dummy_value(vr, patient_deid)));
+                        }
+                    }
+                    mask_nested_tags(item, patient_deid);
+                }
+            }
+        });
+    }
+}
+
+pub fn tags_to_mask(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+    patient_deid: String,
+    mask_config_list: Vec<TagPath>,
+) -> Result<FileDicomObject<InMemDicomObject>> {
+    for each_path in mask_config_list {
+        let value = vr_dummy_value(each_path.vr, &patient_deid);
+        each_path.mask(&mut dcm_obj, value);
+    }
+    Ok(dcm_obj)
+}
+
+pub fn tags_to_add(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+    add_config_list: HashMap<String, String>,
+) -> Result<FileDicomObject<InMemDicomObject>> {
+    for each_element in add_config_list {
+        let config_path = TagPath::from_str(&each_element.0)?;
+        let config_value = each_element.1;
+        let value = dicom_vr_corrected_value(config_path.vr, &config_value)?;
+        config_path.put(&mut dcm_obj, value);
+    }
+    Ok(dcm_obj)
+}
+
+pub fn tags_to_delete(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+    delete_config_list: Vec<TagPath>,
+) -> Result<FileDicomObject<InMemDicomObject>> {
+    for each_path in delete_config_list {
+        if each_path.delete(&mut dcm_obj) == 0 {
+            debug!("Delete Tag: {} not valid/found", each_path);
+        }
+    }
+    Ok(dcm_obj)
+}
+
+pub fn delete_private_tags(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+) -> Result<FileDicomObject<InMemDicomObject>> {
+    fn is_private(tag: Tag) -> bool {
+        tag.group() % 2 == 1
+    }
+
+    let mut private_tags: Vec<Tag> = vec![];
+    for each_element in dcm_obj.clone() {
+        collect_tags(each_element, &mut private_tags);
+    }
+
+    fn collect_tags(data_element: DataElement<InMemDicomObject>, private_tags: &mut Vec<Tag>) {
+        let tag = data_element.tag();
+        if is_private(tag) {
+            private_tags.push(tag);
         };
 
         if data_element.vr() == VR::SQ {
             for each_sq_element in data_element.items().into_iter() {
-                for each_element in each_sq_element.into_iter() {
+                for each_element in each_sq_element.iter() {
                     for each_tag in each_element {
                         collect_tags(each_tag.to_owned(), private_tags)
                     }
@@ -363,28 +1852,72 @@ pub fn delete_private_tags(
     Ok(dcm_obj)
 }
 
-pub fn anon_dicom_uids(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    let uid_tag_list = [
-        "SOPInstanceUID".to_string(),
-        "StudyInstanceUID".to_string(),
-        "SeriesInstanceUID".to_string(),
-        "FrameOfReferenceUID".to_string(),
-    ];
-    let anon_uid_prefix: Vec<_> = "1.2.999.999999.9999.9.9.9.9999".split(".").collect();
-
-    for each_uid in uid_tag_list {
-        let (each_tag, each_vr) = extract_tag_vr_from_str(&each_uid)?;
-        let org_uid_val = dcm_obj.element(each_tag)?.to_str()?;
-        let org_uid_vec: Vec<_> = org_uid_val.split(".").collect();
-        let mut new_uid_parts = anon_uid_prefix.clone();
-        new_uid_parts.extend_from_slice(&org_uid_vec[8..]);
-        let new_uid_val = new_uid_parts.join(".");
-        let value = dicom_vr_corrected_value(each_vr, &new_uid_val)?;
-        dcm_obj.put(DataElement::new(each_tag, each_vr, value));
+// Curves (50xx, audio included), overlay data and comments (60xx,3000/4000) and the retired
+// results group (4008), removed by the basic profile of PS3.15
+pub fn is_legacy_group(tag: Tag) -> bool {
+    let repeating = |base: u16| (base..=base + 0x1E).contains(&tag.group()) && tag.group() % 2 != 1;
+    (repeating(0x6000) && matches!(tag.element(), 0x3000 | 0x4000))
+        || repeating(0x5000)
+        || tag.group() == 0x4008
+}
+
+// Retired attributes that PS3.15 Table E.1-1 removes, outside the legacy groups
+pub const RETIRED_IDENTIFYING_TAGS: [Tag; 33] = [
+    Tag(0x0008, 0x0024), // OverlayDate, retired
+    Tag(0x0008, 0x0025), // CurveDate, retired
+    Tag(0x0008, 0x0034), // OverlayTime, retired
+    Tag(0x0008, 0x0035), // CurveTime, retired
+    Tag(0x0008, 0x1000), // NetworkID, retired
+    Tag(0x0008, 0x4000), // IdentifyingComments, retired
+    Tag(0x0010, 0x1000), // OtherPatientIDs, retired
+    Tag(0x0010, 0x1050), // InsurancePlanIdentification, retired
+    Tag(0x0010, 0x1090), // MedicalRecordLocator, retired
+    Tag(0x0018, 0x4000), // AcquisitionComments, retired
+    Tag(0x0020, 0x3401), // ModifyingDeviceID, retired
+    Tag(0x0020, 0x3403), // ModifiedImageDate, retired
+    Tag(0x0020, 0x3404), // ModifyingDeviceManufacturer, retired
+    Tag(0x0020, 0x3405), // ModifiedImageTime, retired
+    Tag(0x0020, 0x3406), // ModifiedImageDescription, retired
+    Tag(0x0028, 0x4000), // ImagePresentationComments, retired
+    Tag(0x0032, 0x0012), // StudyIDIssuer, retired
+    Tag(0x0032, 0x0032), // StudyVerifiedDate, retired
+    Tag(0x0032, 0x0033), // StudyVerifiedTime, retired
+    Tag(0x0032, 0x0034), // StudyReadDate, retired
+    Tag(0x0032, 0x0035), // StudyReadTime, retired
+    Tag(0x0032, 0x1030), // ReasonForStudy, retired
+    Tag(0x0032, 0x4000), // StudyComments, retired
+    Tag(0x0038, 0x0004), // ReferencedPatientAliasSequence, retired
+    Tag(0x0038, 0x0011), // IssuerOfAdmissionID, retired
+    Tag(0x0038, 0x001A), // ScheduledAdmissionDate, retired
+    Tag(0x0038, 0x001B), // ScheduledAdmissionTime, retired
+    Tag(0x0038, 0x001C), // ScheduledDischargeDate, retired
+    Tag(0x0038, 0x001D), // ScheduledDischargeTime, retired
+    Tag(0x0038, 0x001E), // ScheduledPatientInstitutionResidence, retired
+    Tag(0x0038, 0x0030), // DischargeDate, retired
+    Tag(0x0038, 0x0032), // DischargeTime, retired
+    Tag(0x0038, 0x0040), // DischargeDiagnosisDescription, retired
+];
+
+// Remove the legacy groups and the retired identifying attributes, in the sequence items too
+// Returns the number of removed elements
+pub fn delete_legacy_tags(dcm_obj: &mut InMemDicomObject) -> usize {
+    let elements: Vec<(Tag, VR)> = dcm_obj.iter().map(|e| (e.tag(), e.vr())).collect();
+    let mut removed = 0;
+    for (tag, vr) in elements {
+        if is_legacy_group(tag) || RETIRED_IDENTIFYING_TAGS.contains(&tag) {
+            dcm_obj.remove_element(tag);
+            removed += 1;
+        } else if vr == VR::SQ {
+            dcm_obj.update_value(tag, |value| {
+                if let Some(items) = value.items_mut() {
+                    items
+                        .iter_mut()
+                        .for_each(|item| removed += delete_legacy_tags(item));
+                }
+            });
+        }
     }
-    Ok(dcm_obj)
+    removed
 }
 
 pub fn mask_all_vr(
@@ -392,16 +1925,56 @@ pub fn mask_all_vr(
     vr: VR,
     val: PrimitiveValue,
 ) -> Result<FileDicomObject<InMemDicomObject>> {
-    for each_element in dcm_obj.clone() {
-        if each_element.header().vr() == vr {
+    mask_vr_in(&mut dcm_obj, vr, &val);
+    Ok(dcm_obj)
+}
+
+// Replace every value of the VR, in the items of sequences too
+fn mask_vr_in(dcm_obj: &mut InMemDicomObject, vr: VR, val: &PrimitiveValue) {
+    let elements: Vec<(Tag, VR, u32)> = dcm_obj
+        .iter()
+        .map(|element| (element.tag(), element.vr(), element.value().multiplicity()))
+        .collect();
+    for (tag, element_vr, multiplicity) in elements {
+        if element_vr == VR::SQ {
+            dcm_obj.update_value(tag, |value| {
+                if let Some(items) = value.items_mut() {
+                    items.iter_mut().for_each(|item| mask_vr_in(item, vr, val));
+                }
+            });
+        } else if element_vr == vr {
             dcm_obj.put(DataElement::new(
-                each_element.tag(),
-                each_element.vr(),
-                val.clone(),
+                tag,
+                vr,
+                with_multiplicity(val.clone(), vr, multiplicity),
             ));
         }
     }
-    Ok(dcm_obj)
+}
+
+// Repeat a single dummy value to the multiplicity of the value it replaces, strict validators
+// reject a multi-valued element masked to one value. Binary VRs and empty values are kept as given
+pub fn with_multiplicity(value: PrimitiveValue, vr: VR, multiplicity: u32) -> PrimitiveValue {
+    let binary = matches!(
+        vr,
+        VR::OB | VR::OD | VR::OF | VR::OL | VR::OV | VR::OW | VR::UN
+    );
+    if binary || multiplicity < 2 || value.multiplicity() != 1 {
+        return value;
+    }
+    let count = multiplicity as usize;
+    match value {
+        PrimitiveValue::Strs(values) => PrimitiveValue::Strs(vec![values[0].clone(); count].into()),
+        PrimitiveValue::I16(values) => PrimitiveValue::I16(vec![values[0]; count].into()),
+        PrimitiveValue::U16(values) => PrimitiveValue::U16(vec![values[0]; count].into()),
+        PrimitiveValue::I32(values) => PrimitiveValue::I32(vec![values[0]; count].into()),
+        PrimitiveValue::U32(values) => PrimitiveValue::U32(vec![values[0]; count].into()),
+        PrimitiveValue::I64(values) => PrimitiveValue::I64(vec![values[0]; count].into()),
+        PrimitiveValue::U64(values) => PrimitiveValue::U64(vec![values[0]; count].into()),
+        PrimitiveValue::F32(values) => PrimitiveValue::F32(vec![values[0]; count].into()),
+        PrimitiveValue::F64(values) => PrimitiveValue::F64(vec![values[0]; count].into()),
+        value => value,
+    }
 }
 
 pub fn mask_vr(
@@ -409,9 +1982,9 @@ pub fn mask_vr(
     vr_list: Vec<VR>,
     val: String,
 ) -> Result<FileDicomObject<InMemDicomObject>> {
-    let p_value = dicom_value!(Strs, [val]);
     for each_vr in vr_list {
-        dcm_obj = mask_all_vr(dcm_obj.clone(), each_vr, p_value.clone())?;
+        let p_value = vr_dummy_value(each_vr, &val);
+        dcm_obj = mask_all_vr(dcm_obj.clone(), each_vr, p_value)?;
     }
     Ok(dcm_obj)
 }
@@ -456,7 +2029,7 @@ pub fn generate_dicom_file_name(
 // Generate the path for the dicom files
 pub fn generate_dicom_file_path(
     dicom_tags_values: HashMap<String, String>,
-    destination_path: &PathBuf,
+    destination_path: &Path,
 ) -> Result<String> {
     let temp_trimmed_study_uid = dicom_tags_values
         .get("StudyInstanceUID")
@@ -504,81 +2077,3362 @@ pub fn generate_dicom_file_path(
             .trim()
     );
 
-    create_target_dir(&dir_path)?;
     Ok(dir_path)
 }
 
+// Values longer than this are cut in the output paths
+const TEMPLATE_VALUE_MAX: usize = 64;
+
+// Output path or file name with {Tag} placeholders, eg {PatientID}/{StudyDate}_{StudyDescription}
+// {Tag:fallback} gives the value of a missing or empty tag, NoValue_Tag without a fallback
+// ImagePlane and SeriesClass are the values derived by get_sanitized_tag_values
+#[derive(Debug, Clone)]
+pub struct OutputTemplate {
+    template: String,
+    // Placeholder, tag name, tag and fallback
+    tokens: Vec<(String, String, Option<Tag>, Option<String>)>,
+}
+
+impl FromStr for OutputTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self> {
+        static TOKEN: OnceLock<Regex> = OnceLock::new();
+        let token = TOKEN
+            .get_or_init(|| Regex::new(r"\{(\w+)(?::([^{}]*))?\}").expect("Invalid token pattern"));
+        let mut tokens = vec![];
+        for capture in token.captures_iter(template) {
+            let name = capture[1].to_string();
+            let tag = match name.as_str() {
+                "ImagePlane" | "SeriesClass" => None,
+                _ => match StandardDataDictionary.by_name(&name) {
+                    Some(entry) => Some(entry.tag.inner()),
+                    None => return Err(anyhow::anyhow!("Unknown tag in the template: {}", name)),
+                },
+            };
+            let fallback = capture.get(2).map(|fallback| fallback.as_str().to_string());
+            tokens.push((capture[0].to_string(), name, tag, fallback));
+        }
+        // The values are sanitized, the fixed part is checked here
+        let fixed = token.replace_all(template, "X");
+        if fixed.trim().is_empty()
+            || fixed.starts_with('/')
+            || fixed.contains(['{', '}', '\\'])
+            || fixed.split('/').any(|part| part.is_empty() || part == "..")
+        {
+            return Err(anyhow::anyhow!(
+                "Should be a relative path of {{Tag}} placeholders and text: {}",
+                template
+            ));
+        }
+        Ok(OutputTemplate {
+            template: template.to_string(),
+            tokens,
+        })
+    }
+}
+
+impl std::fmt::Display for OutputTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+impl OutputTemplate {
+    // Replace the placeholders by the values of the output instance
+    pub fn render(
+        &self,
+        dcm_obj: &InMemDicomObject,
+        dicom_tags_values: &HashMap<String, String>,
+    ) -> String {
+        let mut rendered = self.template.clone();
+        for (placeholder, name, tag, fallback) in &self.tokens {
+            let value = match tag {
+                Some(tag) => dcm_obj
+                    .element(*tag)
+                    .ok()
+                    .and_then(|element| element.to_str().ok())
+                    .map(|value| value.trim_end_matches('\0').trim().to_string()),
+                None => dicom_tags_values.get(name).cloned(),
+            }
+            .filter(|value| !value.is_empty());
+            let value = match (value, fallback) {
+                (Some(value), _) => value,
+                (None, Some(fallback)) => fallback.clone(),
+                (None, None) => format!("NoValue_{}", name),
+            };
+            rendered = rendered.replacen(placeholder, &template_value(&value), 1);
+        }
+        rendered
+    }
+}
+
+// Path safe value, the separators, spaces and ^ of a name become _
+fn template_value(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, '-' | '.') {
+                true => c,
+                false => '_',
+            },
+        )
+        .take(TEMPLATE_VALUE_MAX)
+        .collect();
+    // No hidden files or parent directories
+    match value.trim_start_matches('.') {
+        "" => "_".to_string(),
+        value => value.to_string(),
+    }
+}
+
+// Quote the text as a JSON string
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 pub fn print_status(
     total_len: u64,
     total_proc_failed_files: u64,
     total_non_dcm_files: u64,
+    total_skipped_files: u64,
+    total_incomplete_files: u64,
     action: String,
 ) -> Result<()> {
-    let total_processed = total_len - { total_proc_failed_files + total_non_dcm_files };
+    let total_processed = total_len - {
+        total_proc_failed_files + total_non_dcm_files + total_skipped_files + total_incomplete_files
+    };
     info!("Total Files: {}", total_len);
     info!("Failed Cases: {}", total_proc_failed_files);
     info!("NON-DCM files: {}", total_non_dcm_files);
+    if total_incomplete_files > 0 {
+        info!("Incomplete files: {}", total_incomplete_files);
+    }
+    if total_skipped_files > 0 {
+        info!("Other shard files: {}", total_skipped_files);
+    }
     info!("Total {}: {}", action, total_processed);
     Ok(())
 }
 
+// Read, transform and write durations of a processed file
+#[derive(Debug, Clone)]
+pub struct FileTiming {
+    pub path: PathBuf,
+    pub size: u64,
+    pub transfer_syntax: String,
+    pub read: Duration,
+    pub transform: Duration,
+    pub write: Duration,
+}
+
+impl FileTiming {
+    pub fn new(
+        each_file: &Path,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        read: Duration,
+    ) -> Self {
+        FileTiming {
+            path: each_file.to_path_buf(),
+            size: fs::metadata(each_file).map(|m| m.len()).unwrap_or(0),
+            transfer_syntax: dcm_obj
+                .meta()
+                .transfer_syntax()
+                .trim_end_matches('\0')
+                .to_string(),
+            read,
+            transform: Duration::ZERO,
+            write: Duration::ZERO,
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        self.read + self.transform + self.write
+    }
+}
+
+pub type TimingTracker = Arc<Mutex<Vec<FileTiming>>>;
+
+// Output path and SHA-256 of each written file
+pub type ChecksumTracker = Arc<Mutex<Vec<(String, String)>>>;
+
+// Source PatientID > PatientNames and PatientBirthDates seen with it
+pub type IdentityTracker = Arc<Mutex<BTreeMap<String, (BTreeSet<String>, BTreeSet<String>)>>>;
+
+// Bytes of a source file read whole
+pub type SourceBytes = Arc<[u8]>;
+
+// Print the slowest files of the run, to help find the inputs dragging down the throughput
+pub fn print_slowest_files(timing_tracker: &TimingTracker, count: usize) -> Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+    let mut timings = timing_tracker.lock().expect("Failed to lock mutex").clone();
+    if timings.is_empty() {
+        return Ok(());
+    }
+    timings.sort_by_key(|t| std::cmp::Reverse(t.total()));
+    info!("Slowest {} files:", count.min(timings.len()));
+    for each in timings.iter().take(count) {
+        let ts_name = TransferSyntaxRegistry
+            .get(&each.transfer_syntax)
+            .map(|ts| ts.name().to_string())
+            .unwrap_or_else(|| each.transfer_syntax.clone());
+        info!(
+            "{:>8.3}s (read {:.3}s | transform {:.3}s | write {:.3}s) {} bytes | {} | {}",
+            each.total().as_secs_f64(),
+            each.read.as_secs_f64(),
+            each.transform.as_secs_f64(),
+            each.write.as_secs_f64(),
+            each.size,
+            ts_name,
+            each.path.display()
+        );
+    }
+    Ok(())
+}
+
 pub fn extract_tag_vr_from_str(tag_name: &String) -> Result<(Tag, VR)> {
-    match DataDictionary::by_name(&StandardDataDictionary, &tag_name) {
-        Some(v) => return Ok((v.tag.inner(), v.vr.relaxed())),
+    match DataDictionary::by_name(&StandardDataDictionary, tag_name) {
+        Some(v) => Ok((v.tag.inner(), v.vr.relaxed())),
         None => {
             warn!("Tag: {} is not valid!", tag_name);
-            return Err(anyhow::Error::msg("Tag Not Valid, VR not found!!"));
+            Err(anyhow::Error::msg("Tag Not Valid, VR not found!!"))
         }
-    };
+    }
 }
 
-// Generate ANON ID
-pub fn gen_id() -> String {
-    let alpha_numeric = &nanoid::alphabet::SAFE[2..];
-    nanoid!(10, &alpha_numeric)
+// Address of an element, the elements in sequences are reached through their items
+// eg PatientID, RequestAttributesSequence[*].ScheduledProcedureStepID for every item or
+// OtherPatientIDsSequence[0].PatientID for the first item only
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagPath {
+    // Sequence of each level and the selected item, None for every item
+    pub sequences: Vec<(Tag, Option<usize>)>,
+    pub tag: Tag,
+    pub vr: VR,
+    path: String,
 }
 
-fn determine_plane(dcm_obj: &FileDicomObject<InMemDicomObject>) -> Result<String> {
-    let orientation: Vec<f64> = match dcm_obj.element_by_name("ImageOrientationPatient") {
-        Ok(value) => value.to_multi_float64()?,
-        Err(_) => vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
-    };
-    let row_x = orientation[0].round() as i8;
-    let row_y = orientation[1].round() as i8;
-    let col_y = orientation[4].round() as i8;
-    let col_z = orientation[5].round() as i8;
-    let plane = if row_x == 1 && col_z == -1 {
-        "COR".to_string()
-    } else if row_x == 1 && col_y == 1 {
-        "AX".to_string()
-    } else if row_y == 1 && col_z == -1 {
-        "SAG".to_string()
-    } else {
-        "NA".to_string()
-    };
-    Ok(plane)
-}
+impl FromStr for TagPath {
+    type Err = anyhow::Error;
 
-pub fn dicom_vr_corrected_value(vr: VR, value: &String) -> Result<PrimitiveValue> {
-    let r_value = match vr {
-        VR::AE | VR::AS | VR::PN | VR::SH | VR::CS | VR::LO | VR::UI | VR::UC => {
-            dicom_value!(Strs, [value.clone()])
-        }
-        VR::ST | VR::LT | VR::UT | VR::UR => {
-            dicom_value!(Str, value.clone())
-        }
-        VR::DA => {
-            if value.len() != 8 {
-                error!(
-                    "Issue With Date value Does it follow this format YYYYMMDD: {}",
-                    value
-                );
-                exit(1);
+    fn from_str(value: &str) -> Result<Self> {
+        let path = value.trim();
+        let entry_of = |name: &str| {
+            StandardDataDictionary
+                .by_name(name)
+                .ok_or_else(|| anyhow::anyhow!("Tag {} is not valid in {}", name, path))
+        };
+        let mut segments: Vec<&str> = path.split('.').collect();
+        let leaf = entry_of(segments.pop().unwrap_or_default())?;
+        let mut sequences = vec![];
+        for segment in segments {
+            let (name, index) = segment
+                .strip_suffix(']')
+                .and_then(|segment| segment.split_once('['))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Sequence {} needs an item, eg [*] or [0] in {}",
+                        segment,
+                        path
+                    )
+                })?;
+            let entry = entry_of(name)?;
+            if entry.vr.relaxed() != VR::SQ {
+                return Err(anyhow::anyhow!("{} is not a sequence in {}", name, path));
             }
-            let d_date = DicomDate::try_from(&NaiveDate::parse_from_str(&value, "%Y%m%d")?)?;
-            dicom_value!(Date, d_date)
+            let index = match index {
+                "*" => None,
+                index => Some(index.parse::<usize>().map_err(|_| {
+                    anyhow::anyhow!(
+                        "Item {} of {} is not * or a number in {}",
+                        index,
+                        name,
+                        path
+                    )
+                })?),
+            };
+            sequences.push((entry.tag.inner(), index));
         }
-        VR::TM => {
+        Ok(TagPath {
+            sequences,
+            tag: leaf.tag.inner(),
+            vr: leaf.vr.relaxed(),
+            path: path.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for TagPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path)
+    }
+}
+
+impl TagPath {
+    // Run f on the object or on each selected item holding the addressed element
+    fn update_holders(
+        sequences: &[(Tag, Option<usize>)],
+        dcm_obj: &mut InMemDicomObject,
+        f: &mut dyn FnMut(&mut InMemDicomObject),
+    ) {
+        match sequences.split_first() {
+            None => f(dcm_obj),
+            Some(((tag, index), rest)) => {
+                dcm_obj.update_value(*tag, |value| {
+                    if let Some(items) = value.items_mut() {
+                        for (item_index, item) in items.iter_mut().enumerate() {
+                            if index.is_none_or(|index| index == item_index) {
+                                TagPath::update_holders(rest, item, f);
+                            }
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    fn collect_values(
+        sequences: &[(Tag, Option<usize>)],
+        tag: Tag,
+        dcm_obj: &InMemDicomObject,
+        values: &mut Vec<String>,
+    ) {
+        match sequences.split_first() {
+            None => {
+                if let Some(value) = dcm_obj.get(tag).and_then(|e| e.to_str().ok()) {
+                    values.push(value.trim_end_matches(['\0', ' ']).to_string());
+                }
+            }
+            Some(((seq_tag, index), rest)) => {
+                if let Some(items) = dcm_obj.get(*seq_tag).and_then(|e| e.items()) {
+                    for (item_index, item) in items.iter().enumerate() {
+                        if index.is_none_or(|index| index == item_index) {
+                            TagPath::collect_values(rest, tag, item, values);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Values of the addressed elements, one for each selected item holding it
+    pub fn values(&self, dcm_obj: &InMemDicomObject) -> Vec<String> {
+        let mut values = vec![];
+        TagPath::collect_values(&self.sequences, self.tag, dcm_obj, &mut values);
+        values
+    }
+
+    // Put the value in the object or the selected items, missing sequences are not created
+    pub fn put(&self, dcm_obj: &mut InMemDicomObject, value: PrimitiveValue) {
+        TagPath::update_holders(&self.sequences, dcm_obj, &mut |holder| {
+            holder.put(DataElement::new(self.tag, self.vr, value.clone()));
+        });
+    }
+
+    // Replace the addressed elements that exist with the value, repeated to their multiplicity
+    pub fn mask(&self, dcm_obj: &mut InMemDicomObject, value: PrimitiveValue) {
+        TagPath::update_holders(&self.sequences, dcm_obj, &mut |holder| {
+            let multiplicity = holder
+                .get(self.tag)
+                .map_or(1, |element| element.value().multiplicity());
+            holder.put(DataElement::new(
+                self.tag,
+                self.vr,
+                with_multiplicity(value.clone(), self.vr, multiplicity),
+            ));
+        });
+    }
+
+    fn copy_elements(
+        sequences: &[(Tag, Option<usize>)],
+        tag: Tag,
+        source: &InMemDicomObject,
+        dcm_obj: &mut InMemDicomObject,
+        f: &mut dyn FnMut(&InMemElement) -> InMemElement,
+    ) {
+        match sequences.split_first() {
+            None => {
+                if let Some(element) = source.get(tag) {
+                    dcm_obj.put(f(element));
+                }
+            }
+            Some(((seq_tag, index), rest)) => {
+                let source_items = match source.get(*seq_tag).and_then(|e| e.items()) {
+                    Some(items) => items,
+                    None => return,
+                };
+                dcm_obj.update_value(*seq_tag, |value| {
+                    if let Some(items) = value.items_mut() {
+                        for (item_index, (item, source_item)) in
+                            items.iter_mut().zip(source_items).enumerate()
+                        {
+                            if index.is_none_or(|index| index == item_index) {
+                                TagPath::copy_elements(rest, tag, source_item, item, f);
+                            }
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    // Put the addressed elements of the source through f into the same place of the object
+    // The items are paired by position, missing sequences are not created
+    pub fn copy_from(
+        &self,
+        source: &InMemDicomObject,
+        dcm_obj: &mut InMemDicomObject,
+        f: &mut dyn FnMut(&InMemElement) -> InMemElement,
+    ) {
+        TagPath::copy_elements(&self.sequences, self.tag, source, dcm_obj, f);
+    }
+
+    // Remove the addressed elements, returns the number removed
+    pub fn delete(&self, dcm_obj: &mut InMemDicomObject) -> usize {
+        let mut removed = 0;
+        TagPath::update_holders(&self.sequences, dcm_obj, &mut |holder| {
+            if holder.remove_element(self.tag) {
+                removed += 1;
+            }
+        });
+        removed
+    }
+}
+
+// Length of the generated part of the ANON ID
+pub const ANON_ID_LENGTH: usize = 10;
+
+// Generate ANON ID
+// How anon gives a new patient its ANON ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdMode {
+    // gen_id, the mapping of the run is the only link to the patient
+    #[default]
+    Random,
+    // HMAC-SHA256 of the original key with the UID secret
+    Hash,
+}
+
+impl FromStr for IdMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "random" => Ok(IdMode::Random),
+            "hash" => Ok(IdMode::Hash),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of random or hash: {}",
+                value
+            )),
+        }
+    }
+}
+
+pub fn gen_id() -> String {
+    let alpha_numeric = &nanoid::alphabet::SAFE[2..];
+    nanoid!(ANON_ID_LENGTH, &alpha_numeric)
+}
+
+// Maximum length of a single value for the VRs that have one
+pub fn vr_max_length(vr: VR) -> Option<usize> {
+    match vr {
+        VR::AE | VR::CS | VR::DS | VR::SH => Some(16),
+        VR::AS => Some(4),
+        VR::DA => Some(8),
+        VR::DT => Some(26),
+        VR::IS => Some(12),
+        VR::LO | VR::PN | VR::UI => Some(64),
+        VR::ST => Some(1024),
+        VR::LT => Some(10240),
+        VR::TM => Some(14),
+        _ => None,
+    }
+}
+
+// Truncate the value to the maximum length of the given VR
+pub fn fit_to_vr_length(vr: VR, value: &str) -> String {
+    match vr_max_length(vr) {
+        Some(max_len) if value.chars().count() > max_len => {
+            debug!("Value truncated to {} characters for VR {}", max_len, vr);
+            value.chars().take(max_len).collect()
+        }
+        _ => value.to_string(),
+    }
+}
+
+// The ANON ID is written to LO and PN tags (PatientID, PatientName) which are limited to 64 characters
+// Only alphanumerics, '_', '-' and '.' are allowed as the ID is also used in the destination path
+pub fn validate_anon_id(anon_id: &str) -> Result<()> {
+    if let Some(c) = anon_id
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')))
+    {
+        return Err(anyhow::Error::msg(format!(
+            "ANON ID '{}' contains an illegal character '{}'. Only A-Z, a-z, 0-9, '_', '-' and '.' are allowed",
+            anon_id, c
+        )));
+    }
+    let max_len = vr_max_length(VR::LO).expect("LO has a max length");
+    if anon_id.len() > max_len {
+        return Err(anyhow::Error::msg(format!(
+            "ANON ID '{}' is {} characters long, the limit for PatientID/PatientName is {}",
+            anon_id,
+            anon_id.len(),
+            max_len
+        )));
+    }
+    Ok(())
+}
+
+// Check that the prefix together with the generated ID forms a valid ANON ID
+pub fn validate_anon_prefix(anon_prefix: &str) -> Result<()> {
+    if anon_prefix.is_empty() {
+        return Ok(());
+    }
+    let sample_id = format!("{}_{}", anon_prefix, "X".repeat(ANON_ID_LENGTH));
+    validate_anon_id(&sample_id).map_err(|_| {
+        anyhow::Error::msg(format!(
+            "ANON PREFIX '{}' is not valid. Use at most {} characters from A-Z, a-z, 0-9, '_', '-' and '.'",
+            anon_prefix,
+            vr_max_length(VR::LO).expect("LO has a max length") - ANON_ID_LENGTH - 1
+        ))
+    })?;
+    let sh_len = vr_max_length(VR::SH).expect("SH has a max length");
+    if sample_id.len() > sh_len {
+        warn!(
+            "ANON ID is {} characters long, AccessionNumber and StudyID will be truncated to {} characters",
+            sample_id.len(),
+            sh_len
+        );
+    }
+    Ok(())
+}
+
+// ANON ID prefix, either fixed or a template like {InstitutionName} resolved from each file
+// With a lookup table the tag values are replaced by the site codes of the table
+#[derive(Debug, Clone, Default)]
+pub struct AnonPrefix {
+    pub template: String,
+    tokens: Vec<(String, Tag)>,
+    lookup: Option<HashMap<String, String>>,
+}
+
+impl AnonPrefix {
+    pub fn new(template: &str, lookup_path: Option<&Path>) -> Result<Self> {
+        static TOKEN: OnceLock<Regex> = OnceLock::new();
+        let token = TOKEN.get_or_init(|| Regex::new(r"\{(\w+)\}").expect("Invalid token pattern"));
+        let mut tokens = vec![];
+        for capture in token.captures_iter(template) {
+            let name = capture[1].to_string();
+            match StandardDataDictionary.by_name(&name) {
+                Some(entry) => tokens.push((name, entry.tag.inner())),
+                None => return Err(anyhow::anyhow!("Unknown tag in the ANON PREFIX: {}", name)),
+            }
+        }
+        let lookup = match lookup_path {
+            Some(lookup_path) => Some(read_prefix_lookup(lookup_path)?),
+            None => None,
+        };
+        let prefix = AnonPrefix {
+            template: template.to_string(),
+            tokens,
+            lookup,
+        };
+        // The fixed part of a template is checked here, the resolved prefix for each new patient
+        validate_anon_prefix(&token.replace_all(template, "X"))?;
+        Ok(prefix)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.template.is_empty()
+    }
+
+    // Prefix of the file, the tokens are replaced by the site code or the sanitized tag value
+    pub fn resolve(&self, dcm_obj: &InMemDicomObject) -> Result<String> {
+        let mut prefix = self.template.clone();
+        for (name, tag) in &self.tokens {
+            let value = dcm_obj
+                .element(*tag)
+                .ok()
+                .and_then(|element| element.to_str().ok())
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("{} of the ANON PREFIX is missing", name))?;
+            let code = match &self.lookup {
+                Some(lookup) => lookup.get(&value).cloned().ok_or_else(|| {
+                    anyhow::anyhow!("No code for {} {} in the prefix lookup", name, phi(&value))
+                })?,
+                None => value
+                    .replace(' ', "_")
+                    .chars()
+                    .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+                    .collect(),
+            };
+            prefix = prefix.replace(&format!("{{{}}}", name), &code);
+        }
+        Ok(prefix)
+    }
+}
+
+// Lookup table of the ANON PREFIX in the following order seperated by line value,code eg St Mary,SMH
+fn read_prefix_lookup(lookup_path: &Path) -> Result<HashMap<String, String>> {
+    let content = fs::read_to_string(lookup_path).map_err(|e| {
+        anyhow::anyhow!(
+            "Can't read the prefix lookup {}: {}",
+            lookup_path.display(),
+            e
+        )
+    })?;
+    let mut lookup = HashMap::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        match line.rsplit_once(',') {
+            Some((value, code)) => {
+                validate_anon_prefix(code.trim())?;
+                lookup.insert(value.trim().to_string(), code.trim().to_string());
+            }
+            None => warn!("Invalid line: {}", phi(line)),
+        }
+    }
+    Ok(lookup)
+}
+
+// Settings of the Anonymizer, the defaults are the ones of the anon command
+#[derive(Debug, Clone)]
+pub struct AnonConfig {
+    pub prefix: AnonPrefix,
+    // Tag the ANON IDs are keyed on
+    pub key_tag: String,
+    pub date_precision: Option<DatePrecision>,
+    // Shift the dates by a per patient offset instead of replacing them
+    pub date_shift: bool,
+    pub id_mode: IdMode,
+    pub description_map: Option<DescriptionMap>,
+    pub site_profile: Option<AnonProfile>,
+    pub standard_profile: Option<StandardProfile>,
+    pub rules: Option<RuleSet>,
+    pub study_values: Option<StudyValues>,
+    pub uid_mapper: UidMapper,
+    pub review_policy: ReviewPolicy,
+    pub annotation_text: AnnotationText,
+    pub downsample: Option<MatrixSize>,
+    // Write the frames of the enhanced multi-frame instances as classic instances
+    pub split_frames: bool,
+    pub transcode: TranscodeTarget,
+    pub pixel_mask: Option<PixelMask>,
+    pub date_order: DateOrder,
+    pub fix_vr: bool,
+    pub keep_legacy_tags: bool,
+    pub private_tags: PrivateTagPolicy,
+    pub private_allowlist: Option<PrivateAllowlist>,
+}
+
+impl Default for AnonConfig {
+    fn default() -> Self {
+        AnonConfig {
+            prefix: AnonPrefix::default(),
+            key_tag: "PatientID".to_string(),
+            date_precision: None,
+            date_shift: false,
+            id_mode: IdMode::default(),
+            description_map: None,
+            site_profile: None,
+            standard_profile: None,
+            rules: None,
+            study_values: None,
+            uid_mapper: UidMapper::default(),
+            review_policy: ReviewPolicy::default(),
+            annotation_text: AnnotationText::default(),
+            downsample: None,
+            split_frames: false,
+            transcode: TranscodeTarget::default(),
+            pixel_mask: None,
+            date_order: DateOrder::default(),
+            fix_vr: false,
+            keep_legacy_tags: false,
+            private_tags: PrivateTagPolicy::default(),
+            private_allowlist: None,
+        }
+    }
+}
+
+// Patient of an instance and the ANON ID it was given
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnonPatient {
+    // prefix\value of the key tag, the same PatientID at two sites is two subjects
+    pub key: String,
+    pub anon_id: String,
+}
+
+/// The anon transform of one instance, without the filesystem: the identifiers replaced by the
+/// ANON ID, the dates, private tags and UIDs, then the description map, the site profile, the
+/// rules, the study values, the pixel mask, the downsampling, the VR fixes and the transcoding
+/// The ANON IDs given are kept, so the instances of a patient get the same one, eg
+/// `Anonymizer::new(AnonConfig::default()).anonymize(&dcm_obj)?`
+pub struct Anonymizer {
+    pub config: AnonConfig,
+    // Patient key > ANON ID
+    anon_ids: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Anonymizer {
+    pub fn new(config: AnonConfig) -> Self {
+        Anonymizer {
+            config,
+            anon_ids: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Start from the ANON IDs of earlier runs, eg the mapping store
+    pub fn with_anon_ids(mut self, anon_ids: HashMap<String, String>) -> Self {
+        self.anon_ids = Arc::new(Mutex::new(anon_ids));
+        self
+    }
+
+    // ANON IDs as they are given, for the checkpoint of the run
+    pub fn anon_id_store(&self) -> Arc<Mutex<HashMap<String, String>>> {
+        self.anon_ids.clone()
+    }
+
+    // Patient key > ANON ID of all the patients seen so far
+    pub fn anon_ids(&self) -> HashMap<String, String> {
+        self.anon_ids.lock().expect("Failed to lock mutex").clone()
+    }
+
+    /// Patient of the instance, a new ANON ID is given to a patient not seen before
+    pub fn patient(&self, dcm_obj: &FileDicomObject<InMemDicomObject>) -> Result<AnonPatient> {
+        let key_value = dcm_obj
+            .element_by_name(&self.config.key_tag)?
+            .to_str()?
+            .to_string();
+        let prefix = self.config.prefix.resolve(dcm_obj)?;
+        let key = format!("{}\\{}", prefix, key_value);
+        let mut anon_ids = self.anon_ids.lock().expect("Failed to lock mutex");
+        if let Some(anon_id) = anon_ids.get(&key) {
+            return Ok(AnonPatient {
+                key,
+                anon_id: anon_id.clone(),
+            });
+        }
+        let new_id = match self.config.id_mode {
+            IdMode::Random => gen_id(),
+            IdMode::Hash => self.config.uid_mapper.anon_id(&key),
+        };
+        let anon_id = match prefix.is_empty() {
+            true => new_id,
+            false => format!("{}_{}", prefix, new_id),
+        };
+        validate_anon_id(&anon_id)?;
+        anon_ids.insert(key.clone(), anon_id.clone());
+        debug!("New AnonID for: {}", phi(&key_value));
+        Ok(AnonPatient { key, anon_id })
+    }
+
+    /// Anonymized copy of the instance
+    pub fn anonymize(
+        &self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+    ) -> Result<FileDicomObject<InMemDicomObject>> {
+        let patient = self.patient(dcm_obj)?;
+        self.anonymize_patient(dcm_obj, &patient)
+    }
+
+    /// Anonymized copy of the instance of a patient given by patient
+    pub fn anonymize_patient(
+        &self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        patient: &AnonPatient,
+    ) -> Result<FileDicomObject<InMemDicomObject>> {
+        let config = &self.config;
+        let anon_id = &patient.anon_id;
+        let mut new_dicom_object = dcm_obj.clone();
+        normalize_dates(&mut new_dicom_object, config.date_order);
+        delete_patient_photo_references(&mut new_dicom_object, &config.review_policy);
+        match &config.standard_profile {
+            Some(standard_profile) => standard_profile.apply(&mut new_dicom_object, anon_id)?,
+            None => {
+                scrub_sr_content(&mut new_dicom_object, anon_id);
+                scrub_annotation_text(&mut new_dicom_object, config.annotation_text, anon_id);
+                new_dicom_object = mask_tags_with_id(new_dicom_object, anon_id.clone())?;
+                let date_shift = config
+                    .date_shift
+                    .then(|| config.uid_mapper.date_shift_days(&patient.key));
+                new_dicom_object =
+                    dicom_anon_date_time(new_dicom_object, config.date_precision, date_shift)?;
+                new_dicom_object = match &config.private_allowlist {
+                    Some(private_allowlist) => {
+                        private_allowlist.apply(&mut new_dicom_object);
+                        new_dicom_object.remove_element(tags::ORIGINAL_ATTRIBUTES_SEQUENCE);
+                        new_dicom_object
+                    }
+                    None if config.private_tags == PrivateTagPolicy::Keep => {
+                        new_dicom_object.remove_element(tags::ORIGINAL_ATTRIBUTES_SEQUENCE);
+                        new_dicom_object
+                    }
+                    None => delete_private_tags(new_dicom_object)?,
+                };
+                if !config.keep_legacy_tags {
+                    delete_legacy_tags(&mut new_dicom_object);
+                }
+                config.uid_mapper.remap_file(&mut new_dicom_object);
+            }
+        }
+        if let Some(description_map) = &config.description_map {
+            description_map.apply(&mut new_dicom_object);
+        }
+        if let Some(site_profile) = &config.site_profile {
+            site_profile.apply(dcm_obj, &mut new_dicom_object);
+        }
+        if let Some(rules) = &config.rules {
+            rules.apply(dcm_obj, &mut new_dicom_object);
+        }
+        if let Some(study_values) = &config.study_values {
+            study_values.apply(dcm_obj, &mut new_dicom_object);
+        }
+        if let Some(pixel_mask) = &config.pixel_mask {
+            pixel_mask.apply(dcm_obj, &mut new_dicom_object)?;
+        }
+        if let Some(size) = config.downsample {
+            downsample_pixels(&mut new_dicom_object, size)?;
+        }
+        if config.fix_vr {
+            fix_value_violations(&mut new_dicom_object);
+        }
+        transcode_pixels(&mut new_dicom_object, config.transcode)?;
+        Ok(new_dicom_object)
+    }
+}
+
+// Replace all dates and times, only truncate the dates when a date precision is given, or shift
+// them back by the given days
+pub fn dicom_anon_date_time(
+    dcm_obj: FileDicomObject<InMemDicomObject>,
+    date_precision: Option<DatePrecision>,
+    date_shift: Option<u64>,
+) -> Result<FileDicomObject<InMemDicomObject>> {
+    // Setting Up primitives
+
+    let dicom_date_data = vr_dummy_value(VR::DA, "");
+    let dicom_time_data = vr_dummy_value(VR::TM, "");
+    let dicom_date_time = vr_dummy_value(VR::DT, "");
+
+    let mut datetime_deleted_dcm_obj = match (date_precision, date_shift) {
+        (_, Some(days)) => {
+            let mut dcm_obj = dcm_obj;
+            shift_dates(&mut dcm_obj, days);
+            dcm_obj.put(DataElement::new(
+                tags::LONGITUDINAL_TEMPORAL_INFORMATION_MODIFIED,
+                VR::CS,
+                dicom_value!(Strs, ["MODIFIED".to_string()]),
+            ));
+            dcm_obj
+        }
+        (Some(precision), None) => {
+            let mut dcm_obj = mask_all_vr(dcm_obj, VR::TM, dicom_time_data)?;
+            reduce_dates(&mut dcm_obj, precision);
+            dcm_obj
+        }
+        (None, None) => {
+            let date_deleted_dcm_obj = mask_all_vr(dcm_obj.clone(), VR::DA, dicom_date_data)?;
+            let time_deleted_dcm_obj =
+                mask_all_vr(date_deleted_dcm_obj.clone(), VR::TM, dicom_time_data)?;
+            mask_all_vr(time_deleted_dcm_obj.clone(), VR::DT, dicom_date_time)?
+        }
+    };
+
+    datetime_deleted_dcm_obj.put(DataElement::new(
+        tags::PATIENT_AGE,
+        VR::AS,
+        dicom_value!(Strs, ["099Y".to_string()]),
+    ));
+    datetime_deleted_dcm_obj.put(DataElement::new(
+        tags::PATIENT_SEX,
+        VR::CS,
+        dicom_value!(Strs, ["O".to_string()]),
+    ));
+
+    Ok(datetime_deleted_dcm_obj)
+}
+
+// External commands run on the written files, eg import scripts or notifications
+#[derive(Debug, Clone, Default)]
+pub struct PostHooks {
+    // Run with the path of each written file
+    pub post_file: Option<String>,
+    // Run with the paths of all written files of a study at the end of the run
+    pub post_study: Option<String>,
+}
+
+// Study completeness report of the run in the destination
+pub const COMPLETENESS_FILE: &str = "study_completeness.csv";
+
+// Series of the run whose Modality doesn't fit their SOP class or pixels
+pub const MODALITY_CHECK_FILE: &str = "modality_check.csv";
+
+// Instances of the run that had each tag removed, replaced, added or kept
+pub const TAG_CHANGES_FILE: &str = "tag_changes.csv";
+
+// Tags changed the most often that are logged at the end of the run
+const TAG_CHANGES_LOGGED: usize = 20;
+
+// Duplicate instances skipped by a run with --dedup
+pub const DUPLICATES_FILE: &str = "duplicate_instances.csv";
+
+// Instances expected per study, from a DICOMDIR, a CSV or the server the source was searched on,
+// to find the studies of a run that are not complete
+#[derive(Debug, Clone)]
+pub struct ExpectedCounts {
+    // Where the counts come from, for the log
+    pub origin: String,
+    // StudyInstanceUID or AccessionNumber
+    pub key_tag: Tag,
+    pub studies: BTreeMap<String, usize>,
+}
+
+impl ExpectedCounts {
+    /// A DICOMDIR, or a CSV with the key (StudyInstanceUID or AccessionNumber) then the number of
+    /// instances of each study, its header names the key, eg StudyInstanceUID,Instances
+    pub fn from_file(counts_path: &Path) -> Result<Self> {
+        let invalid = |e: anyhow::Error| {
+            anyhow::anyhow!("Invalid expected counts {}: {}", counts_path.display(), e)
+        };
+        let mut head = [0; 132];
+        let is_dicom = fs::File::open(counts_path)
+            .and_then(|mut file| file.read_exact(&mut head))
+            .is_ok()
+            && &head[128..] == b"DICM";
+        let counts = match is_dicom {
+            true => ExpectedCounts::from_dicomdir(counts_path),
+            false => ExpectedCounts::from_csv(counts_path),
+        }
+        .map_err(invalid)?;
+        info!(
+            "Expected counts {} of {} studies loaded",
+            counts_path.display(),
+            counts.studies.len()
+        );
+        Ok(counts)
+    }
+
+    // The instance records that follow a STUDY record belong to it, the records are read in
+    // file order as the DICOMDIR writers lay them out
+    fn from_dicomdir(dicomdir_path: &Path) -> Result<Self> {
+        let dicomdir = dicom::object::open_file(dicomdir_path)?;
+        let records = dicomdir
+            .element(tags::DIRECTORY_RECORD_SEQUENCE)?
+            .items()
+            .ok_or_else(|| anyhow::anyhow!("DirectoryRecordSequence has no items"))?;
+        let value = |record: &InMemDicomObject, tag: Tag| {
+            record
+                .get(tag)
+                .and_then(|element| element.to_str().ok())
+                .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+        };
+        let mut studies = BTreeMap::new();
+        let mut study = None;
+        for record in records {
+            if value(record, tags::DIRECTORY_RECORD_TYPE).as_deref() == Some("STUDY") {
+                study = value(record, tags::STUDY_INSTANCE_UID);
+                if let Some(study_uid) = &study {
+                    studies.entry(study_uid.clone()).or_insert(0);
+                }
+            } else if record
+                .get(tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE)
+                .is_some()
+            {
+                if let Some(study_uid) = &study {
+                    *studies.entry(study_uid.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+        Ok(ExpectedCounts {
+            origin: dicomdir_path.display().to_string(),
+            key_tag: tags::STUDY_INSTANCE_UID,
+            studies,
+        })
+    }
+
+    fn from_csv(counts_path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(counts_path)?;
+        let mut lines = content
+            .lines()
+            .map(|line| line.trim_start_matches('\u{feff}'))
+            .filter(|line| !line.trim().is_empty());
+        let header = csv_fields(lines.next().unwrap_or_default());
+        let key_tag = match header[0].trim() {
+            "AccessionNumber" => tags::ACCESSION_NUMBER,
+            "StudyInstanceUID" => tags::STUDY_INSTANCE_UID,
+            key => {
+                return Err(anyhow::anyhow!(
+                    "The first column is {}, not AccessionNumber or StudyInstanceUID",
+                    key
+                ))
+            }
+        };
+        let mut studies = BTreeMap::new();
+        for line in lines {
+            let fields = csv_fields(line);
+            let count = fields
+                .get(1)
+                .and_then(|count| count.trim().parse::<usize>().ok())
+                .ok_or_else(|| anyhow::anyhow!("No instance count in {}", phi(line)))?;
+            if studies
+                .insert(fields[0].trim().to_string(), count)
+                .is_some()
+            {
+                return Err(anyhow::anyhow!("{} is listed twice", phi(&fields[0])));
+            }
+        }
+        Ok(ExpectedCounts {
+            origin: counts_path.display().to_string(),
+            key_tag,
+            studies,
+        })
+    }
+}
+
+// Files of a study in the run by outcome, against the expected instances
+#[derive(Debug, Clone, Default)]
+pub struct StudyCompleteness {
+    pub key: String,
+    pub expected: Option<usize>,
+    pub written: usize,
+    pub review: usize,
+    pub excluded: usize,
+    pub failed: usize,
+}
+
+impl StudyCompleteness {
+    pub fn status(&self) -> &'static str {
+        match self.expected {
+            None => "not expected",
+            Some(_) if self.written + self.review + self.excluded + self.failed == 0 => "missing",
+            Some(expected) if self.written < expected => "incomplete",
+            Some(expected) if self.written > expected => "over",
+            Some(_) => "complete",
+        }
+    }
+}
+
+// Written files of a study for the post study hook
+#[derive(Debug, Clone, Default)]
+pub struct StudyFiles {
+    pub files: Vec<String>,
+    // NumberOfStudyRelatedInstances of the source, if given
+    pub expected_instances: Option<usize>,
+}
+
+// NumberOfStudyRelatedInstances of the object, to tell if all instances of a study were written
+pub fn study_related_instances(dcm_obj: &FileDicomObject<InMemDicomObject>) -> Option<usize> {
+    dcm_obj
+        .element(tags::NUMBER_OF_STUDY_RELATED_INSTANCES)
+        .ok()
+        .and_then(|element| element.to_int::<usize>().ok())
+        .filter(|count| *count > 0)
+}
+
+// Shell of the hooks, the paths are given to the command as "$@"
+#[cfg(not(windows))]
+fn hook_shell(command: &str) -> Command {
+    let mut hook = Command::new("sh");
+    hook.arg("-c")
+        .arg(format!("{} \"$@\"", command))
+        .arg("dcmrig");
+    hook
+}
+
+// cmd parses its command line itself, the command is given as it is written and the paths
+// follow it
+#[cfg(windows)]
+fn hook_shell(command: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+    let mut hook = Command::new("cmd");
+    hook.arg("/C").raw_arg(command);
+    hook
+}
+
+// Run a hook through the shell, sh or cmd on Windows, the paths are appended to the command as
+// arguments. The StudyInstanceUID of the output is available as DCMRIG_STUDY_UID
+fn run_hook(command: &str, paths: &[String], study_uid: &str) {
+    let mut hook = hook_shell(command);
+    let status = hook.args(paths).env("DCMRIG_STUDY_UID", study_uid).status();
+    match status {
+        Ok(status) if status.success() => debug!("Hook done: {} {:?}", command, paths),
+        Ok(status) => warn!("Hook failed with {}: {} {:?}", status, command, paths),
+        Err(e) => warn!("Can't run hook {}: {}", command, e),
+    }
+}
+
+// Per file results of the run in the destination
+pub const RESULTS_FILE: &str = "results.csv";
+
+// Outcome of one input file
+// status is one of processed, review, excluded, unmapped, failed, non-DICOM, skipped or
+// duplicate
+// review list|approve|reject turns review into processed or rejected
+#[derive(Debug, Clone)]
+pub struct FileResult {
+    pub source: PathBuf,
+    pub status: &'static str,
+    pub output: String,
+    // Output path that was already taken when the file was renamed
+    pub renamed_from: String,
+    // ID of the patient in the output
+    pub patient_id: String,
+    pub study_uid: String,
+    pub series_uid: String,
+    pub duration: Duration,
+    pub error: String,
+    // Tags a dry run would add (+), modify (~) or remove (-)
+    pub changes: Vec<String>,
+}
+
+impl FileResult {
+    pub fn new(source: &Path, status: &'static str) -> Self {
+        FileResult {
+            source: source.to_path_buf(),
+            status,
+            output: String::new(),
+            renamed_from: String::new(),
+            patient_id: String::new(),
+            study_uid: String::new(),
+            series_uid: String::new(),
+            duration: Duration::ZERO,
+            error: String::new(),
+            changes: vec![],
+        }
+    }
+
+    // Take the patient and UIDs from the tag values of the output
+    pub fn with_tags(mut self, dicom_tags_values: &HashMap<String, String>) -> Self {
+        let value_of = |name: &str| {
+            dicom_tags_values
+                .get(name)
+                .map(|value| value.trim_end_matches('\0').trim().to_string())
+                .unwrap_or_default()
+        };
+        self.patient_id = value_of("PatientID");
+        self.study_uid = value_of("StudyInstanceUID");
+        self.series_uid = value_of("SeriesInstanceUID");
+        self
+    }
+}
+
+// Source file with the SOPInstanceUID of another one
+#[derive(Debug, Clone)]
+pub struct DuplicateInstance {
+    pub sop_uid: String,
+    // File with the SOPInstanceUID that is kept
+    pub kept: PathBuf,
+    pub duplicate: PathBuf,
+    // Same bytes as the kept file
+    pub identical: bool,
+}
+
+// Duplicate instances as CSV, one line per duplicate
+pub fn duplicate_instances_csv(duplicates: &[DuplicateInstance]) -> String {
+    let mut content = "SOPInstanceUID,kept,duplicate,identical\n".to_string();
+    for duplicate in duplicates {
+        content.push_str(&format!(
+            "{},{},{},{}\n",
+            csv_field(&duplicate.sop_uid),
+            csv_field(&duplicate.kept.display().to_string()),
+            csv_field(&duplicate.duplicate.display().to_string()),
+            duplicate.identical
+        ));
+    }
+    content
+}
+
+// Check if two files have the same bytes, compared in chunks. A file that can't be read is not
+// the same
+pub fn same_file_content(first: &Path, second: &Path) -> bool {
+    let (Ok(first_file), Ok(second_file)) = (fs::File::open(first), fs::File::open(second)) else {
+        return false;
+    };
+    let same_len = match (first_file.metadata(), second_file.metadata()) {
+        (Ok(first_meta), Ok(second_meta)) => first_meta.len() == second_meta.len(),
+        _ => false,
+    };
+    if !same_len {
+        return false;
+    }
+    let mut first_reader = BufReader::new(first_file);
+    let mut second_reader = BufReader::new(second_file);
+    let mut first_chunk = vec![0u8; 64 * 1024];
+    let mut second_chunk = vec![0u8; 64 * 1024];
+    loop {
+        let Ok(read) = first_reader.read(&mut first_chunk) else {
+            return false;
+        };
+        if read == 0 {
+            return true;
+        }
+        if second_reader.read_exact(&mut second_chunk[..read]).is_err()
+            || first_chunk[..read] != second_chunk[..read]
+        {
+            return false;
+        }
+    }
+}
+
+// Quote a CSV field when it holds a separator, a quote or a line break
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Fields of a CSV line, quoted fields are unescaped
+pub fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+// SHA-256 manifest of the written files in the destination
+pub const MANIFEST_FILE: &str = "MANIFEST.sha256";
+
+// Files that need a manual review are written under this directory of the destination
+pub const REVIEW_REQUIRED_DIR: &str = "REVIEW_REQUIRED";
+
+// Copies of the source files that failed
+pub const FAILED_CASES_DIR: &str = "FAILED_CASES";
+
+// Written in each directory of the destination with data that is not deidentified
+pub const PHI_README: &str = "README.txt";
+
+// What a directory of the destination with data that is not deidentified holds, for its README
+fn phi_description(name: &str) -> &'static str {
+    match name {
+        FAILED_CASES_DIR => "copies of the source files that could not be processed",
+        INCOMPLETE_DIR => "copies of the source files that looked partially transferred",
+        LARGE_FILES_DIR => "copies of the large source files that were set aside",
+        FILTERED_OUT_DIR => "copies of the source files that did not match the filter of the run",
+        _ => "files flagged for a manual review and quarantined non DICOM files, they may still hold patient details",
+    }
+}
+
+// Create a directory of the destination that holds data that is not deidentified: only the user
+// can open it, a README says what it holds, and with hide the file browsers don't show it.
+// An existing directory is left as it is
+pub fn create_phi_dir(dir: &Path, hide: bool) -> std::io::Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    match builder.create(dir) {
+        // Created by another worker
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(()),
+        created => created?,
+    }
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    fs::write(
+        dir.join(PHI_README),
+        format!(
+            "NOT DEIDENTIFIED, this directory holds {}.\n\
+             Written by dcmrig, review them and keep them away from the deidentified data, then delete them.\n",
+            phi_description(&name)
+        ),
+    )?;
+    if hide {
+        hide_dir(dir);
+    }
+    Ok(())
+}
+
+// Hidden flag of Finder
+#[cfg(target_os = "macos")]
+fn hide_dir(dir: &Path) {
+    use std::os::unix::ffi::OsStrExt;
+    let path = match std::ffi::CString::new(dir.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    if unsafe { libc::chflags(path.as_ptr(), libc::UF_HIDDEN) } != 0 {
+        warn!(
+            "Can't hide {}: {}",
+            dir.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+// Hidden and system attributes of Explorer
+#[cfg(windows)]
+fn hide_dir(dir: &Path) {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{
+        SetFileAttributesW, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_SYSTEM,
+    };
+    let path: Vec<u16> = dir.as_os_str().encode_wide().chain([0]).collect();
+    if unsafe { SetFileAttributesW(path.as_ptr(), FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM) }
+        == 0
+    {
+        warn!(
+            "Can't hide {}: {}",
+            dir.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+// Linux and the other platforms only hide the names starting with a dot
+#[cfg(not(any(target_os = "macos", windows)))]
+fn hide_dir(dir: &Path) {
+    debug!(
+        "{} is not hidden, this platform has no hidden attribute",
+        dir.display()
+    );
+}
+
+// What to do with files that can't be cleaned by header edits alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReviewAction {
+    // Write to the output like any other file
+    Keep,
+    // Write under REVIEW_REQUIRED with a note of the reason
+    #[default]
+    Review,
+    // Leave out of the output
+    Exclude,
+}
+
+impl FromStr for ReviewAction {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "keep" => Ok(ReviewAction::Keep),
+            "review" => Ok(ReviewAction::Review),
+            "exclude" => Ok(ReviewAction::Exclude),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of keep, review or exclude: {}",
+                value
+            )),
+        }
+    }
+}
+
+// What to do with the non DICOM files of deid and anon, eg reports and CSVs next to the studies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NonDicomAction {
+    // Copy to NON_DICOM as they are
+    #[default]
+    Copy,
+    // Copy to REVIEW_REQUIRED/NON_DICOM with a note of the reason
+    Quarantine,
+    // Copy the text files to NON_DICOM with the identifiers of the run replaced, quarantine the
+    // other files
+    Scrub,
+}
+
+impl FromStr for NonDicomAction {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "copy" => Ok(NonDicomAction::Copy),
+            "quarantine" => Ok(NonDicomAction::Quarantine),
+            "scrub" => Ok(NonDicomAction::Scrub),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of copy, quarantine or scrub: {}",
+                value
+            )),
+        }
+    }
+}
+
+// Archive the instances of a run are written into instead of a directory tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Zip,
+    Tar,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "zip" => Ok(OutputFormat::Zip),
+            "tar" => Ok(OutputFormat::Tar),
+            _ => Err(anyhow::anyhow!("Should be zip or tar: {}", value)),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Zip => "zip",
+            OutputFormat::Tar => "tar",
+        }
+    }
+}
+
+// Format of the inventory of dcmrig report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReportFormat {
+    // Aligned columns for the terminal
+    #[default]
+    Table,
+    Csv,
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "table" => Ok(ReportFormat::Table),
+            "csv" => Ok(ReportFormat::Csv),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of table, csv or json: {}",
+                value
+            )),
+        }
+    }
+}
+
+// One row of the inventory per series or per study
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InventoryLevel {
+    Study,
+    #[default]
+    Series,
+}
+
+impl FromStr for InventoryLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "study" => Ok(InventoryLevel::Study),
+            "series" => Ok(InventoryLevel::Series),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of study or series: {}",
+                value
+            )),
+        }
+    }
+}
+
+// Output directory of the items that look partially transferred
+pub const INCOMPLETE_DIR: &str = "INCOMPLETE";
+
+// Output directory of the delivery archives
+pub const DELIVERY_DIR: &str = "DELIVERY";
+
+// What each delivery archive holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryUnit {
+    Study,
+    Patient,
+}
+
+impl FromStr for DeliveryUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "study" => Ok(DeliveryUnit::Study),
+            "patient" => Ok(DeliveryUnit::Patient),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of study or patient: {}",
+                value
+            )),
+        }
+    }
+}
+
+// Output directory of the files over the large file limit that are not processed
+pub const LARGE_FILES_DIR: &str = "LARGE_FILES";
+
+// What to do with the files over the large file limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LargeFileAction {
+    // Read without the pixel data, which is copied from the source when written
+    #[default]
+    Stream,
+    // Copy to LARGE_FILES in the destination as they are
+    Quarantine,
+}
+
+impl FromStr for LargeFileAction {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "stream" => Ok(LargeFileAction::Stream),
+            "quarantine" => Ok(LargeFileAction::Quarantine),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of stream or quarantine: {}",
+                value
+            )),
+        }
+    }
+}
+
+// Transfer syntax of the written pixel data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TranscodeTarget {
+    // Written in the transfer syntax of the source
+    #[default]
+    Keep,
+    // Decoded to native pixel data in Explicit VR Little Endian
+    ExplicitLe,
+    // Encoded as JPEG Lossless, Non-Hierarchical, First-Order Prediction
+    JpegLossless,
+}
+
+impl TranscodeTarget {
+    pub fn transfer_syntax(&self) -> Option<&'static str> {
+        match self {
+            TranscodeTarget::Keep => None,
+            TranscodeTarget::ExplicitLe => Some(EXPLICIT_VR_LITTLE_ENDIAN),
+            TranscodeTarget::JpegLossless => Some(JPEG_LOSSLESS_SV1),
+        }
+    }
+}
+
+impl FromStr for TranscodeTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "keep" => Ok(TranscodeTarget::Keep),
+            "explicit-le" => Ok(TranscodeTarget::ExplicitLe),
+            "jpeg-lossless" => Ok(TranscodeTarget::JpegLossless),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of explicit-le, jpeg-lossless or keep: {}",
+                value
+            )),
+        }
+    }
+}
+
+// Size in bytes, K, M, G and T suffixes are powers of 1024, eg 2G or 512M
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Size should be bytes or a number with K, M, G or T: {}",
+                value
+            )
+        };
+        let upper = value.trim().to_uppercase();
+        let number = upper.trim_end_matches("IB").trim_end_matches('B');
+        let (digits, shift) = match number.chars().last() {
+            Some('K') => (&number[..number.len() - 1], 10),
+            Some('M') => (&number[..number.len() - 1], 20),
+            Some('G') => (&number[..number.len() - 1], 30),
+            Some('T') => (&number[..number.len() - 1], 40),
+            _ => (number, 0),
+        };
+        let size: u64 = digits.trim().parse().map_err(|_| invalid())?;
+        size.checked_mul(1 << shift)
+            .map(ByteSize)
+            .ok_or_else(invalid)
+    }
+}
+
+// With the largest suffix under which the size is at least 1, eg 1.5G
+impl std::fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (divisor, suffix) = match self.0 {
+            size if size >= 1 << 40 => (1u64 << 40, "T"),
+            size if size >= 1 << 30 => (1 << 30, "G"),
+            size if size >= 1 << 20 => (1 << 20, "M"),
+            size if size >= 1 << 10 => (1 << 10, "K"),
+            _ => return write!(f, "{}", self.0),
+        };
+        write!(f, "{:.1}{}", self.0 as f64 / divisor as f64, suffix)
+    }
+}
+
+// What to do with the zero byte and truncated items
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IncompleteAction {
+    // Copy to INCOMPLETE in the destination
+    #[default]
+    Copy,
+    // Only list in results.csv
+    Skip,
+}
+
+impl FromStr for IncompleteAction {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "copy" => Ok(IncompleteAction::Copy),
+            "skip" => Ok(IncompleteAction::Skip),
+            _ => Err(anyhow::anyhow!("Should be one of copy or skip: {}", value)),
+        }
+    }
+}
+
+// Reason an item looks partially transferred: it is empty, or it has the DICM prefix but ends
+// inside an element. Other unreadable items are not DICOM
+pub fn incomplete_reason(
+    source: &dyn InstanceSource,
+    item: &Path,
+    error: &anyhow::Error,
+) -> Option<String> {
+    let mut head = vec![];
+    source
+        .open_bytes(item)
+        .ok()?
+        .take(132)
+        .read_to_end(&mut head)
+        .ok()?;
+    if head.is_empty() {
+        return Some("Zero byte file".to_string());
+    }
+    let ended_early = error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+    });
+    (ended_early && head.get(128..132) == Some(b"DICM".as_slice()))
+        .then(|| format!("Truncated DICOM file: {}", error))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReviewPolicy {
+    // Secondary captures of the dose screen, the patient details are burned into the pixels
+    pub dose_screens: ReviewAction,
+    // VL and ophthalmic photographs, a face can't be removed by header edits
+    pub photos: ReviewAction,
+    // LABEL and OVERVIEW images of whole slides, the printed label shows the patient details
+    pub slide_labels: ReviewAction,
+}
+
+impl ReviewPolicy {
+    // Action and reason for a file, None if it needs no special handling
+    pub fn review_reason(
+        &self,
+        dicom_tags_values: &HashMap<String, String>,
+    ) -> Option<(ReviewAction, String)> {
+        match dicom_tags_values.get("SeriesClass").map(|v| v.as_str()) {
+            Some("DoseScreen") => Some((
+                self.dose_screens,
+                "Dose screen secondary capture, patient details may be burned into the pixels"
+                    .to_string(),
+            )),
+            Some("Photo") => Some((
+                self.photos,
+                "Photograph, the face or other identifying features of the patient may be visible"
+                    .to_string(),
+            )),
+            Some("SlideLabel") | Some("SlideOverview") => Some((
+                self.slide_labels,
+                "Slide label or overview image, the printed label may show the patient details"
+                    .to_string(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+// The references to the photos of the patient are only kept along with the photos
+pub fn delete_patient_photo_references(
+    dcm_obj: &mut InMemDicomObject,
+    review_policy: &ReviewPolicy,
+) {
+    if review_policy.photos != ReviewAction::Keep {
+        dcm_obj.remove_element(tags::REFERENCED_PATIENT_PHOTO_SEQUENCE);
+    }
+}
+
+// Note next to a file routed to REVIEW_REQUIRED
+pub fn write_review_note(full_path: &str, reason: &str) -> Result<()> {
+    fs::write(format!("{}.review.txt", full_path), format!("{}\n", reason))?;
+    Ok(())
+}
+
+// Identifiers of the object that may be repeated in free text
+pub fn identifying_values(dcm_obj: &InMemDicomObject) -> Vec<String> {
+    let mut values = vec![];
+    for tag in [
+        tags::PATIENT_ID,
+        tags::PATIENT_NAME,
+        tags::ACCESSION_NUMBER,
+        tags::OPERATORS_NAME,
+        tags::PERFORMING_PHYSICIAN_NAME,
+        tags::REFERRING_PHYSICIAN_NAME,
+        tags::INSTITUTION_NAME,
+        tags::STATION_NAME,
+    ] {
+        if let Some(Ok(element_values)) = dcm_obj.get(tag).map(|element| element.to_multi_str()) {
+            for value in element_values.iter() {
+                // Person names are also written as their separate components
+                for part in value
+                    .split(['^', '='])
+                    .chain(std::iter::once(value.as_str()))
+                {
+                    let part = part.trim();
+                    if part.len() > 2 && !values.iter().any(|v: &String| v == part) {
+                        values.push(part.to_string());
+                    }
+                }
+            }
+        }
+    }
+    // Longest first so a full name is replaced before its components
+    values.sort_by_key(|v| std::cmp::Reverse(v.len()));
+    values
+}
+
+// Scrub the content tree of structured reports, eg radiation dose SRs
+// PNAME items get the replacement ID, the identifiers of the object are removed from TEXT items
+// Must run before the identifiers of the object are masked
+pub fn scrub_sr_content(dcm_obj: &mut InMemDicomObject, replacement_id: &str) {
+    if dcm_obj.get(tags::CONTENT_SEQUENCE).is_none() {
+        return;
+    }
+    let identifiers = identifying_values(dcm_obj);
+    scrub_content_items(dcm_obj, replacement_id, &identifiers);
+}
+
+fn scrub_content_items(
+    dcm_obj: &mut InMemDicomObject,
+    replacement_id: &str,
+    identifiers: &[String],
+) {
+    dcm_obj.update_value(tags::CONTENT_SEQUENCE, |value| {
+        let items = match value.items_mut() {
+            Some(items) => items,
+            None => return,
+        };
+        for item in items.iter_mut() {
+            let value_type = item
+                .get(tags::VALUE_TYPE)
+                .and_then(|element| element.to_str().ok().map(|v| v.trim().to_string()))
+                .unwrap_or_default();
+            match value_type.as_str() {
+                "PNAME" => {
+                    item.put(DataElement::new(
+                        tags::PERSON_NAME,
+                        VR::PN,
+                        vr_dummy_value(VR::PN, replacement_id),
+                    ));
+                }
+                "TEXT" => {
+                    let text = item
+                        .get(tags::TEXT_VALUE)
+                        .and_then(|element| element.to_str().ok().map(|v| v.to_string()));
+                    if let Some(text) = text {
+                        let scrubbed = identifiers.iter().fold(text.clone(), |text, identifier| {
+                            text.replace(identifier.as_str(), replacement_id)
+                        });
+                        if scrubbed != text {
+                            item.put(DataElement::new(
+                                tags::TEXT_VALUE,
+                                VR::UT,
+                                dicom_value!(Str, scrubbed),
+                            ));
+                        }
+                    }
+                }
+                _ => (),
+            }
+            scrub_content_items(item, replacement_id, identifiers);
+        }
+    });
+}
+
+// What to do with the free text of presentation state annotations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnnotationText {
+    // Leave the text objects as they are
+    Keep,
+    // Replace the identifiers of the object in the text
+    Redact,
+    // Remove the text objects, graphic objects are kept
+    #[default]
+    Remove,
+}
+
+impl FromStr for AnnotationText {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "keep" => Ok(AnnotationText::Keep),
+            "redact" => Ok(AnnotationText::Redact),
+            "remove" => Ok(AnnotationText::Remove),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of keep, redact or remove: {}",
+                value
+            )),
+        }
+    }
+}
+
+// Clean the TextObjectSequence of each GraphicAnnotationSequence item, eg in GSPS
+// Annotations left without text or graphic objects are dropped
+// Must run before the identifiers of the object are masked
+pub fn scrub_annotation_text(
+    dcm_obj: &mut InMemDicomObject,
+    action: AnnotationText,
+    replacement_id: &str,
+) {
+    if action == AnnotationText::Keep || dcm_obj.get(tags::GRAPHIC_ANNOTATION_SEQUENCE).is_none() {
+        return;
+    }
+    let identifiers = identifying_values(dcm_obj);
+    dcm_obj.update_value(tags::GRAPHIC_ANNOTATION_SEQUENCE, |value| {
+        let annotations = match value.items_mut() {
+            Some(annotations) => annotations,
+            None => return,
+        };
+        for annotation in annotations.iter_mut() {
+            match action {
+                AnnotationText::Remove => {
+                    annotation.remove_element(tags::TEXT_OBJECT_SEQUENCE);
+                }
+                _ => {
+                    annotation.update_value(tags::TEXT_OBJECT_SEQUENCE, |value| {
+                        for text_object in value.items_mut().into_iter().flatten() {
+                            let text = text_object
+                                .get(tags::UNFORMATTED_TEXT_VALUE)
+                                .and_then(|element| element.to_str().ok().map(|v| v.to_string()));
+                            if let Some(text) = text {
+                                let redacted =
+                                    identifiers.iter().fold(text.clone(), |text, identifier| {
+                                        text.replace(identifier.as_str(), replacement_id)
+                                    });
+                                if redacted != text {
+                                    text_object.put(DataElement::new(
+                                        tags::UNFORMATTED_TEXT_VALUE,
+                                        VR::ST,
+                                        dicom_value!(Str, redacted),
+                                    ));
+                                }
+                            }
+                        }
+                    });
+                }
+            }
+        }
+        annotations.retain(|annotation| {
+            annotation.get(tags::TEXT_OBJECT_SEQUENCE).is_some()
+                || annotation.get(tags::GRAPHIC_OBJECT_SEQUENCE).is_some()
+        });
+    });
+}
+
+// SOP classes of the radiation dose structured reports
+const DOSE_REPORT_SOP_CLASSES: [&str; 3] = [
+    "1.2.840.10008.5.1.4.1.1.88.67",
+    "1.2.840.10008.5.1.4.1.1.88.68",
+    "1.2.840.10008.5.1.4.1.1.88.76",
+];
+
+// SOP classes of the visible light and ophthalmic photographs, they may show the face of the patient
+const PHOTO_SOP_CLASSES: [&str; 6] = [
+    "1.2.840.10008.5.1.4.1.1.77.1.4",
+    "1.2.840.10008.5.1.4.1.1.77.1.4.1",
+    "1.2.840.10008.5.1.4.1.1.77.1.5.1",
+    "1.2.840.10008.5.1.4.1.1.77.1.5.2",
+    "1.2.840.10008.5.1.4.1.1.77.1.5.5",
+    "1.2.840.10008.5.1.4.1.1.77.1.5.6",
+];
+
+// Whole slide microscopy, the LABEL and OVERVIEW images usually show the printed slide label
+const WSI_SOP_CLASS: &str = "1.2.840.10008.5.1.4.1.1.77.1.6";
+
+// Modalities of the image SOP classes, a SOP class that is not listed takes any modality
+const SOP_CLASS_MODALITIES: [(&str, &[&str]); 36] = [
+    ("1.2.840.10008.5.1.4.1.1.1", &["CR"]),
+    ("1.2.840.10008.5.1.4.1.1.1.1", &["DX"]),
+    ("1.2.840.10008.5.1.4.1.1.1.1.1", &["DX"]),
+    ("1.2.840.10008.5.1.4.1.1.1.2", &["MG"]),
+    ("1.2.840.10008.5.1.4.1.1.1.2.1", &["MG"]),
+    ("1.2.840.10008.5.1.4.1.1.1.3", &["IO"]),
+    ("1.2.840.10008.5.1.4.1.1.1.3.1", &["IO"]),
+    ("1.2.840.10008.5.1.4.1.1.2", &["CT"]),
+    ("1.2.840.10008.5.1.4.1.1.2.1", &["CT"]),
+    ("1.2.840.10008.5.1.4.1.1.2.2", &["CT"]),
+    ("1.2.840.10008.5.1.4.1.1.3.1", &["US"]),
+    ("1.2.840.10008.5.1.4.1.1.4", &["MR"]),
+    ("1.2.840.10008.5.1.4.1.1.4.1", &["MR"]),
+    ("1.2.840.10008.5.1.4.1.1.4.2", &["MR"]),
+    ("1.2.840.10008.5.1.4.1.1.4.4", &["MR"]),
+    ("1.2.840.10008.5.1.4.1.1.6.1", &["US"]),
+    ("1.2.840.10008.5.1.4.1.1.6.2", &["US"]),
+    ("1.2.840.10008.5.1.4.1.1.11.1", &["PR"]),
+    ("1.2.840.10008.5.1.4.1.1.12.1", &["XA"]),
+    ("1.2.840.10008.5.1.4.1.1.12.1.1", &["XA"]),
+    ("1.2.840.10008.5.1.4.1.1.12.2", &["RF"]),
+    ("1.2.840.10008.5.1.4.1.1.12.2.1", &["RF"]),
+    ("1.2.840.10008.5.1.4.1.1.20", &["NM"]),
+    ("1.2.840.10008.5.1.4.1.1.66.4", &["SEG"]),
+    ("1.2.840.10008.5.1.4.1.1.88.11", &["SR"]),
+    ("1.2.840.10008.5.1.4.1.1.88.22", &["SR"]),
+    ("1.2.840.10008.5.1.4.1.1.88.33", &["SR"]),
+    ("1.2.840.10008.5.1.4.1.1.88.59", &["KO"]),
+    ("1.2.840.10008.5.1.4.1.1.88.67", &["SR"]),
+    ("1.2.840.10008.5.1.4.1.1.128", &["PT"]),
+    ("1.2.840.10008.5.1.4.1.1.128.1", &["PT"]),
+    ("1.2.840.10008.5.1.4.1.1.130", &["PT"]),
+    ("1.2.840.10008.5.1.4.1.1.481.1", &["RTIMAGE"]),
+    ("1.2.840.10008.5.1.4.1.1.481.2", &["RTDOSE"]),
+    ("1.2.840.10008.5.1.4.1.1.481.3", &["RTSTRUCT"]),
+    ("1.2.840.10008.5.1.4.1.1.481.5", &["RTPLAN"]),
+];
+
+// Secondary captures, eg screenshots and dose screens, don't come from the scanner of their
+// modality
+const SECONDARY_CAPTURE_SOP_CLASSES: [&str; 5] = [
+    "1.2.840.10008.5.1.4.1.1.7",
+    "1.2.840.10008.5.1.4.1.1.7.1",
+    "1.2.840.10008.5.1.4.1.1.7.2",
+    "1.2.840.10008.5.1.4.1.1.7.3",
+    "1.2.840.10008.5.1.4.1.1.7.4",
+];
+
+// Acquisition modalities, a secondary capture labeled with one of them is flagged
+const ACQUISITION_MODALITIES: [&str; 11] = [
+    "CT", "MR", "PT", "NM", "US", "CR", "DX", "MG", "IO", "XA", "RF",
+];
+
+// Modalities with grayscale images only
+const GRAYSCALE_MODALITIES: [&str; 10] =
+    ["CT", "MR", "PT", "NM", "CR", "DX", "MG", "IO", "XA", "RF"];
+
+/// Why the Modality of the instance doesn't fit its SOP class or its pixels, eg a secondary
+/// capture labeled CT or an MR with RGB pixels, which sorts it with the wrong series
+pub fn modality_mismatch(dcm_obj: &InMemDicomObject) -> Option<String> {
+    let value_of = |tag: Tag| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_str().ok())
+            .map(|value| value.trim_end_matches('\0').trim().to_uppercase())
+            .unwrap_or_default()
+    };
+    let modality = value_of(tags::MODALITY);
+    if modality.is_empty() {
+        return None;
+    }
+    let sop_class = value_of(tags::SOP_CLASS_UID);
+    let sop_class_name = StandardSopClassDictionary
+        .by_uid(&sop_class)
+        .map_or(sop_class.as_str(), |entry| entry.name());
+    let mut reasons = vec![];
+    if let Some((_, modalities)) = SOP_CLASS_MODALITIES
+        .iter()
+        .find(|(uid, _)| *uid == sop_class)
+    {
+        if !modalities.contains(&modality.as_str()) {
+            reasons.push(format!(
+                "{} with the SOP class {}, expected {}",
+                modality,
+                sop_class_name,
+                modalities.join(" or ")
+            ));
+        }
+    }
+    if SECONDARY_CAPTURE_SOP_CLASSES.contains(&sop_class.as_str())
+        && ACQUISITION_MODALITIES.contains(&modality.as_str())
+    {
+        reasons.push(format!("{} on a {}", modality, sop_class_name));
+    }
+    if GRAYSCALE_MODALITIES.contains(&modality.as_str()) {
+        let photometric = value_of(tags::PHOTOMETRIC_INTERPRETATION);
+        let samples = value_of(tags::SAMPLES_PER_PIXEL);
+        if samples.parse::<u16>().is_ok_and(|samples| samples > 1)
+            || photometric.starts_with("RGB")
+            || photometric.starts_with("YBR")
+            || photometric == "PALETTE COLOR"
+        {
+            reasons.push(format!("{} with {} color pixels", modality, photometric));
+        }
+    }
+    // Hounsfield units don't fit in 8 bits
+    if modality == "CT"
+        && value_of(tags::BITS_STORED)
+            .parse::<u16>()
+            .is_ok_and(|bits| bits <= 8)
+    {
+        reasons.push("CT with 8 bit pixels".to_string());
+    }
+    match reasons.is_empty() {
+        true => None,
+        false => Some(reasons.join("; ")),
+    }
+}
+
+// Series class > pattern over the lowercase ProtocolName and SeriesDescription
+// The first match wins, so the more specific classes come first
+const SERIES_CLASS_RULES: [(&str, &str); 8] = [
+    ("DoseScreen", r"dose|patient protocol|exam protocol"),
+    (
+        "Localizer",
+        r"localizer|localiser|scout|survey|topogram|surview|3.?plane|\bloc\b",
+    ),
+    ("DWI", r"dwi|diff|dti|\badc\b|trace|\bb\d{3,4}\b"),
+    ("FLAIR", r"flair"),
+    ("T1w", r"t1|mprage|spgr|bravo|\btfl\b|vibe|\bmp.?rage"),
+    ("T2w", r"t2|\btse\b|\bfse\b|haste|space"),
+    ("CTAngio", r"angio|\bcta\b|\bcow\b|runoff|arterial"),
+    ("Perfusion", r"perf|\bpwi\b|\bdsc\b|\bdce\b|\basl\b"),
+];
+
+// Label the series with one of the SERIES_CLASS_RULES classes, DoseReport, Photo, WSI,
+// SlideLabel, SlideOverview or Other
+// Uses the SOP class, ImageType, ProtocolName, SeriesDescription and ScanningSequence
+pub fn classify_series(dcm_obj: &FileDicomObject<InMemDicomObject>) -> String {
+    static RULES: OnceLock<Vec<(&str, Regex)>> = OnceLock::new();
+    let rules = RULES.get_or_init(|| {
+        SERIES_CLASS_RULES
+            .iter()
+            .map(|(label, pattern)| (*label, Regex::new(pattern).expect("Invalid class rule")))
+            .collect()
+    });
+    let value_of = |tag: Tag| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_str().ok().map(|v| v.to_lowercase()))
+            .unwrap_or_default()
+    };
+    let sop_class = value_of(tags::SOP_CLASS_UID);
+    let sop_class = sop_class.trim_end_matches('\0').trim();
+    if DOSE_REPORT_SOP_CLASSES.contains(&sop_class) {
+        return "DoseReport".to_string();
+    }
+    if PHOTO_SOP_CLASSES.contains(&sop_class) {
+        return "Photo".to_string();
+    }
+    let modality = value_of(tags::MODALITY);
+    let image_type = value_of(tags::IMAGE_TYPE);
+    if sop_class == WSI_SOP_CLASS {
+        // The third ImageType value tells the pyramid levels from the other images of the slide
+        return match image_type.split('\\').nth(2).map(str::trim) {
+            Some("label") => "SlideLabel",
+            Some("overview") => "SlideOverview",
+            _ => "WSI",
+        }
+        .to_string();
+    }
+    let scanning_sequence = value_of(tags::SCANNING_SEQUENCE);
+    let description = format!(
+        "{} {}",
+        value_of(tags::PROTOCOL_NAME),
+        value_of(tags::SERIES_DESCRIPTION)
+    );
+    if modality.trim() == "sr" && description.contains("dose") {
+        return "DoseReport".to_string();
+    }
+    if image_type.contains("localizer") {
+        return "Localizer".to_string();
+    }
+    for (label, pattern) in rules {
+        if !pattern.is_match(&description) {
+            continue;
+        }
+        match *label {
+            // Dose screens are secondary captures, a CT series named after its protocol is not one
+            "DoseScreen" if !image_type.contains("secondary") => continue,
+            "CTAngio" if modality.trim() != "ct" => continue,
+            _ => return label.to_string(),
+        }
+    }
+    // Fall back to the acquisition when the description is not telling
+    if scanning_sequence.contains("ep") && image_type.contains("diffusion") {
+        return "DWI".to_string();
+    }
+    if scanning_sequence.contains("ir") && scanning_sequence.contains("gr") {
+        return "T1w".to_string();
+    }
+    "Other".to_string()
+}
+
+fn determine_plane(dcm_obj: &FileDicomObject<InMemDicomObject>) -> Result<String> {
+    let orientation: Vec<f64> = match dcm_obj.element_by_name("ImageOrientationPatient") {
+        Ok(value) => value.to_multi_float64()?,
+        Err(_) => vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    };
+    let row_x = orientation[0].round() as i8;
+    let row_y = orientation[1].round() as i8;
+    let col_y = orientation[4].round() as i8;
+    let col_z = orientation[5].round() as i8;
+    let plane = if row_x == 1 && col_z == -1 {
+        "COR".to_string()
+    } else if row_x == 1 && col_y == 1 {
+        "AX".to_string()
+    } else if row_y == 1 && col_z == -1 {
+        "SAG".to_string()
+    } else {
+        "NA".to_string()
+    };
+    Ok(plane)
+}
+
+// Description mapping file, rewrites free text descriptions to a controlled vocabulary
+// SMTP port of the relays the completion emails are sent through
+pub const DEFAULT_SMTP_PORT: u16 = 25;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmailConfigFile {
+    server: String,
+    port: Option<u16>,
+    from: String,
+    to: Vec<String>,
+    subject: Option<String>,
+    #[serde(default)]
+    attach_failures: bool,
+    #[serde(default)]
+    plaintext_relay: bool,
+}
+
+// Hosts of an SMTP relay on the machine itself, the only ones the plaintext client talks to
+// unless the email config opts in
+const LOCAL_SMTP_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+// Completion email of a job, sent through a plain SMTP relay at the end of the run
+#[derive(Debug, Clone)]
+pub struct EmailConfig {
+    pub path: PathBuf,
+    pub server: String,
+    pub port: u16,
+    pub from: String,
+    pub to: Vec<String>,
+    // {action}, {written} and {failed} are replaced with the values of the run
+    pub subject: String,
+    // Attach the source and error of the failed files
+    pub attach_failures: bool,
+}
+
+impl EmailConfig {
+    pub fn from_file(email_path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(email_path).map_err(|e| {
+            anyhow::anyhow!(
+                "Can't read the email config {}: {}",
+                email_path.display(),
+                e
+            )
+        })?;
+        let email_file: EmailConfigFile = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid email config {}\n{}", email_path.display(), e))?;
+        if email_file.to.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No recipients in the email config {}",
+                email_path.display()
+            ));
+        }
+        // The addresses go in the SMTP commands and the headers as they are
+        for address in email_file.to.iter().chain([&email_file.from]) {
+            if !address.contains('@') || address.contains(['<', '>', '\r', '\n', ' ']) {
+                return Err(anyhow::anyhow!(
+                    "Invalid address in the email config {}: {}",
+                    email_path.display(),
+                    address
+                ));
+            }
+        }
+        // The summary is sent in clear text without authentication
+        if !email_file.plaintext_relay && !LOCAL_SMTP_HOSTS.contains(&email_file.server.as_str()) {
+            return Err(anyhow::anyhow!(
+                "The email is sent without TLS or authentication, set plaintext_relay = true in {} to use the relay {}",
+                email_path.display(),
+                email_file.server
+            ));
+        }
+        Ok(EmailConfig {
+            path: email_path.to_path_buf(),
+            server: email_file.server,
+            port: email_file.port.unwrap_or(DEFAULT_SMTP_PORT),
+            from: email_file.from,
+            to: email_file.to,
+            subject: email_file.subject.unwrap_or_else(|| {
+                "dcmrig {action}: {written} written, {failed} failed".to_string()
+            }),
+            attach_failures: email_file.attach_failures,
+        })
+    }
+}
+
+// What anon does with the private elements
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrivateTagPolicy {
+    #[default]
+    Remove,
+    Keep,
+    // Keep the elements of the allowlist file, remove the others
+    Allowlist,
+}
+
+impl FromStr for PrivateTagPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "remove" => Ok(PrivateTagPolicy::Remove),
+            "keep" => Ok(PrivateTagPolicy::Keep),
+            "allowlist" => Ok(PrivateTagPolicy::Allowlist),
+            _ => Err(anyhow::anyhow!(
+                "Should be one of remove, keep or allowlist: {}",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrivateAllowlistFile {
+    #[serde(default)]
+    keep: Vec<PrivateBlockFile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrivateBlockFile {
+    creator: String,
+    group: u16,
+    // Element offsets in the block of the creator, eg 0x0C for (0019,xx0C)
+    elements: Vec<u8>,
+}
+
+// Vendor private elements known to be safe, identified by the private creator, the group and the
+// element offset as the block number depends on the file
+#[derive(Debug, Clone)]
+pub struct PrivateAllowlist {
+    pub path: PathBuf,
+    blocks: Vec<PrivateBlockFile>,
+}
+
+impl PrivateAllowlist {
+    pub fn from_file(allowlist_path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(allowlist_path).map_err(|e| {
+            anyhow::anyhow!(
+                "Can't read the private tag allowlist {}: {}",
+                allowlist_path.display(),
+                e
+            )
+        })?;
+        let allowlist_file: PrivateAllowlistFile = toml::from_str(&content).map_err(|e| {
+            anyhow::anyhow!(
+                "Invalid private tag allowlist {}\n{}",
+                allowlist_path.display(),
+                e
+            )
+        })?;
+        for block in &allowlist_file.keep {
+            if block.group % 2 == 0 || block.group < 0x0009 {
+                return Err(anyhow::anyhow!(
+                    "Not a private group in the allowlist {}: {:04X} of {}",
+                    allowlist_path.display(),
+                    block.group,
+                    block.creator
+                ));
+            }
+        }
+        Ok(PrivateAllowlist {
+            path: allowlist_path.to_path_buf(),
+            blocks: allowlist_file.keep,
+        })
+    }
+
+    fn allows(&self, creator: &str, group: u16, offset: Option<u8>) -> bool {
+        self.blocks.iter().any(|block| {
+            block.creator == creator
+                && block.group == group
+                && offset.is_none_or(|offset| block.elements.contains(&offset))
+        })
+    }
+
+    // Remove the private elements that are not in the allowlist, in the items of sequences too
+    // A private creator is kept with the elements of its block. Returns the number of removed elements
+    pub fn apply(&self, dcm_obj: &mut InMemDicomObject) -> usize {
+        let creator_of = |dcm_obj: &InMemDicomObject, group: u16, block: u16| {
+            dcm_obj
+                .get(Tag(group, block))
+                .and_then(|element| element.to_str().ok())
+                .map(|creator| creator.trim_end_matches(['\0', ' ']).to_string())
+        };
+        let elements: Vec<(Tag, VR)> = dcm_obj.iter().map(|e| (e.tag(), e.vr())).collect();
+        let mut removed = 0;
+        for (tag, vr) in elements {
+            let (group, element) = (tag.group(), tag.element());
+            let allowed = match (group % 2 == 1, element) {
+                (false, _) => true,
+                (true, 0x0010..=0x00FF) => creator_of(dcm_obj, group, element)
+                    .is_some_and(|creator| self.allows(&creator, group, None)),
+                (true, 0x1000..) => {
+                    creator_of(dcm_obj, group, element >> 8).is_some_and(|creator| {
+                        self.allows(&creator, group, Some((element & 0xFF) as u8))
+                    })
+                }
+                (true, _) => false,
+            };
+            if !allowed {
+                dcm_obj.remove_element(tag);
+                removed += 1;
+            } else if vr == VR::SQ {
+                dcm_obj.update_value(tag, |value| {
+                    if let Some(items) = value.items_mut() {
+                        items
+                            .iter_mut()
+                            .for_each(|item| removed += self.apply(item));
+                    }
+                });
+            }
+        }
+        removed
+    }
+
+    pub fn summary(&self) -> String {
+        let blocks: Vec<String> = self
+            .blocks
+            .iter()
+            .map(|block| format!("{:04X} {}", block.group, block.creator))
+            .collect();
+        format!("{} ({})", self.path.display(), blocks.join(", "))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DescriptionMapFile {
+    tags: Option<Vec<String>>,
+    unmatched: Option<String>,
+    #[serde(default)]
+    rule: Vec<DescriptionRuleFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DescriptionRuleFile {
+    label: String,
+    patterns: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct DescriptionRule {
+    label: String,
+    patterns: Vec<Regex>,
+}
+
+// Rules are tried in order and the first matching rule gives the new description
+// Patterns are case insensitive regular expressions searched anywhere in the description
+#[derive(Debug, Clone)]
+pub struct DescriptionMap {
+    pub path: PathBuf,
+    tags: Vec<Tag>,
+    unmatched: Option<String>,
+    rules: Vec<DescriptionRule>,
+}
+
+impl DescriptionMap {
+    pub fn from_file(map_path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(map_path).map_err(|e| {
+            anyhow::anyhow!(
+                "Can't read the description map {}: {}",
+                map_path.display(),
+                e
+            )
+        })?;
+        let map_file: DescriptionMapFile = toml::from_str(&content).map_err(|e| {
+            anyhow::anyhow!("Invalid description map {}\n{}", map_path.display(), e)
+        })?;
+        let tag_names = map_file.tags.unwrap_or_else(|| {
+            vec![
+                "StudyDescription".to_string(),
+                "SeriesDescription".to_string(),
+            ]
+        });
+        let mut tags = vec![];
+        for tag_name in tag_names {
+            match StandardDataDictionary.by_name(&tag_name) {
+                Some(entry) => tags.push(entry.tag.inner()),
+                None => {
+                    return Err(anyhow::anyhow!(
+                        "Unknown tag in description map: {}",
+                        tag_name
+                    ))
+                }
+            }
+        }
+        let mut rules = vec![];
+        for rule in map_file.rule {
+            let mut patterns = vec![];
+            for pattern in &rule.patterns {
+                patterns.push(Regex::new(&format!("(?i){}", pattern)).map_err(|e| {
+                    anyhow::anyhow!("Invalid pattern for {}: {}\n{}", rule.label, pattern, e)
+                })?);
+            }
+            rules.push(DescriptionRule {
+                label: rule.label,
+                patterns,
+            });
+        }
+        info!(
+            "Description map {} with {} rules loaded",
+            map_path.display(),
+            rules.len()
+        );
+        Ok(DescriptionMap {
+            path: map_path.to_path_buf(),
+            tags,
+            unmatched: map_file.unmatched,
+            rules,
+        })
+    }
+
+    // New value of a description, None to keep it as it is
+    pub fn normalize(&self, description: &str) -> Option<String> {
+        let description = description.trim();
+        self.rules
+            .iter()
+            .find(|rule| rule.patterns.iter().any(|p| p.is_match(description)))
+            .map(|rule| rule.label.clone())
+            .or_else(|| self.unmatched.clone())
+    }
+
+    // Rewrite the mapped description tags of the object
+    pub fn apply(&self, dcm_obj: &mut InMemDicomObject) {
+        for tag in &self.tags {
+            let (vr, description) = match dcm_obj.get(*tag) {
+                Some(element) => match element.to_str() {
+                    Ok(description) => (element.vr(), description.to_string()),
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            if let Some(new_description) = self.normalize(&description) {
+                debug!("Description {} > {}", description.trim(), new_description);
+                dcm_obj.put(DataElement::new(
+                    *tag,
+                    vr,
+                    dicom_value!(Strs, [fit_to_vr_length(vr, &new_description)]),
+                ));
+            }
+        }
+    }
+}
+
+// Column of the study values CSV: a standard tag, or a private element given as
+// GGGGxxEE@CREATOR, eg 0013xx10@TRIAL INFO for (0013,xx10) in the block of TRIAL INFO
+#[derive(Debug, Clone)]
+enum StudyColumn {
+    Standard(TagPath),
+    Private {
+        group: u16,
+        offset: u8,
+        creator: String,
+    },
+}
+
+impl FromStr for StudyColumn {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let Some((element, creator)) = value.split_once('@') else {
+            let tag_path = TagPath::from_str(value)?;
+            if !is_text_vr(tag_path.vr) {
+                return Err(anyhow::anyhow!(
+                    "Only text tags can be set from the study values: {}",
+                    value
+                ));
+            }
+            return Ok(StudyColumn::Standard(tag_path));
+        };
+        let invalid = || {
+            anyhow::anyhow!(
+                "Private element {} is not GGGGxxEE@CREATOR, eg 0013xx10@TRIAL INFO",
+                value
+            )
+        };
+        let (group, offset) = element
+            .to_ascii_lowercase()
+            .split_once("xx")
+            .and_then(|(group, offset)| {
+                Some((
+                    u16::from_str_radix(group, 16).ok()?,
+                    u8::from_str_radix(offset, 16).ok()?,
+                ))
+            })
+            .ok_or_else(invalid)?;
+        if group % 2 == 0 || group < 0x0009 || creator.trim().is_empty() {
+            return Err(invalid());
+        }
+        Ok(StudyColumn::Private {
+            group,
+            offset,
+            creator: creator.trim().to_string(),
+        })
+    }
+}
+
+// Per study values inserted into the written files, eg the timepoint, arm or reading status of
+// a trial, from a CSV whose first column is AccessionNumber or StudyInstanceUID and whose other
+// columns are the tags to set. Studies are matched on the source file so they are not affected
+// by the masking, the values are set after the rules. Empty cells leave the tag as it is
+#[derive(Debug, Clone)]
+pub struct StudyValues {
+    pub path: PathBuf,
+    key_tag: Tag,
+    columns: Vec<StudyColumn>,
+    // Key value > value of each column
+    rows: HashMap<String, Vec<String>>,
+}
+
+impl StudyValues {
+    pub fn from_file(values_path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(values_path).map_err(|e| {
+            anyhow::anyhow!(
+                "Can't read the study values {}: {}",
+                values_path.display(),
+                e
+            )
+        })?;
+        let mut lines = content
+            .lines()
+            .map(|line| line.trim_start_matches('\u{feff}'))
+            .filter(|line| !line.trim().is_empty());
+        let header = csv_fields(lines.next().unwrap_or_default());
+        let key_tag = match header[0].trim() {
+            "AccessionNumber" => tags::ACCESSION_NUMBER,
+            "StudyInstanceUID" => tags::STUDY_INSTANCE_UID,
+            key => {
+                return Err(anyhow::anyhow!(
+                    "The first column of the study values {} is {}, not AccessionNumber or StudyInstanceUID",
+                    values_path.display(),
+                    key
+                ))
+            }
+        };
+        let columns = header[1..]
+            .iter()
+            .map(|column| StudyColumn::from_str(column.trim()))
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| {
+                anyhow::anyhow!("Invalid study values {}: {}", values_path.display(), e)
+            })?;
+        let mut rows = HashMap::new();
+        for line in lines {
+            let mut fields = csv_fields(line);
+            if fields.len() != header.len() {
+                return Err(anyhow::anyhow!(
+                    "Line of the study values {} has {} fields instead of {}: {}",
+                    values_path.display(),
+                    fields.len(),
+                    header.len(),
+                    phi(line)
+                ));
+            }
+            let key = fields.remove(0).trim().to_string();
+            if rows.insert(key.clone(), fields).is_some() {
+                return Err(anyhow::anyhow!(
+                    "{} is listed twice in the study values {}",
+                    phi(&key),
+                    values_path.display()
+                ));
+            }
+        }
+        info!(
+            "Study values {} with {} studies loaded",
+            values_path.display(),
+            rows.len()
+        );
+        Ok(StudyValues {
+            path: values_path.to_path_buf(),
+            key_tag,
+            columns,
+            rows,
+        })
+    }
+
+    // Set the values of the study of the source object to the new object
+    pub fn apply(&self, source: &InMemDicomObject, dcm_obj: &mut InMemDicomObject) {
+        let key = source
+            .get(self.key_tag)
+            .and_then(|element| element.to_str().ok())
+            .map(|value| value.trim_end_matches('\0').trim().to_string())
+            .unwrap_or_default();
+        let Some(values) = self.rows.get(&key) else {
+            debug!("No study values for {}", phi(&key));
+            return;
+        };
+        for (column, value) in self.columns.iter().zip(values) {
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            match column {
+                StudyColumn::Standard(tag_path) => tag_path.put(
+                    dcm_obj,
+                    dicom_value!(Strs, [fit_to_vr_length(tag_path.vr, value)]),
+                ),
+                StudyColumn::Private {
+                    group,
+                    offset,
+                    creator,
+                } => {
+                    let Some(block) = private_block(dcm_obj, *group, creator) else {
+                        warn!(
+                            "No free private block in group {:04X} for {}",
+                            group, creator
+                        );
+                        continue;
+                    };
+                    dcm_obj.put(DataElement::new(
+                        Tag(*group, block << 8 | u16::from(*offset)),
+                        VR::LO,
+                        dicom_value!(Strs, [fit_to_vr_length(VR::LO, value)]),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+// Block of a private creator in a group, the creator is added to the first free block when the
+// object has none
+fn private_block(dcm_obj: &mut InMemDicomObject, group: u16, creator: &str) -> Option<u16> {
+    let mut free = None;
+    for block in 0x0010..=0x00FF {
+        match dcm_obj.get(Tag(group, block)) {
+            Some(element) => {
+                let block_creator = element.to_str().unwrap_or_default();
+                if block_creator.trim_end_matches(['\0', ' ']) == creator {
+                    return Some(block);
+                }
+            }
+            None => {
+                free.get_or_insert(block);
+            }
+        }
+    }
+    let block = free?;
+    dcm_obj.put(DataElement::new(
+        Tag(group, block),
+        VR::LO,
+        dicom_value!(Strs, [creator.to_string()]),
+    ));
+    Some(block)
+}
+
+// Site anon profile file, the tags of each list are TagPaths
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AnonProfileFile {
+    name: Option<String>,
+    salt: Option<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+    #[serde(default)]
+    keep: Vec<String>,
+    #[serde(default)]
+    hash: Vec<String>,
+    #[serde(default)]
+    replace: BTreeMap<String, String>,
+}
+
+// Institutional policy applied by anon on top of its defaults, eg keep the StudyDescription, hash
+// the AccessionNumber or remove the InstitutionName. Kept and hashed values are read from the
+// source file, so they are not affected by the masking. Rules are applied after the profile
+// The hashes are the salted SHA-256 of the source value: a 2.25 UID for UI tags, hex digits
+// truncated to the VR length otherwise, so the same value gives the same hash in every run
+#[derive(Debug, Clone)]
+pub struct AnonProfile {
+    pub path: PathBuf,
+    pub name: String,
+    salt: String,
+    remove: Vec<TagPath>,
+    keep: Vec<TagPath>,
+    hash: Vec<TagPath>,
+    replace: Vec<(TagPath, String)>,
+}
+
+impl AnonProfile {
+    pub fn from_file(profile_path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(profile_path).map_err(|e| {
+            anyhow::anyhow!(
+                "Can't read the anon profile {}: {}",
+                profile_path.display(),
+                e
+            )
+        })?;
+        let profile_file: AnonProfileFile = toml::from_str(&content).map_err(|e| {
+            anyhow::anyhow!("Invalid anon profile {}\n{}", profile_path.display(), e)
+        })?;
+        let paths_of = |tag_names: &[String]| -> Result<Vec<TagPath>> {
+            tag_names
+                .iter()
+                .map(|tag_name| {
+                    TagPath::from_str(tag_name)
+                        .map_err(|e| anyhow::anyhow!("Unknown tag in the anon profile: {}", e))
+                })
+                .collect()
+        };
+        let hash = paths_of(&profile_file.hash)?;
+        if let Some(tag_path) = hash
+            .iter()
+            .find(|tag_path| !is_text_vr(tag_path.vr) || is_fixed_format_vr(tag_path.vr))
+        {
+            return Err(anyhow::anyhow!(
+                "Only text and UID tags can be hashed: {}",
+                tag_path
+            ));
+        }
+        let mut replace = vec![];
+        for (tag_path, value) in
+            paths_of(&profile_file.replace.keys().cloned().collect::<Vec<_>>())?
+                .into_iter()
+                .zip(profile_file.replace.values())
+        {
+            if !is_text_vr(tag_path.vr) {
+                return Err(anyhow::anyhow!(
+                    "Only text tags can be replaced: {}",
+                    tag_path
+                ));
+            }
+            replace.push((tag_path, value.clone()));
+        }
+        let profile = AnonProfile {
+            path: profile_path.to_path_buf(),
+            name: profile_file
+                .name
+                .unwrap_or_else(|| profile_path.display().to_string()),
+            salt: profile_file.salt.unwrap_or_default(),
+            remove: paths_of(&profile_file.remove)?,
+            keep: paths_of(&profile_file.keep)?,
+            hash,
+            replace,
+        };
+        info!(
+            "Anon profile {} loaded: {} removed, {} kept, {} hashed and {} replaced tags",
+            profile.name,
+            profile.remove.len(),
+            profile.keep.len(),
+            profile.hash.len(),
+            profile.replace.len()
+        );
+        Ok(profile)
+    }
+
+    // Salted hash of a value for the VR
+    fn hash_value(&self, vr: VR, value: &str) -> String {
+        let digest = sha256(format!("{}{}", self.salt, value).as_bytes());
+        match vr {
+            VR::UI => {
+                let mut high = [0u8; 16];
+                high.copy_from_slice(&digest[..16]);
+                format!("2.25.{}", u128::from_be_bytes(high))
+            }
+            _ => fit_to_vr_length(vr, &to_hex(&digest).to_uppercase()),
+        }
+    }
+
+    // Apply the profile to the anonymized object, in the order remove, keep, hash and replace
+    pub fn apply(&self, source: &InMemDicomObject, dcm_obj: &mut InMemDicomObject) {
+        for tag_path in &self.remove {
+            tag_path.delete(dcm_obj);
+        }
+        for tag_path in &self.keep {
+            tag_path.copy_from(source, dcm_obj, &mut |element| element.clone());
+        }
+        for tag_path in &self.hash {
+            tag_path.copy_from(source, dcm_obj, &mut |element| {
+                let value = element.to_str().unwrap_or_default();
+                let hashed = self.hash_value(tag_path.vr, value.trim_end_matches(['\0', ' ']));
+                DataElement::new(tag_path.tag, tag_path.vr, dicom_value!(Strs, [hashed]))
+            });
+        }
+        for (tag_path, value) in &self.replace {
+            tag_path.put(
+                dcm_obj,
+                dicom_value!(Strs, [fit_to_vr_length(tag_path.vr, value)]),
+            );
+        }
+    }
+}
+
+// Precision kept when dates are reduced instead of replaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatePrecision {
+    Year,
+    Month,
+}
+
+impl FromStr for DatePrecision {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "year" => Ok(DatePrecision::Year),
+            "month" => Ok(DatePrecision::Month),
+            _ => Err(anyhow::anyhow!(
+                "Date precision should be year or month: {}",
+                value
+            )),
+        }
+    }
+}
+
+// Truncate a DA or DT value to the given precision
+// DA stays a full date on the first day of the year or month, DT keeps only the YYYY or YYYYMM
+// component. Values without a year are replaced by the dummy date
+pub fn reduce_date_value(vr: VR, value: &str, precision: DatePrecision) -> String {
+    let digits: String = value
+        .trim()
+        .chars()
+        // Stop at the UTC offset of a DT value
+        .take_while(|c| !matches!(c, '+' | '-'))
+        .filter(|c| c.is_ascii_digit())
+        .collect();
+    if digits.len() < 4 {
+        return match vr {
+            VR::DT => DUMMY_DATE[..4].to_string(),
+            _ => DUMMY_DATE.to_string(),
+        };
+    }
+    let year = &digits[..4];
+    let month = match precision {
+        DatePrecision::Month if digits.len() >= 6 => Some(&digits[4..6]),
+        _ => None,
+    };
+    match (vr, month) {
+        (VR::DT, Some(month)) => format!("{}{}", year, month),
+        (VR::DT, None) => year.to_string(),
+        (_, Some(month)) => format!("{}{}01", year, month),
+        (_, None) => format!("{}0101", year),
+    }
+}
+
+// Reduce the precision of all DA and DT values, including the ones in sequences
+pub fn reduce_dates(dcm_obj: &mut InMemDicomObject, precision: DatePrecision) {
+    update_dates(dcm_obj, &|vr, value| {
+        reduce_date_value(vr, value, precision)
+    });
+}
+
+// Longest shift of --date-shift, dates move 1 to 10 years back
+pub const MAX_DATE_SHIFT_DAYS: u16 = 3650;
+
+// Move the date part of a DA or DT value back by the given days, the time and UTC offset of a
+// DT are kept. Both ends of a DA range are moved, an open end stays open. Values that can't be
+// read get the dummy date
+pub fn shift_date_value(vr: VR, value: &str, days: u64) -> String {
+    let value = value.trim();
+    if let (VR::DA, Some((start, end))) = (vr, value.split_once('-')) {
+        let shift = |date: &str| match date.trim().is_empty() {
+            true => String::new(),
+            false => shift_date_value(vr, date, days),
+        };
+        return format!("{}-{}", shift(start), shift(end));
+    }
+    let shifted = value
+        .get(..8)
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y%m%d").ok())
+        .and_then(|date| date.checked_sub_days(Days::new(days)))
+        .map(|date| date.format("%Y%m%d").to_string());
+    match (shifted, vr) {
+        (Some(date), VR::DT) => format!("{}{}", date, &value[8..]),
+        (Some(date), _) => date,
+        (None, _) => DUMMY_DATE.to_string(),
+    }
+}
+
+// Shift all DA and DT values, including the ones in sequences. The times are kept, so the order
+// and the intervals of the events of a patient are preserved
+pub fn shift_dates(dcm_obj: &mut InMemDicomObject, days: u64) {
+    update_dates(dcm_obj, &|vr, value| shift_date_value(vr, value, days));
+}
+
+// Replace each DA and DT value, including the ones in sequences
+fn update_dates(dcm_obj: &mut InMemDicomObject, f: &dyn Fn(VR, &str) -> String) {
+    let date_tags: Vec<(Tag, VR)> = dcm_obj
+        .iter()
+        .filter(|element| matches!(element.vr(), VR::DA | VR::DT | VR::SQ))
+        .map(|element| (element.tag(), element.vr()))
+        .collect();
+    for (tag, vr) in date_tags {
+        if vr == VR::SQ {
+            dcm_obj.update_value(tag, |value| {
+                if let Some(items) = value.items_mut() {
+                    items.iter_mut().for_each(|item| update_dates(item, f));
+                }
+            });
+            continue;
+        }
+        let updated: Vec<String> = match dcm_obj.get(tag).map(|element| element.to_multi_str()) {
+            // Empty values stay empty, so the VM of the element is kept
+            Some(Ok(values)) => values
+                .iter()
+                .map(|value| match value.trim().is_empty() {
+                    true => String::new(),
+                    false => f(vr, value),
+                })
+                .collect(),
+            _ => continue,
+        };
+        if updated.iter().any(|value| !value.is_empty()) {
+            dcm_obj.put(DataElement::new(
+                tag,
+                vr,
+                PrimitiveValue::Strs(updated.into()),
+            ));
+        }
+    }
+}
+
+// Order of the day, month and year in malformed dates that don't start with the year
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DateOrder {
+    // Only dates starting with a 4 digit year are read
+    #[default]
+    Ymd,
+    Dmy,
+    Mdy,
+}
+
+impl FromStr for DateOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "ymd" => Ok(DateOrder::Ymd),
+            "dmy" => Ok(DateOrder::Dmy),
+            "mdy" => Ok(DateOrder::Mdy),
+            _ => Err(anyhow::anyhow!(
+                "Date order should be one of ymd, dmy or mdy: {}",
+                value
+            )),
+        }
+    }
+}
+
+// Read a malformed DA value like 2023.01.05, 2023-1-5 or 05/01/2023
+// Returns None if the value is already valid or can't be read
+pub fn normalize_date(value: &str, order: DateOrder) -> Option<String> {
+    let value = value.trim();
+    if value.len() == 8 && value.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let parts: Vec<&str> = value
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|part| !part.is_empty())
+        .collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let (year, month, day) = match (parts[0].len(), parts[2].len(), order) {
+        (4, _, _) => (parts[0], parts[1], parts[2]),
+        (_, 4, DateOrder::Dmy) => (parts[2], parts[1], parts[0]),
+        (_, 4, DateOrder::Mdy) => (parts[2], parts[0], parts[1]),
+        _ => return None,
+    };
+    let date = NaiveDate::from_ymd_opt(year.parse().ok()?, month.parse().ok()?, day.parse().ok()?)?;
+    Some(date.format("%Y%m%d").to_string())
+}
+
+// Read a malformed TM value like 9:30 or 09:30:15.123
+// Returns None if the value is already valid or can't be read
+pub fn normalize_time(value: &str) -> Option<String> {
+    static VALID_TIME: OnceLock<Regex> = OnceLock::new();
+    let valid_time = VALID_TIME.get_or_init(|| {
+        Regex::new(r"^\d{2}(\d{2}(\d{2}(\.\d{1,6})?)?)?$").expect("Invalid time pattern")
+    });
+    let value = value.trim();
+    if valid_time.is_match(value) || !value.contains(':') {
+        return None;
+    }
+    let (clock, fraction) = match value.split_once('.') {
+        Some((clock, fraction)) => (clock, Some(fraction)),
+        None => (value, None),
+    };
+    let fields: Vec<u32> = clock
+        .split(':')
+        .map(|field| field.trim().parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    let limits = [23, 59, 60];
+    if fields.is_empty()
+        || fields.len() > 3
+        || fields
+            .iter()
+            .zip(limits)
+            .any(|(field, limit)| *field > limit)
+    {
+        return None;
+    }
+    let mut time: String = fields.iter().map(|field| format!("{:02}", field)).collect();
+    if let Some(fraction) = fraction {
+        if fields.len() != 3 || fraction.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+        time.push('.');
+        time.push_str(&fraction[..fraction.len().min(6)]);
+    }
+    Some(time)
+}
+
+// Read a malformed DT value as a date and a time separated by a space or a T
+fn normalize_date_time(value: &str, order: DateOrder) -> Option<String> {
+    let (date, time) = value.trim().split_once([' ', 'T'])?;
+    let date = normalize_date(date, order).unwrap_or_else(|| date.to_string());
+    let time = normalize_time(time).unwrap_or_else(|| time.to_string());
+    let normalized = format!("{}{}", date, time);
+    (normalized != value.trim()).then_some(normalized)
+}
+
+// Normalize the malformed DA, TM and DT values, including the ones in sequences
+// Valid values and values that can't be read are left as they are. Returns the number of values changed
+pub fn normalize_dates(dcm_obj: &mut InMemDicomObject, order: DateOrder) -> usize {
+    let date_tags: Vec<(Tag, VR)> = dcm_obj
+        .iter()
+        .filter(|element| matches!(element.vr(), VR::DA | VR::TM | VR::DT | VR::SQ))
+        .map(|element| (element.tag(), element.vr()))
+        .collect();
+    let mut changed = 0;
+    for (tag, vr) in date_tags {
+        if vr == VR::SQ {
+            dcm_obj.update_value(tag, |value| {
+                if let Some(items) = value.items_mut() {
+                    for item in items.iter_mut() {
+                        changed += normalize_dates(item, order);
+                    }
+                }
+            });
+            continue;
+        }
+        let values = match dcm_obj.get(tag).map(|element| element.to_multi_str()) {
+            Some(Ok(values)) => values.to_vec(),
+            _ => continue,
+        };
+        let mut any_changed = false;
+        let normalized: Vec<String> = values
+            .iter()
+            .map(|value| {
+                let normalized = match vr {
+                    VR::DA => normalize_date(value, order),
+                    VR::TM => normalize_time(value),
+                    _ => normalize_date_time(value, order),
+                };
+                match normalized {
+                    Some(normalized) => {
+                        debug!(
+                            "{:?} {} normalized to {}",
+                            tag,
+                            phi(value),
+                            phi(&normalized)
+                        );
+                        any_changed = true;
+                        changed += 1;
+                        normalized
+                    }
+                    None => value.to_string(),
+                }
+            })
+            .collect();
+        if any_changed {
+            dcm_obj.put(DataElement::new(
+                tag,
+                vr,
+                PrimitiveValue::Strs(normalized.into()),
+            ));
+        }
+    }
+    changed
+}
+
+fn is_text_vr(vr: VR) -> bool {
+    vr_max_length(vr).is_some() || matches!(vr, VR::PN | VR::UC | VR::UR | VR::UT)
+}
+
+// Text VRs whose values have a fixed format, a hash is not a valid value for them
+fn is_fixed_format_vr(vr: VR) -> bool {
+    matches!(vr, VR::AS | VR::DA | VR::DS | VR::DT | VR::IS | VR::TM)
+}
+
+// Fix a single text value for its VR, None if it is valid or can't be fixed
+fn fix_text_value(tag: Tag, vr: VR, value: &str) -> Option<String> {
+    let fixed = match vr {
+        // Each component group of a name is limited to 64 characters
+        VR::PN => value
+            .split('=')
+            .map(|group| group.chars().take(64).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("="),
+        VR::CS => value.to_uppercase().chars().take(16).collect::<String>(),
+        VR::IS => match value.trim().parse::<f64>() {
+            Ok(number) if number.fract() == 0.0 && number.abs() < 1e11 => {
+                format!("{}", number as i64)
+            }
+            _ => value.to_string(),
+        },
+        VR::DS => match value.trim().parse::<f64>() {
+            Ok(number) if value.len() > 16 && number.abs() < 1e14 => format_decimal_string(number),
+            _ => value.to_string(),
+        },
+        // Truncated UIDs would no longer be unique
+        VR::UI => value.to_string(),
+        _ => fit_to_vr_length(vr, value),
+    };
+    let too_long = vr != VR::PN && vr_max_length(vr).is_some_and(|length| fixed.len() > length);
+    if too_long {
+        warn!("VR fix : {:?} {} value can't be fixed", tag, vr);
+    }
+    (fixed != value && !too_long).then_some(fixed)
+}
+
+// Truncate over-length values, fix the VR of elements that differ from the dictionary when the
+// value can be read as the expected VR and log every correction. Returns the number of corrections
+pub fn fix_value_violations(dcm_obj: &mut InMemDicomObject) -> usize {
+    let elements: Vec<(Tag, VR)> = dcm_obj
+        .iter()
+        .map(|element| (element.tag(), element.vr()))
+        .collect();
+    let mut fixed = 0;
+    for (tag, vr) in elements {
+        if vr == VR::SQ {
+            dcm_obj.update_value(tag, |value| {
+                if let Some(items) = value.items_mut() {
+                    for item in items.iter_mut() {
+                        fixed += fix_value_violations(item);
+                    }
+                }
+            });
+            continue;
+        }
+        let expected_vr = match StandardDataDictionary.by_tag(tag).map(|entry| entry.vr) {
+            Some(VirtualVr::Exact(expected_vr)) if tag.group() % 2 == 0 => expected_vr,
+            _ => vr,
+        };
+        let element = match dcm_obj.get(tag) {
+            Some(element) => element,
+            None => continue,
+        };
+        let values: Vec<String> = if vr == expected_vr && is_text_vr(vr) {
+            match element.to_multi_str() {
+                Ok(values) => values.to_vec(),
+                Err(_) => continue,
+            }
+        } else if vr == VR::UN && is_text_vr(expected_vr) {
+            match element.to_bytes() {
+                Ok(bytes) => String::from_utf8_lossy(&bytes)
+                    .trim_end_matches(['\0', ' '])
+                    .split('\\')
+                    .map(|value| value.to_string())
+                    .collect(),
+                Err(_) => continue,
+            }
+        } else if vr != expected_vr && is_text_vr(vr) && is_text_vr(expected_vr) {
+            match element.to_multi_str() {
+                Ok(values) => values.to_vec(),
+                Err(_) => continue,
+            }
+        } else if vr != expected_vr && is_text_vr(vr) {
+            // Numbers stored as text in a binary VR
+            let numbers = match element.to_multi_str() {
+                Ok(values) => values
+                    .iter()
+                    .map(|value| value.trim().parse::<f64>().ok())
+                    .collect::<Option<Vec<f64>>>(),
+                Err(_) => None,
+            };
+            let value = match (numbers, expected_vr) {
+                (Some(numbers), VR::US) => numbers
+                    .iter()
+                    .map(|number| u16::try_from(*number as i64).ok())
+                    .collect::<Option<Vec<u16>>>()
+                    .map(|numbers| PrimitiveValue::U16(numbers.into())),
+                (Some(numbers), VR::UL) => numbers
+                    .iter()
+                    .map(|number| u32::try_from(*number as i64).ok())
+                    .collect::<Option<Vec<u32>>>()
+                    .map(|numbers| PrimitiveValue::U32(numbers.into())),
+                (Some(numbers), VR::SS) => numbers
+                    .iter()
+                    .map(|number| i16::try_from(*number as i64).ok())
+                    .collect::<Option<Vec<i16>>>()
+                    .map(|numbers| PrimitiveValue::I16(numbers.into())),
+                (Some(numbers), VR::SL) => numbers
+                    .iter()
+                    .map(|number| i32::try_from(*number as i64).ok())
+                    .collect::<Option<Vec<i32>>>()
+                    .map(|numbers| PrimitiveValue::I32(numbers.into())),
+                (Some(numbers), VR::FL) => Some(PrimitiveValue::F32(
+                    numbers.iter().map(|number| *number as f32).collect(),
+                )),
+                (Some(numbers), VR::FD) => Some(PrimitiveValue::F64(numbers.into())),
+                _ => None,
+            };
+            match value {
+                Some(value) => {
+                    info!("VR fix : {:?} {} re-encoded as {}", tag, vr, expected_vr);
+                    dcm_obj.put(DataElement::new(tag, expected_vr, value));
+                    fixed += 1;
+                }
+                None => warn!(
+                    "VR fix : {:?} is {} instead of {}, can't be fixed",
+                    tag, vr, expected_vr
+                ),
+            }
+            continue;
+        } else {
+            continue;
+        };
+        let mut changed = vr != expected_vr;
+        if changed {
+            info!("VR fix : {:?} {} re-encoded as {}", tag, vr, expected_vr);
+            fixed += 1;
+        }
+        let values: Vec<String> = values
+            .into_iter()
+            .map(|value| match fix_text_value(tag, expected_vr, &value) {
+                Some(fixed_value) => {
+                    info!("VR fix : {:?} {} value corrected", tag, expected_vr);
+                    changed = true;
+                    fixed += 1;
+                    fixed_value
+                }
+                None => value,
+            })
+            .collect();
+        if changed {
+            dcm_obj.put(DataElement::new(
+                tag,
+                expected_vr,
+                PrimitiveValue::Strs(values.into()),
+            ));
+        }
+    }
+    fixed
+}
+
+// Replacement values for the date and time VRs
+pub const DUMMY_DATE: &str = "19000101";
+pub const DUMMY_TIME: &str = "090000";
+
+// VR conformant replacement value for a masked tag
+// Text VRs get the given ID within the VR charset and length, the rest get a fixed dummy value
+pub fn vr_dummy_value(vr: VR, id: &str) -> PrimitiveValue {
+    match vr {
+        VR::PN => {
+            // ^ and = are the name component and group delimiters
+            let name: String = id
+                .chars()
+                .filter(|c| !matches!(c, '^' | '=' | '\\'))
+                .collect();
+            dicom_value!(Strs, [fit_to_vr_length(vr, &name)])
+        }
+        VR::LO | VR::SH | VR::AE | VR::UC => {
+            let text: String = id.chars().filter(|c| *c != '\\').collect();
+            dicom_value!(Strs, [fit_to_vr_length(vr, &text)])
+        }
+        VR::ST | VR::LT | VR::UT => dicom_value!(Str, fit_to_vr_length(vr, id)),
+        VR::CS => {
+            // Uppercase letters, digits, space and underscore only
+            let code: String = id
+                .to_uppercase()
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == ' ' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            dicom_value!(Strs, [fit_to_vr_length(vr, &code)])
+        }
+        VR::DA => dicom_value!(Strs, [DUMMY_DATE.to_string()]),
+        VR::TM => dicom_value!(Strs, [DUMMY_TIME.to_string()]),
+        VR::DT => dicom_value!(Strs, [format!("{}{}", DUMMY_DATE, DUMMY_TIME)]),
+        // Stable UID derived from the ID under the 2.25 UUID root
+        VR::UI => dicom_value!(Strs, [format!("2.25.{}", fnv1a_hash(id.as_bytes()))]),
+        VR::IS => dicom_value!(Strs, ["0".to_string()]),
+        VR::DS => dicom_value!(Strs, ["0.0".to_string()]),
+        VR::AS => dicom_value!(Strs, ["000Y".to_string()]),
+        VR::UR => dicom_value!(Str, "".to_string()),
+        VR::US => dicom_value!(U16, [0]),
+        VR::SS => dicom_value!(I16, [0]),
+        VR::UL => dicom_value!(U32, [0]),
+        VR::SL => dicom_value!(I32, [0]),
+        VR::UV => dicom_value!(U64, [0]),
+        VR::SV => dicom_value!(I64, [0]),
+        VR::FL => dicom_value!(F32, [0.0]),
+        VR::FD => dicom_value!(F64, [0.0]),
+        VR::AT => PrimitiveValue::Tags(vec![Tag(0, 0)].into()),
+        _ => PrimitiveValue::Empty,
+    }
+}
+
+pub fn dicom_vr_corrected_value(vr: VR, value: &String) -> Result<PrimitiveValue> {
+    let r_value = match vr {
+        VR::AE | VR::AS | VR::PN | VR::SH | VR::CS | VR::LO | VR::UI | VR::UC => {
+            dicom_value!(Strs, [value.clone()])
+        }
+        VR::ST | VR::LT | VR::UT | VR::UR => {
+            dicom_value!(Str, value.clone())
+        }
+        VR::DA => {
+            if value.len() != 8 {
+                error!(
+                    "Issue With Date value Does it follow this format YYYYMMDD: {}",
+                    value
+                );
+                exit(1);
+            }
+            let d_date = DicomDate::try_from(&NaiveDate::parse_from_str(value, "%Y%m%d")?)?;
+            dicom_value!(Date, d_date)
+        }
+        VR::TM => {
             if value.len() != 6 {
                 error!(
                     "Issue With Time value Does it follow this format HHMMSS: {}",
@@ -604,7 +5458,7 @@ pub fn dicom_vr_corrected_value(vr: VR, value: &String) -> Result<PrimitiveValue
                 );
                 exit(1);
             }
-            let d_date = DicomDate::try_from(&NaiveDate::parse_from_str(&t_date, "%Y%m%d")?)?;
+            let d_date = DicomDate::try_from(&NaiveDate::parse_from_str(t_date, "%Y%m%d")?)?;
 
             if t_time.len() != 6 {
                 error!(
@@ -623,3 +5477,723 @@ pub fn dicom_vr_corrected_value(vr: VR, value: &String) -> Result<PrimitiveValue
     };
     Ok(r_value)
 }
+
+// Largest matrix of the downsampled frames, ROWSxCOLUMNS or a single size for both
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatrixSize {
+    pub rows: u32,
+    pub columns: u32,
+}
+
+impl FromStr for MatrixSize {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("Matrix size should be ROWSxCOLUMNS or SIZE: {}", value);
+        let (rows, columns) = match value.trim().to_lowercase().split_once('x') {
+            Some((rows, columns)) => (rows.trim().to_string(), columns.trim().to_string()),
+            None => (value.trim().to_string(), value.trim().to_string()),
+        };
+        let rows: u32 = rows.parse().map_err(|_| invalid())?;
+        let columns: u32 = columns.parse().map_err(|_| invalid())?;
+        if rows == 0 || columns == 0 {
+            return Err(invalid());
+        }
+        Ok(MatrixSize { rows, columns })
+    }
+}
+
+// Layout of a native little endian frame
+#[derive(Debug, Clone, Copy)]
+struct FrameLayout {
+    rows: usize,
+    columns: usize,
+    samples: usize,
+    sample_bytes: usize,
+    signed: bool,
+    planar: bool,
+}
+
+impl FrameLayout {
+    // Samples of the frame, the decode loops have no branches so they are vectorized
+    fn decode(&self, frame: &[u8]) -> Vec<i32> {
+        match (self.sample_bytes, self.signed) {
+            (1, false) => frame.iter().map(|&b| b as i32).collect(),
+            (1, true) => frame.iter().map(|&b| b as i8 as i32).collect(),
+            (_, false) => frame
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as i32)
+                .collect(),
+            (_, true) => frame
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as i32)
+                .collect(),
+        }
+    }
+
+    // Mean of each block of the plane, the rows of a block are first summed into one row so the
+    // inner loop is a vectorized add over the whole row
+    // Channels are the interleaved samples of a pixel, 1 for a plane of planar data
+    fn box_filter(
+        &self,
+        plane: &[i32],
+        channels: usize,
+        new_rows: usize,
+        new_columns: usize,
+        out: &mut Vec<u8>,
+    ) {
+        let (rows, columns) = (self.rows, self.columns);
+        let width = columns * channels;
+        let mut row_sums = vec![0i64; width];
+        for new_row in 0..new_rows {
+            let row_range = (new_row * rows / new_rows)..((new_row + 1) * rows / new_rows);
+            row_sums.fill(0);
+            for row in row_range.clone() {
+                for (sum, &value) in row_sums
+                    .iter_mut()
+                    .zip(&plane[row * width..(row + 1) * width])
+                {
+                    *sum += value as i64;
+                }
+            }
+            for new_column in 0..new_columns {
+                let column_range = (new_column * columns / new_columns)
+                    ..((new_column + 1) * columns / new_columns);
+                let count = (row_range.len() * column_range.len()) as f64;
+                for channel in 0..channels {
+                    let sum: i64 = column_range
+                        .clone()
+                        .map(|column| row_sums[column * channels + channel])
+                        .sum();
+                    let mean = (sum as f64 / count).round() as i64;
+                    match self.sample_bytes {
+                        1 => out.push(mean as u8),
+                        _ => out.extend_from_slice(&(mean as u16).to_le_bytes()),
+                    }
+                }
+            }
+        }
+    }
+
+    // Downsampled frame, each sample is the mean of its block in the source frame
+    fn downsample(&self, frame: &[u8], new_rows: usize, new_columns: usize) -> Vec<u8> {
+        let samples = self.decode(frame);
+        let mut new_frame =
+            Vec::with_capacity(new_rows * new_columns * self.samples * self.sample_bytes);
+        if self.planar {
+            for plane in samples.chunks_exact(self.rows * self.columns) {
+                self.box_filter(plane, 1, new_rows, new_columns, &mut new_frame);
+            }
+        } else {
+            self.box_filter(
+                &samples,
+                self.samples,
+                new_rows,
+                new_columns,
+                &mut new_frame,
+            );
+        }
+        new_frame
+    }
+}
+
+// Downsample the frames to fit in the matrix size, the aspect ratio is kept
+// Each output pixel is the mean of the source pixels it covers. Rows, Columns and the pixel
+// spacing tags are updated. Only native little endian pixel data of 8 or 16 bits is supported,
+// frames that already fit are left as they are. Returns true if the pixel data was changed
+pub fn downsample_pixels(
+    dcm_obj: &mut FileDicomObject<InMemDicomObject>,
+    size: MatrixSize,
+) -> Result<bool> {
+    let native = TransferSyntaxRegistry
+        .get(dcm_obj.meta().transfer_syntax())
+        .map(|ts| ts.endianness() == Endianness::Little && matches!(ts.codec(), Codec::None))
+        .unwrap_or(false);
+    let pixel_element = match dcm_obj.element_opt(tags::PIXEL_DATA)? {
+        Some(element) => element,
+        None => return Ok(false),
+    };
+    let pixel_vr = pixel_element.vr();
+    let pixel_bytes = match (native, pixel_element.value().primitive()) {
+        (true, Some(value)) => value.to_bytes().to_vec(),
+        _ => {
+            debug!("Pixel data is not native, not downsampled");
+            return Ok(false);
+        }
+    };
+    let int_of = |tag: Tag, default: u32| -> u32 {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_int::<u32>().ok())
+            .unwrap_or(default)
+    };
+    let rows = int_of(tags::ROWS, 0) as usize;
+    let columns = int_of(tags::COLUMNS, 0) as usize;
+    let samples = int_of(tags::SAMPLES_PER_PIXEL, 1) as usize;
+    let bits_allocated = int_of(tags::BITS_ALLOCATED, 16);
+    let signed = int_of(tags::PIXEL_REPRESENTATION, 0) == 1;
+    let planar = int_of(tags::PLANAR_CONFIGURATION, 0) == 1;
+    let frames = int_of(tags::NUMBER_OF_FRAMES, 1).max(1) as usize;
+    let sample_bytes = match bits_allocated {
+        8 => 1,
+        16 => 2,
+        _ => {
+            warn!("{} bits allocated, not downsampled", bits_allocated);
+            return Ok(false);
+        }
+    };
+
+    let scale = (size.rows as f64 / rows as f64).min(size.columns as f64 / columns as f64);
+    if rows == 0 || columns == 0 || scale >= 1.0 {
+        return Ok(false);
+    }
+    let frame_length = rows * columns * samples * sample_bytes;
+    if pixel_bytes.len() < frame_length * frames {
+        return Err(anyhow::anyhow!(
+            "Pixel data is shorter than {} frames of {}x{}",
+            frames,
+            rows,
+            columns
+        ));
+    }
+    let new_rows = ((rows as f64 * scale).round() as usize).max(1);
+    let new_columns = ((columns as f64 * scale).round() as usize).max(1);
+
+    let downsample_start = std::time::Instant::now();
+    // Frames are independent, the frames of multi-frame objects are downsampled in parallel
+    let frame = FrameLayout {
+        rows,
+        columns,
+        samples,
+        sample_bytes,
+        signed,
+        planar,
+    };
+    let mut new_pixels: Vec<u8> = pixel_bytes[..frame_length * frames]
+        .par_chunks(frame_length)
+        .flat_map_iter(|pixels| frame.downsample(pixels, new_rows, new_columns))
+        .collect();
+    debug!(
+        "{} frames downsampled in {} ms",
+        frames,
+        downsample_start.elapsed().as_millis()
+    );
+    if new_pixels.len() % 2 == 1 {
+        new_pixels.push(0);
+    }
+
+    dcm_obj.put(DataElement::new(
+        tags::PIXEL_DATA,
+        pixel_vr,
+        PrimitiveValue::U8(new_pixels.into()),
+    ));
+    dcm_obj.put(DataElement::new(
+        tags::ROWS,
+        VR::US,
+        PrimitiveValue::from(new_rows as u16),
+    ));
+    dcm_obj.put(DataElement::new(
+        tags::COLUMNS,
+        VR::US,
+        PrimitiveValue::from(new_columns as u16),
+    ));
+    let row_factor = rows as f64 / new_rows as f64;
+    let column_factor = columns as f64 / new_columns as f64;
+    scale_pixel_spacing(dcm_obj, row_factor, column_factor);
+    mark_derived(
+        dcm_obj,
+        &format!(
+            "Downsampled from {}x{} to {}x{} by dcmrig",
+            rows, columns, new_rows, new_columns
+        ),
+    );
+    Ok(true)
+}
+
+// Record that the pixel data was changed, for every operation that modifies the pixels
+// ImageType becomes DERIVED\SECONDARY, the description is added to DerivationDescription, the
+// SourceImageSequence references the instance before the change and a new SOPInstanceUID is set.
+// The new UID is derived from the old one so the same input always gives the same output
+pub fn mark_derived(dcm_obj: &mut FileDicomObject<InMemDicomObject>, description: &str) {
+    let value_of = |tag: Tag| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_str().ok())
+            .map(|value| value.trim_end_matches('\0').trim().to_string())
+            .unwrap_or_default()
+    };
+    let sop_class_uid = value_of(tags::SOP_CLASS_UID);
+    let sop_instance_uid = value_of(tags::SOP_INSTANCE_UID);
+    let derivation = match value_of(tags::DERIVATION_DESCRIPTION) {
+        previous if previous.is_empty() => description.to_string(),
+        previous => format!("{}; {}", previous, description),
+    };
+
+    let mut image_type: Vec<String> = dcm_obj
+        .element(tags::IMAGE_TYPE)
+        .ok()
+        .and_then(|element| element.to_multi_str().ok())
+        .map(|values| values.iter().map(|v| v.trim().to_string()).collect())
+        .unwrap_or_default();
+    image_type.resize(image_type.len().max(2), String::new());
+    image_type[0] = "DERIVED".to_string();
+    image_type[1] = "SECONDARY".to_string();
+    dcm_obj.put(DataElement::new(
+        tags::IMAGE_TYPE,
+        VR::CS,
+        PrimitiveValue::Strs(image_type.into()),
+    ));
+
+    dcm_obj.put(DataElement::new(
+        tags::DERIVATION_DESCRIPTION,
+        VR::ST,
+        dicom_value!(Str, derivation),
+    ));
+
+    if !sop_instance_uid.is_empty() {
+        let mut source_image = InMemDicomObject::new_empty();
+        source_image.put(DataElement::new(
+            tags::REFERENCED_SOP_CLASS_UID,
+            VR::UI,
+            dicom_value!(Str, sop_class_uid),
+        ));
+        source_image.put(DataElement::new(
+            tags::REFERENCED_SOP_INSTANCE_UID,
+            VR::UI,
+            dicom_value!(Str, sop_instance_uid.clone()),
+        ));
+        let mut source_images: Vec<InMemDicomObject> = dcm_obj
+            .get(tags::SOURCE_IMAGE_SEQUENCE)
+            .and_then(|element| element.items())
+            .map(|items| items.to_vec())
+            .unwrap_or_default();
+        source_images.push(source_image);
+        dcm_obj.put(DataElement::new(
+            tags::SOURCE_IMAGE_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(source_images),
+        ));
+    }
+
+    let derived_uid = format!(
+        "2.25.{}",
+        fnv1a_hash(format!("{}/{}", sop_instance_uid, description).as_bytes())
+    );
+    dcm_obj.put(DataElement::new(
+        tags::SOP_INSTANCE_UID,
+        VR::UI,
+        dicom_value!(Str, derived_uid.clone()),
+    ));
+    let meta = dcm_obj.meta_mut();
+    meta.media_storage_sop_instance_uid = derived_uid;
+    meta.update_information_group_length();
+}
+
+// Scale the pixel spacing tags, including the pixel measures of the functional groups
+fn scale_pixel_spacing(dcm_obj: &mut InMemDicomObject, row_factor: f64, column_factor: f64) {
+    for tag in [tags::PIXEL_SPACING, tags::IMAGER_PIXEL_SPACING] {
+        let spacing = dcm_obj
+            .get(tag)
+            .and_then(|element| element.to_multi_float64().ok())
+            .filter(|spacing| spacing.len() == 2);
+        if let Some(spacing) = spacing {
+            let scaled = [spacing[0] * row_factor, spacing[1] * column_factor]
+                .iter()
+                .map(|value| format_decimal_string(*value))
+                .collect::<Vec<_>>();
+            dcm_obj.put(DataElement::new(
+                tag,
+                VR::DS,
+                PrimitiveValue::Strs(scaled.into()),
+            ));
+        }
+    }
+    for tag in [
+        tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE,
+        tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+        tags::PIXEL_MEASURES_SEQUENCE,
+    ] {
+        dcm_obj.update_value(tag, |value| {
+            if let Some(items) = value.items_mut() {
+                items
+                    .iter_mut()
+                    .for_each(|item| scale_pixel_spacing(item, row_factor, column_factor));
+            }
+        });
+    }
+}
+
+// Decimal string of at most 16 characters
+fn format_decimal_string(value: f64) -> String {
+    let mut text = format!("{:.6}", value);
+    while text.contains('.') && (text.ends_with('0') || text.ends_with('.')) {
+        text.pop();
+    }
+    text.chars().take(16).collect()
+}
+
+// Convert the pixel data to the transfer syntax of the target. Encapsulated pixel data is decoded
+// by the codecs of this build first, JPEG Lossless is encoded by dcmrig. Objects without pixel
+// data in memory, eg the streamed ones, are left as they are. Returns true if transcoded
+pub fn transcode_pixels(
+    dcm_obj: &mut FileDicomObject<InMemDicomObject>,
+    target: TranscodeTarget,
+) -> Result<bool> {
+    let ts_uid = match target.transfer_syntax() {
+        Some(ts_uid) => ts_uid,
+        None => return Ok(false),
+    };
+    let current = dcm_obj
+        .meta()
+        .transfer_syntax()
+        .trim_end_matches('\0')
+        .to_string();
+    if current == ts_uid || dcm_obj.element_opt(tags::PIXEL_DATA)?.is_none() {
+        return Ok(false);
+    }
+    if current != EXPLICIT_VR_LITTLE_ENDIAN {
+        let encapsulated = TransferSyntaxRegistry
+            .get(&current)
+            .is_some_and(|ts| !ts.is_codec_free());
+        let explicit = TransferSyntaxRegistry
+            .get(EXPLICIT_VR_LITTLE_ENDIAN)
+            .ok_or_else(|| anyhow::anyhow!("No Explicit VR Little Endian in this build"))?;
+        dcm_obj
+            .transcode(explicit)
+            .map_err(|e| anyhow::anyhow!("Can't decode the pixel data of {}: {}", current, e))?;
+        if encapsulated {
+            dcm_obj.remove_element(tags::ENCAPSULATED_PIXEL_DATA_VALUE_TOTAL_LENGTH);
+            // The decoders give interleaved RGB samples
+            let samples = dcm_obj
+                .element(tags::SAMPLES_PER_PIXEL)
+                .ok()
+                .and_then(|element| element.to_int::<u16>().ok())
+                .unwrap_or(1);
+            if samples == 3 {
+                dcm_obj.put(DataElement::new(
+                    tags::PHOTOMETRIC_INTERPRETATION,
+                    VR::CS,
+                    PrimitiveValue::from("RGB"),
+                ));
+                dcm_obj.put(DataElement::new(
+                    tags::PLANAR_CONFIGURATION,
+                    VR::US,
+                    PrimitiveValue::from(0_u16),
+                ));
+            }
+        }
+    }
+    if target == TranscodeTarget::JpegLossless {
+        encode_jpeg_lossless(dcm_obj)?;
+    }
+    debug!("Pixel data transcoded from {} to {}", current, ts_uid);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        hmac_sha256, normalize_date, normalize_dates, normalize_time, sha256, shift_date_value,
+        shift_dates, strip_verbatim_prefix, to_hex, DateOrder, Sha256, DUMMY_DATE,
+    };
+    use dicom::{
+        core::{value::DataSetSequence, DataElement, PrimitiveValue, VR},
+        dicom_value,
+        dictionary_std::tags,
+        object::InMemDicomObject,
+    };
+
+    #[test]
+    fn strip_verbatim_drive_path() {
+        assert_eq!(
+            strip_verbatim_prefix(r"\\?\C:\dest\P9").as_deref(),
+            Some(r"C:\dest\P9")
+        );
+    }
+
+    #[test]
+    fn strip_verbatim_unc_path() {
+        assert_eq!(
+            strip_verbatim_prefix(r"\\?\UNC\server\share\dest").as_deref(),
+            Some(r"\\server\share\dest")
+        );
+    }
+
+    #[test]
+    fn keep_verbatim_volume_guid_path() {
+        assert_eq!(
+            strip_verbatim_prefix(r"\\?\Volume{1b3b1146-4076-11e1-84aa-806e6f6e6963}\dest"),
+            None
+        );
+    }
+
+    #[test]
+    fn keep_plain_paths() {
+        for path in [r"C:\dest", r"\\server\share\dest", "/data/dest", "dest"] {
+            assert_eq!(strip_verbatim_prefix(path), None);
+        }
+    }
+
+    // FIPS 180-4 examples and the long message of the NIST test vectors
+    #[test]
+    fn sha256_known_answers() {
+        let million_a = vec![b'a'; 1_000_000];
+        let cases: [(&[u8], &str); 4] = [
+            (
+                b"",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+            (
+                &million_a,
+                "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0",
+            ),
+        ];
+        for (message, digest) in cases {
+            assert_eq!(to_hex(&sha256(message)), digest);
+        }
+    }
+
+    #[test]
+    fn sha256_updates_across_blocks() {
+        let message: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        for split in [1, 55, 56, 63, 64, 65, 127, 999] {
+            let mut hasher = Sha256::default();
+            for chunk in message.chunks(split) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), sha256(&message), "chunks of {}", split);
+        }
+    }
+
+    // RFC 4231 test cases 1, 2, 3, 6 and 7, the last two with a key longer than a block
+    #[test]
+    fn hmac_sha256_known_answers() {
+        let cases: [(Vec<u8>, Vec<u8>, &str); 5] = [
+            (
+                vec![0x0b; 20],
+                b"Hi There".to_vec(),
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?".to_vec(),
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                vec![0xaa; 20],
+                vec![0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                vec![0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                vec![0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm.".to_vec(),
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, message, mac) in cases {
+            assert_eq!(to_hex(&hmac_sha256(&key, &message)), mac);
+        }
+    }
+
+    #[test]
+    fn shift_date_values() {
+        let cases = [
+            (VR::DA, "20230615", 10, "20230605"),
+            // Month, year and leap day rollovers
+            (VR::DA, "20230301", 1, "20230228"),
+            (VR::DA, "20240301", 1, "20240229"),
+            (VR::DA, "20230101", 1, "20221231"),
+            (VR::DA, "20230101", 3650, "20130103"),
+            (VR::DA, " 20230615 ", 0, "20230615"),
+            // Both ends of a range, an open end stays open
+            (VR::DA, "20230301-20230310", 1, "20230228-20230309"),
+            (VR::DA, "-20230101", 1, "-20221231"),
+            (VR::DA, "20230101-", 1, "20221231-"),
+            // The time and the UTC offset of a DT are kept
+            (VR::DT, "20230101093000.5+0100", 1, "20221231093000.5+0100"),
+            (VR::DT, "20230101", 1, "20221231"),
+            // Unreadable values get the dummy date
+            (VR::DA, "2023", 1, DUMMY_DATE),
+            (VR::DA, "20231301", 1, DUMMY_DATE),
+            (VR::DA, "garbage", 1, DUMMY_DATE),
+        ];
+        for (vr, value, days, shifted) in cases {
+            assert_eq!(shift_date_value(vr, value, days), shifted, "{}", value);
+        }
+    }
+
+    #[test]
+    fn shift_dates_in_multi_valued_elements_and_sequences() {
+        let mut item = InMemDicomObject::new_empty();
+        item.put(DataElement::new(
+            tags::INSTANCE_CREATION_DATE,
+            VR::DA,
+            PrimitiveValue::from("20230101"),
+        ));
+        let mut dcm_obj = InMemDicomObject::new_empty();
+        dcm_obj.put(DataElement::new(
+            tags::CALIBRATION_DATE,
+            VR::DA,
+            dicom_value!(Strs, ["20230110", "", "20230120"]),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::STUDY_DATE,
+            VR::DA,
+            PrimitiveValue::from(""),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::STUDY_TIME,
+            VR::TM,
+            PrimitiveValue::from("093000"),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::REFERENCED_STUDY_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(vec![item]),
+        ));
+        shift_dates(&mut dcm_obj, 10);
+
+        let value = |dcm_obj: &InMemDicomObject, tag| {
+            dcm_obj
+                .element(tag)
+                .unwrap()
+                .to_multi_str()
+                .unwrap()
+                .to_vec()
+        };
+        // The empty values are kept so the VM doesn't change
+        assert_eq!(
+            value(&dcm_obj, tags::CALIBRATION_DATE),
+            ["20221231", "", "20230110"]
+        );
+        assert_eq!(value(&dcm_obj, tags::STUDY_DATE), [""]);
+        assert_eq!(value(&dcm_obj, tags::STUDY_TIME), ["093000"]);
+        let items = dcm_obj
+            .element(tags::REFERENCED_STUDY_SEQUENCE)
+            .unwrap()
+            .items()
+            .unwrap();
+        assert_eq!(value(&items[0], tags::INSTANCE_CREATION_DATE), ["20221222"]);
+    }
+
+    #[test]
+    fn normalize_date_values() {
+        let cases = [
+            ("2023.01.05", DateOrder::Ymd, Some("20230105")),
+            ("2023-1-5", DateOrder::Ymd, Some("20230105")),
+            ("2023/12/31", DateOrder::Dmy, Some("20231231")),
+            // A leading year wins over the order
+            ("2023-01-05", DateOrder::Mdy, Some("20230105")),
+            ("05/01/2023", DateOrder::Dmy, Some("20230105")),
+            ("05/01/2023", DateOrder::Mdy, Some("20230501")),
+            ("5.1.2023", DateOrder::Dmy, Some("20230105")),
+            ("05/01/2023", DateOrder::Ymd, None),
+            // Valid, impossible or unreadable values are left as they are
+            ("20230105", DateOrder::Ymd, None),
+            ("2023-02-29", DateOrder::Ymd, None),
+            ("2024-02-29", DateOrder::Ymd, Some("20240229")),
+            ("31/04/2023", DateOrder::Dmy, None),
+            ("2023-01", DateOrder::Ymd, None),
+            ("", DateOrder::Ymd, None),
+            ("Jan 5 2023", DateOrder::Mdy, None),
+        ];
+        for (value, order, normalized) in cases {
+            assert_eq!(
+                normalize_date(value, order).as_deref(),
+                normalized,
+                "{} {:?}",
+                value,
+                order
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_time_values() {
+        let cases = [
+            ("9:30", Some("0930")),
+            ("09:30:15", Some("093015")),
+            ("9:30:15.123", Some("093015.123")),
+            ("23:59:60", Some("235960")),
+            (" 9:5 ", Some("0905")),
+            // The fraction is cut to 6 digits and needs the seconds
+            ("09:30:15.1234567", Some("093015.123456")),
+            ("09:30.5", None),
+            ("09:30:15.", None),
+            // Valid, out of range or unreadable values are left as they are
+            ("0930", None),
+            ("093015.5", None),
+            ("24:00", None),
+            ("09:60", None),
+            ("09:30:15:10", None),
+            ("9h30", None),
+            ("", None),
+        ];
+        for (value, normalized) in cases {
+            assert_eq!(normalize_time(value).as_deref(), normalized, "{}", value);
+        }
+    }
+
+    #[test]
+    fn normalize_dates_of_a_dataset() {
+        let mut item = InMemDicomObject::new_empty();
+        item.put(DataElement::new(
+            tags::INSTANCE_CREATION_TIME,
+            VR::TM,
+            PrimitiveValue::from("9:30"),
+        ));
+        let mut dcm_obj = InMemDicomObject::new_empty();
+        dcm_obj.put(DataElement::new(
+            tags::STUDY_DATE,
+            VR::DA,
+            PrimitiveValue::from("05/01/2023"),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::SERIES_DATE,
+            VR::DA,
+            PrimitiveValue::from("20230105"),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::ACQUISITION_DATE_TIME,
+            VR::DT,
+            PrimitiveValue::from("2023-01-05 9:30:15"),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::REFERENCED_STUDY_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(vec![item]),
+        ));
+        assert_eq!(normalize_dates(&mut dcm_obj, DateOrder::Dmy), 3);
+
+        let value = |dcm_obj: &InMemDicomObject, tag| {
+            dcm_obj.element(tag).unwrap().to_str().unwrap().to_string()
+        };
+        assert_eq!(value(&dcm_obj, tags::STUDY_DATE), "20230105");
+        assert_eq!(value(&dcm_obj, tags::SERIES_DATE), "20230105");
+        assert_eq!(
+            value(&dcm_obj, tags::ACQUISITION_DATE_TIME),
+            "20230105093015"
+        );
+        let items = dcm_obj
+            .element(tags::REFERENCED_STUDY_SEQUENCE)
+            .unwrap()
+            .items()
+            .unwrap();
+        assert_eq!(value(&items[0], tags::INSTANCE_CREATION_TIME), "0930");
+    }
+}
@@ -3,21 +3,53 @@ The main entry point into dcmrig.
 */
 mod anon;
 mod args;
+mod certificate;
+mod consolidate;
 mod cookbook_parser;
+mod dedup;
 mod deid;
+mod dicomdir;
+mod duplicates;
+mod mapping;
+mod notify;
+mod receive;
+mod report;
+mod review;
+mod runs;
+mod scan;
+mod sidecar;
 mod sort;
+mod test_profile;
 
-use crate::args::EntityType;
+use crate::args::{AnonCommand, EntityType, MappingAction, ReviewQueueAction, RunsAction};
 
 use anon::dicom_anon;
+use dcmrig_rs::delivery::check_age;
+use dcmrig_rs::dicomweb::{check_stow, check_wado};
+use dcmrig_rs::dimse::{check_scp, dicom_send};
+use dcmrig_rs::service::handle_run_stop_signals;
+use dedup::dicom_dedup;
 use deid::dicom_deid;
+use duplicates::dicom_duplicates;
+use mapping::{diff_mappings, merge_mappings, rebuild_mapping};
+use receive::dicom_receive;
+use report::dicom_report;
+use review::{review_approve, review_list, review_reject};
+use runs::{check_run_name, default_runs_db, runs_list, runs_show};
+use scan::dicom_scan;
 use sort::dicom_sort;
+use test_profile::dicom_test_profile;
 
 use anyhow::{Ok, Result};
 use args::ArgsParser;
 use clap::Parser;
-use dcmrig_rs::print_logo;
-use tracing::{error, info, warn, Level};
+use dcmrig_rs::{
+    enter_background_mode, pixel_mask::PixelMask, print_codecs, print_logo, rules::RuleSet,
+    set_log_phi, simplified_path, uid_mapper::UidMapper, DescriptionMap, EmailConfig,
+    ExpectedCounts, PostHooks, ReviewPolicy, RunOptions, StudyValues,
+};
+use std::{process::exit, time::Duration};
+use tracing::{error, info, Level};
 
 fn app() -> Result<()> {
     let start_time = std::time::Instant::now();
@@ -26,34 +58,317 @@ fn app() -> Result<()> {
     tracing::subscriber::set_global_default(
         tracing_subscriber::FmtSubscriber::builder()
             .without_time()
-            .with_max_level(if args.verbose {
+            .with_max_level(if args.print_effective_config {
+                Level::WARN
+            } else if args.verbose {
                 Level::DEBUG
             } else {
                 Level::INFO
             })
             .finish(),
     )?;
-    print_logo();
-    // Only executes if one of the 4 subcommands are provided
-    match args.action_type {
+    set_log_phi(args.log_phi);
+    if !args.print_effective_config {
+        print_logo();
+    }
+    if args.list_codecs {
+        print_codecs();
+        return Ok(());
+    }
+    let action_type = args.action_type.unwrap_or_else(|| {
+        error!("A sub-command is required, see dcmrig --help");
+        exit(1)
+    });
+    if args.background {
+        enter_background_mode()?;
+    }
+    let runs_db = args.runs_db.clone().unwrap_or_else(default_runs_db);
+    if let Some(run_name) = &args.run_name {
+        // Each batch of receive is a run of its own
+        if matches!(action_type, EntityType::Receive(_)) {
+            error!("--run-name names a single run, receive runs the pipeline once per batch");
+            exit(1)
+        }
+        check_run_name(run_name, &runs_db).unwrap_or_else(|e| {
+            error!("{}", e);
+            exit(1)
+        });
+        info!("Run name: {}", run_name);
+    }
+    // The batches of receive are not checkpointed
+    if args.resume.is_some()
+        && !matches!(
+            action_type,
+            EntityType::Sort(_) | EntityType::Deid(_) | EntityType::Anon(_)
+        )
+    {
+        error!("--resume only checkpoints sort, deid and anon");
+        exit(1)
+    }
+    // receive handles the signals itself and completes its batches
+    if matches!(
+        action_type,
+        EntityType::Sort(_) | EntityType::Deid(_) | EntityType::Anon(_)
+    ) {
+        handle_run_stop_signals();
+    }
+    // receive reads the instances of its own SCP
+    if args.wado_url.is_some() && matches!(action_type, EntityType::Receive(_)) {
+        error!("--wado-url can't be the source of receive");
+        exit(1)
+    }
+    let wado_filters = args
+        .wado_filters
+        .iter()
+        .map(|filter| match filter.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                (key.trim().to_string(), value.trim().to_string())
+            }
+            _ => {
+                error!(
+                    "--wado-filter should be KEY=VALUE, eg PatientID=12345: {}",
+                    filter
+                );
+                exit(1)
+            }
+        })
+        .collect();
+    let run_options = RunOptions {
+        slowest: args.slowest,
+        incomplete_action: args.incomplete,
+        incomplete_wait: Duration::from_secs(args.incomplete_wait),
+        email: args.email.as_ref().map(|email_path| {
+            EmailConfig::from_file(email_path).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        }),
+        status_file: args.status_file,
+        status_interval: Duration::from_secs(args.status_interval.max(1)),
+        delivery: args.deliver,
+        dicomdir: args.dicomdir,
+        delivery_recipients: args.deliver_recipients.inspect(|recipients| {
+            check_age(recipients).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        }),
+        shard: args.shard,
+        certificate: args.certificate,
+        operator: args.operator,
+        sign_key: args.sign_key,
+        date_precision: args.reduce_date_precision,
+        description_map: args.description_map.map(|map_path| {
+            DescriptionMap::from_file(&map_path).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        }),
+        rules: args.rules.map(|rules_path| {
+            RuleSet::from_file(&rules_path).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        }),
+        study_values: args.study_values.map(|values_path| {
+            StudyValues::from_file(&values_path).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        }),
+        expected_counts: args.expected_counts.map(|counts_path| {
+            ExpectedCounts::from_file(&counts_path).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        }),
+        uid_mapper: match &args.uid_secret {
+            Some(secret_path) => UidMapper::from_file(secret_path).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            }),
+            None => UidMapper::random(),
+        }
+        .resumed_from(args.resume.as_deref())
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            exit(1)
+        }),
+        review_policy: ReviewPolicy {
+            dose_screens: args.dose_screens,
+            photos: args.photos,
+            slide_labels: args.slide_labels,
+        },
+        annotation_text: args.annotation_text,
+        manifest: args.manifest,
+        read_iso: args.read_iso,
+        downsample: args.downsample,
+        split_frames: args.split_frames,
+        merge_frames: args.merge_frames,
+        transcode: args.transcode,
+        pixel_mask: args.pixel_mask.as_ref().map(|mask_path| {
+            PixelMask::from_file(mask_path).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        }),
+        duplicate_suffix: args.dup_suffix,
+        path_template: args.path_template,
+        name_template: args.name_template,
+        date_order: args.date_order,
+        fix_vr: args.fix_vr,
+        keep_legacy_tags: args.keep_legacy_tags,
+        stream_above: args.stream_above.0,
+        large_file_action: args.large_files,
+        non_dicom: args.non_dicom,
+        dry_run: args.dry_run,
+        dry_run_report: args.dry_run_report,
+        run_name: args.run_name,
+        runs_db: runs_db.clone(),
+        images_only: args.images_only,
+        dedup: args.dedup,
+        resume: args.resume,
+        checkpoint_interval: Duration::from_secs(args.checkpoint_interval.max(1)),
+        filter: args.filter,
+        filter_action: args.filtered_out,
+        series_affinity: args.series_affinity,
+        hide_phi_dirs: args.hide_phi_dirs,
+        output_format: args.output_format,
+        send_to: args.send_to.inspect(|address| {
+            if !args.dry_run && !args.print_effective_config {
+                check_scp(address, &args.calling_aet).unwrap_or_else(|e| {
+                    error!("{}", e);
+                    exit(1)
+                })
+            }
+        }),
+        send_only: args.send_only,
+        calling_aet: args.calling_aet.clone(),
+        stow_url: args.stow_url.inspect(|url| {
+            if !args.dry_run && !args.print_effective_config {
+                check_stow(url, args.stow_token.as_deref(), args.stow_user.as_deref())
+                    .unwrap_or_else(|e| {
+                        error!("{}", e);
+                        exit(1)
+                    })
+            }
+        }),
+        stow_token: args.stow_token,
+        stow_user: args.stow_user,
+        wado_url: args.wado_url.inspect(|url| {
+            if !args.print_effective_config {
+                check_wado(url, args.wado_token.as_deref(), args.wado_user.as_deref())
+                    .unwrap_or_else(|e| {
+                        error!("{}", e);
+                        exit(1)
+                    })
+            }
+        }),
+        wado_filters,
+        wado_token: args.wado_token,
+        wado_user: args.wado_user,
+        print_effective_config: args.print_effective_config,
+        hooks: PostHooks {
+            post_file: args.post_file,
+            post_study: args.post_study,
+        },
+    };
+    // Only executes if one of the subcommands are provided
+    match action_type {
         EntityType::Sort(sort_command) => dicom_sort(
-            sort_command.source,
-            sort_command.destination,
+            simplified_path(sort_command.source),
+            simplified_path(sort_command.destination),
             sort_command.sort_order,
+            run_options,
         )?,
         EntityType::Deid(deid_command) => dicom_deid(
-            deid_command.source,
-            deid_command.destination,
+            simplified_path(deid_command.source),
+            simplified_path(deid_command.destination),
             deid_command.mapping_table,
+            run_options,
         )?,
         EntityType::Anon(anon_command) => dicom_anon(
-            anon_command.source,
-            anon_command.destination,
-            anon_command.prefix,
+            AnonCommand {
+                source: simplified_path(anon_command.source),
+                destination: simplified_path(anon_command.destination),
+                ..anon_command
+            },
+            run_options,
         )?,
-        EntityType::Report(_report_command) => {
-            warn!("Report function Not setup yet");
-        }
+        EntityType::Scan(scan_command) => dicom_scan(
+            simplified_path(scan_command.source),
+            scan_command.output,
+            run_options,
+        )?,
+        EntityType::Report(report_command) => dicom_report(
+            simplified_path(report_command.source),
+            report_command.output,
+            report_command.format,
+            report_command.level,
+            run_options,
+        )?,
+        EntityType::Duplicates(duplicates_command) => dicom_duplicates(
+            simplified_path(duplicates_command.source),
+            duplicates_command.output,
+            duplicates_command.threshold,
+            run_options,
+        )?,
+        EntityType::Dedup(dedup_command) => dicom_dedup(
+            simplified_path(dedup_command.source),
+            dedup_command.output,
+            dedup_command.move_to,
+            run_options,
+        )?,
+        EntityType::TestProfile(test_profile_command) => dicom_test_profile(
+            test_profile_command.profile,
+            test_profile_command.fixtures,
+            test_profile_command.mapping_table,
+            test_profile_command.bless,
+        )?,
+        EntityType::Mapping(mapping_command) => match mapping_command.action {
+            MappingAction::Merge(merge_command) => {
+                merge_mappings(merge_command.mappings, merge_command.output, args.dry_run)?
+            }
+            MappingAction::Diff(diff_command) => {
+                diff_mappings(diff_command.first, diff_command.second)?
+            }
+            MappingAction::Rebuild(rebuild_command) => {
+                rebuild_mapping(rebuild_command, &run_options.uid_mapper, args.dry_run)?
+            }
+        },
+        EntityType::Review(review_command) => match review_command.action {
+            ReviewQueueAction::List(list_command) => {
+                review_list(simplified_path(list_command.destination))?
+            }
+            ReviewQueueAction::Approve(approve_command) => review_approve(
+                simplified_path(approve_command.destination),
+                approve_command.files,
+                approve_command.all,
+                args.dup_suffix,
+                args.dry_run,
+            )?,
+            ReviewQueueAction::Reject(reject_command) => review_reject(
+                simplified_path(reject_command.destination),
+                reject_command.files,
+                reject_command.all,
+                args.dry_run,
+            )?,
+        },
+        EntityType::Runs(runs_command) => match runs_command.action {
+            RunsAction::List => runs_list(&runs_db)?,
+            RunsAction::Show(show_command) => runs_show(&runs_db, &show_command.name)?,
+        },
+        EntityType::Send(send_command) => dicom_send(
+            &simplified_path(send_command.source),
+            &send_command.address,
+            &args.calling_aet,
+            args.read_iso,
+        )?,
+        EntityType::Receive(receive_command) => dicom_receive(receive_command, run_options)
+            .unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            }),
     }
 
     let elapsed_time = std::time::Instant::now() - start_time;
@@ -65,7 +380,9 @@ fn app() -> Result<()> {
     Ok(())
 }
 
-fn main() -> Result<()> {
-    app().unwrap_or_else(|_| error!("Unexpected error during execution!"));
-    Ok(())
+fn main() {
+    app().unwrap_or_else(|e| {
+        error!("{:#}", e);
+        exit(1)
+    });
 }
@@ -0,0 +1,503 @@
+use crate::args::RebuildMappingCommand;
+use anyhow::Result;
+use dcmrig_rs::{
+    csv_field, csv_fields, filter::FILTERED_OUT_DIR, open_source_file, phi, uid_mapper::UidMapper,
+    DELIVERY_DIR, FAILED_CASES_DIR, INCOMPLETE_DIR, LARGE_FILES_DIR, RESULTS_FILE,
+};
+use dicom::{dictionary_std::tags, object::mem::InMemElement};
+use rayon::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::exit,
+};
+use tracing::{error, info, warn};
+use walkdir::WalkDir;
+
+/// Read all DeID,PatientID pairs of a mapping table in order
+/// Lines that dont follow the DeID,PatientID pattern are ignored
+pub fn read_mapping_pairs(mapping_table: &Path) -> Result<Vec<(String, String)>> {
+    let file = File::open(mapping_table).unwrap_or_else(|_| {
+        error!("Failed to open file {}", mapping_table.display());
+        exit(1)
+    });
+    let mut pairs = vec![];
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        let parts: Vec<&str> = line.split(',').collect();
+        if parts.len() == 2 {
+            let deid = parts[0].trim();
+            let patient_id = parts[1].trim();
+            if deid.is_empty() || patient_id.is_empty() {
+                continue;
+            }
+            pairs.push((deid.to_string(), patient_id.to_string()));
+        } else if !line.trim().is_empty() {
+            warn!(
+                "Invalid line in {}: {}",
+                mapping_table.display(),
+                phi(&line)
+            );
+        }
+    }
+    Ok(pairs)
+}
+
+/// Merge the mapping tables of sharded or partial runs into one mapping table
+/// A PatientID mapped to different DeIDs is a conflict and nothing is written
+/// A DeID shared by different PatientIDs is reported as a collision
+/// A dry run only checks the tables
+pub fn merge_mappings(mapping_tables: Vec<PathBuf>, output: PathBuf, dry_run: bool) -> Result<()> {
+    info!(
+        "Merging {} mapping tables into {}",
+        mapping_tables.len(),
+        output.display()
+    );
+    // PatientID > (DeID, first mapping table it was seen in)
+    let mut merged: BTreeMap<String, (String, PathBuf)> = BTreeMap::new();
+    let mut conflicts = 0;
+    for mapping_table in &mapping_tables {
+        for (deid, patient_id) in read_mapping_pairs(mapping_table)? {
+            match merged.get(&patient_id) {
+                Some((existing_deid, _)) if *existing_deid == deid => (),
+                Some((existing_deid, existing_table)) => {
+                    conflicts += 1;
+                    error!(
+                        "Conflict for PatientID {}: {} in {} and {} in {}",
+                        patient_id,
+                        existing_deid,
+                        existing_table.display(),
+                        deid,
+                        mapping_table.display()
+                    );
+                }
+                None => {
+                    merged.insert(patient_id, (deid, mapping_table.clone()));
+                }
+            }
+        }
+    }
+    if conflicts > 0 {
+        error!(
+            "{} conflicting entries found, merged mapping table not written",
+            conflicts
+        );
+        exit(1);
+    }
+
+    let mut deid_owners: BTreeMap<&String, Vec<&String>> = BTreeMap::new();
+    for (patient_id, (deid, _)) in &merged {
+        deid_owners.entry(deid).or_default().push(patient_id);
+    }
+    for (deid, patient_ids) in deid_owners.iter().filter(|(_, ids)| ids.len() > 1) {
+        warn!(
+            "DeID {} is shared by {} PatientIDs: {:?}",
+            deid,
+            patient_ids.len(),
+            patient_ids
+        );
+    }
+
+    if dry_run {
+        info!(
+            "Dry run, merged mapping table with {} entries not written",
+            merged.len()
+        );
+        return Ok(());
+    }
+    let mut out_file = File::create(&output)?;
+    for (patient_id, (deid, _)) in &merged {
+        writeln!(out_file, "{},{}", deid, patient_id)?;
+    }
+    info!("Merged mapping table with {} entries written", merged.len());
+    Ok(())
+}
+
+/// Compare two mapping tables by PatientID
+/// Prints `< DeID,PatientID` for the entries only in the first table, `> DeID,PatientID` for the
+/// ones only in the second and `! PatientID: DeID > DeID` for the PatientIDs mapped differently
+/// Exits with 1 if the tables differ
+pub fn diff_mappings(first: PathBuf, second: PathBuf) -> Result<()> {
+    info!(
+        "Comparing mapping tables {} and {}",
+        first.display(),
+        second.display()
+    );
+    let first_pairs = mapping_by_patient(&first)?;
+    let second_pairs = mapping_by_patient(&second)?;
+    let (mut only_first, mut only_second, mut changed) = (0, 0, 0);
+    for (patient_id, deid) in &first_pairs {
+        match second_pairs.get(patient_id) {
+            Some(second_deid) if second_deid == deid => (),
+            Some(second_deid) => {
+                changed += 1;
+                println!("! {}: {} > {}", phi(patient_id), deid, second_deid);
+            }
+            None => {
+                only_first += 1;
+                println!("< {},{}", deid, phi(patient_id));
+            }
+        }
+    }
+    for (patient_id, deid) in &second_pairs {
+        if !first_pairs.contains_key(patient_id) {
+            only_second += 1;
+            println!("> {},{}", deid, phi(patient_id));
+        }
+    }
+    if only_first + only_second + changed == 0 {
+        info!("Mapping tables match, {} entries", first_pairs.len());
+        return Ok(());
+    }
+    warn!(
+        "Mapping tables differ: {} only in {}, {} only in {}, {} mapped differently",
+        only_first,
+        first.display(),
+        only_second,
+        second.display(),
+        changed
+    );
+    exit(1)
+}
+
+/// Rebuild the mapping store of anon from a destination it wrote, when the --mapping-db is lost
+/// The ANON ID and the UIDs of each instance are read from the destination. The key value of the
+/// patient is recovered from the source of the instance in the results.csv of the destination,
+/// from the source files whose SOPInstanceUID was mapped to one of the destination with the
+/// --uid-secret (or kept), or, for --id-mode hash, from the source values that hash to an ANON ID
+/// of the destination. Entries already in the store are kept
+pub fn rebuild_mapping(
+    command: RebuildMappingCommand,
+    uid_mapper: &UidMapper,
+    dry_run: bool,
+) -> Result<()> {
+    info!(
+        "Rebuilding the mapping store {} from {}",
+        command.output.display(),
+        command.destination.display()
+    );
+    let secret = uid_mapper.secret_path.is_some();
+    let instances = destination_instances(&command.destination);
+    if instances.is_empty() {
+        error!("No DICOM files in {}", command.destination.display());
+        exit(1);
+    }
+    let anon_ids: BTreeSet<&String> = instances
+        .iter()
+        .map(|instance| &instance.patient_id)
+        .collect();
+    // SOPInstanceUID of the destination > ANON ID
+    let by_sop_uid: HashMap<&String, &String> = instances
+        .iter()
+        .map(|instance| (&instance.sop_uid, &instance.patient_id))
+        .collect();
+    let prefixes: BTreeSet<&str> = anon_ids
+        .iter()
+        .map(|anon_id| anon_prefix(anon_id))
+        .collect();
+
+    // Source > ANON ID of results.csv, the sources that still exist
+    let mut sources: BTreeMap<PathBuf, Option<String>> = BTreeMap::new();
+    match fs::read_to_string(command.destination.join(RESULTS_FILE)) {
+        Ok(content) => {
+            for fields in content.lines().skip(1).map(csv_fields) {
+                if fields.len() > 4 && !fields[4].is_empty() && Path::new(&fields[0]).is_file() {
+                    sources.insert(PathBuf::from(&fields[0]), Some(fields[4].clone()));
+                }
+            }
+        }
+        Err(e) => warn!("Can't read the {} of the destination: {}", RESULTS_FILE, e),
+    }
+    if let Some(source) = &command.source {
+        for entry in WalkDir::new(source)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+        {
+            if entry.file_type().is_file() {
+                sources.entry(entry.into_path()).or_insert(None);
+            }
+        }
+    }
+    info!("{} source files to match", sources.len());
+    let source_files: Vec<PathBuf> = sources.keys().cloned().collect();
+    let originals = read_instances_of(&source_files, Some(&command.key_tag));
+
+    // Patient key > ANON ID, prefix\value as anon keys them
+    let mut recovered: HashMap<String, String> = HashMap::new();
+    let mut record = |key_value: &str, anon_id: &String| {
+        let patient_key = format!("{}\\{}", anon_prefix(anon_id), key_value);
+        match recovered.get(&patient_key) {
+            Some(existing) if existing != anon_id => warn!(
+                "{} matches {} and {}, {} is kept",
+                phi(key_value),
+                existing,
+                anon_id,
+                existing
+            ),
+            Some(_) => (),
+            None => {
+                recovered.insert(patient_key, anon_id.clone());
+            }
+        }
+    };
+    for original in &originals {
+        let anon_id = sources
+            .get(&original.path)
+            .and_then(|anon_id| anon_id.as_ref())
+            .filter(|anon_id| anon_ids.contains(anon_id))
+            .or_else(|| by_sop_uid.get(&original.sop_uid).copied())
+            .or_else(|| match secret {
+                true => by_sop_uid
+                    .get(&uid_mapper.new_uid(&original.sop_uid))
+                    .copied(),
+                false => None,
+            });
+        match anon_id {
+            Some(anon_id) => record(&original.patient_id, anon_id),
+            // The ANON IDs of --id-mode hash are derived from the key value
+            None if secret => {
+                for prefix in &prefixes {
+                    let hashed =
+                        uid_mapper.anon_id(&format!("{}\\{}", prefix, original.patient_id));
+                    let hashed = match prefix.is_empty() {
+                        true => hashed,
+                        false => format!("{}_{}", prefix, hashed),
+                    };
+                    if let Some(anon_id) = anon_ids.get(&hashed) {
+                        record(&original.patient_id, anon_id);
+                    }
+                }
+            }
+            None => (),
+        }
+    }
+
+    let found: BTreeSet<&String> = recovered.values().collect();
+    let missing: Vec<&&String> = anon_ids.difference(&found).collect();
+    info!(
+        "{} of {} ANON IDs of the destination recovered",
+        found.len(),
+        anon_ids.len()
+    );
+    if !missing.is_empty() {
+        warn!(
+            "{} ANON IDs have no source, give the original files with --source and the --uid-secret of the run: {:?}",
+            missing.len(),
+            missing
+        );
+    }
+    if dry_run {
+        info!("Dry run, the mapping store is not written");
+        return Ok(());
+    }
+    if let Some(linkage) = &command.linkage {
+        write_linkage(linkage, &instances, &recovered)?;
+    }
+    write_mapping_store(&command.output, &recovered)
+}
+
+// Instance of the destination or of the source, the key value is read instead of the PatientID
+// for the source
+struct InstanceIds {
+    path: PathBuf,
+    patient_id: String,
+    study_uid: String,
+    series_uid: String,
+    sop_uid: String,
+}
+
+// Written instances of a destination, the copies of the original files set aside are left out
+fn destination_instances(destination: &Path) -> Vec<InstanceIds> {
+    let set_aside = [
+        FAILED_CASES_DIR,
+        "NON_DICOM",
+        INCOMPLETE_DIR,
+        LARGE_FILES_DIR,
+        FILTERED_OUT_DIR,
+        DELIVERY_DIR,
+    ];
+    let files: Vec<PathBuf> = WalkDir::new(destination)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() != 1 || !set_aside.contains(&&*entry.file_name().to_string_lossy())
+        })
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+    read_instances_of(&files, None)
+}
+
+// Headers of the DICOM files, the other files are left out
+fn read_instances_of(files: &[PathBuf], key_tag: Option<&str>) -> Vec<InstanceIds> {
+    files
+        .par_iter()
+        .filter_map(|path| {
+            let dcm_obj = open_source_file(path, true).ok()?;
+            let value = |element: Option<&InMemElement>| {
+                element
+                    .and_then(|element| element.to_str().ok())
+                    .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+                    .unwrap_or_default()
+            };
+            let patient_id = match key_tag {
+                Some(key_tag) => value(dcm_obj.element_by_name(key_tag).ok()),
+                None => value(dcm_obj.element(tags::PATIENT_ID).ok()),
+            };
+            let instance = InstanceIds {
+                path: path.clone(),
+                patient_id,
+                study_uid: value(dcm_obj.element(tags::STUDY_INSTANCE_UID).ok()),
+                series_uid: value(dcm_obj.element(tags::SERIES_INSTANCE_UID).ok()),
+                sop_uid: value(dcm_obj.element(tags::SOP_INSTANCE_UID).ok()),
+            };
+            (!instance.patient_id.is_empty()).then_some(instance)
+        })
+        .collect()
+}
+
+// Site prefix of an ANON ID, prefix_ID. The IDs of gen_id have no underscore
+fn anon_prefix(anon_id: &str) -> &str {
+    anon_id.rsplit_once('_').map_or("", |(prefix, _)| prefix)
+}
+
+// ANON ID, recovered patient and UIDs of each instance of the destination
+fn write_linkage(
+    linkage: &Path,
+    instances: &[InstanceIds],
+    recovered: &HashMap<String, String>,
+) -> Result<()> {
+    let patients: HashMap<&String, &str> = recovered
+        .iter()
+        .map(|(patient_key, anon_id)| (anon_id, patient_key.trim_start_matches('\\')))
+        .collect();
+    let mut rows: Vec<&InstanceIds> = instances.iter().collect();
+    rows.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut out_file = File::create(linkage)?;
+    writeln!(
+        out_file,
+        "anon_id,patient,study_uid,series_uid,sop_instance_uid,path"
+    )?;
+    for instance in rows {
+        writeln!(
+            out_file,
+            "{},{},{},{},{},{}",
+            csv_field(&instance.patient_id),
+            csv_field(
+                patients
+                    .get(&instance.patient_id)
+                    .copied()
+                    .unwrap_or_default()
+            ),
+            instance.study_uid,
+            instance.series_uid,
+            instance.sop_uid,
+            csv_field(&instance.path.display().to_string())
+        )?;
+    }
+    info!(
+        "Linkage of {} instances written: {}",
+        instances.len(),
+        linkage.display()
+    );
+    Ok(())
+}
+
+// PatientID > DeID of a mapping table, a PatientID listed twice keeps its first DeID
+fn mapping_by_patient(mapping_table: &Path) -> Result<BTreeMap<String, String>> {
+    let mut mapping = BTreeMap::new();
+    for (deid, patient_id) in read_mapping_pairs(mapping_table)? {
+        match mapping.get(&patient_id) {
+            Some(existing_deid) if *existing_deid != deid => warn!(
+                "PatientID {} is mapped twice in {}: {} and {}",
+                phi(&patient_id),
+                mapping_table.display(),
+                existing_deid,
+                deid
+            ),
+            Some(_) => (),
+            None => {
+                mapping.insert(patient_id, deid);
+            }
+        }
+    }
+    Ok(mapping)
+}
+
+/// Read the ANON IDs of earlier runs from a mapping store, patient key > ANON ID
+/// The store is a mapping table of ANON_ID,PatientID lines, the PatientID is preceded by the
+/// site prefix and a backslash when the run had a prefix. A missing store is empty
+pub fn read_mapping_store(store: &Path) -> Result<HashMap<String, String>> {
+    if !store.exists() {
+        info!("New mapping store: {}", store.display());
+        return Ok(HashMap::new());
+    }
+    let anon_ids = stored_ids(store)?;
+    info!(
+        "{} ANON IDs read from the mapping store {}",
+        anon_ids.len(),
+        store.display()
+    );
+    Ok(anon_ids)
+}
+
+/// Write the ANON IDs of the run to the mapping store
+/// The store is read again first so the entries added by concurrent runs are kept, an ANON ID
+/// already in the store wins over the one of this run
+pub fn write_mapping_store(store: &Path, anon_ids: &HashMap<String, String>) -> Result<()> {
+    let mut merged: BTreeMap<String, String> = match store.exists() {
+        true => stored_ids(store)?.into_iter().collect(),
+        false => BTreeMap::new(),
+    };
+    for (patient_key, anon_id) in anon_ids {
+        match merged.get(patient_key) {
+            Some(stored) if stored != anon_id => warn!(
+                "{} got {} in this run but {} in the mapping store, the stored ID is kept",
+                phi(patient_key.trim_start_matches('\\')),
+                anon_id,
+                stored
+            ),
+            Some(_) => (),
+            None => {
+                merged.insert(patient_key.clone(), anon_id.clone());
+            }
+        }
+    }
+    // Written beside the store and renamed, so an interrupted run leaves the store intact
+    let temp_path = PathBuf::from(format!("{}.tmp", store.display()));
+    let mut out_file = File::create(&temp_path)?;
+    for (patient_key, anon_id) in &merged {
+        writeln!(
+            out_file,
+            "{},{}",
+            anon_id,
+            patient_key.trim_start_matches('\\')
+        )?;
+    }
+    out_file.sync_all()?;
+    fs::rename(&temp_path, store)?;
+    info!(
+        "Mapping store {} written with {} entries",
+        store.display(),
+        merged.len()
+    );
+    Ok(())
+}
+
+// Patient key > ANON ID of the store, a patient listed twice keeps its first ID
+fn stored_ids(store: &Path) -> Result<HashMap<String, String>> {
+    let mut anon_ids = HashMap::new();
+    for (anon_id, patient) in read_mapping_pairs(store)? {
+        anon_ids.entry(store_key(&patient)).or_insert(anon_id);
+    }
+    Ok(anon_ids)
+}
+
+// Patient key of the anon run for a PatientID of the store, prefix\PatientID
+fn store_key(patient: &str) -> String {
+    match patient.contains('\\') {
+        true => patient.to_string(),
+        false => format!("\\{}", patient),
+    }
+}
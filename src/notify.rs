@@ -0,0 +1,198 @@
+use crate::certificate::RunSummary;
+use anyhow::Result;
+use dcmrig_rs::{csv_field, tracker::RunTracker, EmailConfig, RunOptions};
+use dicom::core::chrono::Local;
+use nanoid::nanoid;
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Email the summary of the run, and the failed files when asked, to the recipients of the job
+// A failed send is only logged, the output of the run is already complete
+pub fn send_run_report(summary: &RunSummary, run_options: &RunOptions, tracker: &RunTracker) {
+    let email = match &run_options.email {
+        Some(email) => email,
+        None => return,
+    };
+    let subject = email
+        .subject
+        .replace("{action}", &summary.action)
+        .replace(
+            "{written}",
+            &summary.count("DICOM files written").to_string(),
+        )
+        .replace("{failed}", &summary.count("Failed files").to_string());
+    let report = summary.report_lines(run_options).join("\r\n");
+    let mut attachments = vec![("run-summary.txt", report.clone())];
+    if email.attach_failures {
+        attachments.push(("failures.csv", failure_list(tracker)));
+    }
+    let message = mime_message(email, &subject, &report, &attachments);
+    match send_mail(email, &message) {
+        Ok(()) => info!("Run report emailed to {}", email.to.join(", ")),
+        Err(e) => warn!(
+            "Can't email the run report through {}:{}: {}",
+            email.server, email.port, e
+        ),
+    }
+}
+
+// Source and error of each failed file, as in results.csv
+fn failure_list(tracker: &RunTracker) -> String {
+    let mut results = tracker
+        .results
+        .lock()
+        .expect("Failed to lock mutex")
+        .clone();
+    results.sort_by(|a, b| a.source.cmp(&b.source));
+    let mut list = String::from("source,error\r\n");
+    for result in results.iter().filter(|result| result.status == "failed") {
+        list.push_str(&format!(
+            "{},{}\r\n",
+            csv_field(&result.source.display().to_string()),
+            csv_field(&result.error)
+        ));
+    }
+    list
+}
+
+// multipart/mixed message with the report as the body and base64 attachments
+fn mime_message(
+    email: &EmailConfig,
+    subject: &str,
+    body: &str,
+    attachments: &[(&str, String)],
+) -> String {
+    let boundary = format!("dcmrig-{}", nanoid!(16));
+    let mut message = vec![
+        format!("From: {}", email.from),
+        format!("To: {}", email.to.join(", ")),
+        format!("Subject: {}", subject.replace(['\r', '\n'], " ")),
+        format!("Date: {}", Local::now().to_rfc2822()),
+        "MIME-Version: 1.0".to_string(),
+        format!("Content-Type: multipart/mixed; boundary=\"{}\"", boundary),
+        String::new(),
+        format!("--{}", boundary),
+        "Content-Type: text/plain; charset=utf-8".to_string(),
+        "Content-Transfer-Encoding: base64".to_string(),
+        String::new(),
+        base64_lines(body.as_bytes()),
+    ];
+    for (name, content) in attachments {
+        message.extend([
+            format!("--{}", boundary),
+            format!("Content-Type: text/plain; charset=utf-8; name=\"{}\"", name),
+            "Content-Transfer-Encoding: base64".to_string(),
+            format!("Content-Disposition: attachment; filename=\"{}\"", name),
+            String::new(),
+            base64_lines(content.as_bytes()),
+        ]);
+    }
+    message.push(format!("--{}--", boundary));
+    message.join("\r\n")
+}
+
+// Base64 in lines of 76 characters
+fn base64_lines(bytes: &[u8]) -> String {
+    let mut encoded = vec![];
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            encoded.push(match i <= chunk.len() {
+                true => BASE64_ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize],
+                false => b'=',
+            });
+        }
+    }
+    encoded
+        .chunks(76)
+        .map(|line| String::from_utf8_lossy(line).to_string())
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+// Minimal SMTP client: EHLO, MAIL FROM, RCPT TO, DATA and QUIT without TLS or authentication,
+// for the internal relays of the site
+fn send_mail(email: &EmailConfig, message: &str) -> Result<()> {
+    let address = (email.server.as_str(), email.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Unknown server"))?;
+    let stream = TcpStream::connect_timeout(&address, SMTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(SMTP_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    expect_reply(&mut reader, 220)?;
+    let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
+    smtp_command(&mut writer, &mut reader, &format!("EHLO {}", hostname), 250)?;
+    smtp_command(
+        &mut writer,
+        &mut reader,
+        &format!("MAIL FROM:<{}>", email.from),
+        250,
+    )?;
+    for recipient in &email.to {
+        smtp_command(
+            &mut writer,
+            &mut reader,
+            &format!("RCPT TO:<{}>", recipient),
+            250,
+        )?;
+    }
+    smtp_command(&mut writer, &mut reader, "DATA", 354)?;
+    // Lines starting with a dot are escaped by doubling it
+    let data: Vec<String> = message
+        .split("\r\n")
+        .map(|line| match line.starts_with('.') {
+            true => format!(".{}", line),
+            false => line.to_string(),
+        })
+        .collect();
+    smtp_command(
+        &mut writer,
+        &mut reader,
+        &format!("{}\r\n.", data.join("\r\n")),
+        250,
+    )?;
+    smtp_command(&mut writer, &mut reader, "QUIT", 221)?;
+    Ok(())
+}
+
+fn smtp_command(
+    writer: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    command: &str,
+    expected: u16,
+) -> Result<()> {
+    writer.write_all(format!("{}\r\n", command).as_bytes())?;
+    writer.flush()?;
+    expect_reply(reader, expected)
+}
+
+// Read a reply, including the continuation lines of a multiline reply, and check its code
+fn expect_reply(reader: &mut BufReader<TcpStream>, expected: u16) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow::anyhow!("Connection closed by the server"));
+        }
+        let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        return match code {
+            Some(code) if code == expected => Ok(()),
+            _ => Err(anyhow::anyhow!("Unexpected reply: {}", line.trim_end())),
+        };
+    }
+}
@@ -0,0 +1,335 @@
+use crate::{
+    checkpoint::{Checkpoint, CheckpointWriter},
+    delivery::ArchiveSink,
+    dicomweb::{setup_source, StowClient},
+    dimse::StoreScu,
+    filter::FilterAction,
+    has_pixel_data, print_status, series_batches,
+    service::{exit_stopped_run, run_stop_requested},
+    tracker::RunTracker,
+    transfer_syntax_precheck, FileTiming, RunOptions, StatusWriter, TranscodeTarget,
+};
+use anyhow::Result;
+use crossbeam::sync::WaitGroup;
+use dicom::object::{FileDicomObject, InMemDicomObject};
+use rayon::prelude::*;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+use tracing::{error, info};
+
+// What a sort, deid or anon run does to each DICOM file of its source, FileRun reads, skips and
+// routes the files and gives it the ones to transform
+pub trait FileTransform: Sync {
+    // Name of the action in the log, the checkpoint and the status file, eg DeID
+    fn action(&self) -> &str;
+
+    // The files are copied as they are, eg sorted: only their header is read, whatever their
+    // size, and they are not sent to a DIMSE or STOW-RS peer
+    fn copies(&self) -> bool {
+        false
+    }
+
+    // Transform a file and write it, the write holds wg until it is done
+    fn transform(
+        &self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        destination_path: &Path,
+        timing: FileTiming,
+        tracker: RunTracker,
+        wg: WaitGroup,
+    ) -> Result<()>;
+
+    // Save what the transform has so far, eg the ANON IDs given, before a stopped run exits
+    fn stopped(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// A sort, deid or anon run over the items of its source
+pub struct FileRun {
+    pub tracker: RunTracker,
+    // Items left to process, without the ones done before a resume
+    pub items: Vec<PathBuf>,
+    // Items of the source
+    pub total_len: u64,
+    pub checkpoint: Option<Checkpoint>,
+    pub checkpoint_writer: Option<CheckpointWriter>,
+    pub status_writer: Option<StatusWriter>,
+    // Failed and non DICOM files, the ones before a resume included
+    pub failed: u64,
+    pub non_dicom: u64,
+}
+
+impl FileRun {
+    // Index the source and set up the tracker of the run from the run options. The policy is
+    // the hash of the configuration of the run, a checkpoint of another policy is refused
+    pub fn start(
+        action: &str,
+        policy: &str,
+        source_path: &PathBuf,
+        destination_path: &PathBuf,
+        run_options: &RunOptions,
+    ) -> Result<Self> {
+        let (items, total_len, mut tracker) =
+            setup_source(source_path, destination_path, run_options)?;
+        // A DICOMDIR in the source or the counts of the server it was searched on
+        tracker.expected_counts = run_options
+            .expected_counts
+            .clone()
+            .or_else(|| tracker.source.expected_counts())
+            .map(Arc::new);
+        if run_options.manifest {
+            tracker.collect_checksums();
+        }
+        if run_options.dedup {
+            tracker.collect_instances();
+        }
+        let checkpoint = run_options
+            .open_checkpoint(action, destination_path, policy)
+            .unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            });
+        let items = match &checkpoint {
+            Some(checkpoint) => {
+                tracker.resume(checkpoint);
+                checkpoint.pending(items)
+            }
+            None => items,
+        };
+        tracker.set_hooks(run_options.hooks.clone());
+        if run_options.dry_run {
+            tracker.set_dry_run();
+        }
+        tracker.duplicate_suffix = run_options.duplicate_suffix;
+        tracker.path_template = run_options.path_template.clone().map(Arc::new);
+        tracker.name_template = run_options.name_template.clone().map(Arc::new);
+        tracker.incomplete_action = run_options.incomplete_action;
+        tracker.incomplete_wait = run_options.incomplete_wait;
+        tracker.series_affinity = run_options.series_affinity;
+        tracker.hide_phi_dirs = run_options.hide_phi_dirs;
+        let status_writer = run_options.start_status(action, &tracker);
+        info!("Starting {}", action);
+        transfer_syntax_precheck(
+            tracker.source.as_ref(),
+            &items,
+            run_options.transcode != TranscodeTarget::Keep,
+        );
+        let status_count = |status: &str| {
+            checkpoint
+                .as_ref()
+                .map_or(0, |checkpoint| checkpoint.status_count(status))
+        };
+        Ok(FileRun {
+            failed: status_count("failed"),
+            non_dicom: status_count("non-DICOM"),
+            tracker,
+            items,
+            total_len,
+            checkpoint,
+            checkpoint_writer: None,
+            status_writer,
+        })
+    }
+
+    // Save the checkpoint of --resume every checkpoint interval, with the ANON IDs given of anon
+    pub fn save_checkpoints(
+        &mut self,
+        run_options: &RunOptions,
+        anon_ids: Option<Arc<Mutex<HashMap<String, String>>>>,
+    ) {
+        self.checkpoint_writer = self.checkpoint.as_ref().map(|checkpoint| {
+            CheckpointWriter::start(
+                checkpoint,
+                run_options.checkpoint_interval,
+                self.tracker.clone(),
+                anon_ids,
+            )
+        });
+    }
+
+    // Transform each DICOM file of the items that belongs to the run, the others are skipped,
+    // excluded, quarantined or copied aside. Returns once all the files are written, a run
+    // stopped by a signal saves what it has and exits
+    pub fn process(
+        &mut self,
+        transform: &dyn FileTransform,
+        destination_path: &Path,
+        run_options: &RunOptions,
+    ) -> Result<()> {
+        self.set_sink(transform, destination_path, run_options)?;
+        if !transform.copies() {
+            self.tracker.stream_above = run_options.stream_above;
+            self.tracker.large_file_action = run_options.large_file_action;
+            self.tracker.non_dicom_action = run_options.non_dicom;
+        }
+        let tracker = &self.tracker;
+        let failed = AtomicU64::new(self.failed);
+        let non_dicom = AtomicU64::new(self.non_dicom);
+        let wg = WaitGroup::new();
+
+        // Main Loop
+        series_batches(tracker, &self.items)
+            .par_iter()
+            .flat_map_iter(|batch| batch.iter())
+            .for_each(|working_path| {
+                // Stopped, the remaining files are not taken
+                if run_stop_requested() {
+                    return;
+                }
+                let read_start = Instant::now();
+                if tracker.fetch_item(working_path).is_err() {
+                    failed.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                if let Some(reason) = tracker.large_file_reason(working_path) {
+                    match run_options.owns_non_dicom() {
+                        true => tracker.quarantine(working_path, destination_path, reason),
+                        false => tracker.skip_file(working_path),
+                    }
+                    return;
+                }
+                let streamed = transform.copies() || tracker.streams(working_path);
+                match tracker.open_item(working_path, streamed) {
+                    Ok(dcm_obj) => {
+                        tracker.record_study(working_path, &dcm_obj);
+                        if !run_options.owns_file(&dcm_obj) {
+                            tracker.skip_file(working_path);
+                            return;
+                        }
+                        if tracker.is_duplicate(working_path, &dcm_obj) {
+                            return;
+                        }
+                        if run_options.images_only
+                            && !has_pixel_data(&dcm_obj, working_path, streamed)
+                        {
+                            tracker.exclude_file(working_path, "No pixel data");
+                            return;
+                        }
+                        if !run_options.selects(&dcm_obj) {
+                            match run_options.filter_action {
+                                FilterAction::Exclude => {
+                                    tracker.exclude_file(working_path, "Not matched by --filter")
+                                }
+                                FilterAction::Copy => {
+                                    tracker.copy_filtered(working_path, destination_path)
+                                }
+                            }
+                            return;
+                        }
+                        tracker.record_identity(&dcm_obj);
+                        tracker.check_modality(working_path, &dcm_obj);
+                        tracker.record_identifiers(working_path, &dcm_obj);
+                        let timing = FileTiming::new(working_path, &dcm_obj, read_start.elapsed());
+                        transform
+                            .transform(
+                                &dcm_obj,
+                                destination_path,
+                                timing,
+                                tracker.clone(),
+                                wg.clone(),
+                            )
+                            .unwrap_or_else(|e| {
+                                if tracker.route_incomplete(working_path, destination_path, &e) {
+                                    return;
+                                }
+                                failed.fetch_add(1, Ordering::Relaxed);
+                                error!(
+                                    "Can't {} {:#?} Copying to FAILED_CASES directory",
+                                    transform.action(),
+                                    working_path.file_name().unwrap_or_default()
+                                );
+                                tracker.record_failed(
+                                    working_path,
+                                    destination_path,
+                                    &e,
+                                    read_start.elapsed(),
+                                );
+                            });
+                    }
+                    Err(e)
+                        if run_options.owns_non_dicom()
+                            && tracker.route_incomplete(working_path, destination_path, &e) => {}
+                    Err(_) if run_options.owns_non_dicom() => {
+                        non_dicom.fetch_add(1, Ordering::Relaxed);
+                        tracker.handle_non_dicom(working_path, destination_path);
+                    }
+                    Err(_) => {
+                        tracker.skip_file(working_path);
+                        return;
+                    }
+                }
+                tracker.progress.scanned.inc(1);
+            });
+        // A stopped run keeps the count of the files it took
+        if !run_stop_requested() {
+            tracker.progress.scanned.finish();
+        }
+        self.failed = failed.into_inner();
+        self.non_dicom = non_dicom.into_inner();
+        print_status(
+            self.total_len,
+            self.failed,
+            self.non_dicom,
+            self.tracker.skipped_count(),
+            self.tracker.incomplete_count(),
+            transform.action().to_string(),
+        )?;
+        info!("Waiting for all threads to complete");
+        wg.wait();
+        self.tracker.sink.finalize()?;
+        self.tracker.source.finalize()?;
+        if run_stop_requested() {
+            transform.stopped()?;
+            exit_stopped_run(
+                &self.tracker,
+                destination_path,
+                run_options,
+                self.checkpoint_writer.take(),
+                self.status_writer.take(),
+            );
+        }
+        Ok(())
+    }
+
+    // Write the instances to the C-STORE peer, the STOW-RS server or the archives of the run
+    // options instead of the filesystem. A dry run writes nothing
+    fn set_sink(
+        &mut self,
+        transform: &dyn FileTransform,
+        destination_path: &Path,
+        run_options: &RunOptions,
+    ) -> Result<()> {
+        if run_options.dry_run {
+            return Ok(());
+        }
+        if !transform.copies() {
+            if let Some(address) = &run_options.send_to {
+                self.tracker.sink = Arc::new(StoreScu::new(
+                    address,
+                    &run_options.calling_aet,
+                    run_options.send_only,
+                ));
+            }
+            if let Some(url) = &run_options.stow_url {
+                self.tracker.sink = Arc::new(StowClient::new(
+                    url,
+                    run_options.stow_token.as_deref(),
+                    run_options.stow_user.as_deref(),
+                )?);
+            }
+        }
+        if let Some(output_format) = run_options.output_format {
+            self.tracker.sink = Arc::new(ArchiveSink::new(destination_path, output_format)?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use crate::{mark_derived, ReviewAction};
+use anyhow::Result;
+use dicom::{
+    core::{DataElement, PrimitiveValue, VR},
+    dictionary_std::tags,
+    encoding::{Codec, Endianness, TransferSyntaxIndex},
+    object::{FileDicomObject, InMemDicomObject, Tag},
+    transfer_syntax::TransferSyntaxRegistry,
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PixelMaskFile {
+    // SOP classes masked even without BurnedInAnnotation YES
+    #[serde(default)]
+    sop_classes: Vec<String>,
+    #[serde(default)]
+    region: Vec<MaskRegion>,
+}
+
+// Blackout boxes for the images of a modality, SOP class and matrix, the filters that are not
+// given match every image
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaskRegion {
+    modality: Option<String>,
+    sop_class: Option<String>,
+    rows: Option<u32>,
+    columns: Option<u32>,
+    // x, y, width and height in pixels from the top left corner
+    boxes: Vec<[u32; 4]>,
+}
+
+impl MaskRegion {
+    fn matches(&self, modality: &str, sop_class: &str, rows: u32, columns: u32) -> bool {
+        self.modality
+            .as_ref()
+            .is_none_or(|value| value.eq_ignore_ascii_case(modality))
+            && self
+                .sop_class
+                .as_ref()
+                .is_none_or(|value| value == sop_class)
+            && self.rows.is_none_or(|value| value == rows)
+            && self.columns.is_none_or(|value| value == columns)
+    }
+}
+
+// Blackout of the annotations burned into the pixels of the images with BurnedInAnnotation YES
+// or one of the listed SOP classes
+#[derive(Debug, Clone)]
+pub struct PixelMask {
+    pub path: PathBuf,
+    sop_classes: Vec<String>,
+    regions: Vec<MaskRegion>,
+}
+
+impl PixelMask {
+    pub fn from_file(mask_path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(mask_path).map_err(|e| {
+            anyhow::anyhow!("Can't read the pixel mask {}: {}", mask_path.display(), e)
+        })?;
+        let mask_file: PixelMaskFile = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid pixel mask {}\n{}", mask_path.display(), e))?;
+        for region in &mask_file.region {
+            if region
+                .boxes
+                .iter()
+                .any(|[_, _, width, height]| *width == 0 || *height == 0)
+            {
+                return Err(anyhow::anyhow!(
+                    "Empty box in the pixel mask {}, boxes are [x, y, width, height]",
+                    mask_path.display()
+                ));
+            }
+        }
+        Ok(PixelMask {
+            path: mask_path.to_path_buf(),
+            sop_classes: mask_file.sop_classes,
+            regions: mask_file.region,
+        })
+    }
+
+    // Boxes to black out in the source, None when it has no burned in annotation to mask and
+    // Err with the reason when it has one that can't be masked
+    fn boxes_of(
+        &self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+    ) -> Option<std::result::Result<Vec<[u32; 4]>, String>> {
+        let value_of = |tag: Tag| {
+            dcm_obj
+                .element(tag)
+                .ok()
+                .and_then(|element| element.to_str().ok())
+                .map(|value| value.trim_end_matches('\0').trim().to_string())
+                .unwrap_or_default()
+        };
+        let sop_class = value_of(tags::SOP_CLASS_UID);
+        let burned_in = value_of(tags::BURNED_IN_ANNOTATION).eq_ignore_ascii_case("YES");
+        if !burned_in && !self.sop_classes.contains(&sop_class) {
+            return None;
+        }
+        dcm_obj.element_opt(tags::PIXEL_DATA).ok()??;
+        let modality = value_of(tags::MODALITY);
+        let rows: u32 = value_of(tags::ROWS).parse().unwrap_or(0);
+        let columns: u32 = value_of(tags::COLUMNS).parse().unwrap_or(0);
+        let boxes: Vec<[u32; 4]> = self
+            .regions
+            .iter()
+            .filter(|region| region.matches(&modality, &sop_class, rows, columns))
+            .flat_map(|region| region.boxes.clone())
+            .collect();
+        if boxes.is_empty() {
+            return Some(Err(format!(
+                "Burned in annotation and no pixel mask region for {} {}x{}",
+                modality, rows, columns
+            )));
+        }
+        let native = TransferSyntaxRegistry
+            .get(dcm_obj.meta().transfer_syntax())
+            .map(|ts| ts.endianness() == Endianness::Little && matches!(ts.codec(), Codec::None))
+            .unwrap_or(false);
+        if !native {
+            return Some(Err(
+                "Burned in annotation in compressed pixel data, not masked".to_string(),
+            ));
+        }
+        if !matches!(value_of(tags::BITS_ALLOCATED).as_str(), "8" | "16") {
+            return Some(Err(format!(
+                "Burned in annotation in {} bit pixel data, not masked",
+                value_of(tags::BITS_ALLOCATED)
+            )));
+        }
+        Some(Ok(boxes))
+    }
+
+    // The source goes to review when its burned in annotation can't be masked
+    pub fn review_reason(
+        &self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+    ) -> Option<(ReviewAction, String)> {
+        self.boxes_of(dcm_obj)
+            .and_then(|boxes| boxes.err())
+            .map(|reason| (ReviewAction::Review, reason))
+    }
+
+    // Black out the boxes in every frame of the output, the source decides if the file has a
+    // burned in annotation as the profile can change the tags. BurnedInAnnotation becomes NO and
+    // the output is marked derived. Returns true if the pixel data was changed
+    pub fn apply(
+        &self,
+        source: &FileDicomObject<InMemDicomObject>,
+        dcm_obj: &mut FileDicomObject<InMemDicomObject>,
+    ) -> Result<bool> {
+        let boxes = match self.boxes_of(source) {
+            Some(Ok(boxes)) => boxes,
+            _ => return Ok(false),
+        };
+        let pixel_element = dcm_obj.element(tags::PIXEL_DATA)?;
+        let pixel_vr = pixel_element.vr();
+        let mut pixel_bytes = match pixel_element.value().primitive() {
+            Some(value) => value.to_bytes().to_vec(),
+            None => return Ok(false),
+        };
+        let int_of = |tag: Tag, default: u32| -> u32 {
+            dcm_obj
+                .element(tag)
+                .ok()
+                .and_then(|element| element.to_int::<u32>().ok())
+                .unwrap_or(default)
+        };
+        let rows = int_of(tags::ROWS, 0) as usize;
+        let columns = int_of(tags::COLUMNS, 0) as usize;
+        let samples = int_of(tags::SAMPLES_PER_PIXEL, 1) as usize;
+        let sample_bytes = int_of(tags::BITS_ALLOCATED, 16) as usize / 8;
+        let bits_stored = int_of(tags::BITS_STORED, sample_bytes as u32 * 8).min(16);
+        let planar = int_of(tags::PLANAR_CONFIGURATION, 0) == 1;
+        let frames = int_of(tags::NUMBER_OF_FRAMES, 1).max(1) as usize;
+        let frame_length = rows * columns * samples * sample_bytes;
+        if frame_length == 0 || pixel_bytes.len() < frame_length * frames {
+            return Err(anyhow::anyhow!(
+                "Pixel data is shorter than {} frames of {}x{}",
+                frames,
+                rows,
+                columns
+            ));
+        }
+        // Black of the photometric interpretation for each sample
+        let photometric = dcm_obj
+            .element(tags::PHOTOMETRIC_INTERPRETATION)
+            .ok()
+            .and_then(|element| element.to_str().ok())
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default();
+        let black: Vec<u16> = (0..samples)
+            .map(|sample| match photometric.as_str() {
+                "MONOCHROME1" => ((1u32 << bits_stored) - 1) as u16,
+                ybr if ybr.starts_with("YBR") && sample > 0 => 1u16 << (bits_stored - 1),
+                _ => 0,
+            })
+            .collect();
+
+        for frame in pixel_bytes[..frame_length * frames].chunks_exact_mut(frame_length) {
+            for [x, y, width, height] in &boxes {
+                let (x, y) = (*x as usize, *y as usize);
+                let x_end = (x + *width as usize).min(columns);
+                let y_end = (y + *height as usize).min(rows);
+                for row in y.min(rows)..y_end {
+                    for column in x.min(columns)..x_end {
+                        for (sample, black) in black.iter().enumerate() {
+                            let index = match planar {
+                                true => sample * rows * columns + row * columns + column,
+                                false => (row * columns + column) * samples + sample,
+                            } * sample_bytes;
+                            frame[index..index + sample_bytes]
+                                .copy_from_slice(&black.to_le_bytes()[..sample_bytes]);
+                        }
+                    }
+                }
+            }
+        }
+
+        dcm_obj.put(DataElement::new(
+            tags::PIXEL_DATA,
+            pixel_vr,
+            PrimitiveValue::U8(pixel_bytes.into()),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::BURNED_IN_ANNOTATION,
+            VR::CS,
+            PrimitiveValue::from("NO"),
+        ));
+        mark_derived(
+            dcm_obj,
+            &format!(
+                "{} burned in annotation regions masked by dcmrig",
+                boxes.len()
+            ),
+        );
+        Ok(true)
+    }
+}
@@ -0,0 +1,660 @@
+use crate::anon::dicom_anon;
+use crate::args::{AnonCommand, ReceiveCommand, ReceivePipeline};
+use crate::deid::dicom_deid;
+use crate::sort::dicom_sort;
+use anyhow::{anyhow, Result};
+use dcmrig_rs::dimse::{
+    command_pdu, padded_uid, read_command, trim_uid, IpRange, C_ECHO_RQ, C_STORE_RQ, DIMSE_TIMEOUT,
+    NO_DATA_SET,
+};
+use dcmrig_rs::service::{handle_stop_signals, notify, stop_requested, watchdog_interval};
+use dcmrig_rs::{csv_fields, simplified_path, RunOptions, RESULTS_FILE};
+use dicom::{
+    core::{chrono::Local, dicom_value, DataElement, VR},
+    dictionary_std::tags,
+    object::{FileMetaTableBuilder, InMemDicomObject},
+    ul::{
+        association::server::AccessControl,
+        pdu::{
+            reader::DEFAULT_MAX_PDU, AssociationRJ, AssociationRJResult,
+            AssociationRJServiceProviderPresentationReason, AssociationRJServiceUserReason,
+            AssociationRJSource, PDataValueType, PresentationContextResultReason, UserIdentity,
+        },
+        read_pdu, write_pdu, Pdu, ServerAssociation, ServerAssociationOptions,
+    },
+};
+use std::{
+    collections::HashMap,
+    fs::{self, create_dir_all, File},
+    io::{BufWriter, ErrorKind, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+// Staging directory of the batches with files the pipeline could not process
+const FAILED_DIR: &str = "FAILED";
+
+// Response command fields and the storage status of an instance that could not be written
+const C_STORE_RSP: u16 = 0x8001;
+const C_ECHO_RSP: u16 = 0x8030;
+const OUT_OF_RESOURCES: u16 = 0xA700;
+
+// The listener is polled so a stop is seen between the connections
+const ACCEPT_POLL: Duration = Duration::from_millis(200);
+// Wait for the association request of a caller over the limit, before it is rejected
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Callers the storage SCP accepts and the limits of their associations, any caller without
+// AE titles or IP ranges
+#[derive(Debug, Clone, Default)]
+struct CallerPolicy {
+    ae_titles: Vec<String>,
+    ip_ranges: Vec<IpRange>,
+    max_duration: Option<Duration>,
+    max_instances: Option<u64>,
+    // Associations received at once, by all the callers
+    max_associations: usize,
+}
+
+impl CallerPolicy {
+    fn allows_address(&self, address: IpAddr) -> bool {
+        self.ip_ranges.is_empty() || self.ip_ranges.iter().any(|range| range.contains(address))
+    }
+}
+
+// The association of an AE title not in the allowlist is rejected as not recognized
+impl AccessControl for CallerPolicy {
+    fn check_access(
+        &self,
+        _this_ae_title: &str,
+        calling_ae_title: &str,
+        _called_ae_title: &str,
+        _user_identity: Option<&UserIdentity>,
+    ) -> std::result::Result<(), AssociationRJServiceUserReason> {
+        match self.ae_titles.is_empty()
+            || self
+                .ae_titles
+                .iter()
+                .any(|ae_title| ae_title.trim() == calling_ae_title.trim())
+        {
+            true => Ok(()),
+            false => Err(AssociationRJServiceUserReason::CallingAETitleNotRecognized),
+        }
+    }
+}
+
+// Instance being received, written to <SOPInstanceUID>.part until its data set is complete
+struct IncomingInstance {
+    request: InMemDicomObject,
+    context_id: u8,
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+/// Storage SCP listening on the port until the process is stopped
+/// The instances of each association are staged as one batch under the source of the pipeline,
+/// then the batches are run through sort, anon or deid into its destination one after the other.
+/// Batches left in the staging by a previous receive are run first
+/// On SIGTERM or SIGINT no new association is accepted, the associations in progress and the
+/// batch being processed are completed and the queued batches are kept for the next receive
+pub fn dicom_receive(receive_command: ReceiveCommand, run_options: RunOptions) -> Result<()> {
+    let ReceiveCommand {
+        port,
+        ae_title,
+        allow_aet,
+        allow_ip,
+        max_association_time,
+        max_instances,
+        max_associations,
+        pipeline,
+    } = receive_command;
+    let policy = CallerPolicy {
+        ae_titles: allow_aet,
+        ip_ranges: allow_ip,
+        max_duration: max_association_time.map(Duration::from_secs),
+        max_instances,
+        max_associations: max_associations as usize,
+    };
+    if policy.ae_titles.is_empty() && policy.ip_ranges.is_empty() {
+        warn!("Any caller is accepted, see --allow-aet and --allow-ip");
+    }
+    let staging = simplified_path(match &pipeline {
+        ReceivePipeline::Sort(sort_command) => sort_command.source.clone(),
+        ReceivePipeline::Anon(anon_command) => anon_command.source.clone(),
+        ReceivePipeline::Deid(deid_command) => deid_command.source.clone(),
+    });
+    health_check(&pipeline, &staging)?;
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .map_err(|e| anyhow!("Can't listen on port {}: {}", port, e))?;
+    listener.set_nonblocking(true)?;
+    handle_stop_signals();
+    let (batches, queue) = mpsc::channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    for batch in staged_batches(&staging)? {
+        info!("Batch left by a previous receive: {}", batch.display());
+        queued.fetch_add(1, Ordering::SeqCst);
+        batches.send(batch)?;
+    }
+    info!(
+        "Storage SCP {} listening on port {}, staging in {}",
+        ae_title,
+        port,
+        staging.display()
+    );
+    notify(&format!(
+        "READY=1\nSTATUS=Listening on port {}, {} batches queued",
+        port,
+        queued.load(Ordering::SeqCst)
+    ));
+    let accept_queued = queued.clone();
+    thread::spawn(move || {
+        accept_associations(listener, ae_title, policy, staging, batches, accept_queued)
+    });
+    let mut processed = 0;
+    for batch in queue {
+        let pending = queued.fetch_sub(1, Ordering::SeqCst) - 1;
+        if stop_requested() {
+            info!("Batch {} is kept for the next receive", batch.display());
+            continue;
+        }
+        notify(&format!(
+            "STATUS=Processing a batch, {} batches queued, {} processed",
+            pending, processed
+        ));
+        run_batch(&pipeline, &batch, &run_options);
+        processed += 1;
+        notify(&format!(
+            "STATUS=Listening on port {}, {} batches queued, {} processed",
+            port,
+            queued.load(Ordering::SeqCst),
+            processed
+        ));
+    }
+    info!("Receive stopped, {} batches processed", processed);
+    Ok(())
+}
+
+// Checks of the start, so a service that can't process its batches fails to start instead of
+// staging instances it will never write
+fn health_check(pipeline: &ReceivePipeline, staging: &Path) -> Result<()> {
+    let destination = simplified_path(match pipeline {
+        ReceivePipeline::Sort(sort_command) => sort_command.destination.clone(),
+        ReceivePipeline::Anon(anon_command) => anon_command.destination.clone(),
+        ReceivePipeline::Deid(deid_command) => deid_command.destination.clone(),
+    });
+    for dir in [staging, destination.as_path()] {
+        let probe = dir.join(format!(".dcmrig_probe_{}", std::process::id()));
+        create_dir_all(dir)
+            .and_then(|_| fs::write(&probe, b""))
+            .and_then(|_| fs::remove_file(&probe))
+            .map_err(|e| anyhow!("Can't write to {}: {}", dir.display(), e))?;
+    }
+    if let ReceivePipeline::Deid(deid_command) = pipeline {
+        fs::File::open(&deid_command.mapping_table).map_err(|e| {
+            anyhow!(
+                "Can't read the mapping table {}: {}",
+                deid_command.mapping_table.display(),
+                e
+            )
+        })?;
+    }
+    Ok(())
+}
+
+// Batch directories of the staging, oldest first
+fn staged_batches(staging: &Path) -> Result<Vec<PathBuf>> {
+    let mut batches = vec![];
+    for entry in fs::read_dir(staging)? {
+        let path = entry?.path();
+        if path.is_dir() && path.file_name().is_some_and(|name| name != FAILED_DIR) {
+            batches.push(path);
+        }
+    }
+    batches.sort();
+    Ok(batches)
+}
+
+// One thread per association, its batch is queued once the association ends. The queue is
+// closed once a stop is requested and the associations in progress ended
+// The connections from an address out of the allowed ranges are closed at once, the callers
+// over the associations in progress are rejected
+fn accept_associations(
+    listener: TcpListener,
+    ae_title: String,
+    policy: CallerPolicy,
+    staging: PathBuf,
+    batches: Sender<PathBuf>,
+    queued: Arc<AtomicUsize>,
+) {
+    let watchdog = watchdog_interval();
+    let mut last_watchdog = Instant::now();
+    let mut count = 0;
+    let active = Arc::new(AtomicUsize::new(0));
+    while !stop_requested() {
+        if watchdog.is_some_and(|interval| last_watchdog.elapsed() >= interval) {
+            notify("WATCHDOG=1");
+            last_watchdog = Instant::now();
+        }
+        let mut stream = match listener.accept() {
+            Ok((stream, peer)) if !policy.allows_address(peer.ip()) => {
+                warn!("Connection from {} refused, not an allowed address", peer);
+                drop(stream);
+                continue;
+            }
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => {
+                warn!("Can't accept a connection: {}", e);
+                continue;
+            }
+        };
+        // The connection may inherit the non-blocking mode of the listener
+        if let Err(e) = stream.set_nonblocking(false) {
+            warn!("Can't accept a connection: {}", e);
+            continue;
+        }
+        if active.load(Ordering::SeqCst) >= policy.max_associations {
+            let peer = stream
+                .peer_addr()
+                .map_or("unknown".to_string(), |peer| peer.to_string());
+            warn!(
+                "Association from {} rejected, {} associations in progress",
+                peer, policy.max_associations
+            );
+            if let Err(e) = reject_busy(&mut stream) {
+                debug!("Can't reject the association from {}: {}", peer, e);
+            }
+            continue;
+        }
+        // Named so the batches sort in the order they arrived, across restarts too
+        let batch = staging.join(format!(
+            "{}_{:06}",
+            Local::now().format("%Y%m%dT%H%M%S"),
+            count
+        ));
+        count += 1;
+        let ae_title = ae_title.clone();
+        let policy = policy.clone();
+        let batches = batches.clone();
+        let queued = queued.clone();
+        let active = active.clone();
+        active.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map_or("unknown".to_string(), |peer| peer.to_string());
+            let mut received = 0;
+            match receive_association(stream, &ae_title, &policy, &batch, &mut received) {
+                Ok(calling_aet) => info!(
+                    "{} instances received from {} ({})",
+                    received, calling_aet, peer
+                ),
+                Err(e) => error!(
+                    "Association with {} ended after {} instances: {}",
+                    peer, received, e
+                ),
+            }
+            if received > 0 {
+                queued.fetch_add(1, Ordering::SeqCst);
+                batches.send(batch).expect("Failed to queue the batch");
+            }
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    info!("Stopping, no new association is accepted");
+    notify("STOPPING=1");
+}
+
+// Reject the association request of a caller over the associations in progress as a
+// transient local limit, so it tries again later
+fn reject_busy(stream: &mut TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    read_pdu(stream, DEFAULT_MAX_PDU, false)?;
+    write_pdu(
+        stream,
+        &Pdu::AssociationRJ(AssociationRJ {
+            result: AssociationRJResult::Transient,
+            source: AssociationRJSource::ServiceProviderPresentation(
+                AssociationRJServiceProviderPresentationReason::LocalLimitExceeded,
+            ),
+        }),
+    )?;
+    Ok(())
+}
+
+// Store the instances of the association in the batch until it is released, returns the
+// calling AE title. Echo and store are the only services. An association over the limits of
+// the policy is aborted, the instances it stored are kept
+fn receive_association(
+    stream: TcpStream,
+    ae_title: &str,
+    policy: &CallerPolicy,
+    batch: &Path,
+    received: &mut u64,
+) -> Result<String> {
+    let started = Instant::now();
+    let read_timeout = policy.max_duration.map_or(DIMSE_TIMEOUT, |max_duration| {
+        max_duration.min(DIMSE_TIMEOUT)
+    });
+    stream.set_read_timeout(Some(read_timeout))?;
+    stream.set_write_timeout(Some(DIMSE_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    // Any storage SOP class, in the first transfer syntax of the caller this build can read
+    let mut association = ServerAssociationOptions::new()
+        .ae_access_control(policy.clone())
+        .ae_title(ae_title)
+        .promiscuous(true)
+        .establish(stream)?;
+    let calling_aet = association.client_ae_title().to_string();
+    debug!("Association from {}", calling_aet);
+    let transfer_syntaxes: HashMap<u8, String> = association
+        .presentation_contexts()
+        .iter()
+        .filter(|context| context.reason == PresentationContextResultReason::Acceptance)
+        .map(|context| (context.id, trim_uid(&context.transfer_syntax).to_string()))
+        .collect();
+    let mut incoming = None;
+    let result = receive_messages(
+        &mut association,
+        &transfer_syntaxes,
+        policy,
+        started,
+        batch,
+        &mut incoming,
+        received,
+    );
+    // The data set of an aborted association is incomplete
+    if let Some(IncomingInstance { path, file, .. }) = incoming {
+        drop(file);
+        let _ = fs::remove_file(path);
+    }
+    if result.is_err() {
+        let _ = association.abort();
+    }
+    result.map(|_| calling_aet)
+}
+
+fn receive_messages(
+    association: &mut ServerAssociation,
+    transfer_syntaxes: &HashMap<u8, String>,
+    policy: &CallerPolicy,
+    started: Instant,
+    batch: &Path,
+    incoming: &mut Option<IncomingInstance>,
+    received: &mut u64,
+) -> Result<()> {
+    let mut command_data = vec![];
+    loop {
+        let pdu = association.receive()?;
+        if let Some(max_duration) = policy.max_duration {
+            if started.elapsed() > max_duration {
+                return Err(anyhow!(
+                    "Longer than the {} seconds allowed",
+                    max_duration.as_secs()
+                ));
+            }
+        }
+        match pdu {
+            Pdu::PData { data } => {
+                for value in data {
+                    match value.value_type {
+                        PDataValueType::Command => {
+                            command_data.extend(value.data);
+                            if !value.is_last {
+                                continue;
+                            }
+                            let request = read_command(&command_data)?;
+                            command_data.clear();
+                            let context_id = value.presentation_context_id;
+                            match request.element(tags::COMMAND_FIELD)?.to_int::<u16>()? {
+                                C_ECHO_RQ => {
+                                    association
+                                        .send(&response(context_id, &request, C_ECHO_RSP, 0)?)?;
+                                }
+                                C_STORE_RQ => {
+                                    if policy
+                                        .max_instances
+                                        .is_some_and(|max_instances| *received >= max_instances)
+                                    {
+                                        return Err(anyhow!(
+                                            "More than the {} instances allowed",
+                                            received
+                                        ));
+                                    }
+                                    let ts =
+                                        transfer_syntaxes.get(&context_id).ok_or_else(|| {
+                                            anyhow!("No presentation context {}", context_id)
+                                        })?;
+                                    *incoming =
+                                        Some(start_instance(request, context_id, ts, batch)?);
+                                }
+                                field => return Err(anyhow!("Unsupported command {:04X}", field)),
+                            }
+                        }
+                        PDataValueType::Data => {
+                            let instance = incoming
+                                .as_mut()
+                                .ok_or_else(|| anyhow!("Data set without a C-STORE request"))?;
+                            instance.file.write_all(&value.data)?;
+                            if !value.is_last {
+                                continue;
+                            }
+                            let IncomingInstance {
+                                request,
+                                context_id,
+                                path,
+                                file,
+                            } = incoming.take().expect("Instance being received");
+                            let status = match finish_instance(file, &path) {
+                                Ok(()) => {
+                                    *received += 1;
+                                    0
+                                }
+                                Err(e) => {
+                                    error!("Can't store {}: {}", path.display(), e);
+                                    let _ = fs::remove_file(&path);
+                                    OUT_OF_RESOURCES
+                                }
+                            };
+                            association.send(&response(
+                                context_id,
+                                &request,
+                                C_STORE_RSP,
+                                status,
+                            )?)?;
+                        }
+                    }
+                }
+            }
+            Pdu::ReleaseRQ => {
+                association.send(&Pdu::ReleaseRP)?;
+                return Ok(());
+            }
+            Pdu::AbortRQ { source } => return Err(anyhow!("Aborted by the caller: {:?}", source)),
+            pdu => return Err(anyhow!("Unexpected PDU: {:?}", pdu)),
+        }
+    }
+}
+
+// The file meta group is made from the request and the transfer syntax of the context, the
+// data set is appended as it arrives
+fn start_instance(
+    request: InMemDicomObject,
+    context_id: u8,
+    ts: &str,
+    batch: &Path,
+) -> Result<IncomingInstance> {
+    let uid =
+        |tag| -> Result<String> { Ok(trim_uid(&request.element(tag)?.to_str()?).to_string()) };
+    let sop_class = uid(tags::AFFECTED_SOP_CLASS_UID)?;
+    let sop_instance = uid(tags::AFFECTED_SOP_INSTANCE_UID)?;
+    // The UID names the file, anything else than a UID could escape the batch
+    if sop_instance.is_empty() || !sop_instance.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return Err(anyhow!("Invalid SOP Instance UID: {}", sop_instance));
+    }
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(padded_uid(&sop_class))
+        .media_storage_sop_instance_uid(padded_uid(&sop_instance))
+        .transfer_syntax(padded_uid(ts))
+        .build()?;
+    create_dir_all(batch)?;
+    let path = batch.join(format!("{}.part", sop_instance));
+    let mut file = BufWriter::new(File::create(&path)?);
+    file.write_all(&[0; 128])?;
+    file.write_all(b"DICM")?;
+    meta.write(&mut file)?;
+    Ok(IncomingInstance {
+        request,
+        context_id,
+        path,
+        file,
+    })
+}
+
+// Closed before the rename, the batch only gets complete instances
+fn finish_instance(file: BufWriter<File>, path: &Path) -> Result<()> {
+    file.into_inner()?.sync_all()?;
+    fs::rename(path, path.with_extension("dcm"))?;
+    Ok(())
+}
+
+fn response(
+    context_id: u8,
+    request: &InMemDicomObject,
+    command_field: u16,
+    status: u16,
+) -> Result<Pdu> {
+    let mut elements = vec![
+        DataElement::new(
+            tags::AFFECTED_SOP_CLASS_UID,
+            VR::UI,
+            dicom_value!(
+                Str,
+                request.element(tags::AFFECTED_SOP_CLASS_UID)?.to_str()?
+            ),
+        ),
+        DataElement::new(
+            tags::COMMAND_FIELD,
+            VR::US,
+            dicom_value!(U16, [command_field]),
+        ),
+        DataElement::new(
+            tags::MESSAGE_ID_BEING_RESPONDED_TO,
+            VR::US,
+            dicom_value!(U16, [request.element(tags::MESSAGE_ID)?.to_int::<u16>()?]),
+        ),
+        DataElement::new(
+            tags::COMMAND_DATA_SET_TYPE,
+            VR::US,
+            dicom_value!(U16, [NO_DATA_SET]),
+        ),
+        DataElement::new(tags::STATUS, VR::US, dicom_value!(U16, [status])),
+    ];
+    if let Ok(sop_instance) = request.element(tags::AFFECTED_SOP_INSTANCE_UID) {
+        elements.push(DataElement::new(
+            tags::AFFECTED_SOP_INSTANCE_UID,
+            VR::UI,
+            dicom_value!(Str, sop_instance.to_str()?),
+        ));
+    }
+    command_pdu(
+        context_id,
+        &InMemDicomObject::command_from_element_iter(elements),
+    )
+}
+
+// Run the batch through the pipeline and remove it, the files that failed are moved to
+// FAILED/<batch> in the staging. A batch that could not run is kept for the next receive
+fn run_batch(pipeline: &ReceivePipeline, batch: &Path, run_options: &RunOptions) {
+    info!("Processing the batch {}", batch.display());
+    let (destination, result) = match pipeline {
+        ReceivePipeline::Sort(sort_command) => {
+            let destination = simplified_path(sort_command.destination.clone());
+            let result = dicom_sort(
+                batch.to_path_buf(),
+                destination.clone(),
+                sort_command.sort_order.clone(),
+                run_options.clone(),
+            );
+            (destination, result)
+        }
+        ReceivePipeline::Anon(anon_command) => {
+            let destination = simplified_path(anon_command.destination.clone());
+            let result = dicom_anon(
+                AnonCommand {
+                    source: batch.to_path_buf(),
+                    destination: destination.clone(),
+                    ..anon_command.clone()
+                },
+                run_options.clone(),
+            );
+            (destination, result)
+        }
+        ReceivePipeline::Deid(deid_command) => {
+            let destination = simplified_path(deid_command.destination.clone());
+            let result = dicom_deid(
+                batch.to_path_buf(),
+                destination.clone(),
+                deid_command.mapping_table.clone(),
+                run_options.clone(),
+            );
+            (destination, result)
+        }
+    };
+    if let Err(e) = result {
+        error!("Batch {} is kept in the staging: {}", batch.display(), e);
+        return;
+    }
+    if run_options.dry_run {
+        info!("Dry run, the batch {} is kept", batch.display());
+        return;
+    }
+    if let Err(e) = clear_batch(batch, &destination) {
+        error!("Can't clear the batch {}: {}", batch.display(), e);
+    }
+}
+
+// results.csv of the destination holds the rows of the batch that just ran
+fn clear_batch(batch: &Path, destination: &Path) -> Result<()> {
+    let content = fs::read_to_string(destination.join(RESULTS_FILE))?;
+    let failed: Vec<PathBuf> = content
+        .lines()
+        .skip(1)
+        .map(csv_fields)
+        .filter(|fields| fields.len() > 1 && fields[1] == "failed")
+        .map(|fields| PathBuf::from(&fields[0]))
+        .filter(|source| source.starts_with(batch))
+        .collect();
+    if !failed.is_empty() {
+        let failed_dir = batch
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(FAILED_DIR)
+            .join(batch.file_name().unwrap_or_default());
+        create_dir_all(&failed_dir)?;
+        for source in &failed {
+            fs::rename(
+                source,
+                failed_dir.join(source.file_name().unwrap_or_default()),
+            )?;
+        }
+        warn!(
+            "{} files of the batch failed, they are kept in {}",
+            failed.len(),
+            failed_dir.display()
+        );
+    }
+    fs::remove_dir_all(batch)?;
+    Ok(())
+}
@@ -0,0 +1,379 @@
+use anyhow::Result;
+use dcmrig_rs::{
+    csv_field, json_string, phi,
+    source::{DirectorySource, InstanceSource},
+    ByteSize, InventoryLevel, ReportFormat, RunOptions,
+};
+use dicom::{
+    dictionary_std::tags,
+    encoding::TransferSyntaxIndex,
+    object::{FileDicomObject, InMemDicomObject, Tag},
+    transfer_syntax::TransferSyntaxRegistry,
+};
+use rayon::prelude::*;
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashMap},
+    fs::{self, canonicalize},
+    path::{Path, PathBuf},
+    process::exit,
+};
+use tracing::{error, info, warn};
+
+// Columns of the series and the study inventory
+const SERIES_COLUMNS: [&str; 10] = [
+    "PatientID",
+    "StudyInstanceUID",
+    "StudyDate",
+    "Modality",
+    "SeriesInstanceUID",
+    "SeriesNumber",
+    "SeriesDescription",
+    "Instances",
+    "Bytes",
+    "TransferSyntaxUIDs",
+];
+const STUDY_COLUMNS: [&str; 9] = [
+    "PatientID",
+    "StudyInstanceUID",
+    "StudyDate",
+    "StudyDescription",
+    "Modalities",
+    "Series",
+    "Instances",
+    "Bytes",
+    "TransferSyntaxUIDs",
+];
+
+// Columns of the JSON written as numbers
+const NUMBER_COLUMNS: [&str; 3] = ["Series", "Instances", "Bytes"];
+
+// Longer values are cut in the table, the CSV and JSON have them whole. UIDs are never longer
+const TABLE_WIDTH_MAX: usize = 64;
+
+// Media Storage Directory Storage, the DICOMDIR indexes the instances and is not one
+const DICOMDIR_SOP_CLASS: &str = "1.2.840.10008.1.3.10";
+
+// StudyInstanceUID and SeriesInstanceUID > row
+type InventoryRows = HashMap<(String, String), InventoryRow>;
+
+// Instances of a series, or of a study at the study level
+#[derive(Default)]
+struct InventoryRow {
+    patient_id: String,
+    study_uid: String,
+    study_date: String,
+    study_description: String,
+    modalities: BTreeSet<String>,
+    series_uids: BTreeSet<String>,
+    series_number: String,
+    series_description: String,
+    instances: u64,
+    bytes: u64,
+    transfer_syntaxes: BTreeSet<String>,
+}
+
+impl InventoryRow {
+    fn merge(&mut self, other: InventoryRow) {
+        self.modalities.extend(other.modalities);
+        self.series_uids.extend(other.series_uids);
+        self.transfer_syntaxes.extend(other.transfer_syntaxes);
+        self.instances += other.instances;
+        self.bytes += other.bytes;
+    }
+
+    // Values in the order of the columns of the level
+    fn values(&self, level: InventoryLevel) -> Vec<String> {
+        let joined = |values: &BTreeSet<String>| {
+            values
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join("\\")
+        };
+        match level {
+            InventoryLevel::Series => vec![
+                self.patient_id.clone(),
+                self.study_uid.clone(),
+                self.study_date.clone(),
+                joined(&self.modalities),
+                joined(&self.series_uids),
+                self.series_number.clone(),
+                self.series_description.clone(),
+                self.instances.to_string(),
+                self.bytes.to_string(),
+                joined(&self.transfer_syntaxes),
+            ],
+            InventoryLevel::Study => vec![
+                self.patient_id.clone(),
+                self.study_uid.clone(),
+                self.study_date.clone(),
+                self.study_description.clone(),
+                joined(&self.modalities),
+                self.series_uids.len().to_string(),
+                self.instances.to_string(),
+                self.bytes.to_string(),
+                joined(&self.transfer_syntaxes),
+            ],
+        }
+    }
+}
+
+/// Inventory of the DICOM files of the source, one row per series or per study with the
+/// patient, the study, the modalities, the number of instances, their size and their transfer
+/// syntaxes, read from the headers only. Written as a table, CSV or JSON to the output file, or
+/// printed
+pub fn dicom_report(
+    source_path: PathBuf,
+    output: Option<PathBuf>,
+    format: ReportFormat,
+    level: InventoryLevel,
+    run_options: RunOptions,
+) -> Result<()> {
+    if let Err(e) = canonicalize(&source_path) {
+        error!(
+            "Given source Path doesnot exist: {}\n{}",
+            source_path.display(),
+            e
+        );
+        exit(1)
+    }
+    let source = DirectorySource::new(&source_path, run_options.read_iso);
+    info!("Indexing files from: {}", source.describe());
+    let all_files = source.items()?;
+    let (rows, non_dicom) = all_files
+        .par_iter()
+        .fold(
+            || (HashMap::new(), 0),
+            |(mut rows, mut non_dicom): (InventoryRows, u64), item| {
+                match source.open_dataset(item, true) {
+                    Ok(dcm_obj)
+                        if dcm_obj
+                            .meta()
+                            .media_storage_sop_class_uid()
+                            .trim_end_matches(['\0', ' '])
+                            == DICOMDIR_SOP_CLASS => {}
+                    Ok(dcm_obj) => {
+                        let row = inventory_row(&dcm_obj, item, level);
+                        add_row(&mut rows, row_key(&row, level), row);
+                    }
+                    Err(_) => non_dicom += 1,
+                }
+                (rows, non_dicom)
+            },
+        )
+        .reduce(
+            || (HashMap::new(), 0),
+            |(mut rows, non_dicom), (other_rows, other_non_dicom)| {
+                for (key, row) in other_rows {
+                    add_row(&mut rows, key, row);
+                }
+                (rows, non_dicom + other_non_dicom)
+            },
+        );
+    source.finalize()?;
+
+    let mut rows: Vec<InventoryRow> = rows.into_values().collect();
+    rows.sort_by(|a, b| {
+        (&a.patient_id, &a.study_date, &a.study_uid)
+            .cmp(&(&b.patient_id, &b.study_date, &b.study_uid))
+            .then_with(|| series_order(a).cmp(&series_order(b)))
+    });
+    let content = match format {
+        ReportFormat::Table => inventory_table(&rows, level),
+        ReportFormat::Csv => inventory_csv(&rows, level),
+        ReportFormat::Json => inventory_json(&rows, level),
+    };
+    match &output {
+        Some(output) => {
+            fs::write(output, content)?;
+            info!("Inventory written to {}", output.display());
+        }
+        None => print!("{}", content),
+    }
+
+    let instances: u64 = rows.iter().map(|row| row.instances).sum();
+    let bytes: u64 = rows.iter().map(|row| row.bytes).sum();
+    let patients: BTreeSet<&String> = rows.iter().map(|row| &row.patient_id).collect();
+    let studies: BTreeSet<&String> = rows.iter().map(|row| &row.study_uid).collect();
+    info!(
+        "{} patients, {} studies, {} instances ({}), {} files not DICOM",
+        patients.len(),
+        studies.len(),
+        instances,
+        ByteSize(bytes),
+        non_dicom
+    );
+    if non_dicom > 0 {
+        warn!("The files that are not DICOM are not in the inventory");
+    }
+    Ok(())
+}
+
+// The first instance of a series or study gives its values, the others are counted in
+fn add_row(rows: &mut InventoryRows, key: (String, String), row: InventoryRow) {
+    match rows.entry(key) {
+        Entry::Occupied(mut existing) => existing.get_mut().merge(row),
+        Entry::Vacant(vacant) => {
+            vacant.insert(row);
+        }
+    }
+}
+
+// One instance as a row of the level
+fn inventory_row(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    item: &Path,
+    level: InventoryLevel,
+) -> InventoryRow {
+    let value = |tag: Tag| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_str().ok())
+            .map(|value| value.trim_end_matches('\0').trim().to_string())
+            .unwrap_or_default()
+    };
+    let mut row = InventoryRow {
+        patient_id: value(tags::PATIENT_ID),
+        study_uid: value(tags::STUDY_INSTANCE_UID),
+        study_date: value(tags::STUDY_DATE),
+        study_description: value(tags::STUDY_DESCRIPTION),
+        instances: 1,
+        bytes: fs::metadata(item).map_or(0, |metadata| metadata.len()),
+        ..Default::default()
+    };
+    row.modalities.insert(value(tags::MODALITY));
+    row.series_uids.insert(value(tags::SERIES_INSTANCE_UID));
+    row.transfer_syntaxes.insert(
+        dcm_obj
+            .meta()
+            .transfer_syntax()
+            .trim_end_matches(['\0', ' '])
+            .to_string(),
+    );
+    if level == InventoryLevel::Series {
+        row.series_number = value(tags::SERIES_NUMBER);
+        row.series_description = value(tags::SERIES_DESCRIPTION);
+    }
+    row
+}
+
+// StudyInstanceUID and SeriesInstanceUID, only the study at the study level
+fn row_key(row: &InventoryRow, level: InventoryLevel) -> (String, String) {
+    let series_uid = match level {
+        InventoryLevel::Series => row.series_uids.iter().next().cloned().unwrap_or_default(),
+        InventoryLevel::Study => String::new(),
+    };
+    (row.study_uid.clone(), series_uid)
+}
+
+// Series in SeriesNumber order, the ones without a number last
+fn series_order(row: &InventoryRow) -> (i64, &BTreeSet<String>) {
+    (
+        row.series_number.parse().unwrap_or(i64::MAX),
+        &row.series_uids,
+    )
+}
+
+fn columns(level: InventoryLevel) -> &'static [&'static str] {
+    match level {
+        InventoryLevel::Series => &SERIES_COLUMNS,
+        InventoryLevel::Study => &STUDY_COLUMNS,
+    }
+}
+
+// Aligned columns, with the names of the transfer syntaxes and the sizes with a suffix. The
+// PatientIDs are hashed as in the logs unless --log-phi
+fn inventory_table(rows: &[InventoryRow], level: InventoryLevel) -> String {
+    let columns = columns(level);
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            row.values(level)
+                .into_iter()
+                .zip(columns)
+                .map(|(value, column)| match *column {
+                    "PatientID" if !value.is_empty() => phi(&value),
+                    "Bytes" => ByteSize(value.parse().unwrap_or_default()).to_string(),
+                    "TransferSyntaxUIDs" => value
+                        .split('\\')
+                        .map(|uid| TransferSyntaxRegistry.get(uid).map_or(uid, |ts| ts.name()))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    _ => value,
+                })
+                .map(|value| value.chars().take(TABLE_WIDTH_MAX).collect())
+                .collect()
+        })
+        .collect();
+    let headers: Vec<String> = columns
+        .iter()
+        .map(|column| match *column {
+            "Bytes" => "Size".to_string(),
+            "TransferSyntaxUIDs" => "TransferSyntaxes".to_string(),
+            _ => column.to_string(),
+        })
+        .collect();
+    let widths: Vec<usize> = headers
+        .iter()
+        .enumerate()
+        .map(|(index, header)| {
+            cells
+                .iter()
+                .map(|row| row[index].chars().count())
+                .chain([header.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let line = |values: &[String]| {
+        let padded: Vec<String> = values
+            .iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{:<width$}", value, width = width))
+            .collect();
+        format!("{}\n", padded.join("  ").trim_end())
+    };
+    let mut table = line(&headers);
+    for row in &cells {
+        table.push_str(&line(row));
+    }
+    table
+}
+
+fn inventory_csv(rows: &[InventoryRow], level: InventoryLevel) -> String {
+    let mut content = format!("{}\n", columns(level).join(","));
+    for row in rows {
+        let values: Vec<String> = row
+            .values(level)
+            .iter()
+            .map(|value| csv_field(value))
+            .collect();
+        content.push_str(&format!("{}\n", values.join(",")));
+    }
+    content
+}
+
+// Array of objects keyed by the columns
+fn inventory_json(rows: &[InventoryRow], level: InventoryLevel) -> String {
+    let columns = columns(level);
+    let objects: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let fields: Vec<String> = row
+                .values(level)
+                .iter()
+                .zip(columns)
+                .map(|(value, column)| match NUMBER_COLUMNS.contains(column) {
+                    true => format!("{}:{}", json_string(column), value),
+                    false => format!("{}:{}", json_string(column), json_string(value)),
+                })
+                .collect();
+            format!("  {{{}}}", fields.join(","))
+        })
+        .collect();
+    match objects.is_empty() {
+        true => "[]\n".to_string(),
+        false => format!("[\n{}\n]\n", objects.join(",\n")),
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::Result;
+use dcmrig_rs::{
+    csv_field, csv_fields, unique_output_path, DuplicateSuffix, MANIFEST_FILE, PHI_README,
+    RESULTS_FILE, REVIEW_REQUIRED_DIR,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, canonicalize, create_dir_all},
+    path::{Path, PathBuf},
+    process::exit,
+};
+use tracing::{error, info, warn};
+use walkdir::WalkDir;
+
+const NOTE_SUFFIX: &str = ".review.txt";
+
+// Decision on a flagged file, the paths are relative to the destination
+struct Decision {
+    flagged: PathBuf,
+    // New path of an approved file, None when rejected
+    approved: Option<PathBuf>,
+}
+
+/// List the files routed to REVIEW_REQUIRED with the reason of their note
+/// Paths are relative to the destination, as approve and reject take them
+pub fn review_list(destination_path: PathBuf) -> Result<()> {
+    let flagged = flagged_files(&destination_path);
+    for file in &flagged {
+        println!(
+            "{}\t{}",
+            relative_path(file, &destination_path).display(),
+            review_reason(file)
+        );
+    }
+    info!(
+        "{} files in {} need a manual review",
+        flagged.len(),
+        destination_path.join(REVIEW_REQUIRED_DIR).display()
+    );
+    Ok(())
+}
+
+/// Move the approved files to their place in the main output
+/// results.csv and MANIFEST.sha256 of the destination are updated to the new paths
+/// A dry run only lists where the files would go
+pub fn review_approve(
+    destination_path: PathBuf,
+    files: Vec<PathBuf>,
+    all: bool,
+    duplicate_suffix: DuplicateSuffix,
+    dry_run: bool,
+) -> Result<()> {
+    let selected = select_files(&destination_path, files, all);
+    let review_root = destination_path.join(REVIEW_REQUIRED_DIR);
+    // Canonical path of the flagged file > decision
+    let mut decided: HashMap<PathBuf, Decision> = HashMap::new();
+    for file in &selected {
+        let canonical = canonicalize(file)?;
+        let within_review = file.strip_prefix(&review_root).unwrap_or(file);
+        let new_path = PathBuf::from(unique_output_path(
+            destination_path.join(within_review).display().to_string(),
+            duplicate_suffix,
+            "",
+        ));
+        if dry_run {
+            println!(
+                "approve\t{}\t{}",
+                relative_path(file, &destination_path).display(),
+                relative_path(&new_path, &destination_path).display()
+            );
+            continue;
+        }
+        if let Some(parent) = new_path.parent() {
+            create_dir_all(parent)?;
+        }
+        fs::rename(file, &new_path)?;
+        remove_note(file);
+        info!("Approved: {} > {}", file.display(), new_path.display());
+        decided.insert(
+            canonical,
+            Decision {
+                flagged: relative_path(file, &destination_path),
+                approved: Some(relative_path(&new_path, &destination_path)),
+            },
+        );
+    }
+    if dry_run {
+        info!("Dry run, {} files would be approved", selected.len());
+        return Ok(());
+    }
+    update_results(&destination_path, &decided)?;
+    update_manifest(&destination_path, &decided)?;
+    remove_empty_dirs(&review_root, &selected);
+    info!("{} files approved", selected.len());
+    Ok(())
+}
+
+/// Delete the rejected files and their notes
+/// They are marked rejected in results.csv and removed from MANIFEST.sha256
+/// A dry run only lists the files that would be deleted
+pub fn review_reject(
+    destination_path: PathBuf,
+    files: Vec<PathBuf>,
+    all: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let selected = select_files(&destination_path, files, all);
+    if dry_run {
+        for file in &selected {
+            println!(
+                "reject\t{}",
+                relative_path(file, &destination_path).display()
+            );
+        }
+        info!("Dry run, {} files would be rejected", selected.len());
+        return Ok(());
+    }
+    let mut decided: HashMap<PathBuf, Decision> = HashMap::new();
+    for file in &selected {
+        decided.insert(
+            canonicalize(file)?,
+            Decision {
+                flagged: relative_path(file, &destination_path),
+                approved: None,
+            },
+        );
+        fs::remove_file(file)?;
+        remove_note(file);
+        info!("Rejected: {}", file.display());
+    }
+    update_results(&destination_path, &decided)?;
+    update_manifest(&destination_path, &decided)?;
+    remove_empty_dirs(&destination_path.join(REVIEW_REQUIRED_DIR), &selected);
+    info!("{} files rejected", selected.len());
+    Ok(())
+}
+
+// Files under REVIEW_REQUIRED, without their notes
+fn flagged_files(destination_path: &Path) -> Vec<PathBuf> {
+    let mut flagged: Vec<PathBuf> = WalkDir::new(destination_path.join(REVIEW_REQUIRED_DIR))
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .filter(|path| !path.to_string_lossy().ends_with(NOTE_SUFFIX))
+        .filter(|path| *path != destination_path.join(REVIEW_REQUIRED_DIR).join(PHI_README))
+        .collect();
+    flagged.sort();
+    flagged
+}
+
+fn relative_path(file: &Path, destination_path: &Path) -> PathBuf {
+    file.strip_prefix(destination_path)
+        .unwrap_or(file)
+        .to_path_buf()
+}
+
+fn note_path(file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}{}", file.display(), NOTE_SUFFIX))
+}
+
+fn review_reason(file: &Path) -> String {
+    fs::read_to_string(note_path(file))
+        .map(|note| note.trim().to_string())
+        .unwrap_or_else(|_| "No review note".to_string())
+}
+
+fn remove_note(file: &Path) {
+    fs::remove_file(note_path(file))
+        .unwrap_or_else(|e| warn!("Can't remove the review note of {}: {}", file.display(), e));
+}
+
+// Flagged files to decide on, the given paths are relative to the destination as listed, or to
+// REVIEW_REQUIRED. Nothing is changed when one of them is not a flagged file
+fn select_files(destination_path: &Path, files: Vec<PathBuf>, all: bool) -> Vec<PathBuf> {
+    let flagged = flagged_files(destination_path);
+    if all {
+        return flagged;
+    }
+    if files.is_empty() {
+        error!("Give the files to decide on, or --all");
+        exit(1)
+    }
+    let mut selected = vec![];
+    for file in files {
+        let candidates = [
+            destination_path.join(&file),
+            destination_path.join(REVIEW_REQUIRED_DIR).join(&file),
+            file.clone(),
+        ];
+        match candidates.into_iter().find(|path| flagged.contains(path)) {
+            Some(path) => selected.push(path),
+            None => {
+                error!(
+                    "Not a file of {} in {}: {}",
+                    REVIEW_REQUIRED_DIR,
+                    destination_path.display(),
+                    file.display()
+                );
+                exit(1)
+            }
+        }
+    }
+    selected.sort();
+    selected.dedup();
+    selected
+}
+
+// Status and output of the decided files in results.csv
+fn update_results(destination_path: &Path, decided: &HashMap<PathBuf, Decision>) -> Result<()> {
+    let results_path = destination_path.join(RESULTS_FILE);
+    let content = match fs::read_to_string(&results_path) {
+        Ok(content) => content,
+        Err(_) => {
+            warn!("No {} to update in the destination", RESULTS_FILE);
+            return Ok(());
+        }
+    };
+    // The files are already moved, the rows are matched by the canonical path of their directory
+    let canonical_output = |output: &str| {
+        let output = Path::new(output);
+        output
+            .parent()
+            .and_then(|parent| canonicalize(parent).ok())
+            .zip(output.file_name())
+            .map(|(parent, name)| parent.join(name))
+    };
+    let mut updated = String::new();
+    let mut count = 0;
+    for (index, line) in content.lines().enumerate() {
+        let mut fields = csv_fields(line);
+        let decision = match index > 0 && fields.len() > 2 && fields[1] == "review" {
+            true => canonical_output(&fields[2]).and_then(|output| decided.get(&output)),
+            false => None,
+        };
+        match decision {
+            Some(decision) => {
+                count += 1;
+                match &decision.approved {
+                    Some(approved) => {
+                        // The output keeps the destination as it was given to the run
+                        let flagged = decision.flagged.display().to_string();
+                        fields[1] = "processed".to_string();
+                        fields[2] = match fields[2].strip_suffix(&flagged) {
+                            Some(base) => format!("{}{}", base, approved.display()),
+                            None => destination_path.join(approved).display().to_string(),
+                        };
+                    }
+                    None => {
+                        fields[1] = "rejected".to_string();
+                        fields[2] = String::new();
+                    }
+                }
+                let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+                updated.push_str(&line.join(","));
+            }
+            None => updated.push_str(line),
+        }
+        updated.push('\n');
+    }
+    fs::write(&results_path, updated)?;
+    info!("{} rows of {} updated", count, results_path.display());
+    Ok(())
+}
+
+// Paths of the decided files in MANIFEST.sha256, rejected files are removed from it
+fn update_manifest(destination_path: &Path, decided: &HashMap<PathBuf, Decision>) -> Result<()> {
+    let manifest_path = destination_path.join(MANIFEST_FILE);
+    let content = match fs::read_to_string(&manifest_path) {
+        Ok(content) => content,
+        Err(_) => return Ok(()),
+    };
+    let relative: HashMap<&Path, Option<&PathBuf>> = decided
+        .values()
+        .map(|decision| (decision.flagged.as_path(), decision.approved.as_ref()))
+        .collect();
+    let mut entries = vec![];
+    for line in content.lines() {
+        let (digest, path) = match line.split_once("  ") {
+            Some(entry) => entry,
+            None => {
+                entries.push(line.to_string());
+                continue;
+            }
+        };
+        match relative.get(Path::new(path)) {
+            Some(Some(new_path)) => entries.push(format!("{}  {}", digest, new_path.display())),
+            Some(None) => (),
+            None => entries.push(line.to_string()),
+        }
+    }
+    entries.sort_by_key(|entry| entry.split_once("  ").map(|(_, path)| path.to_string()));
+    fs::write(&manifest_path, entries.join("\n") + "\n")?;
+    info!("{} updated", manifest_path.display());
+    Ok(())
+}
+
+// Remove the directories of REVIEW_REQUIRED left empty by the decided files
+fn remove_empty_dirs(review_root: &Path, files: &[PathBuf]) {
+    for file in files {
+        let mut dir = file.parent();
+        while let Some(current) = dir {
+            if !current.starts_with(review_root) || fs::remove_dir(current).is_err() {
+                break;
+            }
+            dir = current.parent();
+        }
+    }
+}
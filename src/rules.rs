@@ -0,0 +1,178 @@
+use crate::{fit_to_vr_length, is_text_vr, ReviewAction, TagPath};
+use anyhow::Result;
+use dicom::{dicom_value, object::InMemDicomObject};
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::{debug, info};
+
+// Condition > action rules file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSetFile {
+    #[serde(default)]
+    rule: Vec<PolicyRuleFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyRuleFile {
+    name: String,
+    // Tag name > regular expression, all of them have to match
+    when: BTreeMap<String, String>,
+    route: Option<ReviewAction>,
+    #[serde(default)]
+    delete: Vec<String>,
+    #[serde(default)]
+    set: BTreeMap<String, String>,
+}
+
+impl<'de> Deserialize<'de> for ReviewAction {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        ReviewAction::from_str(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+// Rule of a rule set, the actions are taken on the files whose source values match every condition
+#[derive(Debug, Clone)]
+pub struct PolicyRule {
+    pub name: String,
+    conditions: Vec<(TagPath, Regex)>,
+    route: Option<ReviewAction>,
+    delete: Vec<TagPath>,
+    set: Vec<(TagPath, String)>,
+}
+
+impl PolicyRule {
+    // Conditions are searched anywhere in the value, a missing tag never matches
+    // A path to the items of a sequence matches when any of the items matches
+    pub fn matches(&self, dcm_obj: &InMemDicomObject) -> bool {
+        self.conditions.iter().all(|(tag_path, pattern)| {
+            tag_path
+                .values(dcm_obj)
+                .iter()
+                .any(|value| pattern.is_match(value))
+        })
+    }
+
+    // Delete and set the tags of the rule
+    pub fn apply(&self, dcm_obj: &mut InMemDicomObject) {
+        for tag_path in &self.delete {
+            tag_path.delete(dcm_obj);
+        }
+        for (tag_path, value) in &self.set {
+            tag_path.put(
+                dcm_obj,
+                dicom_value!(Strs, [fit_to_vr_length(tag_path.vr, value)]),
+            );
+        }
+    }
+}
+
+// Condition > action rules evaluated on each source dataset, shared by sort, anon and deid
+// eg route the series described as dose to review, or set a tag on the US files. The conditions
+// are read from the source file so they are not affected by the masking
+// Every matching rule deletes and sets its tags in file order, the route of the first matching
+// rule with a route wins over the dose screen, photo and slide label policies
+#[derive(Debug, Clone)]
+pub struct RuleSet {
+    pub path: PathBuf,
+    rules: Vec<PolicyRule>,
+}
+
+impl RuleSet {
+    pub fn from_file(rules_path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(rules_path)
+            .map_err(|e| anyhow::anyhow!("Can't read the rules {}: {}", rules_path.display(), e))?;
+        let rules_file: RuleSetFile = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid rules {}\n{}", rules_path.display(), e))?;
+        let path_of = |rule: &str, tag_name: &str| {
+            TagPath::from_str(tag_name)
+                .map_err(|e| anyhow::anyhow!("Unknown tag in rule {}: {}", rule, e))
+        };
+        let mut rules = vec![];
+        for rule in rules_file.rule {
+            if rule.when.is_empty() {
+                return Err(anyhow::anyhow!("Rule {} has no condition", rule.name));
+            }
+            let mut conditions = vec![];
+            for (tag_name, pattern) in &rule.when {
+                conditions.push((
+                    path_of(&rule.name, tag_name)?,
+                    Regex::new(pattern).map_err(|e| {
+                        anyhow::anyhow!("Invalid pattern for {}: {}\n{}", rule.name, pattern, e)
+                    })?,
+                ));
+            }
+            let mut delete = vec![];
+            for tag_name in &rule.delete {
+                delete.push(path_of(&rule.name, tag_name)?);
+            }
+            let mut set = vec![];
+            for (tag_name, value) in &rule.set {
+                let tag_path = path_of(&rule.name, tag_name)?;
+                if !is_text_vr(tag_path.vr) {
+                    return Err(anyhow::anyhow!(
+                        "Only text tags can be set by rule {}: {}",
+                        rule.name,
+                        tag_name
+                    ));
+                }
+                set.push((tag_path, value.clone()));
+            }
+            rules.push(PolicyRule {
+                name: rule.name,
+                conditions,
+                route: rule.route,
+                delete,
+                set,
+            });
+        }
+        info!(
+            "Rules {} with {} rules loaded",
+            rules_path.display(),
+            rules.len()
+        );
+        Ok(RuleSet {
+            path: rules_path.to_path_buf(),
+            rules,
+        })
+    }
+
+    // Check if any rule deletes or sets tags
+    pub fn edits_tags(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| !rule.delete.is_empty() || !rule.set.is_empty())
+    }
+
+    // Rules matching the source object, in file order
+    pub fn matching(&self, dcm_obj: &InMemDicomObject) -> Vec<&PolicyRule> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(dcm_obj))
+            .collect()
+    }
+
+    // Apply the tag actions of the rules matching the source object to the new object
+    pub fn apply(&self, source: &InMemDicomObject, dcm_obj: &mut InMemDicomObject) {
+        for rule in self.matching(source) {
+            debug!("Rule {} applied", rule.name);
+            rule.apply(dcm_obj);
+        }
+    }
+
+    // Action and reason of the first matching rule with a route
+    pub fn review_reason(&self, source: &InMemDicomObject) -> Option<(ReviewAction, String)> {
+        self.matching(source).into_iter().find_map(|rule| {
+            rule.route
+                .map(|route| (route, format!("Rule {}", rule.name)))
+        })
+    }
+}
@@ -0,0 +1,163 @@
+use crate::certificate::{operator_name, RunSummary};
+use anyhow::Result;
+use dcmrig_rs::{csv_field, csv_fields, RunOptions, MANIFEST_FILE, RESULTS_FILE};
+use dicom::core::chrono::Local;
+use std::{
+    env,
+    fs::{self, create_dir_all, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    process::exit,
+};
+use tracing::{error, info};
+
+const RUNS_HEADER: [&str; 12] = [
+    "name",
+    "action",
+    "date",
+    "operator",
+    "source",
+    "destination",
+    "total",
+    "written",
+    "failed",
+    "review",
+    "manifest_sha256",
+    "certificate",
+];
+
+// Registry shared by the runs of the user, unless --runs-db is given
+pub fn default_runs_db() -> PathBuf {
+    env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(".dcmrig")
+        .join("runs.csv")
+}
+
+// Run names are used in file names and must be new to the registry
+pub fn check_run_name(run_name: &str, runs_db: &Path) -> Result<()> {
+    if run_name.is_empty()
+        || !run_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    {
+        return Err(anyhow::anyhow!(
+            "Run names are made of letters, digits, '.', '_' and '-': {}",
+            run_name
+        ));
+    }
+    if read_runs(runs_db)?.iter().any(|run| run[0] == run_name) {
+        return Err(anyhow::anyhow!(
+            "Run {} is already recorded in {}, see dcmrig runs show {}",
+            run_name,
+            runs_db.display(),
+            run_name
+        ));
+    }
+    Ok(())
+}
+
+/// Append the summary of a named run to the runs registry
+pub fn record_run(summary: &RunSummary, run_options: &RunOptions) -> Result<()> {
+    let run_name = match &run_options.run_name {
+        Some(run_name) => run_name,
+        None => return Ok(()),
+    };
+    let runs_db = &run_options.runs_db;
+    if let Some(parent) = runs_db
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        create_dir_all(parent)?;
+    }
+    let new_registry = !runs_db.exists();
+    let mut registry = OpenOptions::new().create(true).append(true).open(runs_db)?;
+    if new_registry {
+        writeln!(registry, "{}", RUNS_HEADER.join(","))?;
+    }
+    let manifest_digest = summary
+        .options
+        .iter()
+        .find(|(label, _)| label == "Manifest SHA-256")
+        .map_or("NA".to_string(), |(_, digest)| digest.clone());
+    let certificate = match run_options.certificate {
+        true => summary
+            .destination
+            .join(format!("{}_CERTIFICATE.html", summary.action))
+            .display()
+            .to_string(),
+        false => "None".to_string(),
+    };
+    let row = [
+        run_name.clone(),
+        summary.action.clone(),
+        Local::now().format("%Y-%m-%dT%H:%M:%S%:z").to_string(),
+        operator_name(run_options),
+        summary.source.display().to_string(),
+        summary.destination.display().to_string(),
+        summary.count("Total files").to_string(),
+        summary.count("DICOM files written").to_string(),
+        summary.count("Failed files").to_string(),
+        summary.count("Files routed to review").to_string(),
+        manifest_digest,
+        certificate,
+    ];
+    let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+    writeln!(registry, "{}", row.join(","))?;
+    info!("Run {} recorded in {}", run_name, runs_db.display());
+    Ok(())
+}
+
+/// List the named runs of the registry, oldest first
+pub fn runs_list(runs_db: &Path) -> Result<()> {
+    let runs = read_runs(runs_db)?;
+    for run in &runs {
+        // name, action, date, written, failed and destination
+        println!(
+            "{}\t{}\t{}\t{} written\t{} failed\t{}",
+            run[0], run[1], run[2], run[7], run[8], run[5]
+        );
+    }
+    info!("{} runs recorded in {}", runs.len(), runs_db.display());
+    Ok(())
+}
+
+/// Print every field recorded about a named run
+pub fn runs_show(runs_db: &Path, run_name: &str) -> Result<()> {
+    let runs = read_runs(runs_db)?;
+    let run = runs
+        .iter()
+        .find(|run| run[0] == run_name)
+        .unwrap_or_else(|| {
+            error!("No run {} in {}", run_name, runs_db.display());
+            exit(1)
+        });
+    for (label, value) in RUNS_HEADER.iter().zip(run) {
+        println!("{}: {}", label, value);
+    }
+    let destination = Path::new(&run[5]);
+    for file in [RESULTS_FILE, MANIFEST_FILE] {
+        let path = destination.join(file);
+        if path.is_file() {
+            println!("{}: {}", file, path.display());
+        }
+    }
+    Ok(())
+}
+
+// Rows of the registry without the header, an absent registry has no runs
+fn read_runs(runs_db: &Path) -> Result<Vec<Vec<String>>> {
+    let content = match fs::read_to_string(runs_db) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content
+        .lines()
+        .skip(1)
+        .map(csv_fields)
+        .filter(|run| run.len() == RUNS_HEADER.len())
+        .collect())
+}
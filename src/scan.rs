@@ -0,0 +1,197 @@
+use anyhow::Result;
+use dcmrig_rs::{
+    csv_field, read_file_meta,
+    source::{DirectorySource, InstanceSource},
+    RunOptions,
+};
+use dicom::{
+    core::dictionary::{UidDictionary, UidDictionaryEntry},
+    encoding::TransferSyntaxIndex,
+    transfer_syntax::TransferSyntaxRegistry,
+};
+use dicom_dictionary_std::StandardSopClassDictionary;
+use rayon::prelude::*;
+use std::{
+    collections::BTreeMap,
+    fs::{self, canonicalize},
+    path::{Path, PathBuf},
+    process::exit,
+};
+use tracing::{error, info, warn};
+
+// Files of each SOP class and transfer syntax in the corpus, and how many are not DICOM
+#[derive(Default)]
+struct ScanCounts {
+    sop_classes: BTreeMap<String, u64>,
+    transfer_syntaxes: BTreeMap<String, u64>,
+    // DICOM files with a standard SOP class and a transfer syntax this build decodes
+    supported: u64,
+    non_dicom: u64,
+}
+
+impl ScanCounts {
+    fn merge(mut self, other: ScanCounts) -> ScanCounts {
+        for (uid, count) in other.sop_classes {
+            *self.sop_classes.entry(uid).or_default() += count;
+        }
+        for (uid, count) in other.transfer_syntaxes {
+            *self.transfer_syntaxes.entry(uid).or_default() += count;
+        }
+        self.supported += other.supported;
+        self.non_dicom += other.non_dicom;
+        self
+    }
+}
+
+/// List the SOP classes and transfer syntaxes of the source with their file counts and how
+/// far dcmrig handles them, from the file meta group only
+pub fn dicom_scan(
+    source_path: PathBuf,
+    output: Option<PathBuf>,
+    run_options: RunOptions,
+) -> Result<()> {
+    if let Err(e) = canonicalize(&source_path) {
+        error!(
+            "Given source Path doesnot exist: {}\n{}",
+            source_path.display(),
+            e
+        );
+        exit(1)
+    }
+    let source = DirectorySource::new(&source_path, run_options.read_iso);
+    info!("Indexing files from: {}", source.describe());
+    let all_files = source.items()?;
+    let counts = all_files
+        .par_iter()
+        .fold(ScanCounts::default, |mut counts, item| {
+            match read_file_meta(&source, item) {
+                Some(meta) => {
+                    let sop_class = trim_uid(&meta.media_storage_sop_class_uid);
+                    let transfer_syntax = trim_uid(&meta.transfer_syntax);
+                    if sop_class_support(&sop_class) != "private"
+                        && transfer_syntax_support(&transfer_syntax) == "full"
+                    {
+                        counts.supported += 1;
+                    }
+                    *counts.sop_classes.entry(sop_class).or_default() += 1;
+                    *counts.transfer_syntaxes.entry(transfer_syntax).or_default() += 1;
+                }
+                None => counts.non_dicom += 1,
+            }
+            counts
+        })
+        .reduce(ScanCounts::default, ScanCounts::merge);
+    source.finalize()?;
+
+    let rows = scan_rows(&counts);
+    println!(
+        "{:<18} {:<64} {:>8} {:<16} NAME",
+        "KIND", "UID", "FILES", "SUPPORT"
+    );
+    for (kind, uid, name, files, support) in &rows {
+        println!(
+            "{:<18} {:<64} {:>8} {:<16} {}",
+            kind, uid, files, support, name
+        );
+    }
+    if let Some(output) = &output {
+        write_scan_csv(output, &rows)?;
+    }
+
+    let dicom_files = all_files.len() as u64 - counts.non_dicom;
+    info!(
+        "{} files: {} DICOM, {} not DICOM",
+        all_files.len(),
+        dicom_files,
+        counts.non_dicom
+    );
+    let share = match dicom_files {
+        0 => 0.0,
+        _ => counts.supported as f64 * 100.0 / dicom_files as f64,
+    };
+    info!(
+        "{} of {} DICOM files ({:.1}%) have a standard SOP class and a transfer syntax this build decodes",
+        counts.supported, dicom_files, share
+    );
+    if counts.supported < dicom_files {
+        warn!(
+            "See the SUPPORT column for the SOP classes and transfer syntaxes of the other files"
+        );
+    }
+    Ok(())
+}
+
+fn trim_uid(uid: &str) -> String {
+    uid.trim_end_matches(['\0', ' ']).to_string()
+}
+
+// standard or retired, private when the dictionary doesn't know the SOP class. The attributes
+// of private SOP classes are only de-identified as far as they are standard
+fn sop_class_support(uid: &str) -> &'static str {
+    match StandardSopClassDictionary.by_uid(uid) {
+        Some(entry) if entry.is_retired() => "retired",
+        Some(_) => "standard",
+        None => "private",
+    }
+}
+
+// full when the dataset is read and the pixel data decoded, dataset-only when there is no
+// pixel data decoder in this build, unsupported when the files are handled as non DICOM
+fn transfer_syntax_support(uid: &str) -> &'static str {
+    match TransferSyntaxRegistry.get(uid) {
+        Some(ts) if ts.is_unsupported() => "unsupported",
+        Some(ts) if !ts.can_decode_all() => "dataset-only",
+        Some(_) => "full",
+        None => "unsupported",
+    }
+}
+
+// Kind, UID, name, files and support, the SOP classes first
+fn scan_rows(counts: &ScanCounts) -> Vec<(&'static str, String, String, u64, &'static str)> {
+    let mut rows = vec![];
+    for (uid, files) in &counts.sop_classes {
+        let name = StandardSopClassDictionary
+            .by_uid(uid)
+            .map_or("Unknown", |entry| entry.name());
+        rows.push((
+            "SOPClass",
+            uid.clone(),
+            name.to_string(),
+            *files,
+            sop_class_support(uid),
+        ));
+    }
+    for (uid, files) in &counts.transfer_syntaxes {
+        let name = TransferSyntaxRegistry
+            .get(uid)
+            .map_or("Unknown", |ts| ts.name());
+        rows.push((
+            "TransferSyntax",
+            uid.clone(),
+            name.to_string(),
+            *files,
+            transfer_syntax_support(uid),
+        ));
+    }
+    rows
+}
+
+fn write_scan_csv(
+    output: &Path,
+    rows: &[(&'static str, String, String, u64, &'static str)],
+) -> Result<()> {
+    let mut content = String::from("kind,uid,name,files,support\n");
+    for (kind, uid, name, files, support) in rows {
+        content.push_str(&format!(
+            "{},{},{},{},{}\n",
+            kind,
+            csv_field(uid),
+            csv_field(name),
+            files,
+            support
+        ));
+    }
+    fs::write(output, content)?;
+    info!("Scan report written to {}", output.display());
+    Ok(())
+}
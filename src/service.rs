@@ -0,0 +1,147 @@
+use crate::{checkpoint::CheckpointWriter, tracker::RunTracker, RunOptions, StatusWriter};
+use std::{
+    env,
+    path::Path,
+    process::exit,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tracing::{debug, error, info, warn};
+
+// Set by SIGTERM, SIGINT or the console control events, the receive or the run stops once it
+// is set
+static STOP: AtomicBool = AtomicBool::new(false);
+
+// Set for sort, deid and anon, their runs stop on a signal. The runs of the batches of receive
+// complete the batch
+static RUN_STOPS: AtomicBool = AtomicBool::new(false);
+
+// Exit code of a run stopped by a signal, the code of a shell for a process ended by SIGINT
+pub const STOPPED_EXIT_CODE: i32 = 130;
+
+/// Stop the receive or the run cleanly on SIGTERM and SIGINT, or the stop of a Windows service
+/// wrapper (Ctrl-C, Ctrl-Break, close and shutdown), instead of ending the process at once.
+/// A second signal ends the process without waiting
+pub fn handle_stop_signals() {
+    #[cfg(unix)]
+    {
+        extern "C" fn on_signal(_: libc::c_int) {
+            if STOP.swap(true, Ordering::SeqCst) {
+                unsafe { libc::_exit(1) };
+            }
+        }
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        unsafe {
+            libc::signal(libc::SIGTERM, handler);
+            libc::signal(libc::SIGINT, handler);
+        }
+    }
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::{Foundation::BOOL, System::Console::SetConsoleCtrlHandler};
+        unsafe extern "system" fn on_control(_: u32) -> BOOL {
+            if STOP.swap(true, Ordering::SeqCst) {
+                std::process::exit(1);
+            }
+            // Handled, the process is not ended by the default handler
+            1
+        }
+        if unsafe { SetConsoleCtrlHandler(Some(on_control), 1) } == 0 {
+            warn!(
+                "Can't handle the console control events: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+pub fn stop_requested() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
+
+/// Stop the sort, deid or anon run on a signal: no new file is taken, the files being written
+/// are completed, then the run saves what it has and ends with STOPPED_EXIT_CODE
+pub fn handle_run_stop_signals() {
+    RUN_STOPS.store(true, Ordering::SeqCst);
+    handle_stop_signals();
+}
+
+pub fn run_stop_requested() -> bool {
+    RUN_STOPS.load(Ordering::SeqCst) && stop_requested()
+}
+
+/// Tell the service manager about the state of the receive, eg READY=1 or STATUS=<text>, with
+/// the sd_notify protocol of systemd. Nothing is sent without NOTIFY_SOCKET, ie outside a
+/// Type=notify unit, or on the other platforms
+pub fn notify(state: &str) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::net::UnixDatagram;
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+        let Some(socket_path) = env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+        let socket_path = socket_path.to_string_lossy().to_string();
+        // @ is a socket of the abstract namespace
+        let address = match socket_path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+            None => SocketAddr::from_pathname(&socket_path),
+        };
+        let sent = UnixDatagram::unbound().and_then(|socket| {
+            address.and_then(|address| socket.send_to_addr(state.as_bytes(), &address))
+        });
+        match sent {
+            Ok(_) => debug!("Service manager notified: {}", state.replace('\n', " ")),
+            Err(e) => warn!("Can't notify the service manager {}: {}", socket_path, e),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = state;
+}
+
+/// Interval of the WATCHDOG=1 keep-alives, half the WatchdogSec of the unit, if any
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // The watchdog is for this process only, not its children
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid != std::process::id().to_string() {
+            return None;
+        }
+    }
+    let interval = Duration::from_micros(usec / 2);
+    info!("Service watchdog keep-alive every {:?}", interval);
+    Some(interval)
+}
+
+/// End a run stopped by a signal, once the files being written are done: the checkpoint of
+/// --resume is saved, results.csv lists the files done and the status file is stopped. The end
+/// of run reports, hooks and deliveries are left out, the run is not complete
+pub fn exit_stopped_run(
+    tracker: &RunTracker,
+    destination_path: &Path,
+    run_options: &RunOptions,
+    checkpoint_writer: Option<CheckpointWriter>,
+    status_writer: Option<StatusWriter>,
+) -> ! {
+    tracker.progress.finish();
+    // Before results.csv sorts the results
+    if let Some(checkpoint_writer) = checkpoint_writer {
+        checkpoint_writer.finish();
+    }
+    if !run_options.dry_run {
+        tracker
+            .write_results(destination_path)
+            .unwrap_or_else(|e| error!("Can't write the results of the stopped run: {}", e));
+    }
+    if let Some(status_writer) = status_writer {
+        status_writer.stopped();
+    }
+    match &run_options.resume {
+        Some(checkpoint) => warn!(
+            "Run stopped, start it again with --resume {} to process the remaining files",
+            checkpoint.display()
+        ),
+        None => warn!("Run stopped before all the files were processed"),
+    }
+    exit(STOPPED_EXIT_CODE)
+}
@@ -0,0 +1,188 @@
+use anyhow::Result;
+use dcmrig_rs::{check_if_dup_exists, tracker::RunTracker, FileResult};
+use regex::{Regex, RegexBuilder};
+use std::{
+    collections::HashMap,
+    fs::{self, create_dir_all},
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, error, info};
+
+// Values that identify whoever they belong to, replaced in the text sidecars
+const SIDECAR_PATTERNS: [(&str, &str); 4] = [
+    (r"[\w.+-]+@[\w-]+(\.[\w-]+)+", "[EMAIL]"),
+    (r"\b\d{4}-\d{2}-\d{2}\b", "[DATE]"),
+    (r"\b\d{1,2}/\d{1,2}/\d{2,4}\b", "[DATE]"),
+    (
+        r"(\+\d{1,3}[ -]?)?\(?\b\d{2,4}\)?[ -]\d{3,4}[ -]\d{3,4}\b",
+        "[PHONE]",
+    ),
+];
+
+// Identifiers of a source that was not written, eg failed, have no output ID
+const NO_OUTPUT_ID: &str = "[REMOVED]";
+
+/// Copy the text files held by --non-dicom scrub to NON_DICOM, with the identifiers of the
+/// source files of the run replaced by the ID of their patient in the output, then the e-mail
+/// addresses, dates and phone numbers
+pub fn scrub_sidecars(tracker: &RunTracker, destination_path: &Path) -> Result<()> {
+    let sidecars = std::mem::take(&mut *tracker.sidecars.lock().expect("Failed to lock mutex"));
+    if sidecars.is_empty() {
+        return Ok(());
+    }
+    let scrubber = Scrubber::new(tracker)?;
+    let non_dicom_dir = destination_path.join("NON_DICOM");
+    for sidecar in &sidecars {
+        let mut result = FileResult::new(sidecar, "non-DICOM");
+        match scrub_sidecar(tracker, &scrubber, sidecar, &non_dicom_dir) {
+            Ok((output, count)) => {
+                debug!("{} values scrubbed from {}", count, sidecar.display());
+                result.output = output.display().to_string();
+                result.changes = vec![format!("~ {} values scrubbed", count)];
+            }
+            Err(e) => {
+                error!("Can't scrub non dicom file {}: {}", sidecar.display(), e);
+                result.error = e.to_string();
+            }
+        }
+        tracker.record_result(result);
+    }
+    info!(
+        "{} text files scrubbed to {}",
+        sidecars.len(),
+        non_dicom_dir.display()
+    );
+    Ok(())
+}
+
+fn scrub_sidecar(
+    tracker: &RunTracker,
+    scrubber: &Scrubber,
+    sidecar: &Path,
+    non_dicom_dir: &Path,
+) -> Result<(PathBuf, usize)> {
+    let mut text = String::new();
+    tracker
+        .source
+        .open_bytes(sidecar)?
+        .read_to_string(&mut text)?;
+    let (text, count) = scrubber.scrub(&text);
+    // The directories of the entries of an archive source are kept
+    let output_dir = non_dicom_dir.join(tracker.source.non_dicom_dir(sidecar));
+    let output = PathBuf::from(check_if_dup_exists(
+        output_dir
+            .join(sidecar.file_name().unwrap_or_default())
+            .display()
+            .to_string(),
+    ));
+    if !tracker.dry_run {
+        create_dir_all(&output_dir)?;
+        fs::write(&output, text)?;
+    }
+    Ok((output, count))
+}
+
+struct Scrubber {
+    // Any identifier of the run, case insensitive
+    identifiers: Option<Regex>,
+    // Lowercase identifier > output ID
+    replacements: HashMap<String, String>,
+    patterns: Vec<(Regex, &'static str)>,
+}
+
+impl Scrubber {
+    fn new(tracker: &RunTracker) -> Result<Self> {
+        let output_ids: HashMap<PathBuf, String> = tracker
+            .results
+            .lock()
+            .expect("Failed to lock mutex")
+            .iter()
+            .filter(|result| !result.patient_id.is_empty())
+            .map(|result| (result.source.clone(), result.patient_id.clone()))
+            .collect();
+        let mut replacements = HashMap::new();
+        for (identifiers, source) in tracker
+            .sidecar_identifiers
+            .lock()
+            .expect("Failed to lock mutex")
+            .iter()
+        {
+            let output_id = output_ids
+                .get(source)
+                .map_or(NO_OUTPUT_ID, |output_id| output_id.as_str());
+            for identifier in identifiers {
+                replacements
+                    .entry(identifier.to_lowercase())
+                    .or_insert_with(|| output_id.to_string());
+            }
+        }
+        // Longest first so a full name is replaced before its components
+        let mut alternatives: Vec<&String> = replacements.keys().collect();
+        alternatives.sort_by_key(|identifier| std::cmp::Reverse(identifier.len()));
+        let alternatives: Vec<String> = alternatives
+            .iter()
+            .map(|identifier| {
+                format!(
+                    "{}{}{}",
+                    word_boundary(identifier.chars().next()),
+                    regex::escape(identifier),
+                    word_boundary(identifier.chars().last())
+                )
+            })
+            .collect();
+        let identifiers = match alternatives.is_empty() {
+            true => None,
+            false => Some(
+                RegexBuilder::new(&alternatives.join("|"))
+                    .case_insensitive(true)
+                    .size_limit(1 << 28)
+                    .build()?,
+            ),
+        };
+        let patterns = SIDECAR_PATTERNS
+            .iter()
+            .map(|(pattern, replacement)| {
+                (
+                    Regex::new(pattern).expect("Invalid sidecar pattern"),
+                    *replacement,
+                )
+            })
+            .collect();
+        Ok(Scrubber {
+            identifiers,
+            replacements,
+            patterns,
+        })
+    }
+
+    // Scrubbed text and the number of values replaced
+    fn scrub(&self, text: &str) -> (String, usize) {
+        let mut count = 0;
+        let mut text = match &self.identifiers {
+            Some(identifiers) => identifiers
+                .replace_all(text, |captures: &regex::Captures| {
+                    count += 1;
+                    self.replacements
+                        .get(&captures[0].to_lowercase())
+                        .map_or(NO_OUTPUT_ID, |output_id| output_id.as_str())
+                        .to_string()
+                })
+                .into_owned(),
+            None => text.to_string(),
+        };
+        for (pattern, replacement) in &self.patterns {
+            count += pattern.find_iter(&text).count();
+            text = pattern.replace_all(&text, *replacement).into_owned();
+        }
+        (text, count)
+    }
+}
+
+// Whole words only, DOE is not replaced in DOES
+fn word_boundary(c: Option<char>) -> &'static str {
+    match c.is_some_and(|c| c.is_alphanumeric()) {
+        true => r"\b",
+        false => "",
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{create_target_dir, to_hex, Sha256};
+use anyhow::Result;
+use std::{fs, path::Path};
+
+// Writer that hashes the bytes on their way to the output, so the output doesn't need to be read again
+pub struct HashingWriter<W: std::io::Write> {
+    inner: W,
+    hasher: Option<Sha256>,
+}
+
+impl<W: std::io::Write> HashingWriter<W> {
+    // Without hashing the bytes are only passed through
+    pub fn new(inner: W, hashing: bool) -> Self {
+        HashingWriter {
+            inner,
+            hasher: hashing.then(Sha256::default),
+        }
+    }
+
+    // Flush the output and return the hex digest of everything written
+    pub fn finish(mut self) -> Result<Option<String>> {
+        self.inner.flush()?;
+        Ok(self.hasher.map(|hasher| to_hex(&hasher.finalize())))
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Destination of the written DICOM instances, the filesystem is the default
+// Other destinations, eg archives, object stores or a DIMSE peer, implement this trait and are
+// set on the RunTracker of the run
+pub trait OutputSink: Send + Sync {
+    // Write one instance at the output path, encode writes the instance to the given writer
+    // Returns the hex SHA-256 of the written bytes when hashing is set
+    fn write_instance(
+        &self,
+        path: &Path,
+        hashing: bool,
+        encode: &mut dyn FnMut(&mut dyn std::io::Write) -> Result<()>,
+    ) -> Result<Option<String>>;
+
+    // Called once at the end of the run, after the last instance is written
+    fn finalize(&self) -> Result<()>;
+}
+
+// Each instance is a file at its output path
+#[derive(Debug, Default)]
+pub struct FileSystemSink;
+
+impl OutputSink for FileSystemSink {
+    fn write_instance(
+        &self,
+        path: &Path,
+        hashing: bool,
+        encode: &mut dyn FnMut(&mut dyn std::io::Write) -> Result<()>,
+    ) -> Result<Option<String>> {
+        if let Some(parent) = path.parent() {
+            create_target_dir(&parent.display().to_string())?;
+        }
+        let mut writer = HashingWriter::new(fs::File::create(path)?, hashing);
+        encode(&mut writer)?;
+        writer.finish()
+    }
+
+    fn finalize(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Nothing is written, the instances are still encoded so a dry run finds the encoding errors
+#[derive(Debug, Default)]
+pub struct DiscardSink;
+
+impl OutputSink for DiscardSink {
+    fn write_instance(
+        &self,
+        _path: &Path,
+        _hashing: bool,
+        encode: &mut dyn FnMut(&mut dyn std::io::Write) -> Result<()>,
+    ) -> Result<Option<String>> {
+        encode(&mut std::io::sink())?;
+        Ok(None)
+    }
+
+    fn finalize(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,24 +1,25 @@
+use crate::certificate::{policy_hash, RunSummary};
+use crate::notify::send_run_report;
+use crate::runs::record_run;
 use anyhow::Result;
 use crossbeam::sync::WaitGroup;
+use dcmrig_rs::pipeline::{FileRun, FileTransform};
+use dcmrig_rs::tracker::RunTracker;
 use dcmrig_rs::*;
-use dicom::{
-    dictionary_std::tags::PIXEL_DATA,
-    object::{FileDicomObject, InMemDicomObject},
-};
-use rayon::prelude::*;
+use dicom::object::{FileDicomObject, InMemDicomObject};
 use std::{
-    collections::HashMap,
-    fs,
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    time::Instant,
 };
 use tracing::{debug, error, info, warn};
-use walkdir::DirEntry;
 
 pub fn dicom_sort(
     source_path: PathBuf,
     destination_path: PathBuf,
     sort_order: String,
+    run_options: RunOptions,
 ) -> Result<()> {
     info!(
         "Sorting the data for >> SOURCE: {} | DESTINATION: {}",
@@ -26,75 +27,170 @@ pub fn dicom_sort(
         destination_path.display()
     );
 
-    // Set up required variables
-    let (all_files, total_len, pb) = preprocessing_setup(&source_path, &destination_path)?;
     let sort_order_vec = generate_sort_order(sort_order)?;
-    let failed_case: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    let non_dcm_cases: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
+    let policy = policy_hash(
+        &[
+            ("Action".to_string(), "Sort".to_string()),
+            ("Sort order".to_string(), sort_order_vec.join(", ")),
+        ],
+        &[],
+        &run_options,
+    );
+    let mut run = FileRun::start(
+        "Sort",
+        &policy,
+        &source_path,
+        &destination_path,
+        &run_options,
+    )?;
+    if run_options.merge_frames {
+        warn!("Sorted files are not merged, --merge-frames only applies to deid and anon");
+    }
+    if run_options.send_to.is_some() {
+        warn!("Sorted files are not sent, send the destination with dcmrig send");
+    }
+    if run_options.stow_url.is_some() {
+        warn!("Sorted files are not uploaded, --stow-url only applies to deid and anon");
+    }
+    if run_options
+        .rules
+        .as_ref()
+        .is_some_and(|rules| rules.edits_tags())
+    {
+        warn!("Sorted files are copied as they are, only the routes of the rules are applied");
+    }
+    if run_options.study_values.is_some() {
+        warn!("Sorted files are copied as they are, the study values are not set");
+    }
     info!("Sort Order {:?}", sort_order_vec);
-
-    let wg = WaitGroup::new();
-    // Main loop
-    all_files
-        .par_iter()
-        .enumerate()
-        .for_each(|(_index, working_path)| {
-            if let Ok(dcm_obj) = dicom::object::OpenFileOptions::new()
-                .read_until(PIXEL_DATA)
-                .open_file(working_path.path())
-            {
-                sort_each_dcm_file(
-                    working_path,
-                    &dcm_obj,
-                    &destination_path,
-                    &sort_order_vec,
-                    wg.clone(),
-                )
-                .unwrap_or_else(|_| {
-                    let mut map = failed_case.lock().expect("Failed to lock mutex");
-                    *map += 1;
-                    error!(
-                        "Can't SORT {:#?} Copying to FAILED_CASES directory",
-                        &working_path.file_name()
-                    );
-                    failed_case_copy(&working_path.clone().into_path(), &destination_path)
-                        .expect("Failed to copy file to FAILED_CASES directory");
-                });
-            } else {
-                let nwg = wg.clone();
-                let mut map = non_dcm_cases.lock().expect("Failed to lock mutex");
-                *map += 1;
-                copy_non_dicom_files(&working_path, &destination_path).unwrap_or_else(|_| {
-                    error!("Can't copy non dicom file {:#?}", &working_path.file_name())
-                });
-                drop(nwg);
-            }
-            pb.inc(1);
-        });
-    pb.finish();
-    print_status(
-        total_len,
-        *failed_case.lock().expect("Failed to lock mutex"),
-        *non_dcm_cases.lock().expect("Failed to lock mutex"),
-        "Sorted".to_string(),
+    run.save_checkpoints(&run_options, None);
+    run.process(
+        &SortTransform {
+            sort_order_vec: &sort_order_vec,
+            run_options: &run_options,
+        },
+        &destination_path,
+        &run_options,
     )?;
-    wg.wait();
+    let FileRun {
+        tracker,
+        total_len,
+        checkpoint_writer,
+        status_writer,
+        failed,
+        non_dicom,
+        ..
+    } = run;
+    tracker.progress.finish();
+    print_slowest_files(&tracker.timings, run_options.slowest)?;
+    tracker.print_series_classes();
+    tracker.print_routing();
+    if let Some(checkpoint_writer) = checkpoint_writer {
+        checkpoint_writer.finish();
+    }
+    tracker.write_manifest(&destination_path)?;
+    tracker.run_study_hooks();
+    tracker.print_collisions();
+    tracker.print_long_paths();
+    match run_options.dry_run {
+        true => tracker.write_dry_run_report(run_options.dry_run_report.as_deref())?,
+        false => tracker.write_results(&destination_path)?,
+    }
+    tracker.report_completeness(&destination_path)?;
+    tracker.report_modality_mismatches(&destination_path)?;
+    tracker.report_duplicates(&destination_path)?;
+    if let Some(status_writer) = status_writer {
+        status_writer.finish();
+    }
+    if !run_options.dry_run && (run_options.email.is_some() || run_options.run_name.is_some()) {
+        let summary = RunSummary {
+            action: "Sort".to_string(),
+            counts: RunSummary::run_counts(total_len, failed, non_dicom, &tracker),
+            tag_changes: vec![],
+            source: source_path,
+            destination: destination_path,
+            profile: vec![],
+            options: vec![
+                ("Sort order".to_string(), sort_order_vec.join(", ")),
+                ("Shard".to_string(), run_options.shard_summary()),
+            ],
+            config: String::new(),
+        };
+        send_run_report(&summary, &run_options, &tracker);
+        record_run(&summary, &run_options)?;
+    }
     info!("DICOM Sort complete!");
     Ok(())
 }
 
+// Sort of each file by the sort order, the files are copied as they are
+struct SortTransform<'a> {
+    sort_order_vec: &'a Vec<String>,
+    run_options: &'a RunOptions,
+}
+
+impl FileTransform for SortTransform<'_> {
+    fn action(&self) -> &str {
+        "Sort"
+    }
+
+    fn copies(&self) -> bool {
+        true
+    }
+
+    fn transform(
+        &self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        destination_path: &Path,
+        timing: FileTiming,
+        tracker: RunTracker,
+        wg: WaitGroup,
+    ) -> Result<()> {
+        sort_each_dcm_file(
+            dcm_obj,
+            destination_path,
+            self.sort_order_vec,
+            self.run_options,
+            timing,
+            tracker,
+            wg,
+        )
+    }
+}
+
 // DICOM SORT
 fn sort_each_dcm_file(
-    source_path: &DirEntry,
     dcm_obj: &FileDicomObject<InMemDicomObject>,
-    destination_path: &PathBuf,
+    destination_path: &Path,
     sort_order_vec: &Vec<String>,
+    run_options: &RunOptions,
+    mut timing: FileTiming,
+    tracker: RunTracker,
     wg: WaitGroup,
 ) -> Result<()> {
-    let dicom_tags_values = get_sanitized_tag_values(&dcm_obj)?;
-    let order_level = generate_order_level(sort_order_vec, &dicom_tags_values, &dcm_obj)?;
-    let file_name = generate_dicom_file_name(
+    let transform_start = Instant::now();
+    let dicom_tags_values = get_sanitized_tag_values(dcm_obj)?;
+    tracker.record_series(&dicom_tags_values);
+    let review_reason = run_options
+        .rules
+        .as_ref()
+        .and_then(|rules| rules.review_reason(dcm_obj));
+    // Failed files go to the FAILED_CASES of the run, a routed file included
+    let failed_destination = destination_path.to_path_buf();
+    let (destination_path, review_reason) =
+        match tracker.route_file(review_reason, destination_path) {
+            Some(route) => route,
+            None => {
+                let mut result =
+                    FileResult::new(&timing.path, "excluded").with_tags(&dicom_tags_values);
+                result.duration = timing.read + transform_start.elapsed();
+                tracker.record_result(result);
+                return Ok(());
+            }
+        };
+    let file_name = tracker.output_file_name(
         &dicom_tags_values,
+        dcm_obj,
         replace_non_alphanumeric(
             dicom_tags_values
                 .get("PatientName")
@@ -103,56 +199,143 @@ fn sort_each_dcm_file(
         ),
     )?;
 
-    let temp_trimmed_study_uid = dicom_tags_values
-        .get("StudyInstanceUID")
-        .expect("Failed to extract value")
-        .split(".")
-        .last()
-        .expect("Failed to extract value");
+    // The path template replaces the --sort-order levels too
+    let dir_path = match &tracker.path_template {
+        Some(template) => format!(
+            "{}/{}",
+            destination_path.display(),
+            template.render(dcm_obj, &dicom_tags_values)
+        ),
+        None => {
+            let order_level = generate_order_level(sort_order_vec, &dicom_tags_values, dcm_obj)?;
+            let temp_trimmed_study_uid = dicom_tags_values
+                .get("StudyInstanceUID")
+                .expect("Failed to extract value")
+                .split(".")
+                .last()
+                .expect("Failed to extract value");
 
-    let final_trimmed_uid = if temp_trimmed_study_uid.len() > 5 {
-        temp_trimmed_study_uid[temp_trimmed_study_uid.len() - 5..].to_string()
-    } else {
-        temp_trimmed_study_uid.to_string()
-    };
+            let final_trimmed_uid = if temp_trimmed_study_uid.len() > 5 {
+                temp_trimmed_study_uid[temp_trimmed_study_uid.len() - 5..].to_string()
+            } else {
+                temp_trimmed_study_uid.to_string()
+            };
 
-    let dir_path = format!(
-        "{}/{}{}T{}_{}/{:0>4}_{}_{}",
-        destination_path.display(),
-        order_level,
-        dicom_tags_values
-            .get("StudyDate")
-            .expect("Failed to extract value")
-            .trim(),
-        dicom_tags_values
-            .get("StudyTime")
-            .expect("Failed to extract value")
-            .split(".")
-            .next()
-            .expect("Failed to extract value"),
-        final_trimmed_uid,
-        dicom_tags_values
-            .get("SeriesNumber")
-            .expect("Failed to extract value"),
-        replace_non_alphanumeric(
-            dicom_tags_values
-                .get("SeriesDescription")
-                .expect("Failed to extract value")
-                .trim()
-        ),
-        dicom_tags_values
-            .get("ImagePlane")
-            .expect("Failed to extract value")
-            .trim()
-    );
+            format!(
+                "{}/{}{}T{}_{}/{:0>4}_{}_{}",
+                destination_path.display(),
+                order_level,
+                dicom_tags_values
+                    .get("StudyDate")
+                    .expect("Failed to extract value")
+                    .trim(),
+                dicom_tags_values
+                    .get("StudyTime")
+                    .expect("Failed to extract value")
+                    .split(".")
+                    .next()
+                    .expect("Failed to extract value"),
+                final_trimmed_uid,
+                dicom_tags_values
+                    .get("SeriesNumber")
+                    .expect("Failed to extract value"),
+                replace_non_alphanumeric(
+                    dicom_tags_values
+                        .get("SeriesDescription")
+                        .expect("Failed to extract value")
+                        .trim()
+                ),
+                dicom_tags_values
+                    .get("ImagePlane")
+                    .expect("Failed to extract value")
+                    .trim()
+            )
+        }
+    };
 
-    let c_source_path = source_path.clone();
-    rayon::spawn(move || {
-        create_target_dir(&dir_path).expect("Failed to created target dir");
-        let full_path = check_if_dup_exists(format!("{}/{}", dir_path, file_name));
-        debug!("Saving file: {} to: {}", file_name, dir_path);
-        fs::copy(c_source_path.into_path(), full_path)
-            .expect("Failed to copy file to sorted destination");
+    let c_source_path = timing.path.clone();
+    // Transcoded files are read whole and encoded again, the others and the large files are
+    // copied as they are
+    let transcode = run_options.transcode;
+    let transcoded = match transcode != TranscodeTarget::Keep && !tracker.is_large(&timing.path) {
+        true => {
+            let mut full_obj = tracker.open_item(&timing.path, false)?;
+            transcode_pixels(&mut full_obj, transcode)?.then_some(full_obj)
+        }
+        false => None,
+    };
+    let expected_instances = study_related_instances(dcm_obj);
+    let sop_uid = sop_instance_uid(dcm_obj);
+    let study_uid = dicom_tags_values
+        .get("StudyInstanceUID")
+        .cloned()
+        .unwrap_or_default();
+    let mut result = FileResult::new(
+        &timing.path,
+        match review_reason {
+            Some(_) => "review",
+            None => "processed",
+        },
+    )
+    .with_tags(&dicom_tags_values);
+    timing.transform = transform_start.elapsed();
+    tracker.progress.transformed.inc(1);
+    let series_affinity = tracker.series_affinity;
+    spawn_write(series_affinity, move || {
+        let write_start = Instant::now();
+        let full_path =
+            tracker.output_path(format!("{}/{}", dir_path, file_name), &sop_uid, &mut result);
+        let written = (|| -> Result<()> {
+            match &transcoded {
+                _ if tracker.dry_run => (),
+                Some(full_obj) => {
+                    debug!("Saving transcoded file: {} to: {}", file_name, dir_path);
+                    let digest = tracker.write_instance(
+                        &full_path,
+                        &c_source_path,
+                        full_obj,
+                        &HashSet::new(),
+                    )?;
+                    tracker.record_checksum(&full_path, digest);
+                }
+                None => {
+                    debug!("Saving file: {} to: {}", file_name, dir_path);
+                    let digest = tracker.sink.write_instance(
+                        Path::new(&full_path),
+                        tracker.checksums.is_some(),
+                        &mut |to| {
+                            io::copy(&mut tracker.source.open_bytes(&c_source_path)?, to)?;
+                            Ok(())
+                        },
+                    )?;
+                    tracker.record_checksum(&full_path, digest);
+                }
+            }
+            match &review_reason {
+                Some(_) if tracker.dry_run => (),
+                Some(reason) => write_review_note(&full_path, reason)?,
+                None => tracker.file_written(&full_path, &study_uid, expected_instances),
+            }
+            Ok(())
+        })();
+        timing.write = write_start.elapsed();
+        match written {
+            Ok(()) => {
+                tracker.progress.written.inc(1);
+                result.output = full_path;
+                result.duration = timing.total();
+                tracker.record_result(result);
+            }
+            Err(e) => {
+                error!(
+                    "Can't write {:#?}: {:#}",
+                    c_source_path.file_name().unwrap_or_default(),
+                    e
+                );
+                tracker.record_failed(&c_source_path, &failed_destination, &e, timing.total());
+            }
+        }
+        tracker.record_timing(timing);
         drop(wg);
     });
     Ok(())
@@ -162,7 +345,7 @@ fn sort_each_dcm_file(
 // Any combination if I=PatientID, N=PatientName, or M=Modality PatientID is the default
 fn generate_sort_order(ord_input: String) -> Result<Vec<String>> {
     let mut order_level_vec: Vec<String> = vec![];
-    for each in ord_input.to_uppercase().chars().into_iter() {
+    for each in ord_input.to_uppercase().chars() {
         match each.to_string().as_str() {
             "I" => order_level_vec.push("PatientID".to_string()),
             "N" => order_level_vec.push("PatientName".to_string()),
@@ -185,7 +368,7 @@ fn generate_order_level(
     let mut order_level: String = "".to_string();
 
     for each in order_level_vec {
-        dcm_obj.element_by_name(&each)?;
+        dcm_obj.element_by_name(each)?;
         order_level = format!(
             "{}{}/",
             order_level,
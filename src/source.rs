@@ -0,0 +1,678 @@
+use crate::{
+    gen_id, open_source_file, read_source_file, read_u16, read_u32, read_u64, ExpectedCounts,
+    SourceBytes,
+};
+use anyhow::Result;
+use dicom::object::{FileDicomObject, InMemDicomObject};
+use flate2::{
+    read::{DeflateDecoder, MultiGzDecoder},
+    Crc,
+};
+use rayon::iter::{ParallelBridge, ParallelIterator};
+use std::{
+    fs::{self, create_dir_all},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::{error, info, warn};
+use walkdir::{DirEntry, WalkDir};
+
+// Origin of the input items, a directory walk is the default
+// Other origins, eg archives, DICOMweb or an SCP, implement this trait and are given to
+// source_setup. The items are local paths, a remote or packed source stages its items first
+pub trait InstanceSource: Send + Sync {
+    // Shown in the log when the items are indexed
+    fn describe(&self) -> String;
+
+    // Candidate items of the source, DICOM or not
+    fn items(&self) -> Result<Vec<PathBuf>>;
+
+    // Open an item as a dataset, streamed items are only read up to the pixel data
+    fn open_dataset(
+        &self,
+        item: &Path,
+        streamed: bool,
+    ) -> Result<FileDicomObject<InMemDicomObject>> {
+        open_source_file(item, streamed)
+    }
+
+    // Open an item read whole along with its bytes, the writes copy its unchanged elements from
+    // them. A source that opens its datasets otherwise returns no bytes
+    fn read_dataset(
+        &self,
+        item: &Path,
+    ) -> Result<(FileDicomObject<InMemDicomObject>, Option<SourceBytes>)> {
+        read_source_file(item).map(|(dcm_obj, bytes)| (dcm_obj, Some(bytes)))
+    }
+
+    // Make the item a readable local file before it is checked and opened, eg retrieve it from a
+    // remote server. The items of a directory are already there
+    fn fetch(&self, _item: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    // Directory of a non DICOM item under NON_DICOM, the items of a directory are copied flat
+    fn non_dicom_dir(&self, _item: &Path) -> PathBuf {
+        PathBuf::new()
+    }
+
+    // Instances the source expects per study, eg its DICOMDIR or the counts of a PACS, known
+    // once the items are indexed
+    fn expected_counts(&self) -> Option<ExpectedCounts> {
+        None
+    }
+
+    // Raw bytes of an item, for the items copied as they are
+    fn open_bytes(&self, item: &Path) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(fs::File::open(item)?))
+    }
+
+    // Called once at the end of the run, after the last item is written
+    fn finalize(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Each file under the source directory is an item, the files of the ISO images are extracted
+// to a staging directory when read_iso is set
+#[derive(Debug)]
+pub struct DirectorySource {
+    pub path: PathBuf,
+    pub read_iso: bool,
+    // Directory the ISO images of the source were extracted to
+    staging: Mutex<Option<PathBuf>>,
+}
+
+impl DirectorySource {
+    pub fn new(path: &Path, read_iso: bool) -> Self {
+        DirectorySource {
+            path: path.to_path_buf(),
+            read_iso,
+            staging: Mutex::new(None),
+        }
+    }
+}
+
+impl InstanceSource for DirectorySource {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn items(&self) -> Result<Vec<PathBuf>> {
+        let mut all_files: Vec<_> = WalkDir::new(&self.path)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .par_bridge()
+            .filter(|entry| entry.file_type().is_file())
+            .map(DirEntry::into_path)
+            .collect();
+        if self.read_iso {
+            let (iso_images, files): (Vec<_>, Vec<_>) =
+                all_files.into_iter().partition(|path| is_iso_image(path));
+            all_files = files;
+            if !iso_images.is_empty() {
+                let staging_path = std::env::temp_dir().join(format!("dcmrig_iso_{}", gen_id()));
+                extract_iso_images(&iso_images, &staging_path)?;
+                all_files.extend(
+                    WalkDir::new(&staging_path)
+                        .into_iter()
+                        .filter_map(|entry| entry.ok())
+                        .filter(|entry| entry.file_type().is_file())
+                        .map(DirEntry::into_path),
+                );
+                *self.staging.lock().expect("Failed to lock mutex") = Some(staging_path);
+            }
+        }
+        Ok(all_files)
+    }
+
+    // The DICOMDIR at the root of the source, eg a copied CD
+    fn expected_counts(&self) -> Option<ExpectedCounts> {
+        let dicomdir_path = self.path.join("DICOMDIR");
+        if !dicomdir_path.is_file() {
+            return None;
+        }
+        ExpectedCounts::from_file(&dicomdir_path)
+            .inspect_err(|e| warn!("Study completeness not checked: {}", e))
+            .ok()
+    }
+
+    // Remove the files extracted from ISO images
+    fn finalize(&self) -> Result<()> {
+        if let Some(staging) = self.staging.lock().expect("Failed to lock mutex").take() {
+            fs::remove_dir_all(&staging).unwrap_or_else(|e| {
+                warn!(
+                    "Can't remove the extracted ISO files {}: {}",
+                    staging.display(),
+                    e
+                )
+            });
+        }
+        Ok(())
+    }
+}
+
+const ISO_SECTOR: u64 = 2048;
+
+// ISO9660 image, by extension and the CD001 identifier of the first volume descriptor
+fn is_iso_image(path: &Path) -> bool {
+    let is_iso_extension = path
+        .extension()
+        .map(|extension| extension.eq_ignore_ascii_case("iso"))
+        .unwrap_or(false);
+    if !is_iso_extension {
+        return false;
+    }
+    let mut identifier = [0u8; 5];
+    fs::File::open(path)
+        .and_then(|mut image| {
+            image.seek(SeekFrom::Start(16 * ISO_SECTOR + 1))?;
+            image.read_exact(&mut identifier)
+        })
+        .is_ok()
+        && &identifier == b"CD001"
+}
+
+// Extract the files of each ISO9660 image to its own directory under the staging directory
+// Only the ISO9660 names are read, Joliet and Rock Ridge names are ignored as DICOM media use
+// ISO9660 names
+pub fn extract_iso_images(iso_images: &[PathBuf], staging_path: &Path) -> Result<()> {
+    for (index, iso_image) in iso_images.iter().enumerate() {
+        let image_dir = staging_path.join(format!(
+            "{:03}_{}",
+            index,
+            iso_image.file_stem().unwrap_or_default().to_string_lossy()
+        ));
+        match extract_iso_image(iso_image, &image_dir) {
+            Ok(count) => info!(
+                "{} files read from ISO image {}",
+                count,
+                iso_image.display()
+            ),
+            Err(e) => error!("Can't read ISO image {}: {}", iso_image.display(), e),
+        }
+    }
+    Ok(())
+}
+
+fn extract_iso_image(iso_path: &Path, image_dir: &Path) -> Result<u64> {
+    let mut image = fs::File::open(iso_path)?;
+    let mut sector = vec![0u8; ISO_SECTOR as usize];
+    // The volume descriptors start at sector 16 and end with the terminator, type 255
+    for index in 16.. {
+        image.seek(SeekFrom::Start(index * ISO_SECTOR))?;
+        image.read_exact(&mut sector)?;
+        if &sector[1..6] != b"CD001" || sector[0] == 255 {
+            break;
+        }
+        if sector[0] == 1 {
+            // Directory record of the root directory in the primary volume descriptor
+            let root = &sector[156..190];
+            let mut count = 0;
+            read_iso_directory(
+                &mut image,
+                u64::from(read_u32(root, 2).unwrap_or_default()),
+                u64::from(read_u32(root, 10).unwrap_or_default()),
+                image_dir,
+                0,
+                &mut count,
+            )?;
+            return Ok(count);
+        }
+    }
+    Err(anyhow::anyhow!("No primary volume descriptor"))
+}
+
+fn read_iso_directory(
+    image: &mut fs::File,
+    extent: u64,
+    length: u64,
+    out_dir: &Path,
+    depth: usize,
+    count: &mut u64,
+) -> Result<()> {
+    if depth > 32 {
+        return Err(anyhow::anyhow!("Directories nested too deep"));
+    }
+    create_dir_all(out_dir)?;
+    let mut directory = vec![0u8; length as usize];
+    image.seek(SeekFrom::Start(extent * ISO_SECTOR))?;
+    image.read_exact(&mut directory)?;
+    let mut offset = 0;
+    while offset < directory.len() {
+        let record_length = directory[offset] as usize;
+        // Records don't cross sectors, a zero length is the padding up to the next sector
+        if record_length == 0 {
+            offset = (offset / ISO_SECTOR as usize + 1) * ISO_SECTOR as usize;
+            continue;
+        }
+        let record = match directory.get(offset..offset + record_length) {
+            Some(record) if record_length > 33 => record,
+            _ => break,
+        };
+        offset += record_length;
+        let name_length = record[32] as usize;
+        let raw_name = match record.get(33..33 + name_length) {
+            Some(raw_name) => raw_name,
+            None => continue,
+        };
+        // 0x00 and 0x01 are the current and parent directory
+        if raw_name == [0] || raw_name == [1] {
+            continue;
+        }
+        let name = String::from_utf8_lossy(raw_name);
+        let name = name
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches('.');
+        if name.is_empty() || name.contains(['/', '\\']) || name == ".." {
+            warn!("Invalid name in ISO image: {}", name);
+            continue;
+        }
+        let record_extent = u64::from(read_u32(record, 2).unwrap_or_default());
+        let record_size = u64::from(read_u32(record, 10).unwrap_or_default());
+        if record[25] & 0x02 != 0 {
+            read_iso_directory(
+                image,
+                record_extent,
+                record_size,
+                &out_dir.join(name),
+                depth + 1,
+                count,
+            )?;
+        } else {
+            image.seek(SeekFrom::Start(record_extent * ISO_SECTOR))?;
+            let mut out_file = BufWriter::new(fs::File::create(out_dir.join(name))?);
+            std::io::copy(&mut (&mut *image).take(record_size), &mut out_file)?;
+            out_file.flush()?;
+            *count += 1;
+        }
+    }
+    Ok(())
+}
+
+// Archive formats read as a source, by their signature
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+// Kind of archive of a source file, None for the directories and the other files
+pub fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    if !path.is_file() {
+        return None;
+    }
+    let mut header = vec![];
+    fs::File::open(path)
+        .ok()?
+        .take(512)
+        .read_to_end(&mut header)
+        .ok()?;
+    match header.as_slice() {
+        [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => Some(ArchiveKind::Zip),
+        [0x1f, 0x8b, ..] => Some(ArchiveKind::TarGz),
+        header if header.get(257..262) == Some(b"ustar") => Some(ArchiveKind::Tar),
+        _ => None,
+    }
+}
+
+// The entries of a ZIP or tar archive, extracted one after the other to a staging directory
+// with their directory structure when the items are indexed
+#[derive(Debug)]
+pub struct ArchiveSource {
+    pub path: PathBuf,
+    pub kind: ArchiveKind,
+    staging: PathBuf,
+}
+
+impl ArchiveSource {
+    pub fn new(path: &Path, kind: ArchiveKind) -> Self {
+        ArchiveSource {
+            path: path.to_path_buf(),
+            kind,
+            staging: std::env::temp_dir().join(format!("dcmrig_archive_{}", gen_id())),
+        }
+    }
+
+    fn extract(&self) -> Result<u64> {
+        create_dir_all(&self.staging)?;
+        let archive = fs::File::open(&self.path)?;
+        match self.kind {
+            ArchiveKind::Zip => extract_zip(archive, &self.staging),
+            ArchiveKind::Tar => extract_tar(BufReader::new(archive), &self.staging),
+            ArchiveKind::TarGz => {
+                extract_tar(MultiGzDecoder::new(BufReader::new(archive)), &self.staging)
+            }
+        }
+    }
+}
+
+impl InstanceSource for ArchiveSource {
+    fn describe(&self) -> String {
+        format!("{} ({:?} archive)", self.path.display(), self.kind)
+    }
+
+    fn items(&self) -> Result<Vec<PathBuf>> {
+        match self.extract() {
+            Ok(count) => info!("{} files extracted from {}", count, self.path.display()),
+            Err(e) => {
+                error!("Can't extract {}: {}", self.path.display(), e);
+                self.finalize()?;
+                return Err(e);
+            }
+        }
+        Ok(WalkDir::new(&self.staging)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(DirEntry::into_path)
+            .collect())
+    }
+
+    // The directories of the entry in the archive
+    fn non_dicom_dir(&self, item: &Path) -> PathBuf {
+        item.parent()
+            .and_then(|parent| parent.strip_prefix(&self.staging).ok())
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+
+    // Remove the extracted entries
+    fn finalize(&self) -> Result<()> {
+        if self.staging.exists() {
+            fs::remove_dir_all(&self.staging).unwrap_or_else(|e| {
+                warn!(
+                    "Can't remove the extracted archive {}: {}",
+                    self.staging.display(),
+                    e
+                )
+            });
+        }
+        Ok(())
+    }
+}
+
+// Output path of an archive entry in the staging directory, with its directories created
+// Absolute paths are made relative, the entries leaving the staging directory are skipped
+fn archive_output(staging: &Path, name: &str) -> Result<Option<PathBuf>> {
+    let mut output = staging.to_path_buf();
+    for part in name.split(['/', '\\']) {
+        match part {
+            "" | "." => (),
+            part if part == ".." || part.contains(':') => {
+                warn!("Archive entry {} skipped, its path is not relative", name);
+                return Ok(None);
+            }
+            part => output.push(part),
+        }
+    }
+    if output == staging {
+        return Ok(None);
+    }
+    if let Some(parent) = output.parent() {
+        create_dir_all(parent)?;
+    }
+    Ok(Some(output))
+}
+
+// Copy a stream to a new file, with the CRC-32 and the number of the bytes copied
+fn copy_with_crc(reader: &mut impl Read, output: &Path) -> Result<(u32, u64)> {
+    let mut out_file = BufWriter::new(fs::File::create(output)?);
+    let mut crc = Crc::new();
+    let mut copied = 0;
+    let mut buffer = vec![0u8; 1 << 16];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        crc.update(&buffer[..read]);
+        out_file.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+    out_file.flush()?;
+    Ok((crc.sum(), copied))
+}
+
+// Extract the files of a ZIP archive, stored or deflated, Zip64 included
+pub(crate) fn extract_zip(mut zip: fs::File, staging: &Path) -> Result<u64> {
+    let (entries, directory_size, directory_offset) = zip_directory(&mut zip)?;
+    let mut directory = vec![0u8; directory_size as usize];
+    zip.seek(SeekFrom::Start(directory_offset))?;
+    zip.read_exact(&mut directory)?;
+    let invalid = || anyhow::anyhow!("Invalid ZIP central directory");
+    let mut count = 0;
+    let mut pos = 0;
+    for _ in 0..entries {
+        if read_u32(&directory, pos) != Some(0x02014b50) {
+            return Err(invalid());
+        }
+        let header = directory.get(pos..pos + 46).ok_or_else(invalid)?;
+        let flags = read_u16(header, 8).unwrap_or_default();
+        let method = read_u16(header, 10).unwrap_or_default();
+        let crc = read_u32(header, 16).unwrap_or_default();
+        let mut compressed_size = u64::from(read_u32(header, 20).unwrap_or_default());
+        let mut size = u64::from(read_u32(header, 24).unwrap_or_default());
+        let name_length = usize::from(read_u16(header, 28).unwrap_or_default());
+        let extra_length = usize::from(read_u16(header, 30).unwrap_or_default());
+        let comment_length = usize::from(read_u16(header, 32).unwrap_or_default());
+        let mut offset = u64::from(read_u32(header, 42).unwrap_or_default());
+        let name = directory
+            .get(pos + 46..pos + 46 + name_length)
+            .ok_or_else(invalid)?;
+        let name = String::from_utf8_lossy(name).to_string();
+        let extra = directory
+            .get(pos + 46 + name_length..pos + 46 + name_length + extra_length)
+            .ok_or_else(invalid)?;
+        zip64_fields(extra, [&mut size, &mut compressed_size, &mut offset]);
+        pos += 46 + name_length + extra_length + comment_length;
+
+        if name.ends_with('/') {
+            continue;
+        }
+        if flags & 1 != 0 {
+            warn!("Archive entry {} skipped, it is encrypted", name);
+            continue;
+        }
+        if method != 0 && method != 8 {
+            warn!(
+                "Archive entry {} skipped, compression method {} is not supported",
+                name, method
+            );
+            continue;
+        }
+        let output = match archive_output(staging, &name)? {
+            Some(output) => output,
+            None => continue,
+        };
+        // The data follows the local header, its extra field can differ from the central one
+        let mut local_header = [0u8; 30];
+        zip.seek(SeekFrom::Start(offset))?;
+        zip.read_exact(&mut local_header)?;
+        if read_u32(&local_header, 0) != Some(0x04034b50) {
+            return Err(anyhow::anyhow!("Invalid ZIP local header of {}", name));
+        }
+        let data_offset = offset
+            + 30
+            + u64::from(read_u16(&local_header, 26).unwrap_or_default())
+            + u64::from(read_u16(&local_header, 28).unwrap_or_default());
+        zip.seek(SeekFrom::Start(data_offset))?;
+        let data = (&mut zip).take(compressed_size);
+        let copied = match method {
+            0 => copy_with_crc(&mut { data }, &output),
+            _ => copy_with_crc(&mut DeflateDecoder::new(data), &output),
+        };
+        match copied {
+            Ok(copied) if copied == (crc, size) => count += 1,
+            // A corrupted entry is not indexed, the other entries are still read
+            copied => {
+                match copied {
+                    Ok(_) => error!("Archive entry {} skipped, it is corrupted", name),
+                    Err(e) => error!("Archive entry {} skipped: {}", name, e),
+                }
+                fs::remove_file(&output).unwrap_or_default();
+            }
+        }
+    }
+    Ok(count)
+}
+
+// Entry count, size and offset of the central directory of a ZIP archive, from its end records
+pub(crate) fn zip_directory(zip: &mut fs::File) -> Result<(u64, u64, u64)> {
+    let length = zip.seek(SeekFrom::End(0))?;
+    // End record, up to 64 KiB of comment, and the Zip64 locator before it
+    let tail_length = length.min(20 + 22 + 65535);
+    let mut tail = vec![0u8; tail_length as usize];
+    zip.seek(SeekFrom::Start(length - tail_length))?;
+    zip.read_exact(&mut tail)?;
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|pos| read_u32(&tail, *pos) == Some(0x06054b50))
+        .ok_or_else(|| anyhow::anyhow!("No ZIP end of central directory"))?;
+    if end >= 20 && read_u32(&tail, end - 20) == Some(0x07064b50) {
+        let mut record = [0u8; 56];
+        zip.seek(SeekFrom::Start(
+            read_u64(&tail, end - 12).unwrap_or_default(),
+        ))?;
+        zip.read_exact(&mut record)?;
+        if read_u32(&record, 0) != Some(0x06064b50) {
+            return Err(anyhow::anyhow!("Invalid Zip64 end of central directory"));
+        }
+        return Ok((
+            read_u64(&record, 32).unwrap_or_default(),
+            read_u64(&record, 40).unwrap_or_default(),
+            read_u64(&record, 48).unwrap_or_default(),
+        ));
+    }
+    Ok((
+        u64::from(read_u16(&tail, end + 10).unwrap_or_default()),
+        u64::from(read_u32(&tail, end + 12).unwrap_or_default()),
+        u64::from(read_u32(&tail, end + 16).unwrap_or_default()),
+    ))
+}
+
+// Sizes and offset of a central directory entry set to 0xFFFFFFFF are in the Zip64 extra field,
+// in this order
+pub(crate) fn zip64_fields(extra: &[u8], fields: [&mut u64; 3]) {
+    let mut pos = 0;
+    while let (Some(id), Some(length)) = (read_u16(extra, pos), read_u16(extra, pos + 2)) {
+        if id == 1 {
+            let mut field_pos = pos + 4;
+            for field in fields.into_iter().filter(|field| **field == 0xFFFF_FFFF) {
+                if let Some(value) = read_u64(extra, field_pos) {
+                    *field = value;
+                    field_pos += 8;
+                }
+            }
+            return;
+        }
+        pos += 4 + usize::from(length);
+    }
+}
+
+// Extract the regular files of a tar stream, ustar with the GNU long names and the pax paths
+// Links and devices are skipped
+pub(crate) fn extract_tar(mut tar: impl Read, staging: &Path) -> Result<u64> {
+    let mut header = [0u8; 512];
+    let mut long_name: Option<String> = None;
+    let mut count = 0;
+    loop {
+        match tar.read_exact(&mut header) {
+            // Some writers leave out the end blocks
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            read => read?,
+        }
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        // The checksum field counts as spaces
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(pos, b)| match (148..156).contains(&pos) {
+                true => 32,
+                false => u64::from(*b),
+            })
+            .sum();
+        if tar_number(&header[148..156]) != Some(sum) {
+            return Err(anyhow::anyhow!("Invalid tar header"));
+        }
+        let size = tar_number(&header[124..136])
+            .ok_or_else(|| anyhow::anyhow!("Invalid tar entry size"))?;
+        let mut data = (&mut tar).take(size);
+        match header[156] {
+            0 | b'0' | b'7' => {
+                let name = long_name.take().unwrap_or_else(|| tar_name(&header));
+                if let Some(output) = archive_output(staging, &name)? {
+                    if copy_with_crc(&mut data, &output)?.1 != size {
+                        return Err(anyhow::anyhow!("Truncated tar entry {}", name));
+                    }
+                    count += 1;
+                }
+            }
+            b'L' => {
+                let mut name = vec![];
+                data.read_to_end(&mut name)?;
+                let name = String::from_utf8_lossy(&name);
+                long_name = Some(name.trim_end_matches('\0').to_string());
+            }
+            b'x' => {
+                let mut records = vec![];
+                data.read_to_end(&mut records)?;
+                long_name = pax_path(&records);
+            }
+            // Directories, links, devices and the global pax headers
+            _ => long_name = None,
+        }
+        // The rest of a skipped entry and the padding up to the next block
+        std::io::copy(&mut data, &mut std::io::sink())?;
+        let padding = (512 - size % 512) % 512;
+        std::io::copy(&mut (&mut tar).take(padding), &mut std::io::sink())?;
+    }
+    Ok(count)
+}
+
+// Name of a tar entry, with the ustar prefix
+fn tar_name(header: &[u8; 512]) -> String {
+    let field = |range: Range<usize>| {
+        let bytes = &header[range];
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).to_string()
+    };
+    let name = field(0..100);
+    match &header[257..263] == b"ustar\0" && header[345] != 0 {
+        true => format!("{}/{}", field(345..500), name),
+        false => name,
+    }
+}
+
+// Path of the entry after a pax extended header, from its "<length> path=<path>\n" record
+fn pax_path(records: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(records).lines().find_map(|record| {
+        record
+            .split_once(' ')?
+            .1
+            .strip_prefix("path=")
+            .map(str::to_string)
+    })
+}
+
+// Numeric field of a tar header, octal or base-256 for the large sizes
+fn tar_number(field: &[u8]) -> Option<u64> {
+    if field.first()? & 0x80 != 0 {
+        return Some(
+            field[1..]
+                .iter()
+                .fold(u64::from(field[0] & 0x7f), |number, b| {
+                    number << 8 | u64::from(*b)
+                }),
+        );
+    }
+    let text = String::from_utf8_lossy(field);
+    u64::from_str_radix(text.trim_matches(['\0', ' ']), 8).ok()
+}
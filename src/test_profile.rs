@@ -0,0 +1,385 @@
+use crate::cookbook_parser::{parse_cookbook_file, CookBookConfig};
+use crate::deid::{deid_dcm_object, generate_mapping_dict};
+use anyhow::Result;
+use dcmrig_rs::TagPath;
+use dicom::{
+    core::{header::Header, DataDictionary, VR},
+    object::{open_file, FileDicomObject, InMemDicomObject, StandardDataDictionary},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+};
+use tracing::{error, info, warn};
+use walkdir::WalkDir;
+
+// Expected state of a fixture after the profile is applied
+// Stored next to the fixture as <fixture_name>.golden.toml
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct GoldenHeader {
+    // The fixture is expected to be skipped, eg its ID is not in the mapping table
+    #[serde(default)]
+    skipped: bool,
+    // Tags that must not be present after the profile is applied
+    #[serde(default)]
+    absent: Vec<String>,
+    // Tag name > expected value. Multiple values are separated by a backslash
+    #[serde(default)]
+    expected: BTreeMap<String, String>,
+}
+
+pub fn dicom_test_profile(
+    profile_path: PathBuf,
+    fixtures_path: PathBuf,
+    mapping_table: Option<PathBuf>,
+    bless: bool,
+) -> Result<()> {
+    info!(
+        "Testing the profile >> PROFILE: {} | FIXTURES: {}",
+        profile_path.display(),
+        fixtures_path.display()
+    );
+
+    let cookbook = parse_cookbook_file(&profile_path)?;
+    let mapping_table = mapping_table.unwrap_or_else(|| fixtures_path.join("mapping_table.txt"));
+    if !mapping_table.exists() {
+        error!(
+            "Mapping table not found: {}. Provide one with --mapping-table",
+            mapping_table.display()
+        );
+        exit(1);
+    }
+    let mapping_dict = generate_mapping_dict(&mapping_table)?;
+
+    let mut total_fixtures = 0;
+    let mut failed_fixtures = 0;
+    for entry in WalkDir::new(&fixtures_path)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+    {
+        let fixture_path = entry.path();
+        if fixture_path == mapping_table || is_golden_file(fixture_path) {
+            continue;
+        }
+        let dcm_obj = match open_file(fixture_path) {
+            Ok(obj) => obj,
+            Err(_) => {
+                warn!("Not a DICOM fixture, ignoring: {}", fixture_path.display());
+                continue;
+            }
+        };
+        total_fixtures += 1;
+        let golden_path = golden_path_for(fixture_path);
+        let result = deid_dcm_object(&dcm_obj, &mapping_dict, &cookbook)?;
+
+        if bless {
+            let golden = generate_golden_header(result.as_ref(), &cookbook);
+            fs::write(&golden_path, toml::to_string(&golden)?)?;
+            info!("BLESSED {}", golden_path.display());
+            continue;
+        }
+
+        let golden: GoldenHeader = match fs::read_to_string(&golden_path) {
+            Ok(content) => match toml::from_str(&content) {
+                Ok(golden) => golden,
+                Err(e) => {
+                    error!("Invalid golden file {}\n{}", golden_path.display(), e);
+                    failed_fixtures += 1;
+                    continue;
+                }
+            },
+            Err(_) => {
+                error!(
+                    "FAIL {} > Golden file not found: {}",
+                    fixture_path.display(),
+                    golden_path.display()
+                );
+                failed_fixtures += 1;
+                continue;
+            }
+        };
+
+        let mismatches = compare_with_golden(result.as_ref(), &golden);
+        if mismatches.is_empty() {
+            info!("PASS {}", fixture_path.display());
+        } else {
+            failed_fixtures += 1;
+            error!("FAIL {}", fixture_path.display());
+            mismatches.iter().for_each(|m| error!("    {}", m));
+        }
+    }
+
+    if bless {
+        info!("Golden files written for {} fixtures", total_fixtures);
+        return Ok(());
+    }
+    info!("Total fixtures: {}", total_fixtures);
+    info!("Passed: {}", total_fixtures - failed_fixtures);
+    info!("Failed: {}", failed_fixtures);
+    if total_fixtures == 0 {
+        warn!("No DICOM fixtures found in {}", fixtures_path.display());
+    }
+    if failed_fixtures > 0 {
+        exit(1);
+    }
+    Ok(())
+}
+
+fn is_golden_file(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(".golden.toml")
+}
+
+fn golden_path_for(fixture_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.golden.toml", fixture_path.display()))
+}
+
+// Compare the profile output with the golden header and list every difference
+fn compare_with_golden(
+    result: Option<&FileDicomObject<InMemDicomObject>>,
+    golden: &GoldenHeader,
+) -> Vec<String> {
+    let mut mismatches = vec![];
+    let dcm_obj = match (result, golden.skipped) {
+        (None, true) => return mismatches,
+        (None, false) => {
+            mismatches.push(
+                "Fixture was skipped by the profile, expected it to be processed".to_string(),
+            );
+            return mismatches;
+        }
+        (Some(_), true) => {
+            mismatches.push("Fixture was processed, expected it to be skipped".to_string());
+            return mismatches;
+        }
+        (Some(dcm_obj), false) => dcm_obj,
+    };
+
+    for (tag_name, expected_value) in &golden.expected {
+        match dcm_obj.element_by_name(tag_name) {
+            Ok(element) => {
+                let value = element
+                    .to_str()
+                    .map(|v| v.trim().to_string())
+                    .unwrap_or_default();
+                if value != expected_value.trim() {
+                    mismatches.push(format!(
+                        "{}: expected '{}' found '{}'",
+                        tag_name,
+                        expected_value.trim(),
+                        value
+                    ));
+                }
+            }
+            Err(_) => mismatches.push(format!(
+                "{}: expected '{}' but the tag is missing",
+                tag_name,
+                expected_value.trim()
+            )),
+        }
+    }
+    for tag_name in &golden.absent {
+        let present = match TagPath::from_str(tag_name) {
+            Ok(tag_path) => !tag_path.values(dcm_obj).is_empty(),
+            Err(_) => dcm_obj.element_by_name(tag_name).is_ok(),
+        };
+        if present {
+            mismatches.push(format!("{}: expected to be absent", tag_name));
+        }
+    }
+    mismatches
+}
+
+// Capture the current output of the profile as the golden header
+fn generate_golden_header(
+    result: Option<&FileDicomObject<InMemDicomObject>>,
+    cookbook: &CookBookConfig,
+) -> GoldenHeader {
+    let dcm_obj = match result {
+        Some(dcm_obj) => dcm_obj,
+        None => {
+            return GoldenHeader {
+                skipped: true,
+                ..Default::default()
+            }
+        }
+    };
+    let mut expected = BTreeMap::new();
+    for element in dcm_obj.iter() {
+        // Private tags can't be addressed by name
+        if element.tag().group() % 2 == 1 {
+            continue;
+        }
+        if matches!(
+            element.vr(),
+            VR::SQ | VR::OB | VR::OW | VR::OF | VR::OD | VR::OL | VR::OV | VR::UN
+        ) {
+            continue;
+        }
+        let tag_name = match StandardDataDictionary.by_tag(element.tag()) {
+            Some(entry) => entry.alias.to_string(),
+            None => continue,
+        };
+        if let Ok(value) = element.to_str() {
+            expected.insert(tag_name, value.trim().to_string());
+        }
+    }
+    let absent = cookbook
+        .delete_tags
+        .iter()
+        .filter(|entry| entry.values(dcm_obj).is_empty())
+        .map(|entry| entry.to_string())
+        .collect();
+    GoldenHeader {
+        skipped: false,
+        absent,
+        expected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cookbook_parser::parse_cookbook_file;
+    use dcmrig_rs::gen_id;
+    use dicom::{
+        core::{DataElement, PrimitiveValue},
+        dictionary_std::{
+            tags,
+            uids::{EXPLICIT_VR_LITTLE_ENDIAN, SECONDARY_CAPTURE_IMAGE_STORAGE},
+        },
+        object::FileMetaTableBuilder,
+    };
+
+    fn profile_output() -> FileDicomObject<InMemDicomObject> {
+        let mut dcm_obj = InMemDicomObject::new_empty();
+        dcm_obj.put(DataElement::new(
+            tags::PATIENT_ID,
+            VR::LO,
+            PrimitiveValue::from("ANON0001"),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::PATIENT_NAME,
+            VR::PN,
+            PrimitiveValue::from("ANON0001 "),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::IMAGE_TYPE,
+            VR::CS,
+            PrimitiveValue::from("ORIGINAL\\PRIMARY"),
+        ));
+        dcm_obj
+            .with_meta(
+                FileMetaTableBuilder::new()
+                    .media_storage_sop_class_uid(SECONDARY_CAPTURE_IMAGE_STORAGE)
+                    .media_storage_sop_instance_uid("2.25.1")
+                    .transfer_syntax(EXPLICIT_VR_LITTLE_ENDIAN),
+            )
+            .unwrap()
+    }
+
+    fn golden(toml_content: &str) -> GoldenHeader {
+        toml::from_str(toml_content).unwrap()
+    }
+
+    #[test]
+    fn golden_paths() {
+        let golden_path = golden_path_for(Path::new("fixtures/ct/1.dcm"));
+        assert_eq!(golden_path, PathBuf::from("fixtures/ct/1.dcm.golden.toml"));
+        assert!(is_golden_file(&golden_path));
+        assert!(!is_golden_file(Path::new("fixtures/ct/1.dcm")));
+    }
+
+    #[test]
+    fn matching_output_has_no_mismatches() {
+        let dcm_obj = profile_output();
+        let golden = golden(
+            r#"
+absent = ["OtherPatientIDs", "RequestAttributesSequence[*].AccessionNumber"]
+[expected]
+PatientID = "ANON0001"
+PatientName = "ANON0001"
+ImageType = 'ORIGINAL\PRIMARY'
+"#,
+        );
+        assert!(compare_with_golden(Some(&dcm_obj), &golden).is_empty());
+    }
+
+    #[test]
+    fn every_difference_is_listed() {
+        let dcm_obj = profile_output();
+        let golden = golden(
+            r#"
+absent = ["PatientName"]
+[expected]
+PatientID = "ANON0002"
+StudyID = "1"
+"#,
+        );
+        assert_eq!(
+            compare_with_golden(Some(&dcm_obj), &golden),
+            vec![
+                "PatientID: expected 'ANON0002' found 'ANON0001'",
+                "StudyID: expected '1' but the tag is missing",
+                "PatientName: expected to be absent",
+            ]
+        );
+    }
+
+    #[test]
+    fn skipped_fixtures() {
+        let dcm_obj = profile_output();
+        let skipped = golden("skipped = true");
+        assert!(compare_with_golden(None, &skipped).is_empty());
+        assert_eq!(
+            compare_with_golden(Some(&dcm_obj), &skipped),
+            vec!["Fixture was processed, expected it to be skipped"]
+        );
+        assert_eq!(
+            compare_with_golden(None, &GoldenHeader::default()),
+            vec!["Fixture was skipped by the profile, expected it to be processed"]
+        );
+    }
+
+    #[test]
+    fn blessed_golden_header_matches_its_output() {
+        let test_dir = std::env::temp_dir().join(format!("dcmrig_test_{}", gen_id()));
+        fs::create_dir_all(&test_dir).unwrap();
+        let profile_path = test_dir.join("profile.toml");
+        fs::write(
+            &profile_path,
+            r#"
+[matchid]
+tag = "PatientID"
+[mask]
+tags = ["PatientID"]
+vrs = ["PN"]
+[delete]
+tags = ["OtherPatientIDs", "PatientName"]
+private_tags = false
+[add]
+tags.PatientIdentityRemoved = "YES"
+"#,
+        )
+        .unwrap();
+        let cookbook = parse_cookbook_file(&profile_path).unwrap();
+        let dcm_obj = profile_output();
+
+        let golden = generate_golden_header(Some(&dcm_obj), &cookbook);
+        assert!(!golden.skipped);
+        assert_eq!(golden.absent, vec!["OtherPatientIDs"]);
+        assert_eq!(golden.expected["PatientName"], "ANON0001");
+        assert_eq!(golden.expected["ImageType"], "ORIGINAL\\PRIMARY");
+        // The golden file is read back the way test-profile reads it
+        let golden = toml::from_str(&toml::to_string(&golden).unwrap()).unwrap();
+        assert!(compare_with_golden(Some(&dcm_obj), &golden).is_empty());
+        assert!(generate_golden_header(None, &cookbook).skipped);
+
+        fs::remove_dir_all(&test_dir).unwrap();
+    }
+}
@@ -0,0 +1,1579 @@
+use crate::{
+    check_if_dup_exists,
+    checkpoint::Checkpoint,
+    confidentiality::basic_profile_tags,
+    copy_non_dicom_files, create_phi_dir, csv_field, duplicate_instances_csv, failed_case_copy,
+    filter::FILTERED_OUT_DIR,
+    generate_dicom_file_name, generate_dicom_file_path, identifying_values, incomplete_reason,
+    json_string, modality_mismatch, phi, read_transfer_syntax, run_hook, same_file_content,
+    sink::{DiscardSink, FileSystemSink, OutputSink},
+    sop_instance_uid,
+    source::{DirectorySource, InstanceSource},
+    spliceable_transfer_syntax, tag_keyword, unique_output_path, write_dicom_file,
+    write_review_note, write_streamed_dicom_file, ChecksumTracker, DuplicateInstance,
+    DuplicateSuffix, ExpectedCounts, FileResult, FileTiming, IdentityTracker, IncompleteAction,
+    LargeFileAction, NonDicomAction, OutputTemplate, PostHooks, ReviewAction, SourceBytes,
+    StudyCompleteness, StudyFiles, TagChange, TagCounts, TimingTracker, COMPLETENESS_FILE,
+    DUPLICATES_FILE, FAILED_CASES_DIR, INCOMPLETE_DIR, LARGE_FILES_DIR, MANIFEST_FILE,
+    MODALITY_CHECK_FILE, RESULTS_FILE, REVIEW_REQUIRED_DIR, TAG_CHANGES_FILE, TAG_CHANGES_LOGGED,
+    WINDOWS_MAX_PATH,
+};
+use anyhow::Result;
+use dicom::{
+    core::chrono::{DateTime, Local},
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject, Tag},
+};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, create_dir_all},
+    io::{BufWriter, Read, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tracing::{debug, error, info, warn};
+
+// Separate progress bars for each stage of the run
+#[derive(Clone)]
+pub struct RunProgress {
+    pub multi: MultiProgress,
+    pub scanned: ProgressBar,
+    pub transformed: ProgressBar,
+    pub written: ProgressBar,
+    pub failed: ProgressBar,
+    pub sent: ProgressBar,
+}
+
+impl RunProgress {
+    pub fn new(total_len: u64) -> Result<Self> {
+        let multi = MultiProgress::new();
+        let bar_style = ProgressStyle::with_template(
+            "{spinner:.green} {prefix:>12} {percent:>3}% [{elapsed_precise}] [{wide_bar:.cyan/blue}] ({pos}/{len}, ETA {eta})",
+        )?;
+        let count_style = ProgressStyle::with_template("  {prefix:>12} {pos}")?;
+        let add_bar = |name: &'static str, style: &ProgressStyle, len: u64| {
+            let bar = multi.add(ProgressBar::new(len));
+            bar.set_style(style.clone());
+            bar.set_prefix(name);
+            bar
+        };
+        let scanned = add_bar("Scanned", &bar_style, total_len);
+        let transformed = add_bar("Transformed", &bar_style, total_len);
+        let written = add_bar("Written", &bar_style, total_len);
+        let failed = add_bar("Failed", &count_style, total_len);
+        // Only shown once a network destination is in use
+        let sent = ProgressBar::hidden();
+        sent.set_style(bar_style.clone());
+        sent.set_prefix("Sent");
+        Ok(RunProgress {
+            multi,
+            scanned,
+            transformed,
+            written,
+            failed,
+            sent,
+        })
+    }
+
+    // Show the network sent bar with the given total
+    pub fn show_sent(&self, total_len: u64) {
+        self.sent.set_length(total_len);
+        self.multi.add(self.sent.clone());
+    }
+
+    // Stop all bars, keeping the final count of each stage
+    pub fn finish(&self) {
+        for bar in [
+            &self.scanned,
+            &self.transformed,
+            &self.written,
+            &self.failed,
+            &self.sent,
+        ] {
+            bar.abandon();
+        }
+    }
+}
+
+// Shared state of a run, cloned into every task processing a file
+#[derive(Clone)]
+pub struct RunTracker {
+    pub progress: RunProgress,
+    pub timings: TimingTracker,
+    pub skipped: Arc<AtomicU64>,
+    // SeriesInstanceUID > SeriesClass
+    pub series_classes: Arc<Mutex<HashMap<String, String>>>,
+    pub reviewed: Arc<AtomicU64>,
+    pub excluded: Arc<AtomicU64>,
+    // Output path > SHA-256, only collected when a manifest is written
+    pub checksums: Option<ChecksumTracker>,
+    // Identities of the source patients, to find different patients sharing one PatientID
+    pub identities: IdentityTracker,
+    pub hooks: Arc<PostHooks>,
+    // StudyInstanceUID > written files, only collected for the post study hook
+    pub study_files: Arc<Mutex<BTreeMap<String, StudyFiles>>>,
+    // Outcome of each input file for results.csv
+    pub results: Arc<Mutex<Vec<FileResult>>>,
+    pub duplicate_suffix: DuplicateSuffix,
+    // --path-template and --name-template
+    pub path_template: Option<Arc<OutputTemplate>>,
+    pub name_template: Option<Arc<OutputTemplate>>,
+    // Output files renamed because their name was taken
+    pub collisions: Arc<AtomicU64>,
+    // Output paths longer than WINDOWS_MAX_PATH, on Windows
+    pub long_paths: Arc<AtomicU64>,
+    // Zero byte and truncated items, and what to do with them
+    pub incomplete: Arc<AtomicU64>,
+    pub incomplete_action: IncompleteAction,
+    // Items that look truncated are read again while they grow within this time
+    pub incomplete_wait: Duration,
+    // Source and error of the last file that failed, for the status file
+    pub last_error: Arc<Mutex<Option<String>>>,
+    // Files larger than this many bytes are read without the pixel data, which is then
+    // copied from the source file in chunks when written
+    pub stream_above: u64,
+    // Files over stream_above that are quarantined instead
+    pub large_file_action: LargeFileAction,
+    pub quarantined: Arc<AtomicU64>,
+    // Copy, quarantine or scrub the non DICOM items
+    pub non_dicom_action: NonDicomAction,
+    // Text items held to be scrubbed at the end of the run
+    pub sidecars: Arc<Mutex<Vec<PathBuf>>>,
+    // Identifiers of the source files > first source with them, only collected to scrub
+    pub sidecar_identifiers: Arc<Mutex<HashMap<Vec<String>, PathBuf>>>,
+    // Destination of the written instances
+    pub sink: Arc<dyn OutputSink>,
+    // Write each instance on the worker that read it instead of a spawned task
+    pub series_affinity: bool,
+    // Hide the directories with data that is not deidentified from the file browsers
+    pub hide_phi_dirs: bool,
+    // Nothing is written to the destination, the changes of each file are recorded instead
+    pub dry_run: bool,
+    // Origin of the input items
+    pub source: Arc<dyn InstanceSource>,
+    // Instances expected per study, for the completeness report
+    pub expected_counts: Option<Arc<ExpectedCounts>>,
+    // Source file > key of its study, only collected with expected counts
+    pub source_studies: Arc<Mutex<HashMap<PathBuf, String>>>,
+    // Source file > why its Modality doesn't fit its SOP class or pixels
+    pub modality_mismatches: Arc<Mutex<HashMap<PathBuf, String>>>,
+    // SOPInstanceUID > source file kept, only collected with --dedup
+    pub kept_instances: Option<Arc<Mutex<HashMap<String, PathBuf>>>>,
+    // Source files skipped because their SOPInstanceUID was already read
+    pub duplicates: Arc<Mutex<Vec<DuplicateInstance>>>,
+    // Top level standard tag > instances that had it removed, replaced, added or kept by the
+    // transform, over the transformed instances counted in tag_change_instances
+    pub tag_changes: Arc<Mutex<BTreeMap<Tag, TagCounts>>>,
+    pub tag_change_instances: Arc<AtomicU64>,
+    // Transformed instances with private tags removed
+    pub private_tags_removed: Arc<AtomicU64>,
+    // Outputs of the files done before a resume, only set when resuming
+    pub resumed_outputs: Option<Arc<Mutex<HashSet<String>>>>,
+    // Source file > its bytes, from the read of the items that are not streamed until their
+    // result is recorded
+    pub source_bytes: Arc<Mutex<HashMap<PathBuf, SourceBytes>>>,
+}
+
+impl RunTracker {
+    pub fn new(progress: RunProgress) -> Self {
+        RunTracker {
+            progress,
+            timings: Arc::new(Mutex::new(vec![])),
+            skipped: Arc::new(AtomicU64::new(0)),
+            series_classes: Arc::new(Mutex::new(HashMap::new())),
+            reviewed: Arc::new(AtomicU64::new(0)),
+            excluded: Arc::new(AtomicU64::new(0)),
+            checksums: None,
+            identities: Arc::new(Mutex::new(BTreeMap::new())),
+            hooks: Arc::new(PostHooks::default()),
+            study_files: Arc::new(Mutex::new(BTreeMap::new())),
+            results: Arc::new(Mutex::new(vec![])),
+            duplicate_suffix: DuplicateSuffix::default(),
+            path_template: None,
+            name_template: None,
+            collisions: Arc::new(AtomicU64::new(0)),
+            long_paths: Arc::new(AtomicU64::new(0)),
+            incomplete: Arc::new(AtomicU64::new(0)),
+            incomplete_action: IncompleteAction::default(),
+            incomplete_wait: Duration::ZERO,
+            last_error: Arc::new(Mutex::new(None)),
+            stream_above: u64::MAX,
+            large_file_action: LargeFileAction::default(),
+            quarantined: Arc::new(AtomicU64::new(0)),
+            non_dicom_action: NonDicomAction::default(),
+            sidecars: Arc::new(Mutex::new(vec![])),
+            sidecar_identifiers: Arc::new(Mutex::new(HashMap::new())),
+            sink: Arc::new(FileSystemSink),
+            series_affinity: false,
+            hide_phi_dirs: false,
+            dry_run: false,
+            source: Arc::new(DirectorySource::new(Path::new("."), false)),
+            expected_counts: None,
+            source_studies: Arc::new(Mutex::new(HashMap::new())),
+            modality_mismatches: Arc::new(Mutex::new(HashMap::new())),
+            kept_instances: None,
+            duplicates: Arc::new(Mutex::new(vec![])),
+            tag_changes: Arc::new(Mutex::new(BTreeMap::new())),
+            tag_change_instances: Arc::new(AtomicU64::new(0)),
+            private_tags_removed: Arc::new(AtomicU64::new(0)),
+            resumed_outputs: None,
+            source_bytes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Directory of an output instance, from the path template or the default layout
+    pub fn output_dir(
+        &self,
+        dicom_tags_values: HashMap<String, String>,
+        dcm_obj: &InMemDicomObject,
+        destination_path: &Path,
+    ) -> Result<String> {
+        match &self.path_template {
+            Some(template) => Ok(format!(
+                "{}/{}",
+                destination_path.display(),
+                template.render(dcm_obj, &dicom_tags_values)
+            )),
+            None => generate_dicom_file_path(dicom_tags_values, destination_path),
+        }
+    }
+
+    // File name of an output instance, from the name template or the default name
+    pub fn output_file_name(
+        &self,
+        dicom_tags_values: &HashMap<String, String>,
+        dcm_obj: &InMemDicomObject,
+        prefix: String,
+    ) -> Result<String> {
+        match &self.name_template {
+            Some(template) => {
+                let file_name = template.render(dcm_obj, dicom_tags_values);
+                match file_name.to_lowercase().ends_with(".dcm") {
+                    true => Ok(file_name),
+                    false => Ok(format!("{}.dcm", file_name)),
+                }
+            }
+            None => generate_dicom_file_name(dicom_tags_values, prefix),
+        }
+    }
+
+    // Write the modified object to the sink, the unchanged elements and the pixel data of the
+    // streamed files come from the source file. Returns the SHA-256 when checksums are collected
+    pub fn write_instance(
+        &self,
+        full_path: &str,
+        source_path: &Path,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        unchanged: &HashSet<Tag>,
+    ) -> Result<Option<String>> {
+        let streamed = self.streams(source_path);
+        let source_bytes = self
+            .source_bytes
+            .lock()
+            .expect("Failed to lock mutex")
+            .get(source_path)
+            .cloned();
+        self.sink
+            .write_instance(
+                Path::new(full_path),
+                self.checksums.is_some(),
+                &mut |to| match streamed {
+                    true => write_streamed_dicom_file(source_path, dcm_obj, unchanged, to),
+                    false => write_dicom_file(
+                        source_path,
+                        source_bytes.as_deref(),
+                        dcm_obj,
+                        unchanged,
+                        to,
+                    ),
+                },
+            )
+    }
+
+    // Open an item as a dataset. An item that looks partially transferred is read again as long
+    // as it grows within incomplete_wait, for sources that are still being copied
+    pub fn open_item(
+        &self,
+        item: &Path,
+        streamed: bool,
+    ) -> Result<FileDicomObject<InMemDicomObject>> {
+        let item_size = || fs::metadata(item).map(|metadata| metadata.len()).ok();
+        let open = || match streamed {
+            true => self.source.open_dataset(item, true),
+            // The bytes are kept for the write
+            false => self.source.read_dataset(item).map(|(dcm_obj, bytes)| {
+                if let Some(bytes) = bytes {
+                    self.source_bytes
+                        .lock()
+                        .expect("Failed to lock mutex")
+                        .insert(item.to_path_buf(), bytes);
+                }
+                dcm_obj
+            }),
+        };
+        let mut opened = open();
+        while let Err(e) = &opened {
+            if self.incomplete_wait.is_zero()
+                || incomplete_reason(self.source.as_ref(), item, e).is_none()
+            {
+                break;
+            }
+            let size = item_size();
+            thread::sleep(self.incomplete_wait);
+            if item_size() == size {
+                break;
+            }
+            info!("{} is still growing, reading it again", item.display());
+            opened = open();
+        }
+        opened
+    }
+
+    // Copy an item that looks partially transferred to INCOMPLETE, or only record it
+    // Returns false when the error is not one of an incomplete item
+    pub fn route_incomplete(
+        &self,
+        item: &Path,
+        destination_path: &Path,
+        error: &anyhow::Error,
+    ) -> bool {
+        let reason = match incomplete_reason(self.source.as_ref(), item, error) {
+            Some(reason) => reason,
+            None => return false,
+        };
+        warn!("Incomplete {}: {}", item.display(), reason);
+        self.incomplete.fetch_add(1, Ordering::Relaxed);
+        let mut result = FileResult::new(item, "incomplete");
+        result.error = reason;
+        if self.incomplete_action == IncompleteAction::Copy {
+            match self
+                .phi_dir(destination_path, INCOMPLETE_DIR)
+                .and_then(|dir| self.copy_aside(item, &dir))
+            {
+                Ok(output) => result.output = output.display().to_string(),
+                Err(e) => error!("Can't copy {} to {}: {}", item.display(), INCOMPLETE_DIR, e),
+            }
+        }
+        self.record_result(result);
+        true
+    }
+
+    // Directory of the destination with data that is not deidentified, created protected
+    // A dry run doesn't create it
+    fn phi_dir(&self, destination_path: &Path, name: &str) -> std::io::Result<PathBuf> {
+        let dir = destination_path.join(name);
+        if !self.dry_run {
+            create_phi_dir(&dir, self.hide_phi_dirs)?;
+        }
+        Ok(dir)
+    }
+
+    // Copy an item as it is to a directory of the destination, the name is kept when free
+    // A dry run only returns the path it would be copied to
+    fn copy_aside(&self, item: &Path, directory: &Path) -> std::io::Result<PathBuf> {
+        let output = PathBuf::from(check_if_dup_exists(
+            directory
+                .join(item.file_name().unwrap_or_default())
+                .display()
+                .to_string(),
+        ));
+        if self.dry_run {
+            return Ok(output);
+        }
+        create_dir_all(directory)?;
+        std::io::copy(
+            &mut self
+                .source
+                .open_bytes(item)
+                .map_err(std::io::Error::other)?,
+            &mut fs::File::create(&output)?,
+        )?;
+        Ok(output)
+    }
+
+    // Copy a file that failed to FAILED_CASES
+    pub fn copy_failed(&self, item: &Path, destination_path: &Path) -> Result<PathBuf> {
+        let failed_dir = self.phi_dir(destination_path, FAILED_CASES_DIR)?;
+        match self.dry_run {
+            true => Ok(self.copy_aside(item, &failed_dir)?),
+            false => failed_case_copy(item, destination_path),
+        }
+    }
+
+    // Copy an item that failed to FAILED_CASES and record its failed result. A copy that fails
+    // too is recorded with both errors, the run goes on with the other items
+    pub fn record_failed(
+        &self,
+        item: &Path,
+        destination_path: &Path,
+        error: &anyhow::Error,
+        duration: Duration,
+    ) {
+        self.progress.failed.inc(1);
+        let mut result = FileResult::new(item, "failed");
+        result.duration = duration;
+        result.error = error.to_string();
+        match self.copy_failed(item, destination_path) {
+            Ok(failed_path) => result.output = failed_path.display().to_string(),
+            Err(copy_error) => {
+                error!(
+                    "Can't copy {} to the FAILED_CASES directory: {:#}",
+                    item.display(),
+                    copy_error
+                );
+                result.error = format!(
+                    "{} (copy to FAILED_CASES failed: {})",
+                    result.error, copy_error
+                );
+            }
+        }
+        self.record_result(result);
+    }
+
+    // Copy a non DICOM item to NON_DICOM
+    pub fn copy_non_dicom(&self, item: &Path, destination_path: &Path) -> Result<PathBuf> {
+        match self.dry_run {
+            true => Ok(self.copy_aside(
+                item,
+                &destination_path
+                    .join("NON_DICOM")
+                    .join(self.source.non_dicom_dir(item)),
+            )?),
+            false => copy_non_dicom_files(self.source.as_ref(), item, destination_path),
+        }
+    }
+
+    // Copy, quarantine or hold a non DICOM item by the non DICOM action and record its result
+    // Text items to scrub are recorded when they are scrubbed, once the identifiers are known
+    pub fn handle_non_dicom(&self, item: &Path, destination_path: &Path) {
+        let review_reason = match self.non_dicom_action {
+            NonDicomAction::Copy => None,
+            NonDicomAction::Quarantine => Some("Non DICOM file, it may hold patient details"),
+            NonDicomAction::Scrub if self.is_text(item) => {
+                self.sidecars
+                    .lock()
+                    .expect("Failed to lock mutex")
+                    .push(item.to_path_buf());
+                return;
+            }
+            NonDicomAction::Scrub => {
+                Some("Non DICOM file that can't be scrubbed, it may hold patient details")
+            }
+        };
+        let mut result = FileResult::new(item, "non-DICOM");
+        let copied = match review_reason {
+            Some(reason) => {
+                result.status = "review";
+                self.reviewed.fetch_add(1, Ordering::Relaxed);
+                self.quarantine_non_dicom(item, destination_path, reason)
+            }
+            None => self.copy_non_dicom(item, destination_path),
+        };
+        match copied {
+            Ok(output) => result.output = output.display().to_string(),
+            Err(e) => {
+                error!(
+                    "Can't copy non dicom file {:#?}",
+                    item.file_name().unwrap_or_default()
+                );
+                result.error = e.to_string();
+            }
+        }
+        self.record_result(result);
+    }
+
+    // Copy a non DICOM item to REVIEW_REQUIRED/NON_DICOM with a note, review approve moves it
+    // to NON_DICOM
+    fn quarantine_non_dicom(
+        &self,
+        item: &Path,
+        destination_path: &Path,
+        reason: &str,
+    ) -> Result<PathBuf> {
+        let output = self.copy_aside(
+            item,
+            &self
+                .phi_dir(destination_path, REVIEW_REQUIRED_DIR)?
+                .join("NON_DICOM"),
+        )?;
+        if !self.dry_run {
+            write_review_note(&output.display().to_string(), reason)?;
+        }
+        Ok(output)
+    }
+
+    // UTF-8 text without NUL bytes in its first 8 KiB, eg reports, CSV, JSON or XML
+    fn is_text(&self, item: &Path) -> bool {
+        let mut head = vec![];
+        let read = self
+            .source
+            .open_bytes(item)
+            .and_then(|bytes| Ok(bytes.take(8192).read_to_end(&mut head)?));
+        if read.is_err() || head.is_empty() || head.contains(&0) {
+            return false;
+        }
+        match std::str::from_utf8(&head) {
+            Ok(_) => true,
+            // A character cut by the end of the head
+            Err(e) => e.error_len().is_none(),
+        }
+    }
+
+    // Identifiers of a source file, only collected when the text items are scrubbed
+    pub fn record_identifiers(&self, source: &Path, dcm_obj: &InMemDicomObject) {
+        if self.non_dicom_action != NonDicomAction::Scrub {
+            return;
+        }
+        let identifiers = identifying_values(dcm_obj);
+        if !identifiers.is_empty() {
+            self.sidecar_identifiers
+                .lock()
+                .expect("Failed to lock mutex")
+                .entry(identifiers)
+                .or_insert_with(|| source.to_path_buf());
+        }
+    }
+
+    pub fn incomplete_count(&self) -> u64 {
+        self.incomplete.load(Ordering::Relaxed)
+    }
+
+    pub fn is_large(&self, source: &Path) -> bool {
+        fs::metadata(source)
+            .map(|metadata| metadata.len() > self.stream_above)
+            .unwrap_or(false)
+    }
+
+    // Reason to quarantine a file over the large file limit: quarantine is asked, or it can't be
+    // streamed and reading it into memory could stall or exhaust the run
+    pub fn large_file_reason(&self, source: &Path) -> Option<String> {
+        if !self.is_large(source) {
+            return None;
+        }
+        if self.large_file_action == LargeFileAction::Quarantine {
+            return Some(format!(
+                "Larger than the large file limit of {} bytes",
+                self.stream_above
+            ));
+        }
+        match read_transfer_syntax(source) {
+            Some(ts_uid) if spliceable_transfer_syntax(&ts_uid).is_some() => None,
+            // Not DICOM, left to the non DICOM handling
+            None => None,
+            Some(ts_uid) => Some(format!(
+                "Larger than the large file limit of {} bytes and the transfer syntax {} can't be streamed",
+                self.stream_above, ts_uid
+            )),
+        }
+    }
+
+    // Copy a file over the large file limit to LARGE_FILES without reading it
+    pub fn quarantine(&self, item: &Path, destination_path: &Path, reason: String) {
+        warn!("Quarantined {}: {}", item.display(), reason);
+        self.quarantined.fetch_add(1, Ordering::Relaxed);
+        let mut result = FileResult::new(item, "quarantined");
+        match self
+            .phi_dir(destination_path, LARGE_FILES_DIR)
+            .and_then(|dir| self.copy_aside(item, &dir))
+        {
+            Ok(output) => result.output = output.display().to_string(),
+            Err(e) => error!(
+                "Can't copy {} to {}: {}",
+                item.display(),
+                LARGE_FILES_DIR,
+                e
+            ),
+        }
+        result.error = reason;
+        self.record_result(result);
+    }
+
+    // Copy a file not matched by --filter to FILTERED_OUT, it is excluded from the output
+    pub fn copy_filtered(&self, item: &Path, destination_path: &Path) {
+        let mut result = FileResult::new(item, "excluded");
+        match self
+            .phi_dir(destination_path, FILTERED_OUT_DIR)
+            .and_then(|dir| self.copy_aside(item, &dir))
+        {
+            Ok(output) => result.output = output.display().to_string(),
+            Err(e) => error!(
+                "Can't copy {} to {}: {}",
+                item.display(),
+                FILTERED_OUT_DIR,
+                e
+            ),
+        }
+        self.record_result(result);
+        self.excluded.fetch_add(1, Ordering::Relaxed);
+        self.progress.scanned.inc(1);
+    }
+
+    pub fn quarantined_count(&self) -> u64 {
+        self.quarantined.load(Ordering::Relaxed)
+    }
+
+    // Check if the pixel data of the source file is streamed instead of read into memory
+    // Only the little endian transfer syntaxes without a deflated dataset can be streamed,
+    // the large files of the others are quarantined before
+    pub fn streams(&self, source: &Path) -> bool {
+        self.is_large(source)
+            && read_transfer_syntax(source)
+                .and_then(|ts_uid| spliceable_transfer_syntax(&ts_uid))
+                .is_some()
+    }
+
+    // Free output path of a file, the name taken is kept in the result for results.csv
+    pub fn output_path(&self, full_path: String, sop_uid: &str, result: &mut FileResult) -> String {
+        let output_path = match &self.resumed_outputs {
+            // The files done after the last save of the checkpoint are written again in place,
+            // only the outputs of the checkpoint and of this run are taken
+            Some(taken) => {
+                let mut taken = taken.lock().expect("Failed to lock mutex");
+                let output_path = match taken.contains(&full_path) {
+                    true => unique_output_path(full_path.clone(), self.duplicate_suffix, sop_uid),
+                    false => full_path.clone(),
+                };
+                taken.insert(output_path.clone());
+                output_path
+            }
+            None => unique_output_path(full_path.clone(), self.duplicate_suffix, sop_uid),
+        };
+        if output_path != full_path {
+            self.collisions.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "Output name taken: {}, renamed to {}",
+                full_path, output_path
+            );
+            result.renamed_from = full_path;
+        }
+        // Only Windows limits the length of the paths
+        if cfg!(windows) {
+            let length = std::path::absolute(&output_path)
+                .map(|absolute| absolute.as_os_str().len())
+                .unwrap_or(output_path.len());
+            if length > WINDOWS_MAX_PATH {
+                self.long_paths.fetch_add(1, Ordering::Relaxed);
+                debug!("Output path of {} characters: {}", length, output_path);
+            }
+        }
+        output_path
+    }
+
+    pub fn record_result(&self, result: FileResult) {
+        if !result.error.is_empty() {
+            *self.last_error.lock().expect("Failed to lock mutex") =
+                Some(format!("{}: {}", result.source.display(), result.error));
+        }
+        // The file is done with
+        self.source_bytes
+            .lock()
+            .expect("Failed to lock mutex")
+            .remove(&result.source);
+        self.results
+            .lock()
+            .expect("Failed to lock mutex")
+            .push(result);
+    }
+
+    // Write one row per input file to results.csv in the destination
+    pub fn write_results(&self, destination_path: &Path) -> Result<()> {
+        let mut results = self.results.lock().expect("Failed to lock mutex");
+        results.sort_by(|a, b| a.source.cmp(&b.source));
+        let results_path = destination_path.join(RESULTS_FILE);
+        let mut results_file = BufWriter::new(fs::File::create(&results_path)?);
+        writeln!(
+            results_file,
+            "source,status,output,renamed_from,patient_id,study_uid,series_uid,duration_s,error"
+        )?;
+        for result in results.iter() {
+            writeln!(
+                results_file,
+                "{},{},{},{},{},{},{},{:.3},{}",
+                csv_field(&result.source.display().to_string()),
+                result.status,
+                csv_field(&result.output),
+                csv_field(&result.renamed_from),
+                csv_field(&result.patient_id),
+                csv_field(&result.study_uid),
+                csv_field(&result.series_uid),
+                result.duration.as_secs_f64(),
+                csv_field(&result.error)
+            )?;
+        }
+        results_file.flush()?;
+        info!("Results written: {}", results_path.display());
+        Ok(())
+    }
+
+    pub fn set_hooks(&mut self, hooks: PostHooks) {
+        self.hooks = Arc::new(hooks);
+    }
+
+    // Encode the instances without writing them, no hooks are run and no manifest is written
+    pub fn set_dry_run(&mut self) {
+        self.dry_run = true;
+        self.sink = Arc::new(DiscardSink);
+        self.hooks = Arc::new(PostHooks::default());
+        self.checksums = None;
+    }
+
+    // Print the output and the changed tags of each input file, or write them to the report CSV
+    // The tags changed the most often are logged at the end
+    pub fn write_dry_run_report(&self, report_path: Option<&Path>) -> Result<()> {
+        let mut results = self.results.lock().expect("Failed to lock mutex");
+        results.sort_by(|a, b| a.source.cmp(&b.source));
+        let mut tag_counts: BTreeMap<&str, u64> = BTreeMap::new();
+        for change in results.iter().flat_map(|result| &result.changes) {
+            *tag_counts.entry(change).or_default() += 1;
+        }
+        match report_path {
+            Some(report_path) => {
+                let mut report = BufWriter::new(fs::File::create(report_path)?);
+                writeln!(report, "source,status,output,renamed_from,changes,error")?;
+                for result in results.iter() {
+                    writeln!(
+                        report,
+                        "{},{},{},{},{},{}",
+                        csv_field(&result.source.display().to_string()),
+                        result.status,
+                        csv_field(&result.output),
+                        csv_field(&result.renamed_from),
+                        csv_field(&result.changes.join(" ")),
+                        csv_field(&result.error)
+                    )?;
+                }
+                report.flush()?;
+                info!("Dry run report written: {}", report_path.display());
+            }
+            None => {
+                for result in results.iter() {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        result.status,
+                        result.source.display(),
+                        result.output,
+                        result.changes.join(" ")
+                    );
+                }
+            }
+        }
+        let mut tag_counts: Vec<(&str, u64)> = tag_counts.into_iter().collect();
+        tag_counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        info!("Dry run, nothing was written. Tags that would change:");
+        for (change, count) in tag_counts {
+            info!("    {}: {} files", change, count);
+        }
+        Ok(())
+    }
+
+    // Run the post file hook and keep the file for the post study hook
+    // Files routed to REVIEW_REQUIRED are not passed to the hooks
+    pub fn file_written(
+        &self,
+        full_path: &str,
+        study_uid: &str,
+        expected_instances: Option<usize>,
+    ) {
+        if let Some(command) = &self.hooks.post_file {
+            run_hook(command, &[full_path.to_string()], study_uid);
+        }
+        if self.hooks.post_study.is_some() {
+            let mut study_files = self.study_files.lock().expect("Failed to lock mutex");
+            let study = study_files.entry(study_uid.to_string()).or_default();
+            study.files.push(full_path.to_string());
+            study.expected_instances = study.expected_instances.or(expected_instances);
+        }
+    }
+
+    // Run the post study hook once for each complete study, after all files are written
+    // A study is incomplete when fewer files were written than its NumberOfStudyRelatedInstances
+    pub fn run_study_hooks(&self) {
+        let command = match &self.hooks.post_study {
+            Some(command) => command,
+            None => return,
+        };
+        let study_files = self.study_files.lock().expect("Failed to lock mutex");
+        let mut complete = 0;
+        for (study_uid, study) in study_files.iter() {
+            match study.expected_instances {
+                Some(expected) if study.files.len() < expected => {
+                    warn!(
+                        "Study {} is incomplete, {} of {} instances written. Post study hook not run",
+                        study_uid,
+                        study.files.len(),
+                        expected
+                    );
+                    continue;
+                }
+                _ => (),
+            }
+            let mut files = study.files.clone();
+            files.sort();
+            run_hook(command, &files, study_uid);
+            complete += 1;
+        }
+        info!(
+            "Post study hook run for {} of {} studies",
+            complete,
+            study_files.len()
+        );
+    }
+
+    // Keep the study of a source file for the completeness report, before it is selected
+    pub fn record_study(&self, source: &Path, dcm_obj: &InMemDicomObject) {
+        let Some(expected_counts) = &self.expected_counts else {
+            return;
+        };
+        let key = dcm_obj
+            .get(expected_counts.key_tag)
+            .and_then(|element| element.to_str().ok())
+            .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+            .filter(|value| !value.is_empty());
+        // A file without the key, eg the DICOMDIR itself, is in no study
+        if let Some(key) = key {
+            self.source_studies
+                .lock()
+                .expect("Failed to lock mutex")
+                .insert(source.to_path_buf(), key);
+        }
+    }
+
+    // Expected studies and the studies of the run by the outcome of their source files
+    // The studies only seen in other shards are left out
+    pub fn study_completeness(&self) -> Vec<StudyCompleteness> {
+        let Some(expected_counts) = &self.expected_counts else {
+            return vec![];
+        };
+        let source_studies = self.source_studies.lock().expect("Failed to lock mutex");
+        // The frames of a split instance have a result each, the source is counted once
+        let mut outcomes: BTreeMap<&String, HashMap<&Path, &str>> = BTreeMap::new();
+        let results = self.results.lock().expect("Failed to lock mutex");
+        for result in results.iter() {
+            if let Some(key) = source_studies.get(&result.source) {
+                outcomes
+                    .entry(key)
+                    .or_default()
+                    .insert(&result.source, result.status);
+            }
+        }
+        let mut studies: BTreeMap<String, StudyCompleteness> = expected_counts
+            .studies
+            .iter()
+            .map(|(key, expected)| {
+                (
+                    key.clone(),
+                    StudyCompleteness {
+                        key: key.clone(),
+                        expected: Some(*expected),
+                        ..StudyCompleteness::default()
+                    },
+                )
+            })
+            .collect();
+        for (key, files) in outcomes {
+            if files.values().all(|status| *status == "skipped") {
+                continue;
+            }
+            let study = studies
+                .entry(key.clone())
+                .or_insert_with(|| StudyCompleteness {
+                    key: key.clone(),
+                    ..StudyCompleteness::default()
+                });
+            for status in files.values() {
+                match *status {
+                    "processed" => study.written += 1,
+                    "review" => study.review += 1,
+                    "excluded" => study.excluded += 1,
+                    "failed" => study.failed += 1,
+                    _ => (),
+                }
+            }
+        }
+        studies.into_values().collect()
+    }
+
+    // Log the studies with fewer or more instances than expected and write the report to
+    // COMPLETENESS_FILE, a dry run only logs them
+    pub fn report_completeness(&self, destination_path: &Path) -> Result<()> {
+        let Some(expected_counts) = &self.expected_counts else {
+            return Ok(());
+        };
+        let studies = self.study_completeness();
+        // Accession numbers are identifiers
+        let key = |study: &StudyCompleteness| match expected_counts.key_tag {
+            tags::ACCESSION_NUMBER => phi(&study.key),
+            _ => study.key.clone(),
+        };
+        let flagged: Vec<&StudyCompleteness> = studies
+            .iter()
+            .filter(|study| matches!(study.status(), "missing" | "incomplete" | "over"))
+            .collect();
+        match flagged.is_empty() {
+            true => info!(
+                "All {} studies of {} are complete",
+                expected_counts.studies.len(),
+                expected_counts.origin
+            ),
+            false => {
+                warn!(
+                    "{} of {} studies of {} are not complete:",
+                    flagged.len(),
+                    expected_counts.studies.len(),
+                    expected_counts.origin
+                );
+                for study in &flagged {
+                    warn!(
+                        "    {} {}: {} of {} instances written, {} review, {} excluded, {} failed",
+                        key(study),
+                        study.status(),
+                        study.written,
+                        study.expected.unwrap_or_default(),
+                        study.review,
+                        study.excluded,
+                        study.failed
+                    );
+                }
+            }
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+        let report_path = destination_path.join(COMPLETENESS_FILE);
+        let mut report = BufWriter::new(fs::File::create(&report_path)?);
+        writeln!(
+            report,
+            "{},status,expected,written,review,excluded,failed",
+            match expected_counts.key_tag {
+                tags::ACCESSION_NUMBER => "AccessionNumber",
+                _ => "StudyInstanceUID",
+            }
+        )?;
+        for study in &studies {
+            writeln!(
+                report,
+                "{},{},{},{},{},{},{}",
+                csv_field(&study.key),
+                study.status(),
+                study
+                    .expected
+                    .map_or(String::new(), |expected| expected.to_string()),
+                study.written,
+                study.review,
+                study.excluded,
+                study.failed
+            )?;
+        }
+        report.flush()?;
+        info!("Study completeness written: {}", report_path.display());
+        Ok(())
+    }
+
+    // Keep the source files whose Modality doesn't fit, see modality_mismatch
+    pub fn check_modality(&self, source: &Path, dcm_obj: &InMemDicomObject) {
+        if let Some(reason) = modality_mismatch(dcm_obj) {
+            debug!("Modality mismatch of {}: {}", source.display(), reason);
+            self.modality_mismatches
+                .lock()
+                .expect("Failed to lock mutex")
+                .insert(source.to_path_buf(), reason);
+        }
+    }
+
+    // Log the series of the output with a modality mismatch and write them to
+    // MODALITY_CHECK_FILE, a dry run only logs them
+    pub fn report_modality_mismatches(&self, destination_path: &Path) -> Result<()> {
+        let report_path = destination_path.join(MODALITY_CHECK_FILE);
+        let mismatches = self
+            .modality_mismatches
+            .lock()
+            .expect("Failed to lock mutex");
+        // SeriesInstanceUID of the output, reason > instances, first output
+        let mut series: BTreeMap<(String, String), (usize, String)> = BTreeMap::new();
+        for result in self.results.lock().expect("Failed to lock mutex").iter() {
+            if let Some(reason) = mismatches.get(&result.source) {
+                let entry = series
+                    .entry((result.series_uid.clone(), reason.clone()))
+                    .or_insert_with(|| (0, result.output.clone()));
+                entry.0 += 1;
+            }
+        }
+        if series.is_empty() {
+            // Not the report of an earlier run
+            if !self.dry_run && report_path.exists() {
+                fs::remove_file(&report_path)?;
+            }
+            return Ok(());
+        }
+        warn!(
+            "{} series with a Modality that doesn't fit their SOP class or pixels:",
+            series.len()
+        );
+        for ((series_uid, reason), (instances, _)) in &series {
+            warn!("    {} ({} instances): {}", series_uid, instances, reason);
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+        let mut report = BufWriter::new(fs::File::create(&report_path)?);
+        writeln!(report, "SeriesInstanceUID,reason,instances,output")?;
+        for ((series_uid, reason), (instances, output)) in &series {
+            writeln!(
+                report,
+                "{},{},{},{}",
+                csv_field(series_uid),
+                csv_field(reason),
+                instances,
+                csv_field(output)
+            )?;
+        }
+        report.flush()?;
+        info!("Modality check written: {}", report_path.display());
+        Ok(())
+    }
+
+    // Skip the instances whose SOPInstanceUID was already read, see is_duplicate
+    pub fn collect_instances(&mut self) {
+        self.kept_instances = Some(Arc::new(Mutex::new(HashMap::new())));
+    }
+
+    // Check if another source file with the SOPInstanceUID of this one was read before, the
+    // duplicate is then skipped. The first copy read is kept, files without a SOPInstanceUID are
+    // never duplicates
+    pub fn is_duplicate(&self, source: &Path, dcm_obj: &FileDicomObject<InMemDicomObject>) -> bool {
+        let Some(kept_instances) = &self.kept_instances else {
+            return false;
+        };
+        let sop_uid = sop_instance_uid(dcm_obj);
+        if sop_uid.is_empty() {
+            return false;
+        }
+        let kept = {
+            let mut kept_instances = kept_instances.lock().expect("Failed to lock mutex");
+            match kept_instances.get(&sop_uid) {
+                Some(kept) => kept.clone(),
+                None => {
+                    kept_instances.insert(sop_uid, source.to_path_buf());
+                    return false;
+                }
+            }
+        };
+        debug!("Duplicate of {}: {}", kept.display(), source.display());
+        let identical = same_file_content(&kept, source);
+        self.duplicates
+            .lock()
+            .expect("Failed to lock mutex")
+            .push(DuplicateInstance {
+                sop_uid,
+                kept,
+                duplicate: source.to_path_buf(),
+                identical,
+            });
+        self.record_result(FileResult::new(source, "duplicate"));
+        self.progress.scanned.inc(1);
+        true
+    }
+
+    pub fn duplicate_count(&self) -> u64 {
+        self.duplicates.lock().expect("Failed to lock mutex").len() as u64
+    }
+
+    // Log the duplicate instances skipped by the run and write them to DUPLICATES_FILE, a dry
+    // run only logs them
+    pub fn report_duplicates(&self, destination_path: &Path) -> Result<()> {
+        if self.kept_instances.is_none() {
+            return Ok(());
+        }
+        let report_path = destination_path.join(DUPLICATES_FILE);
+        let mut duplicates = self.duplicates.lock().expect("Failed to lock mutex");
+        if duplicates.is_empty() {
+            info!("No duplicate instances");
+            // Not the report of an earlier run
+            if !self.dry_run && report_path.exists() {
+                fs::remove_file(&report_path)?;
+            }
+            return Ok(());
+        }
+        duplicates.sort_by(|a, b| a.duplicate.cmp(&b.duplicate));
+        info!(
+            "{} duplicate instances skipped, their SOPInstanceUID was already read",
+            duplicates.len()
+        );
+        let different: Vec<&DuplicateInstance> = duplicates
+            .iter()
+            .filter(|duplicate| !duplicate.identical)
+            .collect();
+        if !different.is_empty() {
+            warn!(
+                "{} duplicate instances have the SOPInstanceUID of another file but not its content:",
+                different.len()
+            );
+            for duplicate in different {
+                warn!(
+                    "    {} (kept {})",
+                    duplicate.duplicate.display(),
+                    duplicate.kept.display()
+                );
+            }
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+        fs::write(&report_path, duplicate_instances_csv(&duplicates))?;
+        info!("Duplicate instances written: {}", report_path.display());
+        Ok(())
+    }
+
+    // Count what the transform of an instance did to its tags, see tag_changes
+    pub fn record_tag_changes(&self, changes: &[(Tag, TagChange)]) {
+        self.tag_change_instances.fetch_add(1, Ordering::Relaxed);
+        if changes
+            .iter()
+            .any(|(tag, change)| tag.group() % 2 == 1 && *change == TagChange::Removed)
+        {
+            self.private_tags_removed.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut tag_changes = self.tag_changes.lock().expect("Failed to lock mutex");
+        for (tag, change) in changes.iter().filter(|(tag, _)| tag.group() % 2 == 0) {
+            let counts = tag_changes.entry(*tag).or_default();
+            match change {
+                TagChange::Removed => counts.removed += 1,
+                TagChange::Replaced => counts.replaced += 1,
+                TagChange::Added => counts.added += 1,
+                TagChange::Kept => counts.kept += 1,
+            }
+        }
+    }
+
+    // Standard tags changed in at least one instance with their counts, in tag order
+    pub fn changed_tag_counts(&self) -> Vec<(Tag, TagCounts)> {
+        self.tag_changes
+            .lock()
+            .expect("Failed to lock mutex")
+            .iter()
+            .filter(|(_, counts)| counts.changed() > 0)
+            .map(|(tag, counts)| (*tag, *counts))
+            .collect()
+    }
+
+    // Tag changes of the run for the run summary, one line per changed tag
+    pub fn tag_change_lines(&self) -> Vec<(String, String)> {
+        let instances = self.tag_change_instances.load(Ordering::Relaxed);
+        self.changed_tag_counts()
+            .into_iter()
+            .map(|(tag, counts)| (tag_keyword(tag), counts.describe(instances)))
+            .collect()
+    }
+
+    // Log the tags changed the most often and write the counts of every changed tag and of the
+    // attributes of the basic profile to TAG_CHANGES_FILE. The dry run report lists them instead
+    pub fn report_tag_changes(&self, destination_path: &Path) -> Result<()> {
+        let instances = self.tag_change_instances.load(Ordering::Relaxed);
+        if self.dry_run || instances == 0 {
+            return Ok(());
+        }
+        let mut changed = self.changed_tag_counts();
+        changed.sort_by(|a, b| b.1.changed().cmp(&a.1.changed()).then(a.0.cmp(&b.0)));
+        info!("Tags changed in the {} transformed instances:", instances);
+        for (tag, counts) in changed.iter().take(TAG_CHANGES_LOGGED) {
+            info!("    {}: {}", tag_keyword(*tag), counts.describe(instances));
+        }
+        if changed.len() > TAG_CHANGES_LOGGED {
+            info!(
+                "    and {} other tags, see {}",
+                changed.len() - TAG_CHANGES_LOGGED,
+                TAG_CHANGES_FILE
+            );
+        }
+        let private_tags_removed = self.private_tags_removed.load(Ordering::Relaxed);
+        if private_tags_removed > 0 {
+            info!(
+                "    Private tags: removed in {} instances",
+                private_tags_removed
+            );
+        }
+
+        let mut rows = self
+            .tag_changes
+            .lock()
+            .expect("Failed to lock mutex")
+            .clone();
+        for tag in basic_profile_tags() {
+            rows.entry(tag).or_default();
+        }
+        let report_path = destination_path.join(TAG_CHANGES_FILE);
+        let mut report = BufWriter::new(fs::File::create(&report_path)?);
+        writeln!(report, "tag,keyword,removed,replaced,added,kept,missing")?;
+        for (tag, counts) in rows {
+            writeln!(
+                report,
+                "{},{},{},{},{},{},{}",
+                csv_field(&tag.to_string()),
+                tag_keyword(tag),
+                counts.removed,
+                counts.replaced,
+                counts.added,
+                counts.kept,
+                counts.missing(instances)
+            )?;
+        }
+        report.flush()?;
+        info!("Tag changes written: {}", report_path.display());
+        Ok(())
+    }
+
+    // Take the results of the files done before a resume, and count them as the run would have
+    pub fn resume(&mut self, checkpoint: &Checkpoint) {
+        if !checkpoint.resumed {
+            return;
+        }
+        for result in &checkpoint.results {
+            let counter = match result.status {
+                "skipped" => &self.skipped,
+                "excluded" => &self.excluded,
+                "review" => &self.reviewed,
+                "quarantined" => &self.quarantined,
+                "incomplete" => &self.incomplete,
+                _ => {
+                    match result.status {
+                        "processed" => self.progress.written.inc(1),
+                        "failed" => self.progress.failed.inc(1),
+                        _ => (),
+                    }
+                    continue;
+                }
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            if result.status == "review" {
+                self.progress.written.inc(1);
+            }
+        }
+        self.progress.scanned.inc(checkpoint.done.len() as u64);
+        if let Some(checksums) = &self.checksums {
+            checksums
+                .lock()
+                .expect("Failed to lock mutex")
+                .extend(checkpoint.checksums.iter().cloned());
+        }
+        self.resumed_outputs = Some(Arc::new(Mutex::new(
+            checkpoint
+                .results
+                .iter()
+                .map(|result| result.output.clone())
+                .filter(|output| !output.is_empty())
+                .collect(),
+        )));
+        self.results
+            .lock()
+            .expect("Failed to lock mutex")
+            .extend(checkpoint.results.iter().cloned());
+    }
+
+    // Collect the SHA-256 of each written file for the manifest
+    pub fn collect_checksums(&mut self) {
+        self.checksums = Some(Arc::new(Mutex::new(vec![])));
+    }
+
+    pub fn record_checksum(&self, full_path: &str, digest: Option<String>) {
+        if let (Some(checksums), Some(digest)) = (&self.checksums, digest) {
+            checksums
+                .lock()
+                .expect("Failed to lock mutex")
+                .push((full_path.to_string(), digest));
+        }
+    }
+
+    // Write the collected checksums to the destination in the sha256sum format
+    // Verify with: cd DEST && sha256sum -c MANIFEST.sha256
+    pub fn write_manifest(&self, destination_path: &Path) -> Result<Option<PathBuf>> {
+        let checksums = match &self.checksums {
+            Some(checksums) => checksums.lock().expect("Failed to lock mutex"),
+            None => return Ok(None),
+        };
+        let mut entries: Vec<(String, &String)> = checksums
+            .iter()
+            .map(|(full_path, digest)| {
+                let relative = Path::new(full_path)
+                    .strip_prefix(destination_path)
+                    .unwrap_or(Path::new(full_path));
+                (relative.display().to_string(), digest)
+            })
+            .collect();
+        entries.sort();
+        let manifest_path = destination_path.join(MANIFEST_FILE);
+        let mut manifest = BufWriter::new(fs::File::create(&manifest_path)?);
+        for (relative, digest) in entries {
+            writeln!(manifest, "{}  {}", digest, relative)?;
+        }
+        manifest.flush()?;
+        info!(
+            "Manifest of {} files written: {}",
+            checksums.len(),
+            manifest_path.display()
+        );
+        Ok(Some(manifest_path))
+    }
+
+    // Fetch an item of the source before it is read, an item that can't be fetched is failed
+    pub fn fetch_item(&self, source: &Path) -> Result<()> {
+        self.source.fetch(source).inspect_err(|e| {
+            error!("Can't fetch {}: {}", source.display(), e);
+            self.progress.failed.inc(1);
+            self.progress.scanned.inc(1);
+            let mut result = FileResult::new(source, "failed");
+            result.error = e.to_string();
+            self.record_result(result);
+        })
+    }
+
+    // File left for another shard
+    pub fn skip_file(&self, source: &Path) {
+        self.record_result(FileResult::new(source, "skipped"));
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        self.progress.scanned.inc(1);
+    }
+
+    // File left out of the output before it is transformed
+    pub fn exclude_file(&self, source: &Path, reason: &str) {
+        debug!("Excluded from the output: {}: {}", source.display(), reason);
+        self.record_result(FileResult::new(source, "excluded"));
+        self.excluded.fetch_add(1, Ordering::Relaxed);
+        self.progress.scanned.inc(1);
+    }
+
+    pub fn skipped_count(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    pub fn record_timing(&self, timing: FileTiming) {
+        self.timings
+            .lock()
+            .expect("Failed to lock mutex")
+            .push(timing);
+    }
+
+    // Output directory of a transformed file based on the rule or review policy reason
+    // Returns None if the file is excluded from the output
+    pub fn route_file(
+        &self,
+        review_reason: Option<(ReviewAction, String)>,
+        destination_path: &Path,
+    ) -> Option<(PathBuf, Option<String>)> {
+        match review_reason {
+            Some((ReviewAction::Exclude, reason)) => {
+                info!("Excluded from the output: {}", reason);
+                self.excluded.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Some((ReviewAction::Review, reason)) => {
+                self.reviewed.fetch_add(1, Ordering::Relaxed);
+                let review_dir = self
+                    .phi_dir(destination_path, REVIEW_REQUIRED_DIR)
+                    .unwrap_or_else(|e| {
+                        error!("Can't create {}: {}", REVIEW_REQUIRED_DIR, e);
+                        destination_path.join(REVIEW_REQUIRED_DIR)
+                    });
+                Some((review_dir, Some(reason)))
+            }
+            _ => Some((destination_path.to_path_buf(), None)),
+        }
+    }
+
+    // Progress, rates and ETA of the run as JSON
+    pub fn status_json(
+        &self,
+        action: &str,
+        run_name: Option<&str>,
+        state: &str,
+        started: DateTime<Local>,
+        elapsed: Duration,
+    ) -> String {
+        let total = self.progress.scanned.length().unwrap_or(0);
+        let scanned = self.progress.scanned.position();
+        let written = self.progress.written.position();
+        let seconds = elapsed.as_secs_f64();
+        let rate = |count: u64| match seconds > 0.0 {
+            true => count as f64 / seconds,
+            false => 0.0,
+        };
+        let eta = match (state, rate(scanned) > 0.0) {
+            ("running", true) => {
+                format!(
+                    "{:.0}",
+                    total.saturating_sub(scanned) as f64 / rate(scanned)
+                )
+            }
+            ("running", false) => "null".to_string(),
+            _ => "0".to_string(),
+        };
+        let last_error = self
+            .last_error
+            .lock()
+            .expect("Failed to lock mutex")
+            .as_deref()
+            .map_or("null".to_string(), json_string);
+        let fields = [
+            ("action", json_string(action)),
+            ("run_name", run_name.map_or("null".to_string(), json_string)),
+            ("state", json_string(state)),
+            ("pid", std::process::id().to_string()),
+            (
+                "started",
+                json_string(&started.format("%Y-%m-%dT%H:%M:%S%:z").to_string()),
+            ),
+            (
+                "updated",
+                json_string(&Local::now().format("%Y-%m-%dT%H:%M:%S%:z").to_string()),
+            ),
+            ("elapsed_seconds", format!("{:.0}", seconds)),
+            ("total", total.to_string()),
+            ("scanned", scanned.to_string()),
+            (
+                "transformed",
+                self.progress.transformed.position().to_string(),
+            ),
+            ("written", written.to_string()),
+            ("failed", self.progress.failed.position().to_string()),
+            ("skipped", self.skipped_count().to_string()),
+            ("incomplete", self.incomplete_count().to_string()),
+            ("quarantined", self.quarantined_count().to_string()),
+            ("scanned_per_second", format!("{:.2}", rate(scanned))),
+            ("written_per_second", format!("{:.2}", rate(written))),
+            ("eta_seconds", eta),
+            ("last_error", last_error),
+        ];
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|(name, value)| format!("  {}: {}", json_string(name), value))
+            .collect();
+        format!("{{\n{}\n}}\n", fields.join(",\n"))
+    }
+
+    pub fn print_collisions(&self) {
+        let collisions = self.collisions.load(Ordering::Relaxed);
+        if collisions > 0 {
+            warn!(
+                "{} output files were renamed because their name was taken, see {}",
+                collisions, RESULTS_FILE
+            );
+        }
+    }
+
+    // Long paths are written fine, but Windows tools without long path support can't open them
+    // They are only counted on Windows
+    pub fn print_long_paths(&self) {
+        let long_paths = self.long_paths.load(Ordering::Relaxed);
+        if long_paths > 0 {
+            warn!(
+                "{} output paths are longer than {} characters, Explorer and the Windows tools \
+                without long path support can't open them. Use a shorter destination",
+                long_paths, WINDOWS_MAX_PATH
+            );
+        }
+    }
+
+    // Record the PatientName and PatientBirthDate of the source file under its PatientID
+    pub fn record_identity(&self, dcm_obj: &InMemDicomObject) {
+        let value_of = |tag: Tag| {
+            dcm_obj
+                .element(tag)
+                .ok()
+                .and_then(|element| element.to_str().ok().map(|v| v.to_string()))
+                .unwrap_or_default()
+        };
+        let patient_id = value_of(tags::PATIENT_ID).trim().to_string();
+        // DOE^JOHN and doe^john^^ are the same name
+        let name = value_of(tags::PATIENT_NAME)
+            .trim_end_matches(['^', ' ', '\0'])
+            .to_uppercase();
+        let birth_date = value_of(tags::PATIENT_BIRTH_DATE).trim().to_string();
+        let mut identities = self.identities.lock().expect("Failed to lock mutex");
+        let (names, birth_dates) = identities.entry(patient_id).or_default();
+        names.insert(name);
+        // A missing birth date doesn't tell two patients apart
+        if !birth_date.is_empty() {
+            birth_dates.insert(birth_date);
+        }
+    }
+
+    // PatientIDs shared by different PatientNames or PatientBirthDates, likely upstream merge errors
+    pub fn mixed_patients(&self) -> Vec<(String, usize, usize)> {
+        self.identities
+            .lock()
+            .expect("Failed to lock mutex")
+            .iter()
+            .filter(|(_, (names, birth_dates))| names.len() > 1 || birth_dates.len() > 1)
+            .map(|(patient_id, (names, birth_dates))| {
+                (patient_id.clone(), names.len(), birth_dates.len())
+            })
+            .collect()
+    }
+
+    pub fn print_mixed_patients(&self) {
+        let mixed = self.mixed_patients();
+        if mixed.is_empty() {
+            return;
+        }
+        warn!(
+            "{} PatientIDs are shared by different patients, their files are folded into one subject:",
+            mixed.len()
+        );
+        for (patient_id, names, birth_dates) in mixed {
+            warn!(
+                "    {}: {} PatientNames, {} PatientBirthDates",
+                patient_id, names, birth_dates
+            );
+        }
+    }
+
+    pub fn print_routing(&self) {
+        let reviewed = self.reviewed.load(Ordering::Relaxed);
+        let excluded = self.excluded.load(Ordering::Relaxed);
+        if reviewed > 0 {
+            warn!(
+                "{} files routed to {} need a manual review",
+                reviewed, REVIEW_REQUIRED_DIR
+            );
+        }
+        if excluded > 0 {
+            info!("{} files excluded from the output", excluded);
+        }
+    }
+
+    // Keep the class of the series of a written file for the summary
+    pub fn record_series(&self, dicom_tags_values: &HashMap<String, String>) {
+        if let (Some(series_uid), Some(series_class)) = (
+            dicom_tags_values.get("SeriesInstanceUID"),
+            dicom_tags_values.get("SeriesClass"),
+        ) {
+            let mut series_classes = self.series_classes.lock().expect("Failed to lock mutex");
+            // The label and overview of a slide often share the series of its pyramid levels
+            let slide_image = matches!(series_class.as_str(), "SlideLabel" | "SlideOverview");
+            if !(slide_image && series_classes.contains_key(series_uid)) {
+                series_classes.insert(series_uid.clone(), series_class.clone());
+            }
+        }
+    }
+
+    // Number of series of each class
+    pub fn print_series_classes(&self) {
+        let series_classes = self.series_classes.lock().expect("Failed to lock mutex");
+        if series_classes.is_empty() {
+            return;
+        }
+        let mut class_counts: BTreeMap<&String, u64> = BTreeMap::new();
+        for series_class in series_classes.values() {
+            *class_counts.entry(series_class).or_default() += 1;
+        }
+        info!("Series classes:");
+        for (series_class, count) in class_counts {
+            info!("    {}: {} series", series_class, count);
+        }
+    }
+}
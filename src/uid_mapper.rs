@@ -0,0 +1,227 @@
+use crate::{
+    checkpoint::read_checkpoint_records, csv_field, hmac_sha256, sha256, to_hex, ANON_ID_LENGTH,
+    MAX_DATE_SHIFT_DAYS,
+};
+use anyhow::Result;
+use dicom::{
+    core::{header::Header, DataElement, PrimitiveValue, VR},
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject, Tag},
+};
+use nanoid::nanoid;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// UIDs of classes, transfer syntaxes and coding schemes, they name no instance and are kept
+const CLASS_UID_TAGS: [Tag; 16] = [
+    tags::SOP_CLASS_UID,
+    tags::REFERENCED_SOP_CLASS_UID,
+    tags::REFERENCED_SOP_CLASS_UID_IN_FILE,
+    tags::RELATED_GENERAL_SOP_CLASS_UID,
+    tags::REFERENCED_RELATED_GENERAL_SOP_CLASS_UID_IN_FILE,
+    tags::ORIGINAL_SPECIALIZED_SOP_CLASS_UID,
+    tags::TRANSFER_SYNTAX_UID,
+    tags::REFERENCED_TRANSFER_SYNTAX_UID_IN_FILE,
+    tags::STORED_INSTANCE_TRANSFER_SYNTAX_UID,
+    tags::IMPLEMENTATION_CLASS_UID,
+    tags::PRIVATE_INFORMATION_CREATOR_UID,
+    tags::CODING_SCHEME_UID,
+    tags::CONTEXT_GROUP_EXTENSION_CREATOR_UID,
+    tags::MAPPING_RESOURCE_UID,
+    tags::MANUFACTURER_DEVICE_CLASS_UID,
+    tags::CREATOR_VERSION_UID,
+];
+
+// Registered UIDs, eg the SOP classes, transfer syntaxes and well known frames of reference
+const DICOM_UID_ROOT: &str = "1.2.840.10008.";
+
+// Remaps the instance UIDs of a run, each original UID gets a 2.25 UID from the HMAC-SHA256 of
+// the original. Every UI element is remapped wherever it is, in sequences too, so the references
+// between instances, series, studies and frames of reference still resolve
+// With the same secret every run gives the same UIDs, eg the shards of a migration. Without it
+// a random secret is drawn for the run. The secret keeps the UIDs from being linked back to the
+// source by hashing its known UIDs
+#[derive(Debug, Clone)]
+pub struct UidMapper {
+    secret: Vec<u8>,
+    pub secret_path: Option<PathBuf>,
+}
+
+impl Default for UidMapper {
+    fn default() -> Self {
+        UidMapper::random()
+    }
+}
+
+impl UidMapper {
+    pub fn random() -> Self {
+        UidMapper {
+            secret: nanoid!(32).into_bytes(),
+            secret_path: None,
+        }
+    }
+
+    // Secret record of the checkpoint: the random secret of the run, or the SHA-256 of the
+    // secret file, which is not copied
+    pub(crate) fn checkpoint_record(&self) -> String {
+        match &self.secret_path {
+            Some(_) => format!("secret,file,{}", to_hex(&sha256(&self.secret))),
+            None => format!(
+                "secret,random,{}",
+                csv_field(&String::from_utf8_lossy(&self.secret))
+            ),
+        }
+    }
+
+    /// UID mapper of a run resumed from the checkpoint: the random secret of the interrupted run
+    /// is used again, so the rest of its instances get the same UIDs, date shifts and hashed
+    /// ANON IDs. A --uid-secret must be the one the checkpoint was started with
+    pub fn resumed_from(self, checkpoint: Option<&Path>) -> Result<Self> {
+        let Some(checkpoint) = checkpoint else {
+            return Ok(self);
+        };
+        let records = read_checkpoint_records(checkpoint)?;
+        let Some(record) = records.iter().find(|record| record[0] == "secret") else {
+            return Ok(self);
+        };
+        match (record.get(1).map(String::as_str), &self.secret_path) {
+            (Some("random"), None) if record.len() > 2 => Ok(UidMapper {
+                secret: record[2].as_bytes().to_vec(),
+                secret_path: None,
+            }),
+            (Some("file"), Some(_)) if record.get(2) == Some(&to_hex(&sha256(&self.secret))) => {
+                Ok(self)
+            }
+            (Some("random"), Some(_)) => Err(anyhow::anyhow!(
+                "The checkpoint {} was started without --uid-secret",
+                checkpoint.display()
+            )),
+            (Some("file"), None) => Err(anyhow::anyhow!(
+                "The checkpoint {} was started with --uid-secret, give the same one",
+                checkpoint.display()
+            )),
+            _ => Err(anyhow::anyhow!(
+                "The checkpoint {} was started with another --uid-secret",
+                checkpoint.display()
+            )),
+        }
+    }
+
+    pub fn from_file(secret_path: &Path) -> Result<Self> {
+        let secret = fs::read(secret_path).map_err(|e| {
+            anyhow::anyhow!("Can't read the UID secret {}: {}", secret_path.display(), e)
+        })?;
+        let secret = secret.trim_ascii().to_vec();
+        if secret.len() < 16 {
+            return Err(anyhow::anyhow!(
+                "UID secret {} should have at least 16 characters",
+                secret_path.display()
+            ));
+        }
+        Ok(UidMapper {
+            secret,
+            secret_path: Some(secret_path.to_path_buf()),
+        })
+    }
+
+    // New UID of an original UID
+    pub fn new_uid(&self, uid: &str) -> String {
+        let digest = hmac_sha256(&self.secret, uid.trim_end_matches(['\0', ' ']).as_bytes());
+        let mut high = [0u8; 16];
+        high.copy_from_slice(&digest[..16]);
+        format!("2.25.{}", u128::from_be_bytes(high))
+    }
+
+    // Days the dates of a patient are shifted back by, keyed like the UIDs so a patient keeps
+    // the same shift across the runs and shards of one secret
+    pub fn date_shift_days(&self, patient: &str) -> u64 {
+        let digest = hmac_sha256(&self.secret, format!("date-shift\\{}", patient).as_bytes());
+        u64::from(u16::from_be_bytes([digest[0], digest[1]]) % MAX_DATE_SHIFT_DAYS + 1)
+    }
+
+    // ANON ID of a patient keyed on its original identifier, in the characters of gen_id, so the
+    // sites of a study that share the secret give a patient the same ID without a mapping file
+    pub fn anon_id(&self, patient: &str) -> String {
+        let alphabet = &nanoid::alphabet::SAFE[2..];
+        let digest = hmac_sha256(&self.secret, format!("anon-id\\{}", patient).as_bytes());
+        let mut high = [0u8; 16];
+        high.copy_from_slice(&digest[..16]);
+        let mut value = u128::from_be_bytes(high);
+        (0..ANON_ID_LENGTH)
+            .map(|_| {
+                let c = alphabet[(value % alphabet.len() as u128) as usize];
+                value /= alphabet.len() as u128;
+                c
+            })
+            .collect()
+    }
+
+    // Check if the UID of the element names an instance, a series, a study or any other entity
+    // of the data, rather than a class
+    pub fn is_instance_uid(tag: Tag, uid: &str) -> bool {
+        !uid.is_empty() && !uid.starts_with(DICOM_UID_ROOT) && !CLASS_UID_TAGS.contains(&tag)
+    }
+
+    // Remap the instance UIDs of the object and its sequences, returns the number remapped
+    pub fn remap(&self, dcm_obj: &mut InMemDicomObject) -> usize {
+        let elements: Vec<(Tag, VR)> = dcm_obj
+            .iter()
+            .filter(|element| matches!(element.vr(), VR::UI | VR::SQ))
+            .map(|element| (element.tag(), element.vr()))
+            .collect();
+        let mut remapped = 0;
+        for (tag, vr) in elements {
+            if vr == VR::SQ {
+                dcm_obj.update_value(tag, |value| {
+                    for item in value.items_mut().into_iter().flatten() {
+                        remapped += self.remap(item);
+                    }
+                });
+                continue;
+            }
+            let uids: Vec<String> = match dcm_obj.get(tag).map(|element| element.to_multi_str()) {
+                Some(Ok(uids)) => uids
+                    .iter()
+                    .map(|uid| uid.trim_end_matches(['\0', ' ']).to_string())
+                    .collect(),
+                _ => continue,
+            };
+            if !uids.iter().any(|uid| UidMapper::is_instance_uid(tag, uid)) {
+                continue;
+            }
+            let new_uids: Vec<String> = uids
+                .iter()
+                .map(|uid| match UidMapper::is_instance_uid(tag, uid) {
+                    true => {
+                        remapped += 1;
+                        self.new_uid(uid)
+                    }
+                    false => uid.clone(),
+                })
+                .collect();
+            dcm_obj.put(DataElement::new(
+                tag,
+                VR::UI,
+                PrimitiveValue::Strs(new_uids.into()),
+            ));
+        }
+        remapped
+    }
+
+    // Remap the UIDs of the dataset and the MediaStorageSOPInstanceUID of the file meta
+    pub fn remap_file(&self, dcm_obj: &mut FileDicomObject<InMemDicomObject>) -> usize {
+        let remapped = self.remap(dcm_obj);
+        let sop_instance_uid = dcm_obj
+            .get(tags::SOP_INSTANCE_UID)
+            .and_then(|element| element.to_str().ok())
+            .map(|uid| uid.trim_end_matches(['\0', ' ']).to_string());
+        if let Some(sop_instance_uid) = sop_instance_uid {
+            let meta = dcm_obj.meta_mut();
+            meta.media_storage_sop_instance_uid = sop_instance_uid;
+            meta.update_information_group_length();
+        }
+        remapped
+    }
+}